@@ -1,6 +1,9 @@
-use self::common::{bench_compute_hash, bench_get, bench_insert};
-use common::{bench_compute_hash_inserts, bench_compute_hash_sorted};
+use self::common::{
+    bench_compute_hash, bench_get, bench_get_cached, bench_get_missing, bench_insert,
+};
+use common::{bench_compute_hash_inserts, bench_compute_hash_sorted, bench_map_insert};
 use criterion::{criterion_group, criterion_main, Criterion};
+use patricia_merkle_tree::CacheConfig;
 use sha3::Keccak256;
 use std::time::Duration;
 
@@ -43,6 +46,40 @@ fn criterion_benchmark(c: &mut Criterion) {
         .bench_function("10M", bench_get::<10_000_000>())
         .bench_function("100M", bench_get::<100_000_000>());
 
+    // Disabled caches aren't even locked, so they should cost nothing compared to enabled ones.
+    c.benchmark_group("get() from a tree with a leaf cache")
+        .bench_function(
+            "disabled",
+            bench_get_cached::<100_000>(CacheConfig::default()),
+        )
+        .bench_function(
+            "1k entries",
+            bench_get_cached::<100_000>(CacheConfig {
+                leaf_cache: Some(1_000),
+                ..Default::default()
+            }),
+        )
+        .bench_function(
+            "100k entries",
+            bench_get_cached::<100_000>(CacheConfig {
+                leaf_cache: Some(100_000),
+                ..Default::default()
+            }),
+        );
+
+    c.benchmark_group("get() of missing paths from a tree with a negative cache")
+        .bench_function(
+            "disabled",
+            bench_get_missing::<100_000>(CacheConfig::default()),
+        )
+        .bench_function(
+            "1k entries",
+            bench_get_missing::<100_000>(CacheConfig {
+                negative_cache: Some(1_000),
+                ..Default::default()
+            }),
+        );
+
     c.benchmark_group("insert() from a tree made with random values")
         .bench_function("1k", bench_insert::<1_000>())
         .bench_function("10k", bench_insert::<10_000>())
@@ -51,6 +88,12 @@ fn criterion_benchmark(c: &mut Criterion) {
         .sample_size(10)
         .bench_function("10M", bench_insert::<10_000_000>())
         .bench_function("100M", bench_insert::<100_000_000>());
+
+    c.benchmark_group("insert() from a PatriciaMap made with random values")
+        .bench_function("1k", bench_map_insert::<1_000>())
+        .bench_function("10k", bench_map_insert::<10_000>())
+        .bench_function("100k", bench_map_insert::<100_000>())
+        .bench_function("1M", bench_map_insert::<1_000_000>());
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,6 +1,6 @@
 use criterion::{black_box, Bencher};
 use digest::Digest;
-use patricia_merkle_tree::PatriciaMerkleTree;
+use patricia_merkle_tree::{CacheConfig, PatriciaMap, PatriciaMerkleTree};
 use rand::{distributions::Uniform, prelude::Distribution, thread_rng, RngCore};
 use sha3::Keccak256;
 use std::{
@@ -9,8 +9,13 @@ use std::{
 };
 
 pub fn bench_get<const N: usize>() -> impl FnMut(&mut Bencher) {
+    bench_get_cached::<N>(CacheConfig::default())
+}
+
+/// Same as [`bench_get`], but with the given lookup caches.
+pub fn bench_get_cached<const N: usize>(config: CacheConfig) -> impl FnMut(&mut Bencher) {
     // Generate a completely random Patricia Merkle tree.
-    let mut tree = PatriciaMerkleTree::<Vec<u8>, &[u8; 32], Keccak256>::new();
+    let mut tree = PatriciaMerkleTree::<Vec<u8>, &[u8; 32], Keccak256>::with_cache_config(&config);
     let mut all_paths = Vec::with_capacity(N);
 
     let value = &[0; 32];
@@ -35,6 +40,44 @@ pub fn bench_get<const N: usize>() -> impl FnMut(&mut Bencher) {
     }
 }
 
+/// Look up the same thousand paths, none of which are in the tree, with the given lookup caches.
+pub fn bench_get_missing<const N: usize>(config: CacheConfig) -> impl FnMut(&mut Bencher) {
+    // Generate a completely random Patricia Merkle tree.
+    let mut tree = PatriciaMerkleTree::<Vec<u8>, &[u8; 32], Keccak256>::with_cache_config(&config);
+
+    let value = &[0; 32];
+
+    let mut rng = thread_rng();
+    let distr = Uniform::from(16..=64);
+
+    while tree.len() < N {
+        let path_len = distr.sample(&mut rng) as usize;
+
+        let mut path = vec![0; path_len];
+        rng.fill_bytes(&mut path);
+
+        tree.insert(path, value);
+    }
+
+    // Generate random paths which aren't in the tree.
+    let mut missing_paths = Vec::new();
+    while missing_paths.len() < 1000 {
+        let path_len = distr.sample(&mut rng) as usize;
+
+        let mut path = vec![0; path_len];
+        rng.fill_bytes(&mut path);
+
+        if tree.get(&path).is_none() {
+            missing_paths.push(path);
+        }
+    }
+
+    move |b| {
+        let mut path_iter = missing_paths.iter().cycle();
+        b.iter(|| tree.get(black_box(path_iter.next().unwrap())));
+    }
+}
+
 pub fn bench_insert<const N: usize>() -> impl FnMut(&mut Bencher) {
     // Generate a completely random Patricia Merkle tree.
     let mut tree = PatriciaMerkleTree::<Vec<u8>, _, Keccak256>::new();
@@ -81,7 +124,6 @@ pub fn bench_insert<const N: usize>() -> impl FnMut(&mut Bencher) {
                 let mut tree = tree.clone();
 
                 let mut path_iter = new_nodes.into_iter().cycle();
-                tree.reserve_next_power_of_two();
 
                 // To make measurements more effective, values are inserted STEP at a time, making
                 // all values except the first one to be inserted with a tree slightly larger than
@@ -219,3 +261,54 @@ pub fn bench_compute_hash_sorted<const N: usize, H: Digest + Clone>() -> impl Fn
         });
     }
 }
+
+pub fn bench_map_insert<const N: usize>() -> impl FnMut(&mut Bencher) {
+    // Generate a completely random map.
+    let mut map = PatriciaMap::<Vec<u8>, i32>::new();
+
+    let mut rng = thread_rng();
+    let distr = Uniform::from(16..=64);
+
+    while map.len() < N {
+        let path_len = distr.sample(&mut rng) as usize;
+
+        let mut path = vec![0; path_len];
+        rng.fill_bytes(&mut path);
+
+        map.insert(path, 0);
+    }
+
+    // Generate random nodes to insert.
+    let mut new_nodes = Vec::new();
+    while new_nodes.len() < 1000 {
+        let path_len = distr.sample(&mut rng) as usize;
+
+        let mut path = vec![0; path_len];
+        rng.fill_bytes(&mut path);
+
+        if map.get(&path).is_none() {
+            new_nodes.push((path, 0));
+        }
+    }
+
+    move |b| {
+        b.iter_custom(|num_iters| {
+            const STEP: usize = 1024;
+
+            let mut delta = Duration::ZERO;
+            for offset in (0..num_iters).step_by(STEP) {
+                let mut map = map.clone();
+                let mut path_iter = new_nodes.clone().into_iter().cycle();
+
+                let measure = Instant::now();
+                for _ in offset..num_iters.min(offset + STEP as u64) {
+                    let (path, value) = path_iter.next().unwrap();
+                    map.insert(black_box(path), black_box(value));
+                }
+                delta += measure.elapsed();
+            }
+
+            delta
+        });
+    }
+}
@@ -40,7 +40,7 @@ where
     V: Encode + Serialize + for<'de> Deserialize<'de>,
     H: Digest,
 {
-    fn encode(&self) -> Cow<[u8]> {
+    fn encode(&self) -> Cow<'_, [u8]> {
         let value: V = StorageTree::<P, V, H>::load_value(&self.0, &self.1).unwrap();
         Cow::Owned(value.encode().into_owned())
     }
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use patricia_merkle_tree::{apply_ops, TreeOp};
+use sha3::Keccak256;
+
+fuzz_target!(|ops: Vec<TreeOp>| {
+    apply_ops::<Keccak256>(ops);
+});
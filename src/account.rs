@@ -0,0 +1,259 @@
+use crate::{
+    hashing::{NodeEncoder, NodeWriter},
+    rlp::{decode_list, expect_list, DecodeError, RlpItem},
+    PatriciaMerkleTree,
+};
+use digest::Digest;
+
+/// Storage root of an account without storage (the hash of an empty tree).
+pub const EMPTY_STORAGE_ROOT: [u8; 32] = [
+    0x56, 0xE8, 0x1F, 0x17, 0x1B, 0xCC, 0x55, 0xA6, 0xFF, 0x83, 0x45, 0xE6, 0x92, 0xC0, 0xF8, 0x6E,
+    0x5B, 0x48, 0xE0, 0x1B, 0x99, 0x6C, 0xAD, 0xC0, 0x01, 0x62, 0x2F, 0xB5, 0xE3, 0x63, 0xB4, 0x21,
+];
+
+/// Code hash of an account without code (the hash of an empty string).
+pub const EMPTY_CODE_HASH: [u8; 32] = [
+    0xC5, 0xD2, 0x46, 0x01, 0x86, 0xF7, 0x23, 0x3C, 0x92, 0x7E, 0x7D, 0xB2, 0xDC, 0xC7, 0x03, 0xC0,
+    0xE5, 0x00, 0xB6, 0x53, 0xCA, 0x82, 0x27, 0x3B, 0x7B, 0xFA, 0xD8, 0x04, 0x5D, 0x85, 0xA4, 0x70,
+];
+
+/// An Ethereum account, as stored in the state tree.
+///
+/// The hashes are assumed to be Keccak-256 (32 bytes).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Account {
+    pub nonce: u64,
+    /// Big-endian 256-bit balance.
+    pub balance: [u8; 32],
+    pub storage_root: [u8; 32],
+    pub code_hash: [u8; 32],
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Self {
+            nonce: 0,
+            balance: [0; 32],
+            storage_root: EMPTY_STORAGE_ROOT,
+            code_hash: EMPTY_CODE_HASH,
+        }
+    }
+}
+
+impl Account {
+    /// Return the account's RLP encoding.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_fields(&self.storage_root, &self.code_hash)
+    }
+
+    /// Return the account's slim RLP encoding (as used by the snap protocol), where the empty
+    /// storage root and code hash are replaced by empty strings.
+    pub fn encode_slim(&self) -> Vec<u8> {
+        let storage_root: &[u8] = match self.storage_root {
+            EMPTY_STORAGE_ROOT => &[],
+            ref x => x,
+        };
+        let code_hash: &[u8] = match self.code_hash {
+            EMPTY_CODE_HASH => &[],
+            ref x => x,
+        };
+
+        self.encode_fields(storage_root, code_hash)
+    }
+
+    fn encode_fields(&self, storage_root: &[u8], code_hash: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce.to_be_bytes();
+        let nonce = trim_zeros(&nonce);
+        let balance = trim_zeros(&self.balance);
+
+        let payload_len =
+            bytes_len(nonce) + bytes_len(balance) + bytes_len(storage_root) + bytes_len(code_hash);
+
+        let mut encoder = NodeEncoder::default();
+        encoder.write_list_header(payload_len);
+        encoder.write_bytes(nonce);
+        encoder.write_bytes(balance);
+        encoder.write_bytes(storage_root);
+        encoder.write_bytes(code_hash);
+
+        encoder.finalize()
+    }
+
+    /// Decode an account from its RLP encoding.
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_fields(data, false)
+    }
+
+    /// Decode an account from its slim RLP encoding.
+    pub fn decode_slim(data: &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_fields(data, true)
+    }
+
+    fn decode_fields(data: &[u8], slim: bool) -> Result<Self, DecodeError> {
+        let items = decode_list(expect_list(data)?)?;
+        if items.len() != 4 {
+            return Err(DecodeError::InvalidItemCount(items.len()));
+        }
+
+        let mut fields = items.iter().map(|(item, _)| match item {
+            RlpItem::Bytes(x) => Ok(*x),
+            RlpItem::List(_) => Err(DecodeError::ExpectedBytes),
+        });
+        let mut next_field = || fields.next().unwrap();
+        let hash = |field: &[u8], empty| match field {
+            [] if slim => Ok(empty),
+            x => exact(x),
+        };
+
+        Ok(Self {
+            nonce: u64::from_be_bytes(left_pad(next_field()?)?),
+            balance: left_pad(next_field()?)?,
+            storage_root: hash(next_field()?, EMPTY_STORAGE_ROOT)?,
+            code_hash: hash(next_field()?, EMPTY_CODE_HASH)?,
+        })
+    }
+}
+
+/// Iterate over the accounts of a state tree (indexed by hashed address), decoding them.
+pub(crate) fn iter_accounts<H>(
+    state: &PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>,
+) -> impl Iterator<Item = Result<([u8; 32], Account), DecodeError>> + '_
+where
+    H: Digest,
+{
+    state
+        .iter()
+        .map(|(hash, account)| Ok((exact(hash)?, Account::decode(account)?)))
+}
+
+/// Strip the leading zeros of a big-endian integer, as required by RLP.
+pub(crate) fn trim_zeros(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|x| *x != 0).unwrap_or(value.len());
+    &value[start..]
+}
+
+fn bytes_len(value: &[u8]) -> usize {
+    match value.len() {
+        1 if value[0] < 128 => 1,
+        l if l < 56 => l + 1,
+        l => l + 1 + (usize::BITS - l.leading_zeros()).div_ceil(8) as usize,
+    }
+}
+
+pub(crate) fn left_pad<const N: usize>(value: &[u8]) -> Result<[u8; N], DecodeError> {
+    if value.len() > N {
+        return Err(DecodeError::InvalidInteger);
+    }
+
+    let mut output = [0; N];
+    output[N - value.len()..].copy_from_slice(value);
+    Ok(output)
+}
+
+pub(crate) fn exact<const N: usize>(value: &[u8]) -> Result<[u8; N], DecodeError> {
+    value.try_into().map_err(|_| DecodeError::InvalidHash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use digest::Digest;
+    use sha3::Keccak256;
+
+    #[test]
+    fn empty_constants() {
+        assert_eq!(Keccak256::digest([0x80])[..], EMPTY_STORAGE_ROOT);
+        assert_eq!(Keccak256::digest([])[..], EMPTY_CODE_HASH);
+    }
+
+    #[test]
+    fn encode_default() {
+        let encoded = Account::default().encode();
+
+        assert_eq!(encoded.len(), 70);
+        assert_eq!(&encoded[..4], &[0xF8, 0x44, 0x80, 0x80]);
+        assert_eq!(Account::decode(&encoded).unwrap(), Account::default());
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut account = Account {
+            nonce: 0x1234,
+            ..Default::default()
+        };
+        account.balance[24..].copy_from_slice(&0x0DE0_B6B3_A764_0000u64.to_be_bytes());
+
+        let encoded = account.encode();
+        assert_eq!(&encoded[2..5], &[0x82, 0x12, 0x34]);
+        assert_eq!(Account::decode(&encoded).unwrap(), account);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert_eq!(
+            Account::decode(&[0xC2, 0x80, 0x80]).unwrap_err(),
+            DecodeError::InvalidItemCount(2),
+        );
+        assert_eq!(
+            Account::decode(&[0xC4, 0x80, 0x80, 0x80, 0x80]).unwrap_err(),
+            DecodeError::InvalidHash,
+        );
+    }
+
+    #[test]
+    fn encode_slim() {
+        let account = Account {
+            nonce: 1,
+            ..Default::default()
+        };
+
+        let encoded = account.encode_slim();
+        assert_eq!(encoded, [0xC4, 0x01, 0x80, 0x80, 0x80]);
+        assert_eq!(Account::decode_slim(&encoded).unwrap(), account);
+        assert_eq!(
+            Account::decode(&encoded).unwrap_err(),
+            DecodeError::InvalidHash
+        );
+
+        let account = Account {
+            storage_root: [0xAA; 32],
+            ..Default::default()
+        };
+        assert_eq!(
+            Account::decode_slim(&account.encode_slim()).unwrap(),
+            account
+        );
+        assert_eq!(Account::decode_slim(&account.encode()).unwrap(), account);
+    }
+
+    #[test]
+    fn iter_accounts() {
+        let mut state = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, Keccak256>::new();
+        let accounts = (0..4u64)
+            .map(|nonce| {
+                let hash = Keccak256::digest(nonce.to_be_bytes()).into();
+                (
+                    hash,
+                    Account {
+                        nonce,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect::<std::collections::BTreeMap<[u8; 32], _>>();
+        for (hash, account) in &accounts {
+            state.insert(hash.to_vec(), account.encode());
+        }
+
+        let decoded = state.iter_accounts().collect::<Result<Vec<_>, _>>();
+        assert_eq!(decoded, Ok(accounts.clone().into_iter().collect()));
+
+        // Invalid entries are reported without stopping the iteration.
+        state.insert(vec![0x00], Account::default().encode());
+        state.insert(vec![0xFF; 32], vec![0x80]);
+        let decoded = state.iter_accounts().collect::<Vec<_>>();
+        assert_eq!(decoded.len(), 6);
+        assert_eq!(decoded[0], Err(DecodeError::InvalidHash));
+        assert_eq!(decoded[5], Err(DecodeError::ExpectedList));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{
+    account::{Account, EMPTY_STORAGE_ROOT},
+    hex,
+    proof::verify_proof,
+    rlp::DecodeError,
+    witness::{Address, StorageKey},
+    Encode, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use thiserror::Error;
+
+/// Errors found while verifying an [`AccountProof`].
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum AccountProofError {
+    #[error("invalid account proof")]
+    InvalidAccountProof,
+    #[error("undecodable account: {0}")]
+    UndecodableAccount(DecodeError),
+    #[error("invalid storage proof for slot {}", hex::encode(.0))]
+    InvalidStorageProof(StorageKey),
+}
+
+/// The proof of a storage slot's value within an account's storage tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StorageProof {
+    pub key: StorageKey,
+    pub proof: Vec<Vec<u8>>,
+}
+
+/// The proof of an account within the state tree, along with the proofs of some of its storage
+/// slots within its storage tree.
+///
+/// Both levels are verified together by [`verify`](Self::verify), which takes the storage root
+/// the storage proofs are checked against from the proven account itself. Accounts and slots are
+/// indexed by their hash, as in Ethereum.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccountProof {
+    pub address: Address,
+    pub proof: Vec<Vec<u8>>,
+    pub storage_proofs: Vec<StorageProof>,
+}
+
+/// The contents of an account proven by an [`AccountProof`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifiedAccount {
+    /// The account, or `None` if it doesn't exist.
+    pub account: Option<Account>,
+    /// The value of each slot (as stored in the storage tree), in the order of the proofs.
+    pub storage: Vec<(StorageKey, Option<Vec<u8>>)>,
+}
+
+impl AccountProof {
+    /// Generate the proof of an account and some of its slots, given the state tree and the
+    /// account's storage tree (`None` if the account has no storage).
+    pub fn generate<V, S, H>(
+        state: &mut PatriciaMerkleTree<Vec<u8>, V, H>,
+        storage: Option<&mut PatriciaMerkleTree<Vec<u8>, S, H>>,
+        address: &Address,
+        slots: &[StorageKey],
+    ) -> Self
+    where
+        V: Encode,
+        S: Encode,
+        H: Digest,
+    {
+        let proof = state.get_proof(&H::digest(address).to_vec());
+        let storage_proofs = match storage {
+            Some(storage) => slots
+                .iter()
+                .map(|key| StorageProof {
+                    key: *key,
+                    proof: storage.get_proof(&H::digest(key).to_vec()),
+                })
+                .collect(),
+            None => slots
+                .iter()
+                .map(|key| StorageProof {
+                    key: *key,
+                    proof: Vec::new(),
+                })
+                .collect(),
+        };
+
+        Self {
+            address: *address,
+            proof,
+            storage_proofs,
+        }
+    }
+
+    /// Verify the account against `state_root` and every slot against the account's storage root,
+    /// returning their contents.
+    ///
+    /// Slots of accounts which don't exist are verified against the empty storage root, so they
+    /// can only be proven absent.
+    pub fn verify<H>(&self, state_root: &Output<H>) -> Result<VerifiedAccount, AccountProofError>
+    where
+        H: Digest,
+    {
+        let encoded = verify_proof::<H>(
+            state_root,
+            &H::digest(self.address),
+            self.proof.iter().cloned(),
+        )
+        .ok_or(AccountProofError::InvalidAccountProof)?;
+        let account = encoded
+            .map(|x| Account::decode(&x))
+            .transpose()
+            .map_err(AccountProofError::UndecodableAccount)?;
+
+        let mut storage_root = Output::<H>::default();
+        storage_root.copy_from_slice(
+            &account
+                .as_ref()
+                .map_or(EMPTY_STORAGE_ROOT, |x| x.storage_root),
+        );
+
+        let storage = self
+            .storage_proofs
+            .iter()
+            .map(|storage_proof| {
+                let value = verify_proof::<H>(
+                    &storage_root,
+                    &H::digest(storage_proof.key),
+                    storage_proof.proof.iter().cloned(),
+                )
+                .ok_or(AccountProofError::InvalidStorageProof(storage_proof.key))?;
+
+                Ok((storage_proof.key, value))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(VerifiedAccount { account, storage })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use sha3::Keccak256;
+
+    #[test]
+    fn account_proof() {
+        let address = [0xAA; 20];
+        let slots = [[0x01; 32], [0x02; 32]];
+
+        let mut storage = pmt_tree!(Vec<u8>);
+        storage.insert(Keccak256::digest(slots[0]).to_vec(), vec![0x2A]);
+        for i in 0..32u8 {
+            storage.insert(Keccak256::digest([i]).to_vec(), vec![i + 1]);
+        }
+        let account = Account {
+            nonce: 7,
+            storage_root: (*storage.compute_hash()).into(),
+            ..Default::default()
+        };
+
+        let mut state = pmt_tree!(Vec<u8>);
+        state.insert(Keccak256::digest(address).to_vec(), account.encode());
+        for i in 0..32u8 {
+            let other = Account {
+                nonce: i.into(),
+                ..Default::default()
+            };
+            state.insert(Keccak256::digest([i; 20]).to_vec(), other.encode());
+        }
+        let state_root = *state.compute_hash();
+
+        let proof = AccountProof::generate(&mut state, Some(&mut storage), &address, &slots);
+        assert_eq!(
+            proof.verify::<Keccak256>(&state_root),
+            Ok(VerifiedAccount {
+                account: Some(account),
+                storage: vec![(slots[0], Some(vec![0x2A])), (slots[1], None)],
+            }),
+        );
+
+        // Storage proofs only verify against the proven account's storage root.
+        let mut forged = proof.clone();
+        forged.storage_proofs[0].proof = forged.proof.clone();
+        assert_eq!(
+            forged.verify::<Keccak256>(&state_root),
+            Err(AccountProofError::InvalidStorageProof(slots[0])),
+        );
+        assert_eq!(
+            proof.verify::<Keccak256>(storage.compute_hash()),
+            Err(AccountProofError::InvalidAccountProof),
+        );
+
+        let missing = AccountProof::generate(&mut state, Some(&mut storage), &[0xFF; 20], &slots);
+        assert_eq!(
+            missing.verify::<Keccak256>(&state_root),
+            Err(AccountProofError::InvalidStorageProof(slots[0])),
+        );
+        let missing = AccountProof::generate(
+            &mut state,
+            None::<&mut PatriciaMerkleTree<_, Vec<u8>, _>>,
+            &[0xFF; 20],
+            &slots,
+        );
+        assert_eq!(
+            missing.verify::<Keccak256>(&state_root),
+            Ok(VerifiedAccount {
+                account: None,
+                storage: vec![(slots[0], None), (slots[1], None)],
+            }),
+        );
+    }
+}
@@ -0,0 +1,75 @@
+/// Memory allocated by a tree, in bytes.
+#[cfg(feature = "alloc-stats")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AllocStats {
+    /// Memory reserved for nodes.
+    pub nodes: usize,
+    /// Memory reserved for paths and values.
+    pub values: usize,
+    /// Memory allocated by the prefixes of extension nodes too long to be stored inline.
+    pub nibbles: usize,
+    /// Memory reserved for nodes and values when it was the highest.
+    pub peak: usize,
+}
+
+#[cfg(feature = "alloc-stats")]
+impl AllocStats {
+    /// Return the memory currently allocated.
+    pub fn current(&self) -> usize {
+        self.nodes + self.values + self.nibbles
+    }
+}
+
+/// Keeps track of the peak memory reserved by a tree, when the `alloc-stats` feature is enabled.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AllocTracker {
+    #[cfg(feature = "alloc-stats")]
+    peak: usize,
+}
+
+impl AllocTracker {
+    /// Update the peak given the memory currently reserved.
+    #[allow(unused_variables)]
+    pub fn update(&mut self, reserved: usize) {
+        #[cfg(feature = "alloc-stats")]
+        {
+            self.peak = self.peak.max(reserved);
+        }
+    }
+
+    #[cfg(feature = "alloc-stats")]
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+}
+
+#[cfg(all(test, feature = "alloc-stats"))]
+mod test {
+    use crate::pmt_tree;
+
+    #[test]
+    fn alloc_stats() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        assert_eq!(tree.alloc_stats().current(), 0);
+
+        for i in 0..=255u8 {
+            tree.insert(vec![i], vec![i; 4]);
+        }
+        let stats = tree.alloc_stats();
+        assert_eq!(stats.nodes + stats.values, tree.memory_usage().1);
+        assert_eq!(stats.peak, stats.current());
+        assert_eq!(stats.nibbles, 0);
+
+        for i in 0..=255u8 {
+            tree.remove(vec![i]);
+        }
+        tree.compact();
+        assert_eq!(tree.alloc_stats().current(), 0);
+        assert_eq!(tree.alloc_stats().peak, stats.peak);
+
+        // Prefixes longer than 111 bytes are stored on the heap.
+        tree.insert(vec![0; 128], vec![0]);
+        tree.insert([vec![0; 127], vec![1]].concat(), vec![1]);
+        assert!(tree.alloc_stats().nibbles >= 127);
+    }
+}
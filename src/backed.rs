@@ -0,0 +1,916 @@
+use crate::{
+    db::{commit, commit_subtree, HashMismatch, LoadError, MissingNode, NodeDb, UndecodableNode},
+    hashing::NodeHashRef,
+    nibble::{Nibble, NibbleVec},
+    node::Node,
+    nodes::{BranchNode, ExtensionNode, LeafNode, StubNode},
+    proof::to_nibbles,
+    rlp::{ChildRef, DecodeError, RawNode},
+    storage::{NodeRef, ValueRef},
+    PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use std::collections::HashMap;
+
+type Tree<H> = PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>;
+
+/// How a [`BackedTrie`] over its memory budget picks the subtrees to collapse into stubs.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// Evict the subtrees accessed least recently first.
+    #[default]
+    LeastRecentlyUsed,
+    /// Evict the subtrees with the most nodes in memory first.
+    Largest,
+}
+
+/// Limits on the memory used by a [`BackedTrie`].
+///
+/// Subtrees are tracked by the first `prefix_len` bytes of the paths accessed within them. After
+/// every operation, subtrees other than the one just accessed are evicted (as with
+/// [`stub_prefix`](BackedTrie::stub_prefix)) until the trie is within its budget again. Nodes
+/// above the tracked subtrees (and their stubs) are never evicted, so the budget should leave room
+/// for them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EvictionConfig {
+    pub policy: EvictionPolicy,
+    /// Memory the trie may use, as reported by [`memory_usage`](BackedTrie::memory_usage).
+    pub budget: usize,
+    /// Length of the path prefixes identifying subtrees, which must be nonzero.
+    pub prefix_len: usize,
+}
+
+/// A trie stored in a node database, loaded into memory as it's accessed.
+///
+/// Subtrees which haven't been needed yet are kept as stubs holding just their hash, and are
+/// expanded one node at a time by the operations reaching them. Rarely touched subtrees can be
+/// collapsed back into stubs (see [`stub_prefix`](Self::stub_prefix)), which bounds the memory
+/// used by huge tries.
+#[derive(Debug)]
+pub struct BackedTrie<H, D>
+where
+    H: Digest,
+    D: NodeDb,
+{
+    tree: Tree<H>,
+    db: D,
+    eviction: Option<EvictionConfig>,
+    /// Whether loaded nodes are checked against the hash they're referenced by.
+    verify_nodes: bool,
+    /// Bytes used by the paths, values and long extension prefixes in memory, which live outside
+    /// the tree's slots.
+    heap_size: usize,
+    /// Tick of the last access to each subtree, by prefix.
+    accesses: HashMap<Vec<u8>, u64>,
+    tick: u64,
+}
+
+impl<H, D> BackedTrie<H, D>
+where
+    H: Digest,
+    D: NodeDb,
+{
+    /// Create an empty trie over a node database.
+    pub fn new(db: D) -> Self {
+        Self {
+            tree: Tree::new(),
+            db,
+            eviction: None,
+            verify_nodes: false,
+            heap_size: 0,
+            accesses: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Open the trie with the given root hash, without loading anything.
+    ///
+    /// Panics if `root` isn't as long as a hash.
+    pub fn open(db: D, root: &[u8]) -> Self {
+        let mut trie = Self::new(db);
+        if root != &H::digest([0x80])[..] {
+            let mut hash = Output::<H>::default();
+            hash.copy_from_slice(root);
+            trie.tree.root_ref = NodeRef::new(trie.tree.nodes.insert(StubNode::new(hash).into()));
+        }
+
+        trie
+    }
+
+    /// Return the node database.
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Return the number of values loaded in memory.
+    pub fn resident_len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Return the approximate memory used by the nodes and values loaded, including the bytes of
+    /// the paths and values themselves.
+    pub fn memory_usage(&self) -> usize {
+        self.tree.memory_usage().0 + self.heap_size
+    }
+
+    /// Set the limits on memory usage (or remove them with `None`), evicting subtrees right away
+    /// if over the new budget.
+    ///
+    /// Accesses are only tracked while limits are set, so subtrees loaded before are kept.
+    pub fn set_eviction_config(&mut self, config: Option<EvictionConfig>) {
+        match &config {
+            Some(config) => {
+                assert!(config.prefix_len > 0, "subtrees need a nonempty prefix");
+
+                let mut accesses = HashMap::<_, u64>::new();
+                for (mut prefix, tick) in self.accesses.drain() {
+                    prefix.truncate(config.prefix_len);
+                    let last_tick = accesses.entry(prefix).or_default();
+                    *last_tick = tick.max(*last_tick);
+                }
+                self.accesses = accesses;
+            }
+            None => self.accesses.clear(),
+        }
+
+        self.eviction = config;
+        self.evict(None);
+    }
+
+    pub fn eviction_config(&self) -> Option<&EvictionConfig> {
+        self.eviction.as_ref()
+    }
+
+    /// Check every node loaded from now on against the hash its parent references it by (or the
+    /// root hash), failing with [`LoadError::HashMismatch`] when they differ.
+    ///
+    /// Since the root hash is the only thing trusted, this allows using node databases which may
+    /// have been tampered with (such as remote ones), at the cost of hashing every loaded node.
+    pub fn set_verify_nodes(&mut self, enabled: bool) {
+        self.verify_nodes = enabled;
+    }
+
+    pub fn verify_nodes(&self) -> bool {
+        self.verify_nodes
+    }
+
+    /// Retrieve a value given its path, loading the nodes leading to it.
+    pub fn get(&mut self, path: &[u8]) -> Result<Option<&[u8]>, LoadError> {
+        self.expand_path(path, false)?;
+        self.evict(Some(path));
+        Ok(self.tree.try_get(&path.to_vec())?.map(Vec::as_slice))
+    }
+
+    /// Insert a value, loading the nodes leading to its path, and return the previous one (if
+    /// any).
+    pub fn insert(&mut self, path: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, LoadError> {
+        self.expand_path(&path, false)?;
+        let prefixes_size = self.prefixes_heap_size(&path);
+        let size = path.len() + value.len();
+        let old_value = self.tree.try_insert(path.clone(), value)?;
+        self.heap_size = self.heap_size + size + self.prefixes_heap_size(&path)
+            - prefixes_size
+            - old_value.as_ref().map_or(0, |x| path.len() + x.len());
+        self.evict(Some(&path));
+        Ok(old_value)
+    }
+
+    /// Remove a value given its path, loading the nodes leading to it (and those which may take
+    /// their place), and return it (if any).
+    pub fn remove(&mut self, path: &[u8]) -> Result<Option<Vec<u8>>, LoadError> {
+        self.expand_path(path, true)?;
+        let prefixes_size = self.prefixes_heap_size(path);
+        let value = self.tree.try_remove(path.to_vec())?;
+        self.heap_size = self.heap_size + self.prefixes_heap_size(path)
+            - prefixes_size
+            - value.as_ref().map_or(0, |x| path.len() + x.len());
+        self.evict(Some(path));
+        Ok(value)
+    }
+
+    /// Return the root hash of the trie.
+    pub fn compute_hash(&mut self) -> &Output<H> {
+        self.tree.compute_hash()
+    }
+
+    /// Write the nodes loaded in memory into the node database and return the root hash.
+    pub fn commit(&mut self) -> Output<H> {
+        commit(&mut self.tree, &mut self.db)
+    }
+
+    /// Collapse the smallest subtree holding every path starting with `prefix` (as encoded bytes)
+    /// into a stub, and return how many values it dropped from memory.
+    ///
+    /// Its nodes are written into the node database first, unless already stored there. Subtrees
+    /// embedded in their parent (shorter than a hash) are left as they are.
+    pub fn stub_prefix(&mut self, prefix: &[u8]) -> usize {
+        self.tree.compute_hash();
+
+        let Some((node_ref, path_offset)) = self.find_prefix(prefix) else {
+            return 0;
+        };
+
+        let node = &self.tree.nodes[*node_ref];
+        let hash = match node.compute_hash(&self.tree.nodes, &self.tree.values, path_offset) {
+            _ if matches!(node, Node::Stub(_)) => return 0,
+            _ if node_ref == self.tree.root_ref => self.tree.hash.1.clone(),
+            NodeHashRef::Hashed(hash) => hash.clone(),
+            NodeHashRef::Inline(_) => return 0,
+        };
+        if self.db.get(&hash).is_none() {
+            commit_subtree(&self.tree, &mut self.db, node_ref, path_offset, &hash);
+        }
+
+        // The subtree's root is replaced in place, so that its parent still points to it.
+        let len = self.tree.len();
+        let mut stack = vec![node_ref];
+        while let Some(child_ref) = stack.pop() {
+            let value_ref = match &self.tree.nodes[*child_ref] {
+                Node::Branch(branch_node) => {
+                    stack.extend(branch_node.choices.iter().filter(|x| x.is_valid()));
+                    branch_node.value_ref
+                }
+                Node::Extension(extension_node) => {
+                    stack.push(extension_node.child_ref);
+                    self.heap_size -= extension_node.prefix.heap_size();
+                    ValueRef::default()
+                }
+                Node::Leaf(leaf_node) => leaf_node.value_ref,
+                Node::Stub(_) => ValueRef::default(),
+            };
+
+            if value_ref.is_valid() {
+                let (path, value) = self.tree.values.remove(*value_ref);
+                self.heap_size -= path.len() + value.len();
+                #[cfg(feature = "paranoid")]
+                self.tree.shadow.remove(&path);
+            }
+            if child_ref != node_ref {
+                self.tree.nodes.remove(*child_ref);
+            }
+        }
+        self.tree.nodes[*node_ref] = StubNode::new(hash).into();
+
+        len - self.tree.len()
+    }
+
+    /// Record an access to the subtree of `path` (if any), then evict other subtrees while over
+    /// the memory budget.
+    fn evict(&mut self, path: Option<&[u8]>) {
+        let Some(config) = self.eviction.clone() else {
+            return;
+        };
+
+        let prefix = path.map(|x| &x[..x.len().min(config.prefix_len)]);
+        if let Some(prefix) = prefix {
+            self.tick += 1;
+            self.accesses.insert(prefix.to_vec(), self.tick);
+        }
+
+        while self.memory_usage() > config.budget {
+            // Subtrees containing (or contained in) the accessed one are kept.
+            let candidates = self.accesses.iter().filter(|(x, _)| match prefix {
+                Some(prefix) => !x.starts_with(prefix) && !prefix.starts_with(x),
+                None => true,
+            });
+            let victim = match config.policy {
+                EvictionPolicy::LeastRecentlyUsed => candidates.min_by_key(|(_, tick)| **tick),
+                EvictionPolicy::Largest => {
+                    candidates.max_by_key(|(x, _)| self.subtree_node_count(x))
+                }
+            };
+            let Some((victim, _)) = victim else {
+                break;
+            };
+
+            let victim = victim.clone();
+            self.accesses.remove(&victim);
+            self.stub_prefix(&victim);
+        }
+    }
+
+    /// Return the number of nodes in memory within the subtree holding every path starting with
+    /// `prefix`.
+    fn subtree_node_count(&self, prefix: &[u8]) -> usize {
+        let Some((node_ref, _)) = self.find_prefix(prefix) else {
+            return 0;
+        };
+
+        let (mut stack, mut count) = (vec![node_ref], 0);
+        while let Some(node_ref) = stack.pop() {
+            match &self.tree.nodes[*node_ref] {
+                Node::Branch(branch_node) => {
+                    stack.extend(branch_node.choices.iter().filter(|x| x.is_valid()))
+                }
+                Node::Extension(extension_node) => stack.push(extension_node.child_ref),
+                Node::Leaf(_) | Node::Stub(_) => {}
+            }
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Return the heap size of the extension prefixes along `path` (as encoded bytes) and of their
+    /// siblings, which are the only ones an insertion or removal there can change.
+    fn prefixes_heap_size(&self, path: &[u8]) -> usize {
+        let prefix_size = |node_ref: NodeRef| match self.tree.nodes.get(*node_ref) {
+            Some(Node::Extension(extension_node)) => extension_node.prefix.heap_size(),
+            _ => 0,
+        };
+
+        let path = to_nibbles(path);
+        let (mut node_ref, mut offset, mut size) = (self.tree.root_ref, 0, 0);
+        size += prefix_size(node_ref);
+        while let Some(node) = self.tree.nodes.get(*node_ref) {
+            node_ref = match node {
+                Node::Branch(branch_node) => {
+                    size += branch_node
+                        .choices
+                        .iter()
+                        .map(|x| prefix_size(*x))
+                        .sum::<usize>();
+                    let Some(&choice) = path.get(offset) else {
+                        break;
+                    };
+
+                    offset += 1;
+                    branch_node.choices[choice as usize]
+                }
+                Node::Extension(extension_node) => {
+                    let prefix = extension_node.prefix.iter().map(u8::from);
+                    let prefix = prefix.collect::<Vec<_>>();
+                    if !path[offset..].starts_with(&prefix) {
+                        break;
+                    }
+
+                    offset += prefix.len();
+                    extension_node.child_ref
+                }
+                Node::Leaf(_) | Node::Stub(_) => break,
+            };
+        }
+
+        size
+    }
+
+    /// Find the smallest subtree holding every path starting with `prefix`, along with its path
+    /// offset.
+    fn find_prefix(&self, prefix: &[u8]) -> Option<(NodeRef, usize)> {
+        let prefix = to_nibbles(prefix);
+        let mut rest = prefix.as_slice();
+        let (mut node_ref, mut path_offset) = (self.tree.root_ref, 0);
+        while !rest.is_empty() && node_ref.is_valid() {
+            match &self.tree.nodes[*node_ref] {
+                Node::Branch(branch_node) => {
+                    node_ref = branch_node.choices[rest[0] as usize];
+                    rest = &rest[1..];
+                    path_offset += 1;
+                }
+                Node::Extension(extension_node) => {
+                    let extension_prefix = extension_node.prefix.iter().map(u8::from);
+                    let extension_prefix = extension_prefix.collect::<Vec<_>>();
+                    if extension_prefix.starts_with(rest) {
+                        break;
+                    }
+
+                    rest = rest.strip_prefix(extension_prefix.as_slice())?;
+                    path_offset += extension_prefix.len();
+                    node_ref = extension_node.child_ref;
+                }
+                Node::Leaf(_) | Node::Stub(_) => break,
+            }
+        }
+
+        node_ref.is_valid().then_some((node_ref, path_offset))
+    }
+
+    /// Load the whole trie into memory.
+    pub fn into_tree(mut self) -> Result<Tree<H>, LoadError> {
+        let mut stack = vec![(self.tree.root_ref, Vec::new())];
+        while let Some((node_ref, path_prefix)) = stack.pop() {
+            if !node_ref.is_valid() {
+                continue;
+            }
+
+            self.expand(node_ref, &path_prefix)?;
+            match &self.tree.nodes[*node_ref] {
+                Node::Branch(branch_node) => {
+                    for (choice, child_ref) in branch_node.choices.iter().enumerate() {
+                        stack.push((*child_ref, [&path_prefix[..], &[choice as u8]].concat()));
+                    }
+                }
+                Node::Extension(extension_node) => {
+                    let prefix = extension_node.prefix.iter().map(u8::from);
+                    let child_prefix = path_prefix.iter().copied().chain(prefix).collect();
+                    stack.push((extension_node.child_ref, child_prefix));
+                }
+                Node::Leaf(_) => {}
+                Node::Stub(_) => unreachable!("the node has just been expanded"),
+            }
+        }
+
+        Ok(self.tree)
+    }
+
+    /// Expand every stub leading to `path`. Removals may also collapse branches left with a
+    /// single item, moving it up, so the other items of branches with only two are expanded too.
+    fn expand_path(&mut self, path: &[u8], is_removal: bool) -> Result<(), LoadError> {
+        let path = to_nibbles(path);
+        let (mut node_ref, mut offset) = (self.tree.root_ref, 0);
+        while node_ref.is_valid() {
+            self.expand(node_ref, &path[..offset])?;
+            node_ref = match &self.tree.nodes[*node_ref] {
+                Node::Branch(branch_node) => {
+                    let child_ref = path.get(offset).map(|&x| branch_node.choices[x as usize]);
+                    let children = branch_node.choices.iter().enumerate();
+                    let children = children.filter(|(_, x)| x.is_valid()).collect::<Vec<_>>();
+                    if is_removal && children.len() + branch_node.value_ref.is_valid() as usize == 2
+                    {
+                        let children = children
+                            .into_iter()
+                            .map(|(choice, x)| (choice as u8, *x))
+                            .collect::<Vec<_>>();
+                        for (choice, sibling_ref) in children {
+                            self.expand(sibling_ref, &[&path[..offset], &[choice]].concat())?;
+                        }
+                    }
+
+                    // A path ending here removes the branch's own value.
+                    let Some(child_ref) = child_ref else {
+                        break;
+                    };
+
+                    offset += 1;
+                    child_ref
+                }
+                Node::Extension(extension_node) => {
+                    let prefix = extension_node.prefix.iter().map(u8::from);
+                    let prefix = prefix.collect::<Vec<_>>();
+                    if !path[offset..].starts_with(&prefix) {
+                        break;
+                    }
+
+                    offset += prefix.len();
+                    extension_node.child_ref
+                }
+                Node::Leaf(_) => break,
+                Node::Stub(_) => unreachable!("the node has just been expanded"),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Replace the stub at `node_ref` (if it is one) with the node it stands for, whose children
+    /// become stubs themselves. `path_prefix` is the nibbles leading to it.
+    fn expand(&mut self, node_ref: NodeRef, path_prefix: &[u8]) -> Result<(), LoadError> {
+        let Some(Node::Stub(stub_node)) = self.tree.nodes.get(*node_ref) else {
+            return Ok(());
+        };
+
+        let hash = stub_node.hash.to_vec();
+        let Some(encoded) = self.db.get(&hash) else {
+            return Err(LoadError::MissingNode(MissingNode {
+                hash,
+                path_prefix: path_prefix.to_vec(),
+            }));
+        };
+
+        // Embedded children are covered by their parent's hash.
+        if self.verify_nodes {
+            let actual = H::digest(&encoded);
+            if actual[..] != hash {
+                return Err(LoadError::HashMismatch(HashMismatch {
+                    hash,
+                    path_prefix: path_prefix.to_vec(),
+                    actual: actual.to_vec(),
+                }));
+            }
+        }
+
+        let node = RawNode::decode(&encoded)
+            .and_then(|x| match has_byte_paths(&x, path_prefix) {
+                true => Ok(x),
+                false => Err(DecodeError::InvalidPath),
+            })
+            .map_err(|error| {
+                LoadError::UndecodableNode(UndecodableNode {
+                    hash,
+                    path_prefix: path_prefix.to_vec(),
+                    error,
+                })
+            })?;
+
+        self.tree.nodes[*node_ref] = self.build(node, path_prefix);
+        Ok(())
+    }
+
+    /// Build a node (along with its embedded children) from its already validated encoding.
+    fn build(&mut self, node: RawNode, path_prefix: &[u8]) -> Node<Vec<u8>, Vec<u8>, H> {
+        match node {
+            RawNode::Branch { choices, value } => {
+                let mut child_refs = [NodeRef::default(); 16];
+                for (choice, child) in choices.into_iter().enumerate() {
+                    if let Some(child) = child {
+                        let child_prefix = [path_prefix, &[choice as u8]].concat();
+                        child_refs[choice] = self.build_child(child, &child_prefix);
+                    }
+                }
+
+                let mut branch_node = BranchNode::new(child_refs);
+                if let Some(value) = value {
+                    branch_node.update_value_ref(self.insert_value(path_prefix, value));
+                }
+                branch_node.into()
+            }
+            RawNode::Extension { prefix, child } => {
+                let child_prefix = [path_prefix, &prefix].concat();
+                let child_ref = self.build_child(child, &child_prefix);
+                let prefix = to_nibble_vec(&prefix, path_prefix.len());
+                self.heap_size += prefix.heap_size();
+                ExtensionNode::new(prefix, child_ref).into()
+            }
+            RawNode::Leaf { path, value } => {
+                let value_ref = self.insert_value(&[path_prefix, &path].concat(), value);
+                LeafNode::new(value_ref).into()
+            }
+        }
+    }
+
+    fn build_child(&mut self, child: ChildRef, path_prefix: &[u8]) -> NodeRef {
+        let node = match child {
+            ChildRef::Hash(hash) => {
+                let mut output = Output::<H>::default();
+                output.copy_from_slice(&hash);
+                StubNode::new(output).into()
+            }
+            ChildRef::Inline(encoded) => {
+                let node = RawNode::decode(&encoded).expect("embedded nodes are validated");
+                self.build(node, path_prefix)
+            }
+        };
+
+        NodeRef::new(self.tree.nodes.insert(node))
+    }
+
+    fn insert_value(&mut self, nibbles: &[u8], value: Vec<u8>) -> ValueRef {
+        let path = nibbles
+            .chunks(2)
+            .map(|x| (x[0] << 4) | x[1])
+            .collect::<Vec<_>>();
+        #[cfg(feature = "paranoid")]
+        self.tree.shadow.insert(path.clone(), value.clone());
+
+        self.heap_size += path.len() + value.len();
+        ValueRef::new(self.tree.values.insert((path, value)))
+    }
+}
+
+/// Return whether the values within a node (including its embedded children) have paths made of
+/// whole bytes.
+fn has_byte_paths(node: &RawNode, path_prefix: &[u8]) -> bool {
+    let value_len = match node {
+        RawNode::Branch { value: Some(_), .. } => Some(path_prefix.len()),
+        RawNode::Leaf { path, .. } => Some(path_prefix.len() + path.len()),
+        _ => None,
+    };
+
+    !matches!(value_len, Some(x) if x & 1 != 0)
+        && node.children().all(|(nibbles, child)| match child {
+            ChildRef::Inline(encoded) => {
+                let child = RawNode::decode(encoded).expect("embedded nodes are validated");
+                has_byte_paths(&child, &[path_prefix, nibbles].concat())
+            }
+            ChildRef::Hash(_) => true,
+        })
+}
+
+/// Build an extension's prefix, aligned as the tree expects for one at `path_offset`.
+fn to_nibble_vec(nibbles: &[u8], path_offset: usize) -> NibbleVec {
+    let nibble = |x: u8| Nibble::try_from(x).expect("decoded nibbles are valid");
+    let mut prefix = NibbleVec::from_single(nibble(nibbles[0]), path_offset & 1 != 0);
+    for x in &nibbles[1..] {
+        prefix.push(nibble(*x));
+    }
+
+    prefix
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{db::load_tree, get_proof_from_db, MemoryDb};
+    use sha3::Keccak256;
+
+    fn populated() -> (Tree<Keccak256>, MemoryDb, Output<Keccak256>) {
+        let mut tree = Tree::<Keccak256>::new();
+        for i in 0..=255u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 32 + i as usize % 8]);
+        }
+        for i in 0..16u8 {
+            tree.insert(vec![0x40, i, 0x00], vec![i; 32]);
+        }
+        tree.insert(vec![0x12], vec![0x34]);
+
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db);
+        (tree, db, root)
+    }
+
+    #[test]
+    fn lazy_loading() {
+        let (mut tree, db, root) = populated();
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        assert_eq!(trie.resident_len(), 0);
+        assert_eq!(trie.compute_hash(), &root);
+
+        assert_eq!(trie.get(&[0x12]), Ok(Some(&[0x34][..])));
+        assert_eq!(trie.get(&[0x12, 0x7E]), Ok(Some(&[0x12; 34][..])));
+        assert_eq!(trie.get(&[0x12, 0x7F]), Ok(None));
+        assert!(trie.resident_len() < 8);
+
+        // Writes match the fully loaded tree.
+        for i in (0..=255u8).step_by(5) {
+            let path = vec![i, i.wrapping_mul(7)];
+            assert_eq!(trie.remove(&path).unwrap(), tree.remove(path));
+        }
+        for i in (0..=255u8).step_by(3) {
+            let path = vec![i, 0xAA];
+            assert_eq!(
+                trie.insert(path.clone(), vec![i]),
+                Ok(tree.insert(path, vec![i]))
+            );
+        }
+        assert_eq!(trie.compute_hash(), tree.compute_hash());
+        assert!(trie.resident_len() < tree.len());
+
+        let root = trie.commit();
+        let loaded = load_tree::<Keccak256, _>(trie.db(), &root).unwrap();
+        assert!(loaded.iter().eq(tree.iter()));
+        assert!(trie.into_tree().unwrap().iter().eq(tree.iter()));
+    }
+
+    #[test]
+    fn removal_collapse() {
+        // Removing either value moves the other one's stubbed leaf up.
+        let mut tree = Tree::<Keccak256>::new();
+        tree.insert(vec![0x10; 32], vec![0x01; 32]);
+        tree.insert(vec![0x20; 32], vec![0x02; 32]);
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db);
+
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        assert_eq!(trie.remove(&[0x10; 32]), Ok(Some(vec![0x01; 32])));
+        tree.remove(vec![0x10; 32]);
+        assert_eq!(trie.compute_hash(), tree.compute_hash());
+
+        // Removing a branch's own value moves its only child up.
+        let mut tree = Tree::<Keccak256>::new();
+        tree.insert(vec![0x00], vec![0x00]);
+        tree.insert(vec![0x00, 0x00], vec![0x07; 29]);
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db);
+
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        assert_eq!(trie.remove(&[0x00]), Ok(Some(vec![0x00])));
+        tree.remove(vec![0x00]);
+        assert_eq!(trie.compute_hash(), tree.compute_hash());
+    }
+
+    #[test]
+    fn stub_prefix() {
+        let (tree, db, root) = populated();
+        let mut trie = BackedTrie::<Keccak256, _>::new(MemoryDb::new());
+        for (path, value) in tree.iter() {
+            trie.insert(path.clone(), value.clone()).unwrap();
+        }
+
+        // Stubs are written to the database on their way out, and expanded again when needed.
+        assert_eq!(trie.stub_prefix(&[0x40]), 17);
+        assert_eq!(trie.resident_len(), tree.len() - 17);
+        assert_eq!(trie.compute_hash(), &root);
+        assert_eq!(trie.stub_prefix(&[0x40]), 0);
+        assert_eq!(trie.get(&[0x40, 0x0F, 0x00]), Ok(Some(&[0x0F; 32][..])));
+        assert!(trie.resident_len() < tree.len());
+
+        let resident_len = trie.resident_len();
+        assert_eq!(trie.stub_prefix(&[]), resident_len);
+        assert_eq!(trie.resident_len(), 0);
+        assert_eq!(trie.db().len(), db.len());
+        assert!(trie.into_tree().unwrap().iter().eq(tree.iter()));
+
+        // Embedded subtrees are kept.
+        let mut trie = BackedTrie::<Keccak256, _>::new(MemoryDb::new());
+        trie.insert(vec![0x12], vec![0x01]).unwrap();
+        trie.insert(vec![0x13], vec![0x02]).unwrap();
+        assert_eq!(trie.stub_prefix(&[0x12]), 0);
+        assert_eq!(trie.stub_prefix(&[]), 2);
+    }
+
+    /// Return whether the subtree holding the paths starting with `prefix` is loaded.
+    fn is_resident(trie: &BackedTrie<Keccak256, MemoryDb>, prefix: &[u8]) -> bool {
+        trie.find_prefix(prefix)
+            .is_some_and(|(node_ref, _)| !matches!(trie.tree.nodes[*node_ref], Node::Stub(_)))
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let key = |i: u32| Keccak256::digest(i.to_be_bytes()).to_vec();
+        let mut tree = Tree::<Keccak256>::new();
+        for i in 0..2048 {
+            tree.insert(key(i), vec![i as u8; 32]);
+        }
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db);
+
+        let mut trie = BackedTrie::<Keccak256, _>::open(db.clone(), &root);
+        for (path, _) in tree.iter() {
+            trie.get(path).unwrap();
+        }
+        let budget = trie.memory_usage() / 4;
+
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        trie.set_eviction_config(Some(EvictionConfig {
+            policy: EvictionPolicy::LeastRecentlyUsed,
+            budget,
+            prefix_len: 1,
+        }));
+        for (i, (path, value)) in tree.iter().enumerate() {
+            assert_eq!(trie.get(path), Ok(Some(value.as_slice())));
+            assert!(trie.memory_usage() <= budget);
+
+            // Frequently used subtrees are kept.
+            if i % 64 == 0 {
+                trie.get(&key(0)).unwrap();
+            }
+        }
+        let (first, _) = tree.iter().next().unwrap();
+        let (last, _) = tree.iter().last().unwrap();
+        assert!(is_resident(&trie, &key(0)[..1]) && is_resident(&trie, &last[..1]));
+        assert!(!is_resident(&trie, &first[..1]));
+
+        // Evicted changes are written to the database.
+        for i in 2048..2304 {
+            assert_eq!(
+                trie.insert(key(i), vec![0x01]),
+                Ok(tree.insert(key(i), vec![0x01]))
+            );
+            assert!(trie.memory_usage() <= budget);
+        }
+        assert_eq!(trie.compute_hash(), tree.compute_hash());
+        assert!(trie.into_tree().unwrap().iter().eq(tree.iter()));
+    }
+
+    /// Return the heap size of the paths, values and extension prefixes in memory.
+    fn heap_size(trie: &BackedTrie<Keccak256, MemoryDb>) -> usize {
+        let values = trie.tree.values.iter();
+        let prefixes = trie.tree.nodes.iter().map(|(_, node)| match node {
+            Node::Extension(extension_node) => extension_node.prefix.heap_size(),
+            _ => 0,
+        });
+
+        values
+            .map(|(_, (path, value))| path.len() + value.len())
+            .sum::<usize>()
+            + prefixes.sum::<usize>()
+    }
+
+    #[test]
+    fn heap_usage() {
+        // Long shared prefixes don't fit inline in extensions.
+        let path = |i: u8| [vec![0xAB; 150], vec![i; 50]].concat();
+        let mut tree = Tree::<Keccak256>::new();
+        for i in 0..4 {
+            tree.insert(path(i), vec![i; 1 << 20]);
+        }
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db);
+
+        let mut trie = BackedTrie::<Keccak256, _>::open(db.clone(), &root);
+        trie.get(&path(0)).unwrap();
+        assert!(trie.memory_usage() > 1 << 20);
+        assert!(trie.heap_size > 1 << 20);
+        assert_eq!(trie.heap_size, heap_size(&trie));
+
+        trie.insert(path(4), vec![4; 1 << 20]).unwrap();
+        trie.insert(path(2), vec![2]).unwrap();
+        trie.remove(&path(1)).unwrap();
+        trie.insert([vec![0xAB; 100], vec![5; 100]].concat(), vec![5])
+            .unwrap();
+        assert_eq!(trie.heap_size, heap_size(&trie));
+        trie.remove(&[vec![0xAB; 100], vec![5; 100]].concat())
+            .unwrap();
+        assert_eq!(trie.heap_size, heap_size(&trie));
+        trie.stub_prefix(&[]);
+        assert_eq!(trie.heap_size, 0);
+
+        // Large values are evicted to stay within budget.
+        let budget = (5 << 20) / 2;
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        trie.set_eviction_config(Some(EvictionConfig {
+            policy: EvictionPolicy::LeastRecentlyUsed,
+            budget,
+            prefix_len: 151,
+        }));
+        for i in 0..4 {
+            trie.get(&path(i)).unwrap();
+            assert!(trie.memory_usage() <= budget);
+        }
+        assert!(trie.resident_len() <= 2);
+    }
+
+    #[test]
+    fn largest_eviction() {
+        let (_, db, root) = populated();
+        for (policy, evicted) in [
+            (EvictionPolicy::LeastRecentlyUsed, [0x41]),
+            (EvictionPolicy::Largest, [0x40]),
+        ] {
+            let mut config = EvictionConfig {
+                policy,
+                budget: usize::MAX,
+                prefix_len: 1,
+            };
+            let mut trie = BackedTrie::<Keccak256, _>::open(db.clone(), &root);
+            trie.set_eviction_config(Some(config.clone()));
+            for path in [[0x41, 0xC7], [0x42, 0xCE], [0x40, 0xC0]] {
+                trie.get(&path).unwrap();
+            }
+
+            // Values along the paths differ by a few bytes.
+            config.budget = trie.memory_usage() + 16;
+            trie.set_eviction_config(Some(config));
+            trie.get(&[0x43, 0xD5]).unwrap();
+            for prefix in [[0x40], [0x41], [0x42], [0x43]] {
+                assert_eq!(is_resident(&trie, &prefix), prefix != evicted);
+            }
+        }
+    }
+
+    #[test]
+    fn resident_traversals() {
+        // The tree's own traversals only see the values in memory.
+        let (_, db, root) = populated();
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        assert_eq!(trie.get(&[0x12]), Ok(Some(&[0x34][..])));
+        assert!(trie.tree.iter().eq([(&vec![0x12], &vec![0x34])]));
+        assert_eq!(trie.tree.count_range(..), 1);
+        assert_eq!(trie.tree.get_proof(&vec![0x12, 0x7E]).len(), 3);
+
+        // Removals needing a stub's contents fail instead.
+        let error = crate::range::remove_range(&mut trie.tree, ..).unwrap_err();
+        assert_eq!(error.node_kind, Some(crate::node::NodeKind::Stub));
+    }
+
+    #[test]
+    fn missing_nodes() {
+        let (_, mut db, root) = populated();
+        let mut trie = BackedTrie::<Keccak256, _>::open(MemoryDb::new(), &root);
+        assert_eq!(
+            trie.get(&[0x12]),
+            Err(LoadError::MissingNode(MissingNode {
+                hash: root.to_vec(),
+                path_prefix: Vec::new(),
+            }))
+        );
+
+        db.insert(root.to_vec(), vec![0xC0]);
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        assert!(matches!(
+            trie.get(&[0x12]),
+            Err(LoadError::UndecodableNode(_))
+        ));
+    }
+
+    #[test]
+    fn verify_nodes() {
+        let (_, mut db, root) = populated();
+
+        // Replace a leaf with another one, stored under the original's hash.
+        let leaf = |path| {
+            get_proof_from_db::<Keccak256, _>(&db, &root, path)
+                .unwrap()
+                .pop()
+        };
+        let (original, other) = (leaf(&[0x21, 0xE7]).unwrap(), leaf(&[0x22, 0xEE]).unwrap());
+        let hash = Keccak256::digest(&original).to_vec();
+        db.insert(hash.clone(), other.clone());
+
+        let mut trie = BackedTrie::<Keccak256, _>::open(db.clone(), &root);
+        assert_eq!(trie.get(&[0x21, 0xE7]), Ok(None));
+
+        let mut trie = BackedTrie::<Keccak256, _>::open(db, &root);
+        trie.set_verify_nodes(true);
+        assert_eq!(trie.get(&[0x12, 0x7E]), Ok(Some(&[0x12; 34][..])));
+        assert_eq!(
+            trie.get(&[0x21, 0xE7]),
+            Err(LoadError::HashMismatch(HashMismatch {
+                hash,
+                path_prefix: vec![2, 1],
+                actual: Keccak256::digest(&other).to_vec(),
+            }))
+        );
+        assert!(trie.into_tree().is_err());
+    }
+}
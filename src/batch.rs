@@ -0,0 +1,412 @@
+use crate::{
+    db::{commit, RootedDb},
+    hex, Encode, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// The root of a database changed since a batch was created.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error(
+    "expected root {} but the database's root is {}",
+    hex::encode(.expected),
+    hex::encode(.actual)
+)]
+pub struct RootChanged {
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+}
+
+/// A value changed by applying a batch (see [`Batch::apply_block`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StateChange<P, V> {
+    pub path: P,
+    /// The value before applying the batch (`None` if it was inserted).
+    pub old_value: Option<V>,
+    /// The value after applying the batch (`None` if it was removed).
+    pub new_value: Option<V>,
+}
+
+/// A set of pending writes over a tree.
+///
+/// Reads through the batch see its own writes, but the tree isn't modified until
+/// [`apply`](Self::apply) is called. Dropping the batch discards them.
+#[derive(Debug)]
+pub struct Batch<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    tree: &'a mut PatriciaMerkleTree<P, V, H>,
+    /// Pending writes by encoded path. `None` values are removals.
+    pending: BTreeMap<Vec<u8>, (P, Option<V>)>,
+}
+
+impl<'a, P, V, H> Batch<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    pub(crate) fn new(tree: &'a mut PatriciaMerkleTree<P, V, H>) -> Self {
+        Self {
+            tree,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Return the number of pending writes.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Return whether there are no pending writes.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Retrieve a value given its path, as it would be after applying the batch.
+    pub fn get(&self, path: &P) -> Option<&V> {
+        match self.pending.get(path.encode().as_ref()) {
+            Some((_, value)) => value.as_ref(),
+            None => self.tree.get(path),
+        }
+    }
+
+    /// Return whether there's a value at `path`, as it would be after applying the batch.
+    pub fn contains(&self, path: &P) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// Queue inserting a value into the tree.
+    pub fn insert(&mut self, path: P, value: V) {
+        let key = path.encode().into_owned();
+        self.pending.insert(key, (path, Some(value)));
+    }
+
+    /// Queue removing a value from the tree.
+    pub fn remove(&mut self, path: P) {
+        let key = path.encode().into_owned();
+        self.pending.insert(key, (path, None));
+    }
+
+    /// Return the tree the batch will be applied to, without its pending writes.
+    pub fn tree(&self) -> &PatriciaMerkleTree<P, V, H> {
+        self.tree
+    }
+
+    /// Apply the pending writes to the tree, in ascending path order.
+    pub fn apply(self) {
+        self.apply_into_tree();
+    }
+
+    /// Apply the pending writes to the tree, in ascending path order, and return the new root
+    /// hash along with the values they changed (in the same order).
+    ///
+    /// Writes which leave a value as it was (by its encoding) aren't included.
+    pub fn apply_block(self) -> (Output<H>, Vec<StateChange<P, V>>)
+    where
+        P: Clone,
+        V: Clone,
+    {
+        let mut changes = Vec::new();
+        for (_, (path, new_value)) in self.pending {
+            let old_value = match &new_value {
+                Some(value) => self.tree.insert(path.clone(), value.clone()),
+                None => self.tree.remove(path.clone()),
+            };
+
+            let encoded = |value: &Option<V>| value.as_ref().map(|x| x.encode().into_owned());
+            if encoded(&old_value) != encoded(&new_value) {
+                changes.push(StateChange {
+                    path,
+                    old_value,
+                    new_value,
+                });
+            }
+        }
+
+        (self.tree.compute_hash().clone(), changes)
+    }
+
+    fn apply_into_tree(self) -> &'a mut PatriciaMerkleTree<P, V, H> {
+        for (_, (path, value)) in self.pending {
+            match value {
+                Some(value) => {
+                    self.tree.insert(path, value);
+                }
+                None => {
+                    self.tree.remove(path);
+                }
+            }
+        }
+
+        self.tree
+    }
+
+    /// Apply the pending writes, commit the tree into the database and make it the database's
+    /// root, but only if the database's root is still `expected_parent_root` (the root the batch
+    /// was built upon). Databases without a root are treated as holding an empty tree.
+    ///
+    /// When the root changed, neither the tree nor the database are modified and the writes are
+    /// discarded, so that they can be redone on top of the new root. This allows several writers
+    /// to share a database without locking it while preparing their changes.
+    ///
+    /// The new root is swapped in before its nodes are stored, so that nothing is written when the
+    /// swap fails. Changes are only notified to watchers once the swap succeeds.
+    pub fn commit_if_root<D>(
+        self,
+        db: &mut D,
+        expected_parent_root: &[u8],
+    ) -> Result<Output<H>, RootChanged>
+    where
+        P: Clone,
+        D: RootedDb + ?Sized,
+    {
+        let empty_root = || H::digest([0x80]).to_vec();
+        let current = db.root();
+        let actual = current.clone().unwrap_or_else(empty_root);
+        if actual != expected_parent_root {
+            return Err(RootChanged {
+                expected: expected_parent_root.to_vec(),
+                actual,
+            });
+        }
+
+        // Keep the previous values around to be able to revert the writes.
+        let tree = self.tree;
+        let mut writes = Vec::with_capacity(self.pending.len());
+        for (key, (path, value)) in self.pending {
+            let new_value = tree
+                .is_tracked(&key)
+                .then(|| value.as_ref().map(|x| x.encode().into_owned()));
+            let old_value = match value {
+                Some(value) => tree.insert_value(path.clone(), value),
+                None => tree.remove_value(path.clone()),
+            }
+            .unwrap_or_else(|e| panic!("{e}"));
+            writes.push((key, path, old_value, new_value));
+        }
+
+        let root = tree.compute_hash().clone();
+        if let Err(actual) = db.compare_and_swap_root(current.as_deref(), root.to_vec()) {
+            for (_, path, old_value, _) in writes.into_iter().rev() {
+                match old_value {
+                    Some(old_value) => tree.insert_value(path, old_value),
+                    None => tree.remove_value(path),
+                }
+                .unwrap_or_else(|e| panic!("{e}"));
+            }
+
+            return Err(RootChanged {
+                expected: expected_parent_root.to_vec(),
+                actual: actual.unwrap_or_else(empty_root),
+            });
+        }
+
+        commit(tree, db);
+        tree.allocations.update(tree.memory_usage().1);
+        for (key, _, old_value, new_value) in writes {
+            if let Some(new_value) = new_value {
+                let old_value = old_value.map(|x| x.encode().into_owned());
+                tree.track_change(key, old_value, new_value);
+            }
+        }
+
+        Ok(root)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        db::{MemoryDb, NodeDb},
+        load_tree, pmt_tree,
+    };
+    use sha3::Keccak256;
+
+    #[test]
+    fn read_your_writes() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.insert(vec![0x34], vec![2]);
+        let root = *tree.compute_hash();
+
+        let mut batch = tree.batch();
+        batch.insert(vec![0x12], vec![3]);
+        batch.insert(vec![0x56], vec![4]);
+        batch.remove(vec![0x34]);
+        batch.remove(vec![0x78]);
+
+        assert_eq!(batch.len(), 4);
+        assert_eq!(batch.get(&vec![0x12]), Some(&vec![3]));
+        assert_eq!(batch.get(&vec![0x56]), Some(&vec![4]));
+        assert_eq!(batch.get(&vec![0x34]), None);
+        assert!(!batch.contains(&vec![0x78]));
+        assert_eq!(batch.tree().get(&vec![0x34]), Some(&vec![2]));
+        drop(batch);
+
+        assert_eq!(tree.compute_hash(), &root);
+        assert_eq!(tree.get(&vec![0x12]), Some(&vec![1]));
+
+        let mut batch = tree.batch();
+        batch.insert(vec![0x12], vec![3]);
+        batch.insert(vec![0x56], vec![4]);
+        batch.remove(vec![0x34]);
+        batch.insert(vec![0x34], vec![5]);
+        batch.remove(vec![0x34]);
+        batch.apply();
+
+        let mut expected = pmt_tree!(Vec<u8>);
+        expected.insert(vec![0x12], vec![3]);
+        expected.insert(vec![0x56], vec![4]);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.compute_hash(), expected.compute_hash());
+    }
+
+    #[test]
+    fn apply_block() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.insert(vec![0x34], vec![2]);
+        tree.insert(vec![0x56], vec![3]);
+
+        let mut batch = tree.batch();
+        batch.insert(vec![0x12], vec![4]);
+        batch.insert(vec![0x34], vec![2]);
+        batch.remove(vec![0x56]);
+        batch.remove(vec![0x78]);
+        batch.insert(vec![0x9A], vec![5]);
+        let (root, changes) = batch.apply_block();
+
+        let change = |path, old_value, new_value| StateChange {
+            path,
+            old_value,
+            new_value,
+        };
+        assert_eq!(
+            changes,
+            [
+                change(vec![0x12], Some(vec![1]), Some(vec![4])),
+                change(vec![0x56], Some(vec![3]), None),
+                change(vec![0x9A], None, Some(vec![5])),
+            ]
+        );
+        assert_eq!(tree.compute_hash(), &root);
+        assert_eq!(tree.len(), 3);
+
+        let (new_root, changes) = tree.batch().apply_block();
+        assert_eq!((new_root, changes), (root, Vec::new()));
+    }
+
+    #[test]
+    fn commit_if_root() {
+        let mut db = MemoryDb::new();
+        let mut writer1 = pmt_tree!(Vec<u8>);
+        let mut writer2 = pmt_tree!(Vec<u8>);
+        let empty_root = Keccak256::digest([0x80]);
+
+        let mut batch = writer1.batch();
+        batch.insert(vec![0x12], vec![1; 32]);
+        let root1 = batch.commit_if_root(&mut db, &empty_root).unwrap();
+        assert_eq!(db.root(), Some(root1.to_vec()));
+
+        // The second writer prepared its changes over the empty tree, so it has to redo them.
+        let mut batch = writer2.batch();
+        batch.insert(vec![0x34], vec![2; 32]);
+        assert_eq!(
+            batch.commit_if_root(&mut db, &empty_root),
+            Err(RootChanged {
+                expected: empty_root.to_vec(),
+                actual: root1.to_vec(),
+            }),
+        );
+        assert!(writer2.is_empty());
+
+        let mut writer2 = load_tree::<Keccak256, _>(&db, &root1).unwrap();
+        let mut batch = writer2.batch();
+        batch.insert(vec![0x34], vec![2; 32]);
+        let root2 = batch.commit_if_root(&mut db, &root1).unwrap();
+        assert_eq!(db.root(), Some(root2.to_vec()));
+
+        let mut loaded = load_tree::<Keccak256, _>(&db, &root2).unwrap();
+        assert_eq!(loaded.get(&vec![0x12]), Some(&vec![1; 32]));
+        assert_eq!(loaded.compute_hash(), &root2);
+    }
+
+    /// A database whose root is replaced by another writer right after being read.
+    struct RacingDb {
+        db: MemoryDb,
+        root: Option<Vec<u8>>,
+    }
+
+    impl NodeDb for RacingDb {
+        fn get(&self, hash: &[u8]) -> Option<Vec<u8>> {
+            self.db.get(hash)
+        }
+
+        fn insert(&mut self, hash: Vec<u8>, encoded: Vec<u8>) {
+            self.db.insert(hash, encoded);
+        }
+
+        fn remove(&mut self, hash: &[u8]) {
+            NodeDb::remove(&mut self.db, hash);
+        }
+    }
+
+    impl RootedDb for RacingDb {
+        fn root(&self) -> Option<Vec<u8>> {
+            self.root.clone()
+        }
+
+        fn compare_and_swap_root(
+            &mut self,
+            expected: Option<&[u8]>,
+            new: Vec<u8>,
+        ) -> Result<(), Option<Vec<u8>>> {
+            self.db.compare_and_swap_root(expected, new)
+        }
+    }
+
+    #[test]
+    fn commit_if_root_race() {
+        let mut db = RacingDb {
+            db: MemoryDb::new(),
+            root: None,
+        };
+        let mut other = pmt_tree!(Vec<u8>);
+        other.insert(vec![0x12], vec![1; 32]);
+        let other_root = commit(&mut other, &mut db.db);
+        db.db
+            .compare_and_swap_root(None, other_root.to_vec())
+            .unwrap();
+        let len = db.db.len();
+
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x34], vec![2; 32]);
+        tree.insert(vec![0x56], vec![3; 32]);
+        let root = *tree.compute_hash();
+        let receiver = tree.watch(&[]);
+
+        let mut batch = tree.batch();
+        batch.insert(vec![0x34], vec![4; 32]);
+        batch.insert(vec![0x78], vec![5; 32]);
+        batch.remove(vec![0x56]);
+        assert_eq!(
+            batch.commit_if_root(&mut db, &Keccak256::digest([0x80])),
+            Err(RootChanged {
+                expected: Keccak256::digest([0x80]).to_vec(),
+                actual: other_root.to_vec(),
+            }),
+        );
+
+        assert_eq!(tree.compute_hash(), &root);
+        assert_eq!(tree.get(&vec![0x56]), Some(&vec![3; 32]));
+        assert_eq!(tree.get(&vec![0x78]), None);
+        assert_eq!(db.db.len(), len);
+        assert!(receiver.try_recv().is_err());
+    }
+}
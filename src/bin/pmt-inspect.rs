@@ -0,0 +1,181 @@
+//! Command line tool to inspect tries persisted as a chain of snapshots (see
+//! [`SnapshotWriter`](patricia_merkle_tree::SnapshotWriter)), using Keccak-256 hashes.
+//!
+//! ```text
+//! pmt-inspect stats <snapshot> [<delta>...]
+//! pmt-inspect dump [--prefix <hex>] <snapshot> [<delta>...]
+//! pmt-inspect verify <snapshot> [<delta>...]
+//! pmt-inspect proof --key <hex> <snapshot> [<delta>...]
+//! ```
+
+use patricia_merkle_tree::{
+    check, dump::TreeDump, get_proof_from_db, load_tree, read_snapshot_chain, verify_proof,
+    FsckRoot, MemoryDb, RawNode, StatsDb,
+};
+use sha3::{digest::Output, Keccak256};
+use std::{
+    env,
+    error::Error,
+    fs::File,
+    io::{self, BufReader, Write},
+    process::ExitCode,
+};
+
+const USAGE: &str = "usage:
+    pmt-inspect stats <snapshot> [<delta>...]
+    pmt-inspect dump [--prefix <hex>] <snapshot> [<delta>...]
+    pmt-inspect verify <snapshot> [<delta>...]
+    pmt-inspect proof --key <hex> <snapshot> [<delta>...]";
+
+struct Args {
+    command: String,
+    prefix: Option<Vec<u8>>,
+    key: Option<Vec<u8>>,
+    /// The full snapshot followed by its deltas.
+    files: Vec<String>,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Some(args) => args,
+        None => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut parsed = Args {
+        command: args.next()?,
+        prefix: None,
+        key: None,
+        files: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => parsed.prefix = Some(decode_hex(&args.next()?)?),
+            "--key" => parsed.key = Some(decode_hex(&args.next()?)?),
+            _ => parsed.files.push(arg),
+        }
+    }
+
+    let is_valid = match parsed.command.as_str() {
+        "stats" | "verify" => parsed.prefix.is_none() && parsed.key.is_none(),
+        "dump" => parsed.key.is_none(),
+        "proof" => parsed.prefix.is_none() && parsed.key.is_some(),
+        _ => false,
+    };
+    (is_valid && !parsed.files.is_empty()).then_some(parsed)
+}
+
+/// Run a command, returning whether the trie passed it.
+fn run(args: &Args) -> Result<bool, Box<dyn Error>> {
+    let (base, deltas) = args.files.split_first().expect("checked while parsing");
+    let deltas = deltas
+        .iter()
+        .map(|path| File::open(path).map(BufReader::new))
+        .collect::<io::Result<Vec<_>>>()?;
+    let (db, root) =
+        read_snapshot_chain::<Keccak256, _>(BufReader::new(File::open(base)?), deltas)?;
+
+    let mut stdout = io::stdout().lock();
+    match args.command.as_str() {
+        "stats" => write_stats(&mut stdout, &db, &root)?,
+        "dump" => {
+            let tree = load_tree::<Keccak256, _>(&db, &root)?;
+            let dump = TreeDump::new(&tree, &mut stdout, 0);
+            match &args.prefix {
+                Some(prefix) => dump.dump_prefix(prefix),
+                None => dump.dump(),
+            }
+        }
+        "verify" => {
+            let report = check::<Keccak256, _>(&db, &[FsckRoot::new(root.clone())]);
+            write!(stdout, "{report}")?;
+            if !report.is_ok() {
+                return Ok(false);
+            }
+
+            load_tree::<Keccak256, _>(&db, &root)?.verify_integrity()?;
+            writeln!(stdout, "ok")?;
+        }
+        "proof" => {
+            let key = args.key.as_deref().expect("checked while parsing");
+            let proof = get_proof_from_db::<Keccak256, _>(&db, &root, key)?;
+            for node in &proof {
+                writeln!(stdout, "{}", encode_hex(node))?;
+            }
+
+            let mut root_hash = Output::<Keccak256>::default();
+            root_hash.copy_from_slice(&root);
+            match verify_proof::<Keccak256>(&root_hash, key, proof) {
+                Some(Some(value)) => writeln!(stdout, "value {}", encode_hex(&value))?,
+                Some(None) => writeln!(stdout, "absent")?,
+                None => return Err("the generated proof doesn't verify".into()),
+            }
+        }
+        _ => unreachable!("checked while parsing"),
+    }
+
+    Ok(true)
+}
+
+fn write_stats(writer: &mut impl Write, db: &MemoryDb, root: &[u8]) -> Result<(), Box<dyn Error>> {
+    let (mut branches, mut extensions, mut leaves) = (0, 0, 0);
+    for (_, encoded) in db.iter() {
+        match RawNode::decode(encoded)? {
+            RawNode::Branch { .. } => branches += 1,
+            RawNode::Extension { .. } => extensions += 1,
+            RawNode::Leaf { .. } => leaves += 1,
+        }
+    }
+    let report = check::<Keccak256, _>(db, &[FsckRoot::new(root.to_vec())]);
+    let stats = db.storage_stats();
+
+    writeln!(writer, "root {}", encode_hex(root))?;
+    match report.lens[0] {
+        Some(len) => writeln!(writer, "values {len}")?,
+        None => writeln!(writer, "values unknown (incomplete trie)")?,
+    }
+    writeln!(
+        writer,
+        "nodes {} (branches {branches}, extensions {extensions}, leaves {leaves})",
+        stats.node_count,
+    )?;
+    writeln!(
+        writer,
+        "bytes {} (nodes {}, values {}, indices {})",
+        stats.nodes + stats.values + stats.indices,
+        stats.nodes,
+        stats.values,
+        stats.indices,
+    )?;
+
+    Ok(())
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    if value.len() & 1 != 0 {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{x:02x}")).collect()
+}
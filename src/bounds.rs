@@ -0,0 +1,151 @@
+use crate::{node::Node, proof::to_nibbles, Encode, NodeRef, PatriciaMerkleTree, ValueRef};
+use digest::Digest;
+
+/// Return the value with the lowest (or the highest if `last`) path starting with `prefix` (as
+/// encoded bytes), if any.
+///
+/// It only visits the nodes along the prefix, then the leftmost (or rightmost) ones below it.
+pub(crate) fn find_under_prefix<'a, P, V, H>(
+    tree: &'a PatriciaMerkleTree<P, V, H>,
+    prefix: &[u8],
+    last: bool,
+) -> Option<(&'a P, &'a V)>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let node_ref = find_subtree(tree, &to_nibbles(prefix))?;
+    let value_ref = find_extreme(tree, node_ref, last)?;
+    let (path, value) = tree.values.get(*value_ref)?;
+
+    // Leaves aren't split by the prefix, so their paths still have to be checked.
+    path.encode()
+        .as_ref()
+        .starts_with(prefix)
+        .then_some((path, value))
+}
+
+/// Return the root of the smallest subtree holding every path starting with `prefix` (in
+/// nibbles).
+pub(crate) fn find_subtree<P, V, H>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    prefix: &[u8],
+) -> Option<NodeRef>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut rest = prefix;
+    let mut node_ref = tree.root_ref;
+    while !rest.is_empty() {
+        match tree.nodes.get(*node_ref)? {
+            Node::Branch(branch_node) => {
+                node_ref = branch_node.choices[rest[0] as usize];
+                rest = &rest[1..];
+            }
+            Node::Extension(extension_node) => {
+                let extension_prefix = extension_node.prefix.iter().map(u8::from);
+                let extension_prefix = extension_prefix.collect::<Vec<_>>();
+                if extension_prefix.starts_with(rest) {
+                    break;
+                }
+
+                rest = rest.strip_prefix(extension_prefix.as_slice())?;
+                node_ref = extension_node.child_ref;
+            }
+            Node::Leaf(_) | Node::Stub(_) => break,
+        }
+    }
+
+    node_ref.is_valid().then_some(node_ref)
+}
+
+/// Return the value with the lowest (or the highest if `last`) path within a subtree.
+fn find_extreme<P, V, H>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    mut node_ref: NodeRef,
+    last: bool,
+) -> Option<ValueRef>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    loop {
+        node_ref = match tree.nodes.get(*node_ref)? {
+            Node::Branch(branch_node) => {
+                let mut children = branch_node.choices.iter().filter(|x| x.is_valid());
+                // A branch's value has the shortest path within its subtree.
+                let child_ref = match last {
+                    false if branch_node.value_ref.is_valid() => {
+                        return Some(branch_node.value_ref)
+                    }
+                    false => children.next(),
+                    true => children.next_back(),
+                };
+                match child_ref {
+                    Some(child_ref) => *child_ref,
+                    None => {
+                        return branch_node
+                            .value_ref
+                            .is_valid()
+                            .then_some(branch_node.value_ref)
+                    }
+                }
+            }
+            Node::Extension(extension_node) => extension_node.child_ref,
+            Node::Leaf(leaf_node) => return Some(leaf_node.value_ref),
+            Node::Stub(_) => return None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+
+    #[test]
+    fn first_under_prefix() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        assert_eq!(tree.first_under_prefix(&[]), None);
+        for path in [&b"doge"[..], b"do", b"horse", b"dog", b"dot"] {
+            tree.insert(path.to_vec(), path.to_vec());
+        }
+
+        let first = |prefix: &[u8]| tree.first_under_prefix(prefix).map(|x| x.0.as_slice());
+        let last = |prefix: &[u8]| tree.last_under_prefix(prefix).map(|x| x.0.as_slice());
+        assert_eq!(first(b""), Some(&b"do"[..]));
+        assert_eq!(last(b""), Some(&b"horse"[..]));
+        assert_eq!(first(b"dog"), Some(&b"dog"[..]));
+        assert_eq!(last(b"dog"), Some(&b"doge"[..]));
+        assert_eq!(last(b"do"), Some(&b"dot"[..]));
+        assert_eq!(first(b"h"), Some(&b"horse"[..]));
+        assert_eq!(last(b"hors"), Some(&b"horse"[..]));
+        assert_eq!(first(b"horses"), None);
+        assert_eq!(first(b"hat"), None);
+        assert_eq!(last(b"e"), None);
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_first_under_prefix(
+            data in btree_map(vec(0..4u8, 0..4), vec(any::<u8>(), 1..4), 0..64),
+            prefix in vec(0..4u8, 0..3),
+        ) {
+            let mut tree = pmt_tree!(Vec<u8>);
+            for (path, value) in &data {
+                tree.insert(path.clone(), value.clone());
+            }
+
+            let mut expected = data.iter().filter(|(path, _)| path.starts_with(&prefix));
+            prop_assert_eq!(tree.first_under_prefix(&prefix), expected.clone().next());
+            prop_assert_eq!(tree.last_under_prefix(&prefix), expected.next_back());
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use crate::storage::ValueRef;
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::{Mutex, MutexGuard},
+};
+
+/// Sizes of a tree's lookup caches. `None` disables a cache.
+///
+/// Every cache is disabled by default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheConfig {
+    /// Number of recently read paths whose values are located without walking the tree.
+    pub leaf_cache: Option<usize>,
+    /// Number of paths recently found not to be in the tree.
+    pub negative_cache: Option<usize>,
+}
+
+/// Counters of a single cache, since it was enabled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Number of entries currently cached.
+    pub len: usize,
+    pub capacity: usize,
+}
+
+/// Counters of every lookup cache of a tree. Disabled caches have every counter at zero.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub leaf_cache: CacheCounters,
+    pub negative_cache: CacheCounters,
+}
+
+/// A cache which evicts its least recently used entry when full.
+#[derive(Clone, Debug)]
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    /// Entries along with the tick of their last use.
+    entries: HashMap<K, (V, u64)>,
+    /// Keys by the tick of their last use.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    counters: CacheCounters,
+}
+
+impl<K, V> Lru<K, V>
+where
+    K: Clone + Eq + Hash,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            counters: CacheCounters {
+                capacity,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn counters(&self) -> CacheCounters {
+        CacheCounters {
+            len: self.entries.len(),
+            ..self.counters
+        }
+    }
+
+    /// Return an entry, marking it as the most recently used.
+    #[cfg(test)]
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.get_if(key, |_| true)
+    }
+
+    /// Return an entry if it's still valid, marking it as the most recently used. Invalid
+    /// entries are removed.
+    pub fn get_if<Q>(&mut self, key: &Q, is_valid: impl FnOnce(&V) -> bool) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.entries.get(key) {
+            Some((value, _)) if is_valid(value) => self.counters.hits += 1,
+            Some(_) => {
+                self.remove(key);
+                self.counters.misses += 1;
+                return None;
+            }
+            None => {
+                self.counters.misses += 1;
+                return None;
+            }
+        }
+
+        let (value, tick) = self.entries.get_mut(key)?;
+        let key = self.order.remove(tick).expect("inconsistent cache order");
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, key);
+
+        Some(value)
+    }
+
+    /// Insert an entry, evicting the least recently used one if full.
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&tick);
+        } else if self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("inconsistent cache order");
+            self.entries.remove(&oldest);
+            self.counters.evictions += 1;
+        }
+        self.order.insert(self.tick, key);
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let (value, tick) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+
+        Some(value)
+    }
+}
+
+/// A cache keyed by encoded paths, when enabled.
+///
+/// Lookups only borrow the tree, hence the lock. It's only created when enabling the cache (which
+/// borrows the tree mutably), so that lookups don't lock anything while it's disabled.
+#[derive(Debug)]
+pub(crate) struct PathCache<V>(Option<Mutex<Lru<Vec<u8>, V>>>);
+
+/// Encoded paths recently found not to be in the tree.
+pub(crate) type NegativeCache = PathCache<()>;
+
+impl<V> PathCache<V>
+where
+    V: Clone,
+{
+    pub fn enable(&mut self, capacity: usize) {
+        self.0 = Some(Mutex::new(Lru::new(capacity)));
+    }
+
+    pub fn disable(&mut self) {
+        self.0 = None;
+    }
+
+    /// Enable the cache with the given capacity, or disable it if `None`.
+    pub fn configure(&mut self, capacity: Option<usize>) {
+        match capacity {
+            Some(capacity) => self.enable(capacity),
+            None => self.disable(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.lock()?.capacity)
+    }
+
+    pub fn counters(&self) -> CacheCounters {
+        self.lock().map(|lru| lru.counters()).unwrap_or_default()
+    }
+
+    pub fn get(&self, path: &[u8]) -> Option<V> {
+        self.get_if(path, |_| true)
+    }
+
+    pub fn get_if(&self, path: &[u8], is_valid: impl FnOnce(&V) -> bool) -> Option<V> {
+        self.lock()?.get_if(path, is_valid).cloned()
+    }
+
+    pub fn insert(&self, path: &[u8], value: V) {
+        if let Some(mut lru) = self.lock() {
+            lru.insert(path.to_vec(), value);
+        }
+    }
+
+    pub fn remove(&mut self, path: &[u8]) {
+        if let Some(lru) = self.0.as_mut() {
+            // The cache is always consistent between operations.
+            lru.get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(path);
+        }
+    }
+
+    /// Lock the cache if it's enabled.
+    fn lock(&self) -> Option<MutexGuard<'_, Lru<Vec<u8>, V>>> {
+        // The cache is always consistent between operations.
+        let lru = self.0.as_ref()?;
+        Some(lru.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl<V> Clone for PathCache<V>
+where
+    V: Clone,
+{
+    fn clone(&self) -> Self {
+        Self(self.lock().map(|lru| Mutex::new(lru.clone())))
+    }
+}
+
+impl<V> Default for PathCache<V> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+/// References to the values of recently read paths.
+///
+/// Entries are tagged with the generation they were cached in, which is bumped whenever values
+/// are moved or removed in bulk so that they can't point to the wrong value.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LeafCache {
+    entries: PathCache<(ValueRef, u64)>,
+    generation: u64,
+}
+
+impl LeafCache {
+    pub fn configure(&mut self, capacity: Option<usize>) {
+        self.entries.configure(capacity);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.entries.is_enabled()
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.entries.capacity()
+    }
+
+    pub fn counters(&self) -> CacheCounters {
+        self.entries.counters()
+    }
+
+    /// Return the cached reference to the value at `path`, unless `is_current` says it no longer
+    /// points to it (in which case the entry is dropped).
+    pub fn get(&self, path: &[u8], is_current: impl FnOnce(ValueRef) -> bool) -> Option<ValueRef> {
+        let (value_ref, _) = self.entries.get_if(path, |(value_ref, generation)| {
+            *generation == self.generation && is_current(*value_ref)
+        })?;
+
+        Some(value_ref)
+    }
+
+    pub fn insert(&self, path: &[u8], value_ref: ValueRef) {
+        self.entries.insert(path, (value_ref, self.generation));
+    }
+
+    /// Forget about `path`, whose value was removed.
+    pub fn remove(&mut self, path: &[u8]) {
+        self.entries.remove(path);
+    }
+
+    /// Forget about every path.
+    pub fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn lru() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, 'a');
+        lru.insert(2, 'b');
+        assert_eq!(lru.get(&1), Some(&'a'));
+
+        lru.insert(3, 'c');
+        assert_eq!(lru.len(), 2);
+        assert_eq!(
+            lru.counters(),
+            CacheCounters {
+                hits: 1,
+                misses: 0,
+                evictions: 1,
+                len: 2,
+                capacity: 2,
+            }
+        );
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.remove(&1), Some('a'));
+        assert_eq!(lru.get(&3), Some(&'c'));
+    }
+
+    #[test]
+    fn negative_cache() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.enable_negative_cache(2);
+
+        assert_eq!(tree.get(&vec![0x34]), None);
+        assert_eq!(tree.get(&vec![0x56]), None);
+        assert!(tree.negative_cache.get(&[0x34]).is_some());
+
+        tree.insert(vec![0x34], vec![2]);
+        assert_eq!(tree.get(&vec![0x34]), Some(&vec![2]));
+        tree.remove(vec![0x34]);
+        assert_eq!(tree.get(&vec![0x34]), None);
+
+        assert_eq!(tree.get(&vec![0x78]), None);
+        assert!(tree.negative_cache.get(&[0x56]).is_none());
+
+        let clone = tree.clone();
+        tree.insert(vec![0x78], vec![3]);
+        assert_eq!(tree.get(&vec![0x78]), Some(&vec![3]));
+        assert_eq!(clone.get(&vec![0x78]), None);
+    }
+
+    #[test]
+    fn negative_cache_patch() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.enable_negative_cache(2);
+        assert_eq!(tree.get(&vec![0x34]), None);
+
+        let mut new = tree.clone();
+        new.insert(vec![0x34], vec![2]);
+        let patch = tree.create_patch(&mut new);
+        tree.apply_patch(&patch).unwrap();
+        assert_eq!(tree.get(&vec![0x34]), Some(&vec![2]));
+    }
+
+    #[test]
+    fn leaf_cache() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..16u8 {
+            tree.insert(vec![i], vec![i]);
+        }
+        tree.enable_leaf_cache(4);
+
+        assert_eq!(tree.get(&vec![3]), Some(&vec![3]));
+        assert!(tree.leaf_cache.get(&[3], |_| true).is_some());
+
+        tree.insert(vec![3], vec![0xFF]);
+        assert_eq!(tree.get(&vec![3]), Some(&vec![0xFF]));
+
+        // The value's slot is reused by another path.
+        tree.remove(vec![3]);
+        tree.insert(vec![0x33], vec![0x33]);
+        assert_eq!(tree.get(&vec![3]), None);
+        assert_eq!(tree.get(&vec![0x33]), Some(&vec![0x33]));
+
+        assert_eq!(tree.get(&vec![4]), Some(&vec![4]));
+        tree.remove_range(vec![0]..vec![4]);
+        tree.compact();
+        assert!(tree.leaf_cache.get(&[4], |_| true).is_none());
+        for i in 4..16u8 {
+            assert_eq!(tree.get(&vec![i]), Some(&vec![i]));
+        }
+    }
+
+    #[test]
+    fn leaf_cache_patch() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![1, 1], vec![1]);
+        tree.insert(vec![2, 2], vec![2]);
+        tree.enable_leaf_cache(4);
+        assert_eq!(tree.get(&vec![1, 1]), Some(&vec![1]));
+
+        // The removed value's slot is reused by the inserted one.
+        let mut new = tree.clone();
+        new.remove(vec![1, 1]);
+        new.insert(vec![200, 1], vec![200]);
+        let patch = tree.create_patch(&mut new);
+        tree.apply_patch(&patch).unwrap();
+
+        assert_eq!(tree.get(&vec![1, 1]), None);
+        assert_eq!(tree.get(&vec![200, 1]), Some(&vec![200]));
+    }
+
+    #[test]
+    fn cache_config() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.set_cache_config(&CacheConfig {
+            leaf_cache: Some(8),
+            negative_cache: Some(1),
+        });
+
+        for _ in 0..3 {
+            tree.get(&vec![0x12]);
+        }
+        tree.get(&vec![0x34]);
+        tree.get(&vec![0x56]);
+        tree.get(&vec![0x56]);
+
+        let stats = tree.cache_stats();
+        assert_eq!((stats.leaf_cache.hits, stats.leaf_cache.misses), (2, 3));
+        assert_eq!(
+            stats.negative_cache,
+            CacheCounters {
+                hits: 1,
+                misses: 5,
+                evictions: 1,
+                len: 1,
+                capacity: 1,
+            }
+        );
+
+        tree.set_cache_config(&CacheConfig {
+            leaf_cache: None,
+            ..tree.cache_config()
+        });
+        assert_eq!(tree.cache_stats().leaf_cache, CacheCounters::default());
+        assert_eq!(tree.cache_config().negative_cache, Some(1));
+        assert_eq!(tree.get(&vec![0x12]), Some(&vec![1]));
+    }
+}
@@ -0,0 +1,118 @@
+use digest::{Digest, Output};
+
+/// An order-independent digest of a set of path-value pairs.
+///
+/// Each pair is hashed on its own and the results are added together (as big-endian integers,
+/// wrapping around), so the digest only depends on the contents and not on how they're stored
+/// or in which order they're added. Pairs can also be removed, which allows keeping it up to
+/// date incrementally.
+#[derive(Clone, Debug)]
+pub struct ContentDigest<H>
+where
+    H: Digest,
+{
+    sum: Output<H>,
+}
+
+impl<H> ContentDigest<H>
+where
+    H: Digest,
+{
+    /// Create the digest of an empty set.
+    pub fn new() -> Self {
+        Self {
+            sum: Default::default(),
+        }
+    }
+
+    /// Add a path-value pair (both encoded) to the set.
+    pub fn add(&mut self, path: &[u8], value: &[u8]) {
+        let entry_hash = Self::hash_entry(path, value);
+
+        let mut carry = 0;
+        for (x, y) in self.sum.iter_mut().zip(&entry_hash).rev() {
+            let sum = *x as u16 + *y as u16 + carry;
+            *x = sum as u8;
+            carry = sum >> 8;
+        }
+    }
+
+    /// Remove a path-value pair (both encoded) previously added to the set.
+    pub fn remove(&mut self, path: &[u8], value: &[u8]) {
+        let entry_hash = Self::hash_entry(path, value);
+
+        let mut borrow = 0;
+        for (x, y) in self.sum.iter_mut().zip(&entry_hash).rev() {
+            let difference = *x as i16 - *y as i16 - borrow;
+            *x = difference as u8;
+            borrow = (difference < 0) as i16;
+        }
+    }
+
+    /// Return the digest.
+    pub fn finalize(&self) -> Output<H> {
+        self.sum.clone()
+    }
+
+    /// Hash a single pair, prefixing the path with its length so that the boundary between
+    /// both is unambiguous.
+    fn hash_entry(path: &[u8], value: &[u8]) -> Output<H> {
+        H::new()
+            .chain_update((path.len() as u64).to_be_bytes())
+            .chain_update(path)
+            .chain_update(value)
+            .finalize()
+    }
+}
+
+impl<H> Default for ContentDigest<H>
+where
+    H: Digest,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use sha3::Keccak256;
+
+    #[test]
+    fn content_digest_order_independent() {
+        let mut a = pmt_tree!(Vec<u8>);
+        let mut b = pmt_tree!(Vec<u8>);
+        for i in 0..32u8 {
+            a.insert(vec![i], vec![i; 4]);
+            b.insert(vec![31 - i], vec![31 - i; 4]);
+        }
+        assert_eq!(a.content_digest(), b.content_digest());
+
+        b.insert(vec![0], vec![1; 4]);
+        assert_ne!(a.content_digest(), b.content_digest());
+        b.remove(vec![0]);
+        assert_ne!(a.content_digest(), b.content_digest());
+    }
+
+    #[test]
+    fn content_digest_remove() {
+        let mut digest = ContentDigest::<Keccak256>::new();
+        digest.add(b"dog", b"puppy");
+        let expected = digest.finalize();
+
+        digest.add(b"do", b"verb");
+        digest.add(b"dogverb", b"");
+        assert_ne!(digest.finalize(), expected);
+        digest.remove(b"do", b"verb");
+        digest.remove(b"dogverb", b"");
+        assert_eq!(digest.finalize(), expected);
+
+        digest.remove(b"dog", b"puppy");
+        assert_eq!(
+            digest.finalize(),
+            ContentDigest::<Keccak256>::new().finalize()
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{rlp::DecodeError, Decode, Encode};
+use std::{
+    borrow::Cow,
+    io::{self, Read},
+};
+
+/// A byte string kept as a sequence of chunks of up to a configurable size, so that
+/// multi-megabyte values (such as contract code) can be read, stored and hashed without ever
+/// being held in a single contiguous buffer.
+///
+/// Hashing streams the chunks into the hasher. Only [`encode`](Encode::encode) builds the whole
+/// value, for the operations that need it contiguously (such as generating proofs).
+#[derive(Clone, Debug)]
+pub struct ChunkedBytes {
+    chunks: Vec<Vec<u8>>,
+    chunk_size: usize,
+    len: usize,
+}
+
+impl ChunkedBytes {
+    pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+    /// Create an empty byte string split into chunks of up to `chunk_size` bytes.
+    pub fn new(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunks can't be empty");
+        Self {
+            chunks: Vec::new(),
+            chunk_size,
+            len: 0,
+        }
+    }
+
+    /// Read a whole byte string from `reader`, one chunk at a time.
+    pub fn from_reader(mut reader: impl Read, chunk_size: usize) -> io::Result<Self> {
+        let mut bytes = Self::new(chunk_size);
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            (&mut reader)
+                .take(chunk_size as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                return Ok(bytes);
+            }
+
+            bytes.extend_from_slice(&chunk);
+        }
+    }
+
+    /// Append bytes, filling the last chunk before starting new ones.
+    pub fn extend_from_slice(&mut self, mut data: &[u8]) {
+        self.len += data.len();
+        while !data.is_empty() {
+            let chunk = match self.chunks.last_mut() {
+                Some(chunk) if chunk.len() < self.chunk_size => chunk,
+                _ => {
+                    self.chunks.push(Vec::with_capacity(self.chunk_size));
+                    self.chunks.last_mut().unwrap()
+                }
+            };
+
+            let split = data.len().min(self.chunk_size - chunk.len());
+            chunk.extend_from_slice(&data[..split]);
+            data = &data[split..];
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Iterate over the chunks, in order.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        self.chunks.iter().map(Vec::as_slice)
+    }
+
+    /// Copy the whole byte string into a single buffer.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.chunks.concat()
+    }
+}
+
+impl Default for ChunkedBytes {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CHUNK_SIZE)
+    }
+}
+
+/// Byte strings are equal if their contents are, regardless of how they're split.
+impl PartialEq for ChunkedBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.chunks().flatten().eq(other.chunks().flatten())
+    }
+}
+
+impl Eq for ChunkedBytes {}
+
+impl Encode for ChunkedBytes {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        match self.chunks.as_slice() {
+            [] => Cow::Borrowed(&[]),
+            [chunk] => Cow::Borrowed(chunk),
+            _ => Cow::Owned(self.to_vec()),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.len
+    }
+
+    fn encode_chunks(&self, sink: &mut dyn FnMut(&[u8])) {
+        self.chunks().for_each(sink);
+    }
+}
+
+impl Decode for ChunkedBytes {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut bytes = Self::default();
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use std::cell::Cell;
+
+    thread_local! {
+        static CONCATENATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// A value which counts how many times its contiguous encoding is built.
+    struct Streamed(Vec<Vec<u8>>);
+
+    impl Encode for Streamed {
+        fn encode(&self) -> Cow<'_, [u8]> {
+            CONCATENATIONS.set(CONCATENATIONS.get() + 1);
+            Cow::Owned(self.0.concat())
+        }
+
+        fn encoded_len(&self) -> usize {
+            self.0.iter().map(Vec::len).sum()
+        }
+
+        fn encode_chunks(&self, sink: &mut dyn FnMut(&[u8])) {
+            self.0.iter().for_each(|x| sink(x));
+        }
+    }
+
+    #[test]
+    fn chunked_bytes() {
+        let data = (0..100_000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let bytes = ChunkedBytes::from_reader(data.as_slice(), 4096).unwrap();
+        assert_eq!(bytes.len(), data.len());
+        assert_eq!(bytes.chunks().count(), 25);
+        assert!(bytes.chunks().all(|x| x.len() <= 4096));
+        assert_eq!(bytes.to_vec(), data);
+
+        let mut other = ChunkedBytes::new(1000);
+        other.extend_from_slice(&data[..10]);
+        other.extend_from_slice(&data[10..]);
+        assert_eq!(other.chunks().count(), 100);
+        assert_eq!(other, bytes);
+        assert_eq!(ChunkedBytes::decode(&data), Ok(bytes));
+    }
+
+    #[test]
+    fn streamed_hash() {
+        let data = (0..1_000_000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let chunks = data.chunks(4096).map(<[u8]>::to_vec).collect::<Vec<_>>();
+
+        // Streamed values in both leaves and branches.
+        let mut tree = pmt_tree!(Streamed);
+        tree.insert(vec![0x12], Streamed(chunks.clone()));
+        tree.insert(vec![0x12, 0x34], Streamed(chunks.clone()));
+        tree.insert(vec![0x56], Streamed(vec![vec![0x01], vec![0x02]]));
+        CONCATENATIONS.set(0);
+
+        let mut expected = pmt_tree!(Vec<u8>);
+        expected.insert(vec![0x12], data.clone());
+        expected.insert(vec![0x12, 0x34], data.clone());
+        expected.insert(vec![0x56], vec![0x01, 0x02]);
+        assert_eq!(tree.compute_hash(), expected.compute_hash());
+        assert_eq!(CONCATENATIONS.get(), 0);
+
+        let mut chunked = pmt_tree!(ChunkedBytes);
+        for (path, value) in expected.iter() {
+            chunked.insert(path.clone(), ChunkedBytes::decode(value).unwrap());
+        }
+        chunked.insert(vec![0x78], ChunkedBytes::decode(&[0x05]).unwrap());
+        expected.insert(vec![0x78], vec![0x05]);
+        assert_eq!(chunked.compute_hash(), expected.compute_hash());
+    }
+}
@@ -1,7 +1,41 @@
-use std::borrow::Cow;
+use crate::{
+    account::{left_pad, trim_zeros},
+    hashing::{NodeEncoder, NodeWriter},
+    rlp::{decode_item, decode_list, expect_list, write_list, DecodeError, RlpItem, RlpList},
+};
+use std::{
+    borrow::Cow,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::{Deref, DerefMut},
+};
 
 pub trait Encode {
-    fn encode(&self) -> Cow<[u8]>;
+    fn encode(&self) -> Cow<'_, [u8]>;
+
+    /// Return the length of the encoding.
+    ///
+    /// Types which don't keep their encoding in a single buffer (see [`ChunkedBytes`](crate::ChunkedBytes)) can
+    /// override it along with [`encode_chunks`](Self::encode_chunks), so that hashing them
+    /// doesn't require building it.
+    fn encoded_len(&self) -> usize {
+        self.encode().len()
+    }
+
+    /// Feed the encoding to `sink`, in as many consecutive pieces as needed.
+    fn encode_chunks(&self, sink: &mut dyn FnMut(&[u8])) {
+        sink(&self.encode());
+    }
+}
+
+/// The inverse of [`Encode`], for types which have to be read back from their encoding.
+pub trait Decode: Sized {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError>;
+}
+
+impl Encode for [u8] {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
 }
 
 impl<'a> Encode for &'a [u8] {
@@ -11,7 +45,7 @@ impl<'a> Encode for &'a [u8] {
 }
 
 impl Encode for Vec<u8> {
-    fn encode(&self) -> Cow<[u8]> {
+    fn encode(&self) -> Cow<'_, [u8]> {
         Cow::Borrowed(self)
     }
 }
@@ -23,7 +57,7 @@ impl<'a> Encode for &'a str {
 }
 
 impl Encode for String {
-    fn encode(&self) -> Cow<[u8]> {
+    fn encode(&self) -> Cow<'_, [u8]> {
         Cow::Borrowed(self.as_bytes())
     }
 }
@@ -35,7 +69,386 @@ impl<'a, const N: usize> Encode for &'a [u8; N] {
 }
 
 impl<const N: usize> Encode for [u8; N] {
-    fn encode(&self) -> Cow<[u8]> {
+    fn encode(&self) -> Cow<'_, [u8]> {
         Cow::Borrowed(self.as_slice())
     }
 }
+
+impl Decode for Vec<u8> {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        Ok(data.to_vec())
+    }
+}
+
+impl Decode for String {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        String::from_utf8(data.to_vec()).map_err(|_| DecodeError::InvalidValue)
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        data.try_into().map_err(|_| DecodeError::InvalidValue)
+    }
+}
+
+/// Integers are encoded big-endian, so that paths sort in numeric order.
+macro_rules! impl_integer_codec {
+    ( $( $ty:ty ),* ) => {
+        $(
+            impl Encode for $ty {
+                fn encode(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned(self.to_be_bytes().to_vec())
+                }
+            }
+
+            impl Decode for $ty {
+                fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+                    Ok(Self::from_be_bytes(Decode::decode(data)?))
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_codec!(u16, u32, u64, u128);
+
+/// An index encoded as an RLP integer, as used for the paths of Ethereum's ordered tries
+/// (transactions, receipts and withdrawals).
+///
+/// Unlike plain integers, the encoding doesn't preserve numeric order: index zero is encoded as
+/// `0x80`, which sorts after indices up to `0x7F`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct RlpIndex(pub u64);
+
+impl Encode for RlpIndex {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        let value = self.0.to_be_bytes();
+        Cow::Owned(match trim_zeros(&value) {
+            [x] if *x < 0x80 => vec![*x],
+            x => [&[0x80 + x.len() as u8], x].concat(),
+        })
+    }
+}
+
+impl Decode for RlpIndex {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let (item, _, rest) = decode_item(data)?;
+        if !rest.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        let value = match item {
+            RlpItem::Bytes(x) => Self(u64::from_be_bytes(left_pad(x)?)),
+            RlpItem::List(_) => return Err(DecodeError::ExpectedBytes),
+        };
+
+        // Reject non-canonical encodings (leading zeros or needlessly wrapped single bytes).
+        match value.encode().as_ref() == data {
+            true => Ok(value),
+            false => Err(DecodeError::InvalidInteger),
+        }
+    }
+}
+
+impl Encode for Ipv4Addr {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.octets().to_vec())
+    }
+}
+
+impl Decode for Ipv4Addr {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        <[u8; 4]>::decode(data).map(Self::from)
+    }
+}
+
+impl Encode for Ipv6Addr {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.octets().to_vec())
+    }
+}
+
+impl Decode for Ipv6Addr {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        <[u8; 16]>::decode(data).map(Self::from)
+    }
+}
+
+/// Addresses are encoded as their octets, so both versions can be told apart by their length.
+impl Encode for IpAddr {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        match self {
+            IpAddr::V4(x) => x.encode(),
+            IpAddr::V6(x) => x.encode(),
+        }
+    }
+}
+
+impl Decode for IpAddr {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        match data.len() {
+            4 => Ipv4Addr::decode(data).map(Self::V4),
+            _ => Ipv6Addr::decode(data).map(Self::V6),
+        }
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl Encode for uuid::Uuid {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl Decode for uuid::Uuid {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        Self::from_slice(data).map_err(|_| DecodeError::InvalidValue)
+    }
+}
+
+/// A structured value which can be stored in a tree as the RLP list of its fields, through
+/// [`Rlp`].
+pub trait ValueEncode {
+    /// Write the value's fields, in order.
+    fn encode_fields(&self, fields: &mut FieldEncoder);
+}
+
+/// The inverse of [`ValueEncode`].
+pub trait ValueDecode: Sized {
+    /// Read the value's fields, in the same order they were written.
+    fn decode_fields(fields: &mut FieldDecoder<'_>) -> Result<Self, DecodeError>;
+}
+
+/// Writes the fields of a [`ValueEncode`], each one as an RLP string holding its encoding.
+#[derive(Debug, Default)]
+pub struct FieldEncoder(NodeEncoder);
+
+impl FieldEncoder {
+    pub fn field(&mut self, value: &impl Encode) -> &mut Self {
+        self.0.write_bytes(value.encode().as_ref());
+        self
+    }
+}
+
+/// Reads the fields of a [`ValueDecode`].
+#[derive(Debug)]
+pub struct FieldDecoder<'a> {
+    items: RlpList<'a>,
+    next: usize,
+}
+
+impl<'a> FieldDecoder<'a> {
+    /// Decode the next field, failing if there are no fields left.
+    pub fn field<T>(&mut self) -> Result<T, DecodeError>
+    where
+        T: Decode,
+    {
+        let item = self.items.get(self.next);
+        self.next += 1;
+        match item {
+            Some((RlpItem::Bytes(x), _)) => T::decode(x),
+            Some((RlpItem::List(_), _)) => Err(DecodeError::ExpectedBytes),
+            None => Err(DecodeError::UnexpectedEnd),
+        }
+    }
+}
+
+/// A structured value stored as the RLP list of its fields (see [`ValueEncode`] and
+/// [`ValueDecode`]), so that it can be inserted into a tree directly.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Rlp<T>(pub T);
+
+impl<T> Deref for Rlp<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Rlp<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T> Encode for Rlp<T>
+where
+    T: ValueEncode,
+{
+    fn encode(&self) -> Cow<'_, [u8]> {
+        let mut fields = FieldEncoder::default();
+        self.0.encode_fields(&mut fields);
+
+        let mut encoder = NodeEncoder::default();
+        write_list(&mut encoder, &fields.0.finalize());
+        Cow::Owned(encoder.finalize())
+    }
+}
+
+impl<T> Decode for Rlp<T>
+where
+    T: ValueDecode,
+{
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut fields = FieldDecoder {
+            items: decode_list(expect_list(data)?)?,
+            next: 0,
+        };
+        let value = T::decode_fields(&mut fields)?;
+
+        match fields.items.len() {
+            len if len == fields.next => Ok(Self(value)),
+            len => Err(DecodeError::InvalidItemCount(len)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn integer_order() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let mut tree = tree.sub_trie::<u64, u32>(b"");
+        for i in [256u64, 1, 0, u64::MAX, 255] {
+            tree.insert(&i, &(i as u32)).unwrap();
+        }
+
+        let keys = tree
+            .iter()
+            .map(|x| x.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>();
+        assert_eq!(keys, Ok(vec![0, 1, 255, 256, u64::MAX]));
+        assert_eq!(tree.get(&255), Ok(Some(255)));
+        assert_eq!(u16::decode(&[1, 2, 3]), Err(DecodeError::InvalidValue));
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Position {
+        owner: String,
+        amount: u64,
+        tag: Vec<u8>,
+    }
+
+    impl ValueEncode for Position {
+        fn encode_fields(&self, fields: &mut FieldEncoder) {
+            fields
+                .field(&self.owner)
+                .field(&self.amount)
+                .field(&self.tag);
+        }
+    }
+
+    impl ValueDecode for Position {
+        fn decode_fields(fields: &mut FieldDecoder<'_>) -> Result<Self, DecodeError> {
+            Ok(Self {
+                owner: fields.field()?,
+                amount: fields.field()?,
+                tag: fields.field()?,
+            })
+        }
+    }
+
+    #[test]
+    fn rlp_value() {
+        let position = Position {
+            owner: "alice".into(),
+            amount: 1000,
+            tag: vec![],
+        };
+
+        let mut tree = pmt_tree!(Rlp<Position>);
+        tree.insert(vec![0x12], Rlp(position.clone()));
+        assert_eq!(tree.get(&vec![0x12]).map(|x| &x.amount), Some(&1000));
+
+        let encoded = tree.get(&vec![0x12]).unwrap().encode().into_owned();
+        assert_eq!(
+            encoded,
+            [
+                &[0xD0, 0x85][..],
+                b"alice",
+                &[0x88, 0, 0, 0, 0, 0, 0, 0x03, 0xE8, 0x80]
+            ]
+            .concat()
+        );
+        assert_eq!(Rlp::<Position>::decode(&encoded), Ok(Rlp(position)));
+
+        // Missing, extra or malformed fields.
+        let short = [&[0xC6, 0x85][..], b"alice"].concat();
+        assert_eq!(
+            Rlp::<Position>::decode(&short),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        let long = [&[0xD1][..], &encoded[1..], &[0x80]].concat();
+        assert_eq!(
+            Rlp::<Position>::decode(&long),
+            Err(DecodeError::InvalidItemCount(4))
+        );
+        assert_eq!(
+            Rlp::<Position>::decode(&[0xC2, 0xC1, 0xC0]),
+            Err(DecodeError::ExpectedBytes)
+        );
+        assert_eq!(
+            Rlp::<Position>::decode(&[0x80]),
+            Err(DecodeError::ExpectedList)
+        );
+    }
+
+    #[test]
+    fn ip_addr() {
+        let v4 = IpAddr::from([192, 168, 0, 1]);
+        let v6 = IpAddr::from([0x2001, 0xDB8, 0, 0, 0, 0, 0, 1]);
+
+        assert_eq!(v4.encode().as_ref(), [192, 168, 0, 1]);
+        assert_eq!(IpAddr::decode(&v4.encode()), Ok(v4));
+        assert_eq!(IpAddr::decode(&v6.encode()), Ok(v6));
+        assert_eq!(IpAddr::decode(&[0; 5]), Err(DecodeError::InvalidValue));
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let mut tree = tree.sub_trie::<uuid::Uuid, String>(b"");
+
+        let key = uuid::Uuid::new_v4();
+        tree.insert(&key, &"value".into()).unwrap();
+        assert_eq!(
+            tree.iter().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![(key, "value".into())])
+        );
+    }
+
+    #[test]
+    fn rlp_index() {
+        for (index, encoded) in [
+            (0, &[0x80][..]),
+            (1, &[0x01]),
+            (0x7F, &[0x7F]),
+            (0x80, &[0x81, 0x80]),
+            (0x0400, &[0x82, 0x04, 0x00]),
+        ] {
+            assert_eq!(RlpIndex(index).encode().as_ref(), encoded);
+            assert_eq!(RlpIndex::decode(encoded), Ok(RlpIndex(index)));
+        }
+
+        assert_eq!(
+            RlpIndex::decode(&[0x81, 0x01]),
+            Err(DecodeError::InvalidInteger)
+        );
+        assert_eq!(
+            RlpIndex::decode(&[0x82, 0x00, 0x80]),
+            Err(DecodeError::InvalidInteger)
+        );
+        assert_eq!(RlpIndex::decode(&[0x00]), Err(DecodeError::InvalidInteger));
+        assert_eq!(
+            RlpIndex::decode(&[0x01, 0x02]),
+            Err(DecodeError::TrailingBytes)
+        );
+    }
+}
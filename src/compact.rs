@@ -0,0 +1,122 @@
+use crate::{
+    node::Node, Encode, NodeRef, NodesStorage, PatriciaMerkleTree, ValueRef, ValuesStorage,
+};
+use digest::Digest;
+use std::mem::take;
+
+/// Move every node and value into freshly allocated slabs with no vacant entries, fixing up the
+/// references between them.
+///
+/// Nodes are moved intact, so their cached hashes are kept.
+pub(crate) fn compact<P, V, H>(tree: &mut PatriciaMerkleTree<P, V, H>)
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut compactor = Compactor {
+        old_nodes: take(&mut tree.nodes),
+        values: tree.values.split_off_interner(),
+        old_values: take(&mut tree.values),
+        nodes: NodesStorage::new(),
+    };
+
+    if tree.root_ref.is_valid() {
+        tree.root_ref = compactor.move_node(tree.root_ref);
+    }
+
+    tree.nodes = compactor.nodes;
+    tree.values = compactor.values;
+}
+
+struct Compactor<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    old_nodes: NodesStorage<P, V, H>,
+    old_values: ValuesStorage<P, V>,
+
+    nodes: NodesStorage<P, V, H>,
+    values: ValuesStorage<P, V>,
+}
+
+impl<P, V, H> Compactor<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    /// Move a node along with its whole subtree, returning its new reference.
+    fn move_node(&mut self, node_ref: NodeRef) -> NodeRef {
+        let mut node = self
+            .old_nodes
+            .try_remove(*node_ref)
+            .expect("inconsistent internal tree structure");
+
+        match &mut node {
+            Node::Branch(branch_node) => {
+                for child_ref in &mut branch_node.choices {
+                    if child_ref.is_valid() {
+                        *child_ref = self.move_node(*child_ref);
+                    }
+                }
+                if branch_node.value_ref.is_valid() {
+                    branch_node.value_ref = self.move_value(branch_node.value_ref);
+                }
+            }
+            Node::Extension(extension_node) => {
+                extension_node.child_ref = self.move_node(extension_node.child_ref);
+            }
+            Node::Leaf(leaf_node) => leaf_node.value_ref = self.move_value(leaf_node.value_ref),
+            Node::Stub(_) => {}
+        }
+
+        NodeRef::new(self.nodes.insert(node))
+    }
+
+    fn move_value(&mut self, value_ref: ValueRef) -> ValueRef {
+        let value_ref = self
+            .values
+            .move_entry(&mut self.old_values, *value_ref)
+            .expect("inconsistent internal tree structure");
+
+        ValueRef::new(value_ref)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+
+    #[test]
+    fn compact() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..=255u8 {
+            tree.insert(vec![i], vec![i; 4]);
+        }
+        let root = *tree.compute_hash();
+        for i in 0..=255u8 {
+            if i % 16 != 0 {
+                tree.remove(vec![i]);
+            }
+        }
+        let expected = *tree.compute_hash();
+
+        let (used, allocated) = tree.memory_usage();
+        assert_eq!(tree.compact(), allocated - used);
+        assert_eq!(tree.memory_usage(), (used, used));
+        assert_eq!(tree.len(), 16);
+        assert_eq!(*tree.compute_hash(), expected);
+
+        for i in 0..=255u8 {
+            if i % 16 != 0 {
+                tree.insert(vec![i], vec![i; 4]);
+            }
+        }
+        assert_eq!(*tree.compute_hash(), root);
+        tree.compact();
+        assert_eq!(tree.compact(), 0);
+    }
+}
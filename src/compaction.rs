@@ -0,0 +1,381 @@
+use crate::{
+    db::{MemoryDb, NodeDb, UndecodableNode},
+    rlp::{ChildRef, RawNode},
+};
+use digest::Digest;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// A node database whose unreferenced nodes can be garbage-collected.
+pub trait CompactableDb: NodeDb {
+    /// Return the hashes of every stored node.
+    fn hashes(&self) -> Vec<Vec<u8>>;
+
+    /// Ask the backend to reclaim the space used by removed nodes (for example, by compacting its
+    /// files). Does nothing by default.
+    fn compact(&mut self) {}
+}
+
+impl CompactableDb for MemoryDb {
+    fn hashes(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|(hash, _)| hash.clone()).collect()
+    }
+}
+
+/// Limits on how much a compaction run may interfere with other users of the database.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompactionConfig {
+    /// Maximum number of node reads and removals per second, or `None` for no limit.
+    pub max_ops_per_second: Option<u32>,
+    /// Number of nodes processed each time the database lock is taken.
+    pub batch_size: usize,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            max_ops_per_second: None,
+            batch_size: 1024,
+        }
+    }
+}
+
+/// Progress of a compaction run, updated while it runs.
+#[derive(Debug, Default)]
+pub struct CompactionProgress {
+    visited: AtomicUsize,
+    bytes_read: AtomicU64,
+    removed: AtomicUsize,
+    finished: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+impl CompactionProgress {
+    /// Return the number of reachable nodes read so far.
+    pub fn visited(&self) -> usize {
+        self.visited.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of bytes of reachable nodes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of unreferenced nodes removed so far.
+    pub fn removed(&self) -> usize {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    /// Return whether the run has ended, either by completing or by being cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    /// Stop the run before its next batch.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// A compaction run in a background thread.
+#[derive(Debug)]
+pub struct CompactionHandle {
+    progress: Arc<CompactionProgress>,
+    thread: JoinHandle<Result<(), UndecodableNode>>,
+}
+
+impl CompactionHandle {
+    pub fn progress(&self) -> &CompactionProgress {
+        &self.progress
+    }
+
+    /// Wait for the run to end, returning its result (see [`collect_garbage`]).
+    pub fn join(self) -> Result<(), UndecodableNode> {
+        self.thread
+            .join()
+            .unwrap_or_else(|payload| std::panic::resume_unwind(payload))
+    }
+}
+
+/// Start garbage-collecting the database in a background thread. See [`collect_garbage`].
+pub fn spawn_compaction<H, D>(
+    db: Arc<Mutex<D>>,
+    roots: Vec<Vec<u8>>,
+    config: CompactionConfig,
+) -> CompactionHandle
+where
+    H: Digest,
+    D: CompactableDb + Send + 'static,
+{
+    let progress = Arc::new(CompactionProgress::default());
+    let thread = thread::spawn({
+        let progress = progress.clone();
+        move || collect_garbage::<H, D>(&db, &roots, &config, &progress)
+    });
+
+    CompactionHandle { progress, thread }
+}
+
+/// Remove every node not reachable from `roots`, then let the backend reclaim their space.
+///
+/// The database is locked in batches, so it can still be used while the run goes on. Nodes
+/// inserted since the run started are never removed, but nodes unreferenced when it started must
+/// not become referenced again (for example, by committing an old version of a tree).
+///
+/// Nodes missing from the database are skipped, along with their descendants. Reachable nodes
+/// which can't be decoded abort the run before anything is removed, since their descendants
+/// couldn't be told apart from garbage.
+pub fn collect_garbage<H, D>(
+    db: &Mutex<D>,
+    roots: &[Vec<u8>],
+    config: &CompactionConfig,
+    progress: &CompactionProgress,
+) -> Result<(), UndecodableNode>
+where
+    H: Digest,
+    D: CompactableDb + ?Sized,
+{
+    let mut throttle = Throttle::new(config.max_ops_per_second);
+    let batch_size = config.batch_size.max(1);
+
+    // Only nodes already present are candidates for removal.
+    let candidates = lock(db).hashes();
+
+    let empty_root = H::digest([0x80]);
+    let mut reachable = HashSet::new();
+    let mut stack = roots
+        .iter()
+        .filter(|root| root[..] != empty_root[..])
+        .map(|root| (ChildRef::Hash(root.clone()), Vec::new()))
+        .collect::<Vec<_>>();
+    while !stack.is_empty() && !progress.is_cancelled() {
+        let mut ops = 0;
+        let db = lock(db);
+        while ops < batch_size {
+            let Some((child_ref, path_prefix)) = stack.pop() else {
+                break;
+            };
+
+            let (hash, encoded) = match child_ref {
+                ChildRef::Hash(hash) => {
+                    if !reachable.insert(hash.clone()) {
+                        continue;
+                    }
+
+                    ops += 1;
+                    match db.get(&hash) {
+                        Some(encoded) => (hash, encoded),
+                        None => continue,
+                    }
+                }
+                ChildRef::Inline(encoded) => (encoded.clone(), encoded),
+            };
+
+            progress.visited.fetch_add(1, Ordering::Relaxed);
+            progress
+                .bytes_read
+                .fetch_add(encoded.len() as u64, Ordering::Relaxed);
+            let node = match RawNode::decode(&encoded) {
+                Ok(node) => node,
+                Err(error) => {
+                    progress.finished.store(true, Ordering::Release);
+                    return Err(UndecodableNode {
+                        hash,
+                        path_prefix,
+                        error,
+                    });
+                }
+            };
+            for (nibbles, child_ref) in node.children() {
+                let mut child_prefix = path_prefix.clone();
+                child_prefix.extend_from_slice(nibbles);
+                stack.push((child_ref.clone(), child_prefix));
+            }
+        }
+
+        drop(db);
+        throttle.wait(ops);
+    }
+
+    let mut garbage = candidates
+        .into_iter()
+        .filter(|hash| !reachable.contains(hash));
+    while !progress.is_cancelled() {
+        let batch = garbage.by_ref().take(batch_size).collect::<Vec<_>>();
+        if batch.is_empty() {
+            lock(db).compact();
+            break;
+        }
+
+        let mut db = lock(db);
+        for hash in &batch {
+            db.remove(hash);
+        }
+        drop(db);
+
+        progress.removed.fetch_add(batch.len(), Ordering::Relaxed);
+        throttle.wait(batch.len());
+    }
+
+    progress.finished.store(true, Ordering::Release);
+    Ok(())
+}
+
+fn lock<D>(db: &Mutex<D>) -> MutexGuard<'_, D>
+where
+    D: ?Sized,
+{
+    // The database itself is still consistent if another user panicked while holding it.
+    db.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sleeps as needed to keep the average rate of operations under a limit.
+struct Throttle {
+    max_ops_per_second: Option<u32>,
+    start: Instant,
+    ops: u64,
+}
+
+impl Throttle {
+    fn new(max_ops_per_second: Option<u32>) -> Self {
+        Self {
+            max_ops_per_second,
+            start: Instant::now(),
+            ops: 0,
+        }
+    }
+
+    fn wait(&mut self, ops: usize) {
+        let Some(max_ops_per_second) = self.max_ops_per_second else {
+            return;
+        };
+
+        self.ops += ops as u64;
+        let target =
+            Duration::from_secs_f64(self.ops as f64 / f64::from(max_ops_per_second.max(1)));
+        if let Some(delay) = target.checked_sub(self.start.elapsed()) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{check_completeness, db::commit, load_tree, pmt_tree};
+    use sha3::Keccak256;
+
+    #[test]
+    fn garbage_collection() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+
+        let mut db = MemoryDb::new();
+        let old_root = commit(&mut tree, &mut db).to_vec();
+        tree.insert(vec![0x20, 0], vec![1; 24]);
+        tree.remove(vec![0x30, 0x50]);
+        let root = commit(&mut tree, &mut db).to_vec();
+
+        let mut reference = MemoryDb::new();
+        commit(&mut tree, &mut reference);
+        let empty_root = Keccak256::digest([0x80]).to_vec();
+        let len = db.len();
+
+        let db = Mutex::new(db);
+        let progress = CompactionProgress::default();
+        let config = CompactionConfig {
+            max_ops_per_second: Some(100_000),
+            batch_size: 4,
+        };
+        collect_garbage::<Keccak256, _>(&db, &[root.clone(), empty_root], &config, &progress)
+            .unwrap();
+        let db = db.into_inner().unwrap();
+
+        assert!(progress.is_finished());
+        assert_eq!(db.len(), reference.len());
+        assert_eq!(progress.removed() + reference.len(), len);
+        assert!(progress.visited() >= reference.len());
+        assert!(check_completeness::<Keccak256, _>(&db, &root).is_complete());
+        assert!(db.get(&old_root).is_none());
+
+        let mut loaded = load_tree::<Keccak256, _>(&db, &root).unwrap();
+        assert_eq!(loaded.compute_hash(), tree.compute_hash());
+    }
+
+    #[test]
+    fn background_compaction() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let mut db = MemoryDb::new();
+        let mut roots = Vec::new();
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+            roots.push(commit(&mut tree, &mut db).to_vec());
+        }
+        let before = db.len();
+
+        let db = Arc::new(Mutex::new(db));
+        let handle = spawn_compaction::<Keccak256, _>(
+            db.clone(),
+            roots[32..].to_vec(),
+            CompactionConfig::default(),
+        );
+        while !handle.progress().is_finished() {
+            thread::yield_now();
+        }
+        let removed = handle.progress().removed();
+        handle.join().unwrap();
+
+        let db = db.lock().unwrap();
+        assert!(removed > 0);
+        assert_eq!(db.len(), before - removed);
+        for root in &roots[32..] {
+            assert!(check_completeness::<Keccak256, _>(&*db, root).is_complete());
+        }
+        assert!(db.get(&roots[0]).is_none());
+    }
+
+    #[test]
+    fn undecodable_node() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+
+        let mut db = MemoryDb::new();
+        let root = commit(&mut tree, &mut db).to_vec();
+        let child = match RawNode::decode(&db.get(&root).unwrap()).unwrap() {
+            RawNode::Branch { choices, .. } => match choices[1].clone() {
+                Some(ChildRef::Hash(hash)) => hash,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        db.insert(child.clone(), vec![0xC0, 0x00]);
+        let len = db.len();
+
+        let db = Mutex::new(db);
+        let progress = CompactionProgress::default();
+        let error =
+            collect_garbage::<Keccak256, _>(&db, &[root], &CompactionConfig::default(), &progress)
+                .unwrap_err();
+
+        assert_eq!(error.hash, child);
+        assert_eq!(error.path_prefix, vec![1]);
+        assert!(progress.is_finished());
+        assert_eq!(progress.removed(), 0);
+        assert_eq!(db.into_inner().unwrap().len(), len);
+    }
+}
@@ -0,0 +1,184 @@
+//! Traversals which yield to the executor regularly.
+//!
+//! Iterating over or hashing a tree with millions of nodes can take long enough to starve an
+//! async runtime's other tasks if done within a single poll. The variants here do the same work
+//! but return control to the executor every few steps, without depending on any runtime.
+
+use crate::{node::Node, Encode, PatriciaMerkleTree};
+use digest::{Digest, Output};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A future which is pending once (asking to be polled again right away), letting the executor
+/// run other tasks in between.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Counts the steps done since the last time the executor was yielded to.
+struct Budget {
+    every: usize,
+    remaining: usize,
+}
+
+impl Budget {
+    fn new(every: usize) -> Self {
+        let every = every.max(1);
+        Self {
+            every,
+            remaining: every,
+        }
+    }
+
+    /// Record a step, yielding if it was the last one allowed.
+    async fn step(&mut self) {
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.remaining = self.every;
+            YieldNow(false).await;
+        }
+    }
+}
+
+/// Call `f` with every value of the tree in ascending path order, yielding every `yield_every`
+/// values.
+pub(crate) async fn for_each<P, V, H>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    yield_every: usize,
+    mut f: impl FnMut(&P, &V),
+) where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut budget = Budget::new(yield_every);
+    for (path, value) in tree.iter() {
+        f(path, value);
+        budget.step().await;
+    }
+}
+
+/// Hash every dirty node of the tree bottom-up, yielding every `yield_every` nodes hashed, and
+/// return the root hash.
+pub(crate) async fn compute_hash<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    yield_every: usize,
+) -> &Output<H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut budget = Budget::new(yield_every);
+    // Nodes along with their path offset, and whether their children were hashed already.
+    let mut stack = vec![(tree.root_ref, 0, false)];
+    while let Some((node_ref, path_offset, children_hashed)) = stack.pop() {
+        let node = match tree.nodes.get(*node_ref) {
+            Some(node) if !node.is_hashed() => node,
+            _ => continue,
+        };
+
+        if children_hashed {
+            // Every child's hash is cached, so this only hashes the node itself.
+            node.compute_hash(&tree.nodes, &tree.values, path_offset);
+            budget.step().await;
+            continue;
+        }
+
+        stack.push((node_ref, path_offset, true));
+        match node {
+            Node::Branch(branch_node) => stack.extend(
+                branch_node
+                    .choices
+                    .iter()
+                    .filter(|x| x.is_valid())
+                    .map(|x| (*x, path_offset + 1, false)),
+            ),
+            Node::Extension(extension_node) => stack.push((
+                extension_node.child_ref,
+                path_offset + extension_node.prefix.len(),
+                false,
+            )),
+            Node::Leaf(_) | Node::Stub(_) => {}
+        }
+    }
+
+    tree.compute_hash()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use std::{
+        pin::pin,
+        sync::Arc,
+        task::{Wake, Waker},
+    };
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Run a future to completion, returning its output and how many times it yielded.
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        let mut yields = 0;
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return (output, yields),
+                Poll::Pending => yields += 1,
+            }
+        }
+    }
+
+    #[test]
+    fn cooperative() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..1000u16 {
+            tree.insert(
+                i.wrapping_mul(40503).to_be_bytes().to_vec(),
+                vec![i as u8; 8],
+            );
+        }
+        let mut expected = tree.clone();
+
+        let mut values = Vec::new();
+        let ((), yields) = block_on(tree.for_each_yielding(100, |path, value| {
+            values.push((path.clone(), value.clone()));
+        }));
+        assert_eq!(yields, 10);
+        assert_eq!(values.len(), 1000);
+
+        let (hash, yields) = block_on(tree.compute_hash_yielding(16));
+        assert_eq!(hash, expected.compute_hash());
+        assert!(yields > 1000 / 16);
+        assert!(tree.nodes.get(*tree.root_ref).unwrap().is_hashed());
+
+        // Only the nodes along the changed path are hashed again.
+        tree.insert(vec![0x12, 0x34], vec![0xFF]);
+        expected.insert(vec![0x12, 0x34], vec![0xFF]);
+        let (hash, yields) = block_on(tree.compute_hash_yielding(1));
+        assert_eq!(hash, expected.compute_hash());
+        assert!(yields <= 4);
+    }
+}
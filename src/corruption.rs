@@ -0,0 +1,152 @@
+use crate::{db::to_nibble_hex, node::NodeKind, NodeRef};
+use std::fmt;
+use thiserror::Error;
+
+/// An inconsistency found in a tree's internal structure, such as a reference to a node or value
+/// which isn't stored.
+///
+/// It can't be caused by using the tree's API, so finding one means there's a bug somewhere.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error(
+    "inconsistent internal tree structure at {} node {node_ref} (path {})",
+    NodeKindName(.node_kind),
+    to_nibble_hex(.path_prefix)
+)]
+pub struct CorruptionError {
+    /// Storage index of the node where the inconsistency was found.
+    pub node_ref: usize,
+    /// The node's kind, or `None` if there's no node at `node_ref`.
+    pub node_kind: Option<NodeKind>,
+    /// Nibbles from the root up to the node.
+    pub path_prefix: Vec<u8>,
+}
+
+impl CorruptionError {
+    /// A reference to a node which isn't stored.
+    pub(crate) fn missing(node_ref: NodeRef, path_prefix: Vec<u8>) -> Self {
+        Self {
+            node_ref: *node_ref,
+            node_kind: None,
+            path_prefix,
+        }
+    }
+
+    /// An inconsistency found within a node (for example, a reference to a value which isn't
+    /// stored), which is attributed to the node's reference by [`at`](Self::at).
+    pub(crate) fn within(node_kind: NodeKind, path_prefix: Vec<u8>) -> Self {
+        Self {
+            node_ref: *NodeRef::default(),
+            node_kind: Some(node_kind),
+            path_prefix,
+        }
+    }
+
+    /// Attribute the error to `node_ref` if it was found within the node itself.
+    pub(crate) fn at(mut self, node_ref: NodeRef) -> Self {
+        if self.node_ref == *NodeRef::default() {
+            self.node_ref = *node_ref;
+        }
+
+        self
+    }
+}
+
+struct NodeKindName<'a>(&'a Option<NodeKind>);
+
+impl fmt::Display for NodeKindName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            Some(NodeKind::Branch) => "branch",
+            Some(NodeKind::Extension) => "extension",
+            Some(NodeKind::Leaf) => "leaf",
+            Some(NodeKind::Stub) => "stub",
+            None => "missing",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::Node, pmt_tree};
+
+    #[test]
+    fn corrupted_value() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![0x12]);
+        tree.insert(vec![0x13], vec![0x13]);
+
+        let (value_ref, _) = tree
+            .values
+            .iter()
+            .find(|(_, (path, _))| path == &[0x12])
+            .unwrap();
+        let (leaf_ref, _) = tree
+            .nodes
+            .iter()
+            .find(|(_, node)| matches!(node, Node::Leaf(x) if *x.value_ref == value_ref))
+            .unwrap();
+        tree.values.remove(value_ref);
+
+        let error = CorruptionError {
+            node_ref: leaf_ref,
+            node_kind: Some(NodeKind::Leaf),
+            path_prefix: vec![1, 2],
+        };
+        assert_eq!(tree.try_get(&vec![0x12]), Err(error.clone()));
+        assert_eq!(tree.try_get(&vec![0x13]), Ok(Some(&vec![0x13])));
+        assert_eq!(tree.try_insert(vec![0x12], vec![0]), Err(error));
+        assert_eq!(
+            CorruptionError::missing(NodeRef::new(7), vec![1, 10]).to_string(),
+            "inconsistent internal tree structure at missing node 7 (path 1a)",
+        );
+    }
+
+    #[test]
+    fn corrupted_node() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![0x12]);
+        tree.insert(vec![0x13], vec![0x13]);
+
+        let (branch_ref, _) = tree
+            .nodes
+            .iter()
+            .find(|(_, node)| matches!(node, Node::Branch(_)))
+            .unwrap();
+        tree.nodes.remove(branch_ref);
+
+        let error = CorruptionError {
+            node_ref: branch_ref,
+            node_kind: None,
+            path_prefix: vec![1],
+        };
+        assert_eq!(tree.try_get(&vec![0x12]), Err(error.clone()));
+        assert_eq!(tree.try_get(&vec![0x20]), Ok(None));
+        assert_eq!(tree.try_remove(vec![0x13]), Err(error));
+    }
+
+    #[test]
+    fn corrupted_branch_value() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![0x12]);
+        tree.insert(vec![0x12, 0x34], vec![0x34]);
+
+        let (branch_ref, value_ref) = tree
+            .nodes
+            .iter()
+            .find_map(|(node_ref, node)| match node {
+                Node::Branch(x) => Some((node_ref, *x.value_ref)),
+                _ => None,
+            })
+            .unwrap();
+        tree.values.remove(value_ref);
+
+        let error = CorruptionError {
+            node_ref: branch_ref,
+            node_kind: Some(NodeKind::Branch),
+            path_prefix: vec![1, 2],
+        };
+        assert_eq!(tree.try_insert(vec![0x12], vec![0]), Err(error));
+        assert_eq!(tree.len(), 1);
+    }
+}
@@ -0,0 +1,1126 @@
+use crate::{
+    corruption::CorruptionError,
+    hash_index,
+    hashing::NodeHashRef,
+    hex,
+    node::Node,
+    rlp::{ChildRef, DecodeError, RawNode},
+    storage::NodeRef,
+    Encode, PatriciaMerkleTree, RootChangeEvent,
+};
+use digest::{Digest, Output};
+use std::{
+    collections::{hash_map, HashMap, HashSet},
+    fmt::{self, Display, Write},
+    io,
+};
+use thiserror::Error;
+
+/// A key-value store of encoded nodes indexed by their hash.
+pub trait NodeDb {
+    /// Return the RLP encoding of the node with the given hash.
+    fn get(&self, hash: &[u8]) -> Option<Vec<u8>>;
+    /// Store the RLP encoding of a node under its hash.
+    fn insert(&mut self, hash: Vec<u8>, encoded: Vec<u8>);
+    /// Remove the node with the given hash, if present.
+    fn remove(&mut self, hash: &[u8]);
+}
+
+/// A node database which also records the current root, so that writers sharing it can detect
+/// each other's commits.
+pub trait RootedDb: NodeDb {
+    /// Return the current root, or `None` if none was set.
+    fn root(&self) -> Option<Vec<u8>>;
+    /// Replace the current root with `new` only if it's still `expected`, atomically. Otherwise
+    /// return the current root.
+    fn compare_and_swap_root(
+        &mut self,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> Result<(), Option<Vec<u8>>>;
+}
+
+/// Writes to a node database which must be persisted all at once.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WriteBatch {
+    /// Nodes to store, as `(hash, encoded node)` pairs.
+    pub nodes: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Roots to record, as `(trie id, root hash)` pairs.
+    pub roots: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// A node database which can persist a batch of writes atomically, along with the roots of the
+/// tries they belong to.
+pub trait AtomicDb: NodeDb {
+    /// Persist every write in the batch so that, even after a crash, either all or none of them
+    /// are visible.
+    ///
+    /// On failure, none of them must be visible either.
+    fn write_batch(&mut self, batch: WriteBatch) -> io::Result<()>;
+    /// Return the root last recorded for the trie `id`.
+    fn trie_root(&self, id: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Space used by a node database, in bytes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StorageStats {
+    /// Number of nodes stored.
+    pub node_count: usize,
+    /// Encoded nodes, without the values embedded in them.
+    pub nodes: usize,
+    /// Values embedded in the nodes.
+    pub values: usize,
+    /// Keys the nodes and roots are stored under, and the roots themselves.
+    pub indices: usize,
+    /// Nodes held in memory by the backend's own cache. Backends without one report zero.
+    pub cached: usize,
+}
+
+impl StorageStats {
+    /// Account for a node stored under `hash`, splitting its encoding between node and value
+    /// bytes.
+    pub fn add_node(&mut self, hash: &[u8], encoded: &[u8]) {
+        let values = embedded_values_len(encoded);
+        self.node_count += 1;
+        self.nodes += encoded.len() - values;
+        self.values += values;
+        self.indices += hash.len();
+    }
+}
+
+/// Return the length of the values within a node and the nodes embedded in it.
+fn embedded_values_len(encoded: &[u8]) -> usize {
+    let node = match RawNode::decode(encoded) {
+        Ok(node) => node,
+        Err(_) => return 0,
+    };
+
+    let value = match &node {
+        RawNode::Branch { value, .. } => value.as_ref().map_or(0, Vec::len),
+        RawNode::Extension { .. } => 0,
+        RawNode::Leaf { value, .. } => value.len(),
+    };
+    let embedded = node
+        .children()
+        .map(|(_, child_ref)| match child_ref {
+            ChildRef::Inline(encoded) => embedded_values_len(encoded),
+            ChildRef::Hash(_) => 0,
+        })
+        .sum::<usize>();
+
+    value + embedded
+}
+
+/// A node database which can report how much space it uses.
+pub trait StatsDb: NodeDb {
+    fn storage_stats(&self) -> StorageStats;
+}
+
+/// An in-memory node database.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryDb {
+    nodes: HashMap<Vec<u8>, Vec<u8>>,
+    root: Option<Vec<u8>>,
+    trie_roots: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn remove(&mut self, hash: &[u8]) -> Option<Vec<u8>> {
+        self.nodes.remove(hash)
+    }
+
+    /// Iterate over every `(hash, encoded node)` pair in the database.
+    pub fn iter(&self) -> hash_map::Iter<'_, Vec<u8>, Vec<u8>> {
+        self.nodes.iter()
+    }
+}
+
+impl IntoIterator for MemoryDb {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = hash_map::IntoIter<Vec<u8>, Vec<u8>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.nodes.into_iter()
+    }
+}
+
+impl NodeDb for MemoryDb {
+    fn get(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        self.nodes.get(hash).cloned()
+    }
+
+    fn insert(&mut self, hash: Vec<u8>, encoded: Vec<u8>) {
+        self.nodes.insert(hash, encoded);
+    }
+
+    fn remove(&mut self, hash: &[u8]) {
+        self.nodes.remove(hash);
+    }
+}
+
+impl RootedDb for MemoryDb {
+    fn root(&self) -> Option<Vec<u8>> {
+        self.root.clone()
+    }
+
+    fn compare_and_swap_root(
+        &mut self,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> Result<(), Option<Vec<u8>>> {
+        if self.root.as_deref() != expected {
+            return Err(self.root.clone());
+        }
+
+        self.root = Some(new);
+        Ok(())
+    }
+}
+
+impl AtomicDb for MemoryDb {
+    fn write_batch(&mut self, batch: WriteBatch) -> io::Result<()> {
+        self.nodes.extend(batch.nodes);
+        self.trie_roots.extend(batch.roots);
+        Ok(())
+    }
+
+    fn trie_root(&self, id: &[u8]) -> Option<Vec<u8>> {
+        self.trie_roots.get(id).cloned()
+    }
+}
+
+impl StatsDb for MemoryDb {
+    fn storage_stats(&self) -> StorageStats {
+        let mut stats = StorageStats::default();
+        for (hash, encoded) in &self.nodes {
+            stats.add_node(hash, encoded);
+        }
+        stats.indices += self.root.as_ref().map_or(0, Vec::len)
+            + self
+                .trie_roots
+                .iter()
+                .map(|(id, root)| id.len() + root.len())
+                .sum::<usize>();
+
+        stats
+    }
+}
+
+/// Write every hashed node of the tree into the database and return the root hash.
+///
+/// Nodes whose encoding is shorter than a hash are embedded within their parents and therefore
+/// not stored on their own, except for the root which is always stored.
+pub fn commit<P, V, H, D>(tree: &mut PatriciaMerkleTree<P, V, H>, db: &mut D) -> Output<H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let root_hash = write_nodes(tree, db);
+    record_commit(tree, &root_hash);
+
+    root_hash
+}
+
+/// Write every hashed node of the tree into the database and return the root hash, without
+/// recording it as committed (see [`record_commit`]).
+pub(crate) fn write_nodes<P, V, H, D>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    db: &mut D,
+) -> Output<H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let root_hash = tree.compute_hash().clone();
+    if tree.root_ref.is_valid() {
+        commit_subtree(tree, db, tree.root_ref, 0, &root_hash);
+    }
+
+    root_hash
+}
+
+/// Record the tree's nodes as written under `root_hash`, notifying the root change callbacks if
+/// it differs from the previous commit's.
+pub(crate) fn record_commit<P, V, H>(tree: &mut PatriciaMerkleTree<P, V, H>, root_hash: &[u8])
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let dirty_nodes = tree.lifecycle.take_dirty_count(&tree.nodes);
+    let committed = (root_hash.to_vec(), tree.len());
+    let (old_root, old_len) = tree
+        .committed
+        .replace(committed)
+        .unwrap_or_else(|| (H::digest([0x80]).to_vec(), 0));
+    if tree.lifecycle.has_root_change_hooks() && old_root != root_hash {
+        tree.lifecycle.notify_root_change(&RootChangeEvent {
+            old_root,
+            new_root: root_hash.to_vec(),
+            entry_delta: tree.len() as isize - old_len as isize,
+            dirty_nodes,
+        });
+    }
+}
+
+/// Write the nodes of the subtree at `subtree_ref` (at `path_offset` nibbles from the root) into a
+/// node database, storing its root under `subtree_hash`. Hashes must have been computed already.
+pub(crate) fn commit_subtree<P, V, H, D>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    db: &mut D,
+    subtree_ref: NodeRef,
+    path_offset: usize,
+    subtree_hash: &[u8],
+) where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let mut stack = vec![(subtree_ref, path_offset)];
+    while let Some((node_ref, path_offset)) = stack.pop() {
+        let node = tree
+            .nodes
+            .get(*node_ref)
+            .expect("inconsistent internal tree structure");
+
+        // Stubbed subtrees are already stored in the backend.
+        let Some(encoded) = node.encode(&tree.nodes, &tree.values, path_offset) else {
+            continue;
+        };
+        if node_ref == subtree_ref {
+            db.insert(subtree_hash.to_vec(), encoded);
+        } else if encoded.len() >= 32 {
+            db.insert(H::digest(&encoded).to_vec(), encoded);
+        }
+
+        match node {
+            Node::Branch(branch_node) => stack.extend(
+                branch_node
+                    .choices
+                    .iter()
+                    .filter(|x| x.is_valid())
+                    .map(|x| (*x, path_offset + 1)),
+            ),
+            Node::Extension(extension_node) => stack.push((
+                extension_node.child_ref,
+                path_offset + extension_node.prefix.len(),
+            )),
+            Node::Leaf(_) | Node::Stub(_) => {}
+        }
+    }
+}
+
+/// Return the RLP encoding of the tree's node with the given hash, if any.
+///
+/// Only nodes which are referenced by their hash (the root and those not shorter than a hash) can
+/// be found.
+pub fn get_node_by_hash<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    hash: &[u8],
+) -> Option<Vec<u8>>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    if let Some(found) = hash_index::lookup(tree, hash) {
+        let (node_ref, path_offset) = found?;
+        let node = tree
+            .nodes
+            .get(*node_ref)
+            .expect("inconsistent internal tree structure");
+        return node.encode(&tree.nodes, &tree.values, path_offset);
+    }
+    if !tree.root_ref.is_valid() {
+        return None;
+    }
+
+    let mut stack = vec![(tree.root_ref, 0)];
+    let root_hash = tree.compute_hash().clone();
+    while let Some((node_ref, path_offset)) = stack.pop() {
+        let node = tree
+            .nodes
+            .get(*node_ref)
+            .expect("inconsistent internal tree structure");
+
+        // Hashes are cached after computing the root hash, so this doesn't rehash anything.
+        let matches = match node.compute_hash(&tree.nodes, &tree.values, path_offset) {
+            _ if node_ref == tree.root_ref => root_hash[..] == *hash,
+            NodeHashRef::Hashed(x) => x[..] == *hash,
+            NodeHashRef::Inline(_) => false,
+        };
+        if matches {
+            return node.encode(&tree.nodes, &tree.values, path_offset);
+        }
+
+        match node {
+            Node::Branch(branch_node) => stack.extend(
+                branch_node
+                    .choices
+                    .iter()
+                    .filter(|x| x.is_valid())
+                    .map(|x| (*x, path_offset + 1)),
+            ),
+            Node::Extension(extension_node) => stack.push((
+                extension_node.child_ref,
+                path_offset + extension_node.prefix.len(),
+            )),
+            Node::Leaf(_) | Node::Stub(_) => {}
+        }
+    }
+
+    None
+}
+
+/// A node referenced by its parent but not present in the database.
+///
+/// Returned by operations over incomplete tries, so that callers can fetch the node and retry.
+#[derive(Clone, Debug, Eq, Error, Hash, PartialEq)]
+#[error("missing node {} at path {}", hex::encode(.hash), to_nibble_hex(.path_prefix))]
+pub struct MissingNode {
+    pub hash: Vec<u8>,
+    /// Nibbles from the root up to the node.
+    pub path_prefix: Vec<u8>,
+}
+
+/// A node present in the database which couldn't be decoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UndecodableNode {
+    /// The node's hash (or its raw encoding if it was inlined in its parent).
+    pub hash: Vec<u8>,
+    /// Nibbles from the root up to the node.
+    pub path_prefix: Vec<u8>,
+    pub error: DecodeError,
+}
+
+/// A node whose encoding doesn't hash to the key it's stored under.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HashMismatch {
+    pub hash: Vec<u8>,
+    /// Nibbles from the root up to the node.
+    pub path_prefix: Vec<u8>,
+    /// The hash of the stored encoding.
+    pub actual: Vec<u8>,
+}
+
+/// A node which couldn't be read because the background thread reading it panicked.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReadPanicked {
+    pub hash: Vec<u8>,
+    /// Nibbles from the root up to the node.
+    pub path_prefix: Vec<u8>,
+}
+
+/// Result of walking a trie stored in a node database.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompletenessReport {
+    /// Number of nodes successfully decoded (including inlined ones).
+    pub visited: usize,
+    pub missing: Vec<MissingNode>,
+    pub undecodable: Vec<UndecodableNode>,
+}
+
+impl CompletenessReport {
+    /// Return whether every node reachable from the root is present and decodable.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.undecodable.is_empty()
+    }
+}
+
+impl Display for CompletenessReport {
+    /// Write the report as one whitespace-separated record per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "visited {}", self.visited)?;
+        for entry in &self.missing {
+            writeln!(
+                f,
+                "missing {} {}",
+                hex::encode(&entry.hash),
+                to_nibble_hex(&entry.path_prefix)
+            )?;
+        }
+        for entry in &self.undecodable {
+            writeln!(
+                f,
+                "undecodable {} {} {}",
+                hex::encode(&entry.hash),
+                to_nibble_hex(&entry.path_prefix),
+                entry.error,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Walk a trie stored in the database starting from its root, collecting the nodes which are
+/// missing or can't be decoded instead of stopping at the first one.
+pub fn check_completeness<H, D>(db: &D, root: &[u8]) -> CompletenessReport
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let mut report = CompletenessReport::default();
+    if root == &H::digest([0x80])[..] {
+        return report;
+    }
+
+    let mut visited = HashSet::new();
+    let mut stack = vec![(ChildRef::Hash(root.to_vec()), Vec::new())];
+    while let Some((child_ref, path_prefix)) = stack.pop() {
+        let (hash, encoded) = match child_ref {
+            ChildRef::Hash(hash) => {
+                if !visited.insert(hash.clone()) {
+                    continue;
+                }
+
+                match db.get(&hash) {
+                    Some(encoded) => (hash, encoded),
+                    None => {
+                        report.missing.push(MissingNode { hash, path_prefix });
+                        continue;
+                    }
+                }
+            }
+            ChildRef::Inline(encoded) => (encoded.clone(), encoded),
+        };
+
+        match RawNode::decode(&encoded) {
+            Ok(node) => {
+                report.visited += 1;
+                for (nibbles, child_ref) in node.children() {
+                    let mut child_prefix = path_prefix.clone();
+                    child_prefix.extend_from_slice(nibbles);
+                    stack.push((child_ref.clone(), child_prefix));
+                }
+            }
+            Err(error) => report.undecodable.push(UndecodableNode {
+                hash,
+                path_prefix,
+                error,
+            }),
+        }
+    }
+
+    report
+}
+
+/// Errors found while loading a trie from a node database.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum LoadError {
+    #[error(transparent)]
+    MissingNode(#[from] MissingNode),
+    #[error("node {} can't be decoded: {}", hex::encode(&.0.hash), .0.error)]
+    UndecodableNode(UndecodableNode),
+    #[error("node {} doesn't match its hash", hex::encode(&.0.hash))]
+    HashMismatch(HashMismatch),
+    #[error("reading node {} panicked", hex::encode(&.0.hash))]
+    ReadPanicked(ReadPanicked),
+    #[error(transparent)]
+    Corruption(#[from] CorruptionError),
+}
+
+impl LoadError {
+    /// Return the nibbles leading to the node the error is about, under which every path is
+    /// affected.
+    pub fn path_prefix(&self) -> &[u8] {
+        match self {
+            LoadError::MissingNode(x) => &x.path_prefix,
+            LoadError::UndecodableNode(x) => &x.path_prefix,
+            LoadError::HashMismatch(x) => &x.path_prefix,
+            LoadError::ReadPanicked(x) => &x.path_prefix,
+            LoadError::Corruption(x) => &x.path_prefix,
+        }
+    }
+
+    /// Return whether the node exists but is corrupt.
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self,
+            LoadError::UndecodableNode(_) | LoadError::HashMismatch(_),
+        )
+    }
+}
+
+/// Rebuild a trie with byte paths from the nodes stored in the database.
+pub fn load_tree<H, D>(
+    db: &D,
+    root: &[u8],
+) -> Result<PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>, LoadError>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let mut tree = PatriciaMerkleTree::new();
+    if root == &H::digest([0x80])[..] {
+        return Ok(tree);
+    }
+
+    let mut stack = vec![(ChildRef::Hash(root.to_vec()), Vec::new())];
+    while let Some((child_ref, path_prefix)) = stack.pop() {
+        let (hash, encoded) = match child_ref {
+            ChildRef::Hash(hash) => match db.get(&hash) {
+                Some(encoded) => (hash, encoded),
+                None => return Err(LoadError::MissingNode(MissingNode { hash, path_prefix })),
+            },
+            ChildRef::Inline(encoded) => (encoded.clone(), encoded),
+        };
+
+        let undecodable = |path_prefix, error| {
+            LoadError::UndecodableNode(UndecodableNode {
+                hash: hash.clone(),
+                path_prefix,
+                error,
+            })
+        };
+
+        let node = RawNode::decode(&encoded).map_err(|e| undecodable(path_prefix.clone(), e))?;
+        let value = match &node {
+            RawNode::Branch { value, .. } => value.as_ref().map(|x| (path_prefix.clone(), x)),
+            RawNode::Extension { .. } => None,
+            RawNode::Leaf { path, value } => {
+                let mut full_path = path_prefix.clone();
+                full_path.extend_from_slice(path);
+                Some((full_path, value))
+            }
+        };
+        if let Some((nibbles, value)) = value {
+            if nibbles.len() & 1 != 0 {
+                return Err(undecodable(path_prefix, DecodeError::InvalidPath));
+            }
+
+            let path = nibbles.chunks(2).map(|x| (x[0] << 4) | x[1]).collect();
+            tree.insert(path, value.clone());
+        }
+
+        for (nibbles, child_ref) in node.children() {
+            let mut child_prefix = path_prefix.clone();
+            child_prefix.extend_from_slice(nibbles);
+            stack.push((child_ref.clone(), child_prefix));
+        }
+    }
+
+    tree.committed = Some((root.to_vec(), tree.len()));
+    Ok(tree)
+}
+
+/// Generate the proof of the value at `path` (or its absence) by walking the nodes stored in the
+/// database from `root`, without loading the trie.
+///
+/// The proof is the same one [`get_proof`](PatriciaMerkleTree::get_proof) returns for the trie
+/// `root` refers to, so any root still stored can be proven against (not just the latest one).
+pub fn get_proof_from_db<H, D>(db: &D, root: &[u8], path: &[u8]) -> Result<Vec<Vec<u8>>, LoadError>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let mut proof = Vec::new();
+    if root == &H::digest([0x80])[..] {
+        return Ok(proof);
+    }
+
+    let nibbles = path
+        .iter()
+        .flat_map(|x| [x >> 4, x & 0x0F])
+        .collect::<Vec<_>>();
+    let mut offset = 0;
+    let mut child_ref = ChildRef::Hash(root.to_vec());
+    loop {
+        let path_prefix = &nibbles[..offset];
+        let (hash, encoded) = match child_ref {
+            ChildRef::Hash(hash) => match db.get(&hash) {
+                Some(encoded) => {
+                    proof.push(encoded.clone());
+                    (hash, encoded)
+                }
+                None => {
+                    return Err(LoadError::MissingNode(MissingNode {
+                        hash,
+                        path_prefix: path_prefix.to_vec(),
+                    }))
+                }
+            },
+            ChildRef::Inline(encoded) => (encoded.clone(), encoded),
+        };
+
+        let node = RawNode::decode(&encoded).map_err(|error| {
+            LoadError::UndecodableNode(UndecodableNode {
+                hash,
+                path_prefix: path_prefix.to_vec(),
+                error,
+            })
+        })?;
+        child_ref = match node {
+            RawNode::Branch { mut choices, .. } => {
+                match nibbles
+                    .get(offset)
+                    .and_then(|x| choices[*x as usize].take())
+                {
+                    Some(child_ref) => {
+                        offset += 1;
+                        child_ref
+                    }
+                    None => break,
+                }
+            }
+            RawNode::Extension { prefix, child } if nibbles[offset..].starts_with(&prefix) => {
+                offset += prefix.len();
+                child
+            }
+            RawNode::Extension { .. } | RawNode::Leaf { .. } => break,
+        };
+    }
+
+    Ok(proof)
+}
+
+/// Maximum number of nodes [`compute_root_from_store`] keeps decoded at once.
+pub const DEFAULT_WORKING_SET: usize = 1024;
+
+/// Errors found while recomputing the root of a trie stored in a node database.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum RootAuditError {
+    #[error(transparent)]
+    Load(#[from] LoadError),
+    #[error(
+        "node at path {} hashes to {} but is stored as {}",
+        to_nibble_hex(.path_prefix),
+        hex::encode(.actual),
+        hex::encode(.expected)
+    )]
+    HashMismatch {
+        path_prefix: Vec<u8>,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    #[error("the trie is too deep for the working set limit")]
+    WorkingSetExceeded,
+}
+
+/// Recompute the root hash of the trie stored in the database under `root_hint`, re-hashing every
+/// node from the leaves up with the hashes computed for its children, and check it matches.
+///
+/// Only the nodes along the current path are kept decoded, up to [`DEFAULT_WORKING_SET`] of
+/// them, so any trie can be audited regardless of its size.
+pub fn compute_root_from_store<H, D>(
+    store: &D,
+    root_hint: &[u8],
+) -> Result<Output<H>, RootAuditError>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    compute_root_from_store_bounded::<H, D>(store, root_hint, DEFAULT_WORKING_SET)
+}
+
+/// Same as [`compute_root_from_store`], keeping at most `working_set` nodes decoded at once.
+pub fn compute_root_from_store_bounded<H, D>(
+    store: &D,
+    root_hint: &[u8],
+    working_set: usize,
+) -> Result<Output<H>, RootAuditError>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let empty = H::digest([0x80]);
+    if root_hint == &empty[..] {
+        return Ok(empty);
+    }
+
+    let working_set = working_set
+        .checked_sub(1)
+        .ok_or(RootAuditError::WorkingSetExceeded)?;
+    let root_ref = ChildRef::Hash(root_hint.to_vec());
+    rehash::<H, D>(store, &root_ref, &mut Vec::new(), working_set)?;
+
+    let mut root = Output::<H>::default();
+    root.copy_from_slice(root_hint);
+    Ok(root)
+}
+
+/// Recompute the reference to a node from its children's, checking it matches `child_ref`.
+///
+/// `working_set` is the number of nodes which can still be decoded below the current one.
+fn rehash<H, D>(
+    store: &D,
+    child_ref: &ChildRef,
+    path_prefix: &mut Vec<u8>,
+    working_set: usize,
+) -> Result<(), RootAuditError>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let (hash, encoded) = match child_ref {
+        ChildRef::Hash(hash) => match store.get(hash) {
+            Some(encoded) => (hash.clone(), encoded),
+            None => {
+                return Err(LoadError::MissingNode(MissingNode {
+                    hash: hash.clone(),
+                    path_prefix: path_prefix.clone(),
+                })
+                .into())
+            }
+        },
+        ChildRef::Inline(encoded) => (encoded.clone(), encoded.clone()),
+    };
+
+    let node = RawNode::decode(&encoded).map_err(|error| {
+        LoadError::UndecodableNode(UndecodableNode {
+            hash,
+            path_prefix: path_prefix.clone(),
+            error,
+        })
+    })?;
+
+    // Children are only re-encoded by the parent after their own hashes were recomputed.
+    for (nibbles, child_ref) in node.children() {
+        let working_set = working_set
+            .checked_sub(1)
+            .ok_or(RootAuditError::WorkingSetExceeded)?;
+
+        path_prefix.extend_from_slice(nibbles);
+        rehash::<H, D>(store, child_ref, path_prefix, working_set)?;
+        path_prefix.truncate(path_prefix.len() - nibbles.len());
+    }
+
+    let encoded = node.encode();
+    let (expected, actual) = match child_ref {
+        ChildRef::Hash(hash) => (hash, H::digest(&encoded).to_vec()),
+        ChildRef::Inline(inline) => (inline, encoded),
+    };
+    if *expected != actual {
+        return Err(RootAuditError::HashMismatch {
+            path_prefix: path_prefix.clone(),
+            expected: expected.clone(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Errors found while assembling a trie from a stream of nodes.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum NodeStreamError {
+    #[error("node {} doesn't match its hash", hex::encode(.0))]
+    HashMismatch(Vec<u8>),
+    #[error("node {} is not reachable from the root", hex::encode(.0))]
+    UnreachableNode(Vec<u8>),
+    #[error(transparent)]
+    Load(#[from] LoadError),
+}
+
+/// Assemble a trie with byte paths from its `(hash, encoded node)` pairs, in any order (for
+/// example, as downloaded from peers).
+///
+/// Every node is checked against its hash as it's received, and once they're linked up from the
+/// root, every node in the stream must have been used.
+pub fn from_node_stream<H>(
+    root: &[u8],
+    nodes: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+) -> Result<PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>, NodeStreamError>
+where
+    H: Digest,
+{
+    let mut db = MemoryDb::new();
+    for (hash, encoded) in nodes {
+        if H::digest(&encoded)[..] != hash[..] {
+            return Err(NodeStreamError::HashMismatch(hash));
+        }
+
+        db.insert(hash, encoded);
+    }
+
+    let mut tree = load_tree::<H, _>(&db, root)?;
+
+    // Committing the tree writes exactly the nodes reachable from the root.
+    let mut reachable = MemoryDb::new();
+    commit(&mut tree, &mut reachable);
+    match db.iter().find(|(hash, _)| reachable.get(hash).is_none()) {
+        Some((hash, _)) => Err(NodeStreamError::UnreachableNode(hash.clone())),
+        None => Ok(tree),
+    }
+}
+
+pub(crate) fn to_nibble_hex(nibbles: &[u8]) -> String {
+    if nibbles.is_empty() {
+        return "-".to_string();
+    }
+
+    nibbles
+        .iter()
+        .fold(String::with_capacity(nibbles.len()), |mut acc, x| {
+            write!(acc, "{x:x}").unwrap();
+            acc
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use sha3::Keccak256;
+
+    fn build_db() -> (MemoryDb, Vec<u8>) {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+
+        let mut db = MemoryDb::new();
+        let root = commit(&mut tree, &mut db);
+
+        (db, root.to_vec())
+    }
+
+    #[test]
+    fn complete() {
+        let (db, root) = build_db();
+        let report = check_completeness::<Keccak256, _>(&db, &root);
+
+        assert!(report.is_complete());
+        assert!(report.visited > db.len());
+    }
+
+    #[test]
+    fn storage_stats() {
+        let (mut db, root) = build_db();
+        let stats = db.storage_stats();
+
+        assert_eq!(stats.node_count, db.len());
+        assert_eq!(stats.values, 64 * 24);
+        assert_eq!(
+            stats.nodes + stats.values,
+            db.iter().map(|(_, x)| x.len()).sum::<usize>()
+        );
+        assert_eq!(stats.indices, 32 * db.len());
+        assert_eq!(stats.cached, 0);
+
+        db.compare_and_swap_root(None, root).unwrap();
+        assert_eq!(db.storage_stats().indices, 32 * db.len() + 32);
+    }
+
+    #[test]
+    fn node_by_hash() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+
+        let mut db = MemoryDb::new();
+        commit(&mut tree, &mut db);
+        for (hash, encoded) in db.iter() {
+            assert_eq!(tree.get_node_by_hash(hash).as_ref(), Some(encoded));
+        }
+        assert_eq!(tree.get_node_by_hash(&[0xAA; 32]), None);
+
+        // The root is found by its hash even if it's shorter than one.
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![0x34]);
+        let root = *tree.compute_hash();
+        assert_eq!(
+            tree.get_node_by_hash(&root),
+            Some(vec![0xC4, 0x82, 0x20, 0x12, 0x34])
+        );
+    }
+
+    #[test]
+    fn empty_tree() {
+        let db = MemoryDb::new();
+        let report = check_completeness::<Keccak256, _>(&db, &Keccak256::digest([0x80]));
+
+        assert!(report.is_complete());
+        assert_eq!(report.visited, 0);
+    }
+
+    #[test]
+    fn missing_root() {
+        let db = MemoryDb::new();
+        let report = check_completeness::<Keccak256, _>(&db, &[0xAA; 32]);
+
+        assert_eq!(
+            report.missing,
+            vec![MissingNode {
+                hash: vec![0xAA; 32],
+                path_prefix: vec![],
+            }],
+        );
+    }
+
+    #[test]
+    fn missing_and_undecodable() {
+        let (mut db, root) = build_db();
+
+        let mut hashes = db
+            .iter()
+            .map(|(hash, _)| hash.clone())
+            .filter(|hash| hash != &root)
+            .collect::<Vec<_>>();
+        hashes.sort();
+
+        db.remove(&hashes[0]);
+        db.insert(hashes[1].clone(), vec![0xC1, 0x80]);
+
+        let report = check_completeness::<Keccak256, _>(&db, &root);
+        assert!(!report.is_complete());
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].hash, hashes[0]);
+        assert_eq!(report.undecodable.len(), 1);
+        assert_eq!(report.undecodable[0].hash, hashes[1]);
+        assert_eq!(
+            report.undecodable[0].error,
+            DecodeError::InvalidItemCount(1)
+        );
+
+        let text = report.to_string();
+        assert!(text.contains(&format!("missing {}", hex::encode(&hashes[0]))));
+    }
+
+    #[test]
+    fn load() {
+        let (mut db, root) = build_db();
+        let mut tree = load_tree::<Keccak256, _>(&db, &root).unwrap();
+
+        assert_eq!(tree.len(), 64);
+        assert_eq!(tree.get(&vec![3, 21]), Some(&vec![3; 24]));
+        assert_eq!(&tree.compute_hash()[..], &root[..]);
+
+        let hash = db
+            .iter()
+            .map(|(hash, _)| hash.clone())
+            .find(|hash| hash != &root)
+            .unwrap();
+        db.remove(&hash);
+        assert!(matches!(
+            load_tree::<Keccak256, _>(&db, &root),
+            Err(LoadError::MissingNode(MissingNode { hash: x, .. })) if x == hash,
+        ));
+    }
+
+    #[test]
+    fn proof_from_db() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+        tree.insert(vec![0x12], vec![0x34]);
+
+        let mut db = MemoryDb::new();
+        let old_root = commit(&mut tree, &mut db).to_vec();
+        let mut old_tree = tree.clone();
+        tree.insert(vec![3, 21], vec![0xFF; 24]);
+        let root = commit(&mut tree, &mut db).to_vec();
+
+        for path in [
+            vec![3, 21],
+            vec![0x12],
+            vec![0x12, 0x34],
+            vec![],
+            vec![0xFF, 0xFF],
+        ] {
+            let proof = get_proof_from_db::<Keccak256, _>(&db, &root, &path).unwrap();
+            assert_eq!(proof, tree.get_proof(&path));
+            let proof = get_proof_from_db::<Keccak256, _>(&db, &old_root, &path).unwrap();
+            assert_eq!(proof, old_tree.get_proof(&path));
+        }
+
+        let empty = Keccak256::digest([0x80]);
+        assert_eq!(
+            get_proof_from_db::<Keccak256, _>(&db, &empty, &[3, 21]),
+            Ok(Vec::new())
+        );
+        assert!(matches!(
+            get_proof_from_db::<Keccak256, _>(&db, &[0xAA; 32], &[3, 21]),
+            Err(LoadError::MissingNode(_)),
+        ));
+    }
+
+    #[test]
+    fn root_from_store() {
+        let (mut db, root) = build_db();
+        let computed = compute_root_from_store::<Keccak256, _>(&db, &root).unwrap();
+        assert_eq!(&computed[..], &root[..]);
+
+        assert_eq!(
+            compute_root_from_store_bounded::<Keccak256, _>(&db, &root, 2),
+            Err(RootAuditError::WorkingSetExceeded),
+        );
+        let empty = Keccak256::digest([0x80]);
+        assert_eq!(
+            compute_root_from_store_bounded::<Keccak256, _>(&db, &empty, 0),
+            Ok(empty),
+        );
+
+        // A node whose contents no longer match the hash it's stored under.
+        let mut hashes = db
+            .iter()
+            .map(|(hash, _)| hash.clone())
+            .filter(|hash| *hash != root)
+            .collect::<Vec<_>>();
+        hashes.sort();
+        let swapped = db.get(&hashes[1]).unwrap();
+        db.insert(hashes[0].clone(), swapped);
+        assert!(matches!(
+            compute_root_from_store::<Keccak256, _>(&db, &root),
+            Err(RootAuditError::HashMismatch { expected, actual, .. })
+                if expected == hashes[0] && actual == hashes[1]
+        ));
+    }
+
+    #[test]
+    fn node_stream() {
+        let (db, root) = build_db();
+        let mut nodes = db.into_iter().collect::<Vec<_>>();
+        nodes.sort();
+
+        let mut tree = from_node_stream::<Keccak256>(&root, nodes.clone()).unwrap();
+        assert_eq!(tree.len(), 64);
+        assert_eq!(&tree.compute_hash()[..], &root[..]);
+
+        let mut tampered = nodes.clone();
+        tampered[0].1.push(0x80);
+        assert_eq!(
+            from_node_stream::<Keccak256>(&root, tampered).map(|_| ()),
+            Err(NodeStreamError::HashMismatch(nodes[0].0.clone())),
+        );
+
+        let extra = vec![0xC2, 0x80, 0x80];
+        let mut unreachable = nodes.clone();
+        unreachable.push((Keccak256::digest(&extra).to_vec(), extra.clone()));
+        assert_eq!(
+            from_node_stream::<Keccak256>(&root, unreachable).map(|_| ()),
+            Err(NodeStreamError::UnreachableNode(
+                Keccak256::digest(&extra).to_vec()
+            )),
+        );
+
+        let missing = nodes
+            .iter()
+            .filter(|(hash, _)| *hash != root)
+            .skip(1)
+            .cloned();
+        assert!(matches!(
+            from_node_stream::<Keccak256>(&root, missing),
+            Err(NodeStreamError::Load(LoadError::MissingNode(_))),
+        ));
+    }
+}
@@ -0,0 +1,887 @@
+use crate::{
+    db::{HashMismatch, LoadError, MissingNode, NodeDb, ReadPanicked, UndecodableNode},
+    rlp::{ChildRef, DecodeError, RawNode},
+    telemetry::{Operation, SlowOpEvent, SlowOps},
+};
+use digest::{Digest, Output};
+use std::{
+    collections::{HashMap, HashSet},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Iterator over the values of a trie stored in a node database, in ascending path order.
+///
+/// Nodes are read as they're reached and dropped once their subtree has been visited, so only
+/// the nodes along the current path are kept in memory. Iteration stops after the first missing
+/// or undecodable node, unless iterating over a [`DbTrie`] (see [`Quarantine`]).
+pub struct DbIterator<'a, D>
+where
+    D: NodeDb + ?Sized,
+{
+    source: Source<'a, D>,
+    /// Pending nodes (along with the nibbles leading to them) and values, in reverse order.
+    stack: Vec<StackItem>,
+    /// Only values whose path starts with it are returned.
+    prefix: Vec<u8>,
+    /// Where to record corrupt nodes, along with the hash function to check nodes with.
+    quarantine: Option<(&'a Quarantine, HashFn)>,
+    /// Number of nodes expanded, and how many of them were read from the database.
+    visited: usize,
+    reads: usize,
+}
+
+/// Hash function of a trie's nodes.
+type HashFn = fn(&[u8]) -> Vec<u8>;
+
+/// The corrupt nodes found while reading a [`DbTrie`], by hash.
+///
+/// Once a node is found not to hash to its key or not to decode, reads under it fail with the
+/// same error without reading it again, while the rest of the trie is still served: iterations
+/// return the error in place of the node's subtree and carry on with the following one.
+#[derive(Debug, Default)]
+pub struct Quarantine(Mutex<HashMap<Vec<u8>, LoadError>>);
+
+impl Quarantine {
+    /// Return the number of nodes quarantined.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Return whether no nodes were quarantined.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Return the errors found for each quarantined node, sorted by the path leading to it.
+    pub fn errors(&self) -> Vec<LoadError> {
+        let mut errors = self.lock().values().cloned().collect::<Vec<_>>();
+        errors.sort_by(|a, b| a.path_prefix().cmp(b.path_prefix()));
+        errors
+    }
+
+    fn get(&self, hash: &[u8]) -> Option<LoadError> {
+        self.lock().get(hash).cloned()
+    }
+
+    fn insert(&self, error: &LoadError) {
+        let hash = match error {
+            LoadError::UndecodableNode(x) => &x.hash,
+            LoadError::HashMismatch(x) => &x.hash,
+            LoadError::MissingNode(_) | LoadError::ReadPanicked(_) | LoadError::Corruption(_) => {
+                return
+            }
+        };
+        self.lock().insert(hash.clone(), error.clone());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Vec<u8>, LoadError>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A trie stored in a node database, read on demand.
+///
+/// Nothing is loaded up front: lookups and iterations read the nodes they need from the database
+/// and drop them once done, so tries much larger than the available memory can be exported.
+#[derive(Debug)]
+pub struct DbTrie<'a, D, H>
+where
+    D: NodeDb + ?Sized,
+    H: Digest,
+{
+    db: &'a D,
+    root: Output<H>,
+    quarantine: Quarantine,
+    slow_ops: SlowOps,
+}
+
+enum Source<'a, D>
+where
+    D: NodeDb + ?Sized,
+{
+    Direct(&'a D),
+    Prefetched(Prefetcher<D>),
+}
+
+enum StackItem {
+    Node(ChildRef, Vec<u8>),
+    Value(Vec<u8>, Vec<u8>),
+    Error(LoadError),
+}
+
+/// How far a prefetching iterator reads ahead of its position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrefetchConfig {
+    /// Number of background threads issuing reads.
+    pub threads: usize,
+    /// Maximum number of upcoming nodes requested but not yet reached.
+    pub lookahead: usize,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            lookahead: 64,
+        }
+    }
+}
+
+/// An estimate of the number of values in a trie, as returned by [`DbTrie::estimate_len`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LenEstimate {
+    pub estimate: f64,
+    /// Standard error of the estimate: the actual number of values is within two of them of the
+    /// estimate about 95% of the time.
+    pub standard_error: f64,
+}
+
+/// Iterate over the values of a trie stored in the database, as `(path, value)` pairs.
+pub fn iter_db<'a, H, D>(db: &'a D, root: &[u8]) -> DbIterator<'a, D>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    DbIterator::new::<H>(Source::Direct(db), root)
+}
+
+/// Like [`iter_db`], but reading the nodes which are coming up next in background threads, so
+/// that the latency of the database is hidden when scanning a whole trie.
+pub fn iter_db_prefetching<H, D>(
+    db: Arc<D>,
+    root: &[u8],
+    config: PrefetchConfig,
+) -> DbIterator<'static, D>
+where
+    H: Digest,
+    D: NodeDb + Send + Sync + ?Sized + 'static,
+{
+    let prefetcher = Prefetcher::new(db, &config);
+    DbIterator::new::<H>(Source::Prefetched(prefetcher), root)
+}
+
+/// Iterate over the values of a trie stored in the database whose path starts with `prefix`.
+///
+/// Only the nodes leading to the prefix and those below it are read.
+pub fn scan_prefix_db<'a, H, D>(db: &'a D, root: &[u8], prefix: &[u8]) -> DbIterator<'a, D>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let mut iter = DbIterator::new::<H>(Source::Direct(db), root);
+    iter.seek(prefix);
+    iter
+}
+
+impl<'a, D, H> DbTrie<'a, D, H>
+where
+    D: NodeDb + ?Sized,
+    H: Digest,
+{
+    pub fn new(db: &'a D, root: Output<H>) -> Self {
+        Self {
+            db,
+            root,
+            quarantine: Quarantine::default(),
+            slow_ops: SlowOps::default(),
+        }
+    }
+
+    /// Return the trie's root hash.
+    pub fn root(&self) -> &Output<H> {
+        &self.root
+    }
+
+    /// Return the corrupt nodes found so far.
+    pub fn quarantine(&self) -> &Quarantine {
+        &self.quarantine
+    }
+
+    /// Retrieve a value given its path.
+    pub fn get(&self, path: &[u8]) -> Result<Option<Vec<u8>>, LoadError> {
+        let start = self.slow_ops.start();
+        let mut iter = self.scan_prefix(path);
+        // The value at the prefix itself is the first one under it.
+        let value = match iter.next() {
+            Some(Ok((value_path, value))) if value_path == path => Ok(Some(value)),
+            Some(Err(error)) => Err(error),
+            _ => Ok(None),
+        };
+        self.slow_ops.finish(start, |duration| {
+            SlowOpEvent::new(Operation::Get, path, duration, iter.visited, iter.reads)
+        });
+
+        value
+    }
+
+    /// Report the reads which take longer than `threshold`.
+    pub fn report_slow_ops(&mut self, threshold: Duration) -> Receiver<SlowOpEvent> {
+        self.slow_ops.enable(threshold)
+    }
+
+    /// Iterate over the trie's values in ascending path order.
+    ///
+    /// Corrupt nodes are quarantined and returned as errors in place of their subtrees.
+    pub fn iter(&self) -> DbIterator<'_, D> {
+        let mut iter = DbIterator::new::<H>(Source::Direct(self.db), &self.root);
+        iter.quarantine = Some((&self.quarantine, |x| H::digest(x).to_vec()));
+        iter
+    }
+
+    /// Iterate over the values whose path starts with `prefix`, in ascending path order.
+    ///
+    /// Corrupt nodes are quarantined and returned as errors in place of their subtrees.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> DbIterator<'_, D> {
+        let mut iter = self.iter();
+        iter.seek(prefix);
+        iter
+    }
+
+    /// Estimate the number of values in the trie by walking `samples` random paths from the root,
+    /// without reading the whole trie.
+    ///
+    /// Each walk counts the values it finds weighted by the inverse of the probability of taking
+    /// its path (the product of the number of children of the branches along it), which averages
+    /// out to the actual count. The error shrinks with the square root of the number of samples.
+    pub fn estimate_len(&self, samples: usize) -> Result<LenEstimate, LoadError> {
+        if self.root == H::digest([0x80]) || samples == 0 {
+            return Ok(LenEstimate {
+                estimate: 0.0,
+                standard_error: 0.0,
+            });
+        }
+
+        // A fixed seed, so that estimates are reproducible.
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut random = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+
+        let mut counts = Vec::with_capacity(samples);
+        for _ in 0..samples {
+            let mut count = 0.0;
+            let mut weight = 1.0;
+            let mut child_ref = ChildRef::Hash(self.root.to_vec());
+            let mut path_prefix = Vec::new();
+            loop {
+                let (hash, encoded) = match child_ref {
+                    ChildRef::Hash(hash) => match self.db.get(&hash) {
+                        Some(encoded) => (hash, encoded),
+                        None => {
+                            return Err(LoadError::MissingNode(MissingNode { hash, path_prefix }))
+                        }
+                    },
+                    ChildRef::Inline(encoded) => (encoded.clone(), encoded),
+                };
+                let node = RawNode::decode(&encoded).map_err(|error| {
+                    LoadError::UndecodableNode(UndecodableNode {
+                        hash,
+                        path_prefix: path_prefix.clone(),
+                        error,
+                    })
+                })?;
+
+                if matches!(
+                    node,
+                    RawNode::Leaf { .. } | RawNode::Branch { value: Some(_), .. }
+                ) {
+                    count += weight;
+                }
+
+                let children = node.children().collect::<Vec<_>>();
+                if children.is_empty() {
+                    break;
+                }
+                let (nibbles, next) = children[random(children.len())];
+                weight *= children.len() as f64;
+                path_prefix.extend_from_slice(nibbles);
+                child_ref = next.clone();
+            }
+
+            counts.push(count);
+        }
+
+        let n = counts.len() as f64;
+        let mean = counts.iter().sum::<f64>() / n;
+        let variance = counts.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+
+        Ok(LenEstimate {
+            estimate: mean,
+            standard_error: (variance / n).sqrt(),
+        })
+    }
+}
+
+impl<'a, D> DbIterator<'a, D>
+where
+    D: NodeDb + ?Sized,
+{
+    fn new<H>(source: Source<'a, D>, root: &[u8]) -> Self
+    where
+        H: Digest,
+    {
+        let mut stack = Vec::new();
+        if root != &H::digest([0x80])[..] {
+            stack.push(StackItem::Node(ChildRef::Hash(root.to_vec()), Vec::new()));
+        }
+
+        let mut iter = Self {
+            source,
+            stack,
+            prefix: Vec::new(),
+            quarantine: None,
+            visited: 0,
+            reads: 0,
+        };
+        iter.prefetch();
+        iter
+    }
+
+    /// Skip every node and value which can't be under `prefix`, expanding the nodes leading to it.
+    fn seek(&mut self, prefix: &[u8]) {
+        let nibbles = prefix
+            .iter()
+            .flat_map(|x| [x >> 4, x & 0x0F])
+            .collect::<Vec<_>>();
+        self.prefix = prefix.to_vec();
+
+        loop {
+            self.stack.retain(|item| match item {
+                StackItem::Node(_, path_prefix) => {
+                    let len = path_prefix.len().min(nibbles.len());
+                    path_prefix[..len] == nibbles[..len]
+                }
+                StackItem::Value(path, _) => path.starts_with(prefix),
+                StackItem::Error(_) => true,
+            });
+
+            match self.stack.pop() {
+                Some(StackItem::Node(child_ref, path_prefix))
+                    if path_prefix.len() < nibbles.len() =>
+                {
+                    if let Err(error) = self.expand(child_ref, path_prefix) {
+                        if !self.is_recoverable(&error) {
+                            self.stack.clear();
+                        }
+                        self.stack.push(StackItem::Error(error));
+                        break;
+                    }
+                }
+                Some(item) => {
+                    self.stack.push(item);
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        self.prefetch();
+    }
+
+    /// Return whether iteration can go on after an error, skipping the node's subtree.
+    fn is_recoverable(&self, error: &LoadError) -> bool {
+        self.quarantine.is_some() && error.is_corruption()
+    }
+
+    /// Read a node and push its children and value onto the stack, quarantining it if corrupt.
+    fn expand(&mut self, child_ref: ChildRef, path_prefix: Vec<u8>) -> Result<(), LoadError> {
+        let result = self.expand_node(child_ref, path_prefix);
+        if let (Err(error), Some((quarantine, _))) = (&result, self.quarantine) {
+            quarantine.insert(error);
+        }
+
+        result
+    }
+
+    fn expand_node(&mut self, child_ref: ChildRef, path_prefix: Vec<u8>) -> Result<(), LoadError> {
+        self.visited += 1;
+        let (hash, encoded) = match child_ref {
+            ChildRef::Hash(hash) => {
+                if let Some(error) = self.quarantine.and_then(|(x, _)| x.get(&hash)) {
+                    return Err(error);
+                }
+
+                self.reads += 1;
+                match self.source.get(&hash, &path_prefix)? {
+                    Some(encoded) => (hash, encoded),
+                    None => return Err(LoadError::MissingNode(MissingNode { hash, path_prefix })),
+                }
+            }
+            ChildRef::Inline(encoded) => (encoded.clone(), encoded),
+        };
+
+        if let Some((_, digest)) = self.quarantine {
+            let actual = digest(&encoded);
+            if encoded != hash && actual != hash {
+                return Err(LoadError::HashMismatch(HashMismatch {
+                    hash,
+                    path_prefix,
+                    actual,
+                }));
+            }
+        }
+
+        let undecodable = |path_prefix, error| {
+            LoadError::UndecodableNode(UndecodableNode {
+                hash: hash.clone(),
+                path_prefix,
+                error,
+            })
+        };
+
+        let node = RawNode::decode(&encoded).map_err(|e| undecodable(path_prefix.clone(), e))?;
+        let children = node.children().collect::<Vec<_>>();
+        for (nibbles, child_ref) in children.into_iter().rev() {
+            let mut child_prefix = path_prefix.clone();
+            child_prefix.extend_from_slice(nibbles);
+            self.stack
+                .push(StackItem::Node(child_ref.clone(), child_prefix));
+        }
+
+        let value = match node {
+            RawNode::Branch { value, .. } => value.map(|x| (path_prefix.clone(), x)),
+            RawNode::Extension { .. } => None,
+            RawNode::Leaf { path, value } => {
+                let mut full_path = path_prefix.clone();
+                full_path.extend_from_slice(&path);
+                Some((full_path, value))
+            }
+        };
+        if let Some((nibbles, value)) = value {
+            if nibbles.len() & 1 != 0 {
+                return Err(undecodable(path_prefix, DecodeError::InvalidPath));
+            }
+
+            let path = nibbles.chunks(2).map(|x| (x[0] << 4) | x[1]).collect();
+            self.stack.push(StackItem::Value(path, value));
+        }
+
+        Ok(())
+    }
+
+    /// Request the upcoming hashed nodes from the prefetcher, if any.
+    fn prefetch(&mut self) {
+        if let Source::Prefetched(prefetcher) = &mut self.source {
+            let upcoming = self.stack.iter().rev().filter_map(|item| match item {
+                StackItem::Node(ChildRef::Hash(hash), _) => Some(hash),
+                _ => None,
+            });
+            prefetcher.request(upcoming);
+        }
+    }
+}
+
+impl<D> Iterator for DbIterator<'_, D>
+where
+    D: NodeDb + ?Sized,
+{
+    type Item = Result<(Vec<u8>, Vec<u8>), LoadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.stack.pop()? {
+                StackItem::Node(child_ref, path_prefix) => {
+                    if let Err(error) = self.expand(child_ref, path_prefix) {
+                        if !self.is_recoverable(&error) {
+                            self.stack.clear();
+                        }
+                        return Some(Err(error));
+                    }
+
+                    self.prefetch();
+                }
+                StackItem::Value(path, value) if path.starts_with(&self.prefix) => {
+                    return Some(Ok((path, value)))
+                }
+                StackItem::Value(..) => {}
+                StackItem::Error(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+impl<D> Source<'_, D>
+where
+    D: NodeDb + ?Sized,
+{
+    fn get(&mut self, hash: &[u8], path_prefix: &[u8]) -> Result<Option<Vec<u8>>, LoadError> {
+        match self {
+            Source::Direct(db) => Ok(db.get(hash)),
+            Source::Prefetched(prefetcher) => prefetcher.get(hash).ok_or_else(|| {
+                LoadError::ReadPanicked(ReadPanicked {
+                    hash: hash.to_vec(),
+                    path_prefix: path_prefix.to_vec(),
+                })
+            }),
+        }
+    }
+}
+
+/// State shared with the background threads.
+#[derive(Default)]
+struct Fetched {
+    state: Mutex<FetchedState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct FetchedState {
+    /// Nodes read, by hash (`None` if missing, `Err` if reading it panicked).
+    nodes: HashMap<Vec<u8>, thread::Result<Option<Vec<u8>>>>,
+    /// Number of threads still running.
+    workers: usize,
+}
+
+impl Fetched {
+    fn lock(&self) -> MutexGuard<'_, FetchedState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Marks a background thread as stopped when dropped, even if it panicked.
+struct WorkerGuard(Arc<Fetched>);
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.0.lock().workers -= 1;
+        self.0.ready.notify_all();
+    }
+}
+
+/// Reads nodes in background threads, ahead of when they're needed.
+struct Prefetcher<D>
+where
+    D: ?Sized,
+{
+    db: Arc<D>,
+    lookahead: usize,
+    /// Hashes requested but not yet taken.
+    in_flight: HashSet<Vec<u8>>,
+    requests: Sender<Vec<u8>>,
+    fetched: Arc<Fetched>,
+}
+
+impl<D> Prefetcher<D>
+where
+    D: NodeDb + Send + Sync + ?Sized + 'static,
+{
+    fn new(db: Arc<D>, config: &PrefetchConfig) -> Self {
+        let (requests, receiver) = mpsc::channel::<Vec<u8>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let fetched = Arc::new(Fetched::default());
+
+        let threads = config.threads.max(1);
+        fetched.lock().workers = threads;
+        for _ in 0..threads {
+            let (db, receiver, fetched) = (db.clone(), receiver.clone(), fetched.clone());
+            // Threads exit once the iterator (and therefore the sender) is dropped.
+            thread::spawn(move || {
+                let _guard = WorkerGuard(fetched.clone());
+                loop {
+                    let hash = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                        Ok(hash) => hash,
+                        Err(_) => break,
+                    };
+
+                    let encoded = panic::catch_unwind(AssertUnwindSafe(|| db.get(&hash)));
+                    fetched.lock().nodes.insert(hash, encoded);
+                    fetched.ready.notify_all();
+                }
+            });
+        }
+
+        Self {
+            db,
+            lookahead: config.lookahead,
+            in_flight: HashSet::new(),
+            requests,
+            fetched,
+        }
+    }
+}
+
+impl<D> Prefetcher<D>
+where
+    D: NodeDb + ?Sized,
+{
+    /// Request the first upcoming hashes, up to the lookahead limit.
+    fn request<'a>(&mut self, upcoming: impl Iterator<Item = &'a Vec<u8>>) {
+        for hash in upcoming.take(self.lookahead) {
+            if self.in_flight.len() >= self.lookahead {
+                break;
+            }
+
+            if self.in_flight.insert(hash.clone()) && self.requests.send(hash.clone()).is_err() {
+                self.in_flight.remove(hash);
+            }
+        }
+    }
+
+    /// Return a node, waiting for it if it was requested already.
+    ///
+    /// Returns `None` if reading it panicked, or if every background thread stopped before
+    /// reading it.
+    fn get(&mut self, hash: &[u8]) -> Option<Option<Vec<u8>>> {
+        if !self.in_flight.remove(hash) {
+            return Some(self.db.get(hash));
+        }
+
+        let mut state = self.fetched.lock();
+        loop {
+            if let Some(encoded) = state.nodes.remove(hash) {
+                return encoded.ok();
+            }
+            if state.workers == 0 {
+                return None;
+            }
+
+            state = self
+                .fetched
+                .ready
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{db::MemoryDb, pmt_tree};
+    use sha3::Keccak256;
+    use std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, time::Duration};
+
+    type Values = Vec<(Vec<u8>, Vec<u8>)>;
+
+    fn build_db(count: u16) -> (MemoryDb, Vec<u8>, Values) {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..count {
+            let path = (i.wrapping_mul(40503)).to_be_bytes().to_vec();
+            tree.insert(path, vec![i as u8; 24]);
+        }
+        // Paths with a prefix in common, so that branches have values too.
+        tree.insert(vec![0x12], vec![1; 40]);
+        tree.insert(vec![0x12, 0x34, 0x56], vec![2; 40]);
+
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db).to_vec();
+        let values = tree.iter().map(|(p, v)| (p.clone(), v.clone())).collect();
+
+        (db, root, values)
+    }
+
+    #[test]
+    fn db_iteration() {
+        let (db, root, expected) = build_db(512);
+        let values = iter_db::<Keccak256, _>(&db, &root)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(values, expected);
+
+        let empty = Keccak256::digest([0x80]);
+        assert_eq!(iter_db::<Keccak256, _>(&db, &empty).count(), 0);
+
+        let mut db = db;
+        let (hash, _) = db.iter().find(|(hash, _)| **hash != root).unwrap();
+        let hash = hash.clone();
+        db.remove(&hash);
+        let values = iter_db::<Keccak256, _>(&db, &root).collect::<Vec<_>>();
+        assert!(matches!(
+            values.last(),
+            Some(Err(LoadError::MissingNode(x))) if x.hash == hash
+        ));
+    }
+
+    /// A database whose reads take a while.
+    struct SlowDb(MemoryDb, AtomicUsize);
+
+    impl NodeDb for SlowDb {
+        fn get(&self, hash: &[u8]) -> Option<Vec<u8>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_micros(200));
+            self.0.get(hash)
+        }
+
+        fn insert(&mut self, hash: Vec<u8>, encoded: Vec<u8>) {
+            self.0.insert(hash, encoded);
+        }
+
+        fn remove(&mut self, hash: &[u8]) {
+            NodeDb::remove(&mut self.0, hash);
+        }
+    }
+
+    #[test]
+    fn prefix_scan() {
+        let (db, root, values) = build_db(512);
+        let mut output = Output::<Keccak256>::default();
+        output.copy_from_slice(&root);
+        let trie = DbTrie::<_, Keccak256>::new(&db, output);
+
+        let all = trie.iter().collect::<Result<Values, _>>().unwrap();
+        assert_eq!(all, values);
+
+        for prefix in [
+            vec![],
+            vec![0x12],
+            vec![0x12, 0x34],
+            vec![0x9C],
+            vec![0x9C, 0x00],
+        ] {
+            let expected = values
+                .iter()
+                .filter(|(path, _)| path.starts_with(&prefix))
+                .cloned()
+                .collect::<Values>();
+            let scanned = trie
+                .scan_prefix(&prefix)
+                .collect::<Result<Values, _>>()
+                .unwrap();
+            assert_eq!(scanned, expected);
+        }
+        assert_eq!(trie.scan_prefix(&[0x12, 0x34, 0x56, 0x78]).count(), 0);
+
+        let mut db = db;
+        db.remove(&root);
+        let trie = DbTrie::<_, Keccak256>::new(&db, output);
+        assert!(matches!(
+            trie.scan_prefix(&[0x12]).collect::<Vec<_>>()[..],
+            [Err(LoadError::MissingNode(_))]
+        ));
+    }
+
+    #[test]
+    fn quarantine() {
+        let (db, root, expected) = build_db(512);
+        let mut output = Output::<Keccak256>::default();
+        output.copy_from_slice(&root);
+
+        let mut hashes = db
+            .iter()
+            .map(|(hash, _)| hash.clone())
+            .filter(|hash| *hash != root)
+            .collect::<Vec<_>>();
+        hashes.sort();
+        let mut db = db;
+        let swapped = db.get(&hashes[1]).unwrap();
+        db.insert(hashes[0].clone(), swapped);
+
+        let trie = DbTrie::<_, Keccak256>::new(&db, output);
+        let (values, errors): (Vec<_>, Vec<_>) = trie.iter().partition(Result::is_ok);
+        let path_prefix = match &errors[..] {
+            [Err(LoadError::HashMismatch(x))] if x.hash == hashes[0] => x.path_prefix.clone(),
+            _ => panic!("unexpected errors: {errors:?}"),
+        };
+
+        // Every value outside of the corrupt node's subtree is still returned.
+        let nibbles = |path: &[u8]| {
+            path.iter()
+                .flat_map(|x| [x >> 4, x & 0x0F])
+                .collect::<Vec<_>>()
+        };
+        let (affected, unaffected): (Vec<_>, Vec<_>) = expected
+            .into_iter()
+            .partition(|(path, _)| nibbles(path).starts_with(&path_prefix));
+        assert!(!affected.is_empty());
+        assert_eq!(
+            values.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            unaffected
+        );
+
+        assert_eq!(trie.quarantine().len(), 1);
+        assert!(matches!(
+            trie.get(&affected[0].0),
+            Err(LoadError::HashMismatch(x)) if x.hash == hashes[0]
+        ));
+        assert_eq!(
+            trie.get(&unaffected[0].0),
+            Ok(Some(unaffected[0].1.clone()))
+        );
+        assert_eq!(trie.get(&[0x12, 0x34]), Ok(None));
+        assert_eq!(
+            trie.quarantine().errors(),
+            errors[0].clone().err().into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn len_estimate() {
+        let (db, root, values) = build_db(4096);
+        let mut output = Output::<Keccak256>::default();
+        output.copy_from_slice(&root);
+        let trie = DbTrie::<_, Keccak256>::new(&db, output);
+
+        let LenEstimate {
+            estimate,
+            standard_error,
+        } = trie.estimate_len(256).unwrap();
+        let actual = values.len() as f64;
+        assert!(
+            (estimate - actual).abs() <= 4.0 * standard_error.max(1.0),
+            "{estimate} ± {standard_error} (actual {actual})"
+        );
+        assert!(standard_error < actual * 0.1);
+
+        let empty = Keccak256::digest([0x80]);
+        let trie = DbTrie::<_, Keccak256>::new(&db, empty);
+        assert_eq!(trie.estimate_len(16).unwrap().estimate, 0.0);
+    }
+
+    #[test]
+    fn prefetching() {
+        let (db, root, expected) = build_db(512);
+        let db = Arc::new(SlowDb(db, AtomicUsize::new(0)));
+        iter_db::<Keccak256, _>(&*db, &root).for_each(drop);
+        let reads = db.1.swap(0, Ordering::Relaxed);
+
+        let config = PrefetchConfig {
+            threads: 8,
+            lookahead: 16,
+        };
+        let values = iter_db_prefetching::<Keccak256, _>(db.clone(), &root, config)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(values, expected);
+        assert_eq!(db.1.load(Ordering::Relaxed), reads);
+    }
+
+    /// A database which panics when reading anything but the root.
+    struct PanickingDb(MemoryDb, Vec<u8>);
+
+    impl NodeDb for PanickingDb {
+        fn get(&self, hash: &[u8]) -> Option<Vec<u8>> {
+            assert_eq!(hash, self.1, "only the root can be read");
+            self.0.get(hash)
+        }
+
+        fn insert(&mut self, hash: Vec<u8>, encoded: Vec<u8>) {
+            self.0.insert(hash, encoded);
+        }
+
+        fn remove(&mut self, hash: &[u8]) {
+            NodeDb::remove(&mut self.0, hash);
+        }
+    }
+
+    #[test]
+    fn prefetching_panic() {
+        let (db, root, _) = build_db(512);
+        let db = Arc::new(PanickingDb(db, root.clone()));
+
+        let values = iter_db_prefetching::<Keccak256, _>(db, &root, PrefetchConfig::default())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            values[..],
+            [Err(LoadError::ReadPanicked(ref x))] if x.path_prefix == [0]
+        ));
+    }
+}
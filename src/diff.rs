@@ -0,0 +1,312 @@
+use crate::{
+    node::Node, proof::to_nibbles, storage::ValueRef, Encode, NodeRef, PatriciaMerkleTree,
+};
+use digest::Digest;
+
+/// A path whose value differs between two trees.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Difference<'a, P, V> {
+    pub path: &'a P,
+    /// The value in the old tree (`None` if it was inserted).
+    pub old_value: Option<&'a V>,
+    /// The value in the new tree (`None` if it was removed).
+    pub new_value: Option<&'a V>,
+}
+
+/// Return every path whose value differs between both trees, in ascending path order.
+///
+/// Both trees are walked in lockstep, skipping subtrees whose hashes match without visiting their
+/// values. Values are compared by their encoding.
+pub fn diff<'a, P, V, H>(
+    old: &'a PatriciaMerkleTree<P, V, H>,
+    new: &'a PatriciaMerkleTree<P, V, H>,
+) -> Vec<Difference<'a, P, V>>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut differences = Vec::new();
+    diff_cursors(
+        old,
+        new,
+        Cursor::root(old),
+        Cursor::root(new),
+        0,
+        &mut |path, old_value, new_value| {
+            differences.push(Difference {
+                path,
+                old_value,
+                new_value,
+            })
+        },
+    );
+
+    differences
+}
+
+/// A position within a tree, at some nibble offset.
+#[derive(Clone, Copy)]
+pub(crate) enum Cursor {
+    Empty,
+    /// A node placed at the current offset.
+    Node(NodeRef),
+    /// An extension node with some of its prefix's nibbles already consumed.
+    Extension(NodeRef, usize),
+    /// A leaf placed above the current offset, whose path continues at it.
+    Leaf(ValueRef),
+}
+
+impl Cursor {
+    pub fn root<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>) -> Self
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        match tree.root_ref.is_valid() {
+            true => Cursor::Node(tree.root_ref),
+            false => Cursor::Empty,
+        }
+    }
+
+    /// Return the value at the current offset (if any) and the cursors one nibble below.
+    pub fn expand<P, V, H>(
+        self,
+        tree: &PatriciaMerkleTree<P, V, H>,
+        path_offset: usize,
+    ) -> (Option<ValueRef>, [Cursor; 16])
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        let mut children = [Cursor::Empty; 16];
+        let value_ref = match self {
+            Cursor::Empty => None,
+            Cursor::Node(node_ref) => match tree
+                .nodes
+                .get(*node_ref)
+                .expect("inconsistent internal tree structure")
+            {
+                Node::Branch(branch_node) => {
+                    for (child, child_ref) in children.iter_mut().zip(&branch_node.choices) {
+                        if child_ref.is_valid() {
+                            *child = Cursor::Node(*child_ref);
+                        }
+                    }
+
+                    branch_node
+                        .value_ref
+                        .is_valid()
+                        .then_some(branch_node.value_ref)
+                }
+                Node::Extension(_) => {
+                    return Cursor::Extension(node_ref, 0).expand(tree, path_offset)
+                }
+                Node::Leaf(leaf_node) => {
+                    return Cursor::Leaf(leaf_node.value_ref).expand(tree, path_offset)
+                }
+                // Stubbed subtrees have no values in memory.
+                Node::Stub(_) => None,
+            },
+            Cursor::Extension(node_ref, consumed) => {
+                let Some(Node::Extension(extension_node)) = tree.nodes.get(*node_ref) else {
+                    panic!("inconsistent internal tree structure");
+                };
+
+                let choice = extension_node
+                    .prefix
+                    .get(consumed)
+                    .expect("inconsistent internal tree structure");
+                children[u8::from(choice) as usize] =
+                    match consumed + 1 == extension_node.prefix.len() {
+                        true => Cursor::Node(extension_node.child_ref),
+                        false => Cursor::Extension(node_ref, consumed + 1),
+                    };
+
+                None
+            }
+            Cursor::Leaf(value_ref) => {
+                let (path, _) = tree
+                    .values
+                    .get(*value_ref)
+                    .expect("inconsistent internal tree structure");
+
+                let path = to_nibbles(path.encode().as_ref());
+                match path.get(path_offset) {
+                    Some(choice) => {
+                        children[*choice as usize] = Cursor::Leaf(value_ref);
+                        None
+                    }
+                    None => Some(value_ref),
+                }
+            }
+        };
+
+        (value_ref, children)
+    }
+}
+
+/// Return whether both subtrees are known to be identical without visiting them: either both are
+/// empty, or both are nodes with the same hash.
+pub(crate) fn is_same_subtree<P, V, H>(
+    old: &PatriciaMerkleTree<P, V, H>,
+    new: &PatriciaMerkleTree<P, V, H>,
+    old_cursor: Cursor,
+    new_cursor: Cursor,
+    path_offset: usize,
+) -> bool
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    match (old_cursor, new_cursor) {
+        (Cursor::Empty, Cursor::Empty) => true,
+        // Identical hashes mean identical subtrees.
+        (Cursor::Node(old_ref), Cursor::Node(new_ref)) => {
+            let old_node = old
+                .nodes
+                .get(*old_ref)
+                .expect("inconsistent internal tree structure");
+            let new_node = new
+                .nodes
+                .get(*new_ref)
+                .expect("inconsistent internal tree structure");
+
+            old_node
+                .compute_hash(&old.nodes, &old.values, path_offset)
+                .as_ref()
+                == new_node
+                    .compute_hash(&new.nodes, &new.values, path_offset)
+                    .as_ref()
+        }
+        _ => false,
+    }
+}
+
+/// Call `report` for every path whose value differs between both subtrees, in ascending path
+/// order.
+pub(crate) fn diff_cursors<'a, P, V, H>(
+    old: &'a PatriciaMerkleTree<P, V, H>,
+    new: &'a PatriciaMerkleTree<P, V, H>,
+    old_cursor: Cursor,
+    new_cursor: Cursor,
+    path_offset: usize,
+    report: &mut impl FnMut(&'a P, Option<&'a V>, Option<&'a V>),
+) where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    if is_same_subtree(old, new, old_cursor, new_cursor, path_offset) {
+        return;
+    }
+
+    let (old_value_ref, old_children) = old_cursor.expand(old, path_offset);
+    let (new_value_ref, new_children) = new_cursor.expand(new, path_offset);
+
+    let old_entry = old_value_ref.map(|x| {
+        old.values
+            .get(*x)
+            .expect("inconsistent internal tree structure")
+    });
+    let new_entry = new_value_ref.map(|x| {
+        new.values
+            .get(*x)
+            .expect("inconsistent internal tree structure")
+    });
+    match (old_entry, new_entry) {
+        (Some((path, old_value)), Some((_, new_value))) => {
+            if old_value.encode() != new_value.encode() {
+                report(path, Some(old_value), Some(new_value));
+            }
+        }
+        (Some((path, old_value)), None) => report(path, Some(old_value), None),
+        (None, Some((path, new_value))) => report(path, None, Some(new_value)),
+        (None, None) => {}
+    }
+
+    for (old_child, new_child) in old_children.into_iter().zip(new_children) {
+        diff_cursors(old, new, old_child, new_child, path_offset + 1, report);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn diff_trees() {
+        let mut old = pmt_tree!(Vec<u8>);
+        old.insert(b"do".to_vec(), b"verb".to_vec());
+        old.insert(b"dog".to_vec(), b"puppy".to_vec());
+        old.insert(b"horse".to_vec(), b"stallion".to_vec());
+
+        let mut new = old.clone();
+        new.insert(b"dog".to_vec(), b"hound".to_vec());
+        new.insert(b"doge".to_vec(), b"coin".to_vec());
+        new.remove(b"horse".to_vec());
+
+        assert_eq!(
+            old.diff(&new),
+            [
+                Difference {
+                    path: &b"dog".to_vec(),
+                    old_value: Some(&b"puppy".to_vec()),
+                    new_value: Some(&b"hound".to_vec()),
+                },
+                Difference {
+                    path: &b"doge".to_vec(),
+                    old_value: None,
+                    new_value: Some(&b"coin".to_vec()),
+                },
+                Difference {
+                    path: &b"horse".to_vec(),
+                    old_value: Some(&b"stallion".to_vec()),
+                    new_value: None,
+                },
+            ]
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_diff(
+            old_data in btree_map(vec(any::<u8>(), 0..4), vec(any::<u8>(), 1..4), 0..64),
+            new_data in btree_map(vec(any::<u8>(), 0..4), vec(any::<u8>(), 1..4), 0..64),
+        ) {
+            let mut old = pmt_tree!(Vec<u8>);
+            for (path, value) in &old_data {
+                old.insert(path.clone(), value.clone());
+            }
+            let mut new = pmt_tree!(Vec<u8>);
+            for (path, value) in &new_data {
+                new.insert(path.clone(), value.clone());
+            }
+
+            let expected = old_data
+                .keys()
+                .chain(new_data.keys())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .filter(|path| old_data.get(*path) != new_data.get(*path))
+                .map(|path| (path.clone(), old_data.get(path), new_data.get(path)))
+                .collect::<Vec<_>>();
+            let differences = old
+                .diff(&new)
+                .into_iter()
+                .map(|x| (x.path.clone(), x.old_value, x.new_value))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(differences, expected);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use crate::{
+    nibble::NibbleSlice,
+    node::{Node, NodeKind},
+    Encode, NodeRef, NodesStorage, PatriciaMerkleTree, ValuesStorage,
+};
+use digest::Digest;
+
+/// The first structural difference found between two trees.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+    /// Nibbles from the root up to (but not including) the diverging nodes.
+    pub path: Vec<u8>,
+    /// What differs between both nodes.
+    pub reason: DivergenceReason,
+
+    /// The node on the left tree (`None` if missing).
+    pub left: Option<NodeSummary>,
+    /// The node on the right tree (`None` if missing).
+    pub right: Option<NodeSummary>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DivergenceReason {
+    /// One of the trees doesn't have a node where the other one has.
+    Missing,
+    /// Both nodes are of different kinds.
+    NodeKind,
+    /// The extension prefixes or the leaves' remaining paths are different.
+    Prefix,
+    /// The stored values are different.
+    Value,
+    /// Both nodes look the same but their hashes are different.
+    Hash,
+}
+
+/// A description of a node within a divergence report.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NodeSummary {
+    pub kind: NodeKind,
+    /// The extension's prefix or the leaf's remaining path (as nibbles). Empty for branches and
+    /// stubs.
+    pub prefix: Vec<u8>,
+    /// The encoded value (if any).
+    pub value: Option<Vec<u8>>,
+    /// The node's reference as it'd be stored in its parent (either the inline encoding or its
+    /// hash).
+    pub hash: Vec<u8>,
+}
+
+struct TreeView<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    nodes: &'a NodesStorage<P, V, H>,
+    values: &'a ValuesStorage<P, V>,
+}
+
+impl<'a, P, V, H> TreeView<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    fn node(&self, node_ref: NodeRef) -> &'a Node<P, V, H> {
+        self.nodes
+            .get(*node_ref)
+            .expect("inconsistent internal tree structure")
+    }
+
+    fn hash(&self, node_ref: NodeRef, path_offset: usize) -> Vec<u8> {
+        self.node(node_ref)
+            .compute_hash(self.nodes, self.values, path_offset)
+            .as_ref()
+            .to_vec()
+    }
+
+    fn summarize(&self, node_ref: NodeRef, path_offset: usize) -> NodeSummary {
+        let (prefix, value) = match self.node(node_ref) {
+            Node::Branch(branch_node) => (
+                Vec::new(),
+                branch_node
+                    .value_ref
+                    .is_valid()
+                    .then(|| self.encoded_value(*branch_node.value_ref)),
+            ),
+            Node::Extension(extension_node) => {
+                (extension_node.prefix.iter().map(u8::from).collect(), None)
+            }
+            Node::Leaf(leaf_node) => {
+                let (path, _) = self
+                    .values
+                    .get(*leaf_node.value_ref)
+                    .expect("inconsistent internal tree structure");
+
+                let encoded_path = path.encode();
+                let mut path_slice = NibbleSlice::new(encoded_path.as_ref());
+                path_slice.offset_add(path_offset);
+
+                (
+                    path_slice.map(u8::from).collect(),
+                    Some(self.encoded_value(*leaf_node.value_ref)),
+                )
+            }
+            Node::Stub(_) => (Vec::new(), None),
+        };
+
+        NodeSummary {
+            kind: self.node(node_ref).kind(),
+            prefix,
+            value,
+            hash: self.hash(node_ref, path_offset),
+        }
+    }
+
+    fn encoded_value(&self, value_ref: usize) -> Vec<u8> {
+        let (_, value) = self
+            .values
+            .get(value_ref)
+            .expect("inconsistent internal tree structure");
+
+        value.encode().into_owned()
+    }
+}
+
+/// Walk both trees in lockstep and return the first structural difference.
+pub fn find_divergence<P, V, H>(
+    left: &PatriciaMerkleTree<P, V, H>,
+    right: &PatriciaMerkleTree<P, V, H>,
+) -> Option<Divergence>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let left_view = TreeView {
+        nodes: &left.nodes,
+        values: &left.values,
+    };
+    let right_view = TreeView {
+        nodes: &right.nodes,
+        values: &right.values,
+    };
+
+    let mut path = Vec::new();
+    match (left.root_ref.is_valid(), right.root_ref.is_valid()) {
+        (true, true) => diverge(
+            &left_view,
+            &right_view,
+            left.root_ref,
+            right.root_ref,
+            &mut path,
+        ),
+        (false, false) => None,
+        (l, r) => Some(Divergence {
+            path,
+            reason: DivergenceReason::Missing,
+            left: l.then(|| left_view.summarize(left.root_ref, 0)),
+            right: r.then(|| right_view.summarize(right.root_ref, 0)),
+        }),
+    }
+}
+
+fn diverge<P, V, H>(
+    left: &TreeView<P, V, H>,
+    right: &TreeView<P, V, H>,
+    left_ref: NodeRef,
+    right_ref: NodeRef,
+    path: &mut Vec<u8>,
+) -> Option<Divergence>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    // Identical hashes mean identical subtrees.
+    let path_offset = path.len();
+    if left.hash(left_ref, path_offset) == right.hash(right_ref, path_offset) {
+        return None;
+    }
+
+    let report = |path: &Vec<u8>, reason| {
+        Some(Divergence {
+            path: path.clone(),
+            reason,
+            left: Some(left.summarize(left_ref, path_offset)),
+            right: Some(right.summarize(right_ref, path_offset)),
+        })
+    };
+
+    match (left.node(left_ref), right.node(right_ref)) {
+        (Node::Branch(left_node), Node::Branch(right_node)) => {
+            let left_value = left_node
+                .value_ref
+                .is_valid()
+                .then(|| left.encoded_value(*left_node.value_ref));
+            let right_value = right_node
+                .value_ref
+                .is_valid()
+                .then(|| right.encoded_value(*right_node.value_ref));
+            if left_value != right_value {
+                return report(path, DivergenceReason::Value);
+            }
+
+            for (choice, (l, r)) in left_node
+                .choices
+                .iter()
+                .zip(right_node.choices.iter())
+                .enumerate()
+            {
+                path.push(choice as u8);
+                let divergence = match (l.is_valid(), r.is_valid()) {
+                    (true, true) => diverge(left, right, *l, *r, path),
+                    (false, false) => None,
+                    (l_valid, r_valid) => Some(Divergence {
+                        path: path.clone(),
+                        reason: DivergenceReason::Missing,
+                        left: l_valid.then(|| left.summarize(*l, path_offset + 1)),
+                        right: r_valid.then(|| right.summarize(*r, path_offset + 1)),
+                    }),
+                };
+                path.pop();
+
+                if divergence.is_some() {
+                    return divergence;
+                }
+            }
+
+            report(path, DivergenceReason::Hash)
+        }
+        (Node::Extension(left_node), Node::Extension(right_node)) => {
+            if !left_node.prefix.iter().eq(right_node.prefix.iter()) {
+                return report(path, DivergenceReason::Prefix);
+            }
+
+            path.extend(left_node.prefix.iter().map(u8::from));
+            let divergence = diverge(left, right, left_node.child_ref, right_node.child_ref, path);
+            path.truncate(path_offset);
+
+            divergence.or_else(|| report(path, DivergenceReason::Hash))
+        }
+        (Node::Leaf(_), Node::Leaf(_)) => {
+            let left_summary = left.summarize(left_ref, path_offset);
+            let right_summary = right.summarize(right_ref, path_offset);
+
+            let reason = if left_summary.prefix != right_summary.prefix {
+                DivergenceReason::Prefix
+            } else if left_summary.value != right_summary.value {
+                DivergenceReason::Value
+            } else {
+                DivergenceReason::Hash
+            };
+
+            Some(Divergence {
+                path: path.clone(),
+                reason,
+                left: Some(left_summary),
+                right: Some(right_summary),
+            })
+        }
+        (Node::Stub(_), Node::Stub(_)) => report(path, DivergenceReason::Hash),
+        _ => report(path, DivergenceReason::NodeKind),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn identical_trees() {
+        let mut left = pmt_tree!(Vec<u8>);
+        let mut right = pmt_tree!(Vec<u8>);
+
+        for tree in [&mut left, &mut right] {
+            tree.insert(vec![0x12, 0x34], vec![0x01]);
+            tree.insert(vec![0x12, 0x56], vec![0x02]);
+            tree.insert(vec![0x78], vec![0x03]);
+        }
+
+        assert_eq!(left.find_divergence(&right), None);
+    }
+
+    #[test]
+    fn empty_and_non_empty() {
+        let left = pmt_tree!(Vec<u8>);
+        let mut right = pmt_tree!(Vec<u8>);
+        right.insert(vec![0x12], vec![0x01]);
+
+        let divergence = left.find_divergence(&right).unwrap();
+        assert_eq!(divergence.reason, DivergenceReason::Missing);
+        assert_eq!(divergence.left, None);
+        assert_eq!(divergence.right.unwrap().kind, NodeKind::Leaf);
+    }
+
+    #[test]
+    fn different_leaf_value() {
+        let mut left = pmt_tree!(Vec<u8>);
+        let mut right = pmt_tree!(Vec<u8>);
+
+        for tree in [&mut left, &mut right] {
+            tree.insert(vec![0x12, 0x34], vec![0x01]);
+            tree.insert(vec![0x12, 0x56], vec![0x02]);
+        }
+        left.insert(vec![0x78], vec![0x03]);
+        right.insert(vec![0x78], vec![0x04]);
+
+        let divergence = left.find_divergence(&right).unwrap();
+        assert_eq!(divergence.path, vec![0x7]);
+        assert_eq!(divergence.reason, DivergenceReason::Value);
+        assert_eq!(divergence.left.unwrap().value, Some(vec![0x03]));
+        assert_eq!(divergence.right.unwrap().value, Some(vec![0x04]));
+    }
+
+    #[test]
+    fn missing_branch_choice() {
+        let mut left = pmt_tree!(Vec<u8>);
+        let mut right = pmt_tree!(Vec<u8>);
+
+        for tree in [&mut left, &mut right] {
+            tree.insert(vec![0x12, 0x34], vec![0x01]);
+            tree.insert(vec![0x12, 0x56], vec![0x02]);
+        }
+        left.insert(vec![0x12, 0x78], vec![0x03]);
+
+        let divergence = left.find_divergence(&right).unwrap();
+        assert_eq!(divergence.path, vec![0x1, 0x2, 0x7]);
+        assert_eq!(divergence.reason, DivergenceReason::Missing);
+        assert!(divergence.left.is_some());
+        assert!(divergence.right.is_none());
+    }
+
+    #[test]
+    fn different_node_kind() {
+        let mut left = pmt_tree!(Vec<u8>);
+        let mut right = pmt_tree!(Vec<u8>);
+
+        left.insert(vec![0x12, 0x34], vec![0x01]);
+        right.insert(vec![0x12, 0x34], vec![0x01]);
+        right.insert(vec![0x12, 0x35], vec![0x02]);
+
+        let divergence = left.find_divergence(&right).unwrap();
+        assert_eq!(divergence.path, Vec::<u8>::new());
+        assert_eq!(divergence.reason, DivergenceReason::NodeKind);
+        assert_eq!(divergence.left.unwrap().kind, NodeKind::Leaf);
+        assert_eq!(divergence.right.unwrap().kind, NodeKind::Extension);
+    }
+}
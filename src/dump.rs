@@ -1,11 +1,15 @@
 use crate::{
+    bounds::find_subtree,
     node::Node,
     nodes::{BranchNode, ExtensionNode, LeafNode},
+    proof::to_nibbles,
     Encode, NodeRef, PatriciaMerkleTree,
 };
 use digest::Digest;
 use std::io::Write;
 
+/// Writes a human-readable representation of a tree's structure, with the paths and values
+/// in hex.
 pub struct TreeDump<'a, P, V, H, W>
 where
     P: Encode,
@@ -26,6 +30,7 @@ where
     H: Digest,
     W: Write,
 {
+    /// Create a dump of `parent` into `writer`, with every line indented by `indent` spaces.
     pub fn new(parent: &'a PatriciaMerkleTree<P, V, H>, writer: W, indent: usize) -> Self {
         Self {
             parent,
@@ -34,14 +39,28 @@ where
         }
     }
 
-    pub fn dump(mut self) {
+    /// Dump the whole tree.
+    pub fn dump(self) {
+        let root_ref = self.parent.root_ref;
+        self.dump_from(root_ref);
+    }
+
+    /// Dump the smallest subtree holding every path starting with `prefix` (as encoded bytes).
+    ///
+    /// It may contain other paths when it's a single leaf, since leaves aren't split by prefix.
+    pub fn dump_prefix(self, prefix: &[u8]) {
+        let node_ref = find_subtree(self.parent, &to_nibbles(prefix)).unwrap_or_default();
+        self.dump_from(node_ref);
+    }
+
+    fn dump_from(mut self, node_ref: NodeRef) {
         let indent = " ".repeat(self.indent);
         write!(self.writer, "{indent}").unwrap();
 
-        if !self.parent.root_ref.is_valid() {
+        if !node_ref.is_valid() {
             writeln!(self.writer, "(nil)").unwrap()
         } else {
-            self.write_node(self.parent.root_ref);
+            self.write_node(node_ref);
             writeln!(self.writer).unwrap();
         }
 
@@ -59,6 +78,9 @@ where
             Node::Branch(branch_node) => self.write_branch(branch_node),
             Node::Extension(extension_node) => self.write_extension(extension_node),
             Node::Leaf(leaf_node) => self.write_leaf(leaf_node),
+            Node::Stub(stub_node) => {
+                write!(self.writer, "stub {{ {:02x?} }}", stub_node.hash).unwrap()
+            }
         }
     }
 
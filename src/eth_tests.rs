@@ -0,0 +1,235 @@
+//! Runner for the [`TrieTests`](https://github.com/ethereum/tests/tree/develop/TrieTests)
+//! fixtures of the official Ethereum test suite.
+//!
+//! Supports `trietest.json`, `trieanyorder.json` and their secure variants (including
+//! `hex_encoded_securetrie_test.json`). Keys and values starting with `0x` are decoded as hex, and
+//! null or empty values are treated as removals.
+
+use crate::{hex, PatriciaMerkleTree};
+use digest::Digest;
+use serde::Deserialize;
+use std::{collections::BTreeMap, io::Read};
+use thiserror::Error;
+
+/// Errors found while loading a fixtures file.
+#[derive(Debug, Error)]
+pub enum EthTestsError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid hex string in test {0}")]
+    InvalidHex(String),
+}
+
+/// A single trie test case.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TrieTest {
+    pub name: String,
+    /// The operations to apply, in order. `None` values are removals.
+    pub ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    /// The expected root hash after applying every operation.
+    pub root: Vec<u8>,
+}
+
+/// The outcome of running a test case.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TrieTestResult {
+    pub name: String,
+    pub expected: Vec<u8>,
+    pub computed: Vec<u8>,
+}
+
+impl TrieTestResult {
+    pub fn passed(&self) -> bool {
+        self.expected == self.computed
+    }
+}
+
+#[derive(Deserialize)]
+struct FixtureJson {
+    #[serde(rename = "in")]
+    input: FixtureInput,
+    root: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FixtureInput {
+    /// Ordered operations (`trietest.json`).
+    List(Vec<(String, Option<String>)>),
+    /// Unordered insertions (`trieanyorder.json`).
+    Map(BTreeMap<String, Option<String>>),
+}
+
+/// Parse a fixtures file.
+pub fn parse_fixtures(reader: impl Read) -> Result<Vec<TrieTest>, EthTestsError> {
+    let fixtures: BTreeMap<String, FixtureJson> = serde_json::from_reader(reader)?;
+
+    fixtures
+        .into_iter()
+        .map(|(name, fixture)| {
+            let decode = |x: &str| {
+                if x.starts_with("0x") {
+                    hex::decode(x).ok_or_else(|| EthTestsError::InvalidHex(name.clone()))
+                } else {
+                    Ok(x.as_bytes().to_vec())
+                }
+            };
+
+            let ops = match fixture.input {
+                FixtureInput::List(x) => x,
+                FixtureInput::Map(x) => x.into_iter().collect(),
+            }
+            .into_iter()
+            .map(|(path, value)| {
+                let value = match value {
+                    Some(x) => Some(decode(&x)?).filter(|x| !x.is_empty()),
+                    None => None,
+                };
+
+                Ok((decode(&path)?, value))
+            })
+            .collect::<Result<_, EthTestsError>>()?;
+
+            Ok(TrieTest {
+                root: decode(&fixture.root)?,
+                name,
+                ops,
+            })
+        })
+        .collect()
+}
+
+/// Run a test case and return its outcome. When `secure` is set, paths are hashed before being
+/// inserted (as the `*_secureTrie` fixtures require).
+pub fn run_test<H>(test: &TrieTest, secure: bool) -> TrieTestResult
+where
+    H: Digest,
+{
+    let mut tree = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, H>::new();
+    for (path, value) in &test.ops {
+        let path = if secure {
+            H::digest(path).to_vec()
+        } else {
+            path.clone()
+        };
+
+        match value {
+            Some(value) => tree.insert(path, value.clone()),
+            None => tree.remove(path),
+        };
+    }
+
+    TrieTestResult {
+        name: test.name.clone(),
+        expected: test.root.clone(),
+        computed: tree.compute_hash().to_vec(),
+    }
+}
+
+/// Parse and run every test case in a fixtures file.
+pub fn run_fixtures<H>(
+    reader: impl Read,
+    secure: bool,
+) -> Result<Vec<TrieTestResult>, EthTestsError>
+where
+    H: Digest,
+{
+    Ok(parse_fixtures(reader)?
+        .iter()
+        .map(|test| run_test::<H>(test, secure))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sha3::Keccak256;
+
+    const TRIE_TEST: &str = r#"{
+        "emptyValues": {
+            "in": [
+                ["do", "verb"],
+                ["ether", "wookiedoo"],
+                ["horse", "stallion"],
+                ["shaman", "horse"],
+                ["doge", "coin"],
+                ["ether", null],
+                ["dog", "puppy"],
+                ["shaman", null]
+            ],
+            "root": "0x5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+        },
+        "hexKeys": {
+            "in": [
+                ["0x646f", "verb"],
+                ["0x686f727365", "stallion"],
+                ["0x646f6765", "coin"],
+                ["0x646f67", "0x7075707079"]
+            ],
+            "root": "0x5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+        }
+    }"#;
+
+    const TRIE_ANY_ORDER: &str = r#"{
+        "dogs": {
+            "in": {
+                "doe": "reindeer",
+                "dog": "puppy",
+                "dogglesworth": "cat"
+            },
+            "root": "0x8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        },
+        "foo": {
+            "in": {
+                "foo": "bar",
+                "food": "bass"
+            },
+            "root": "0x17beaa1648bafa633cda809c90c04af50fc8aed3cb40d16efbddee6fdf63c4c3"
+        }
+    }"#;
+
+    #[test]
+    fn trie_test() {
+        let results = run_fixtures::<Keccak256>(TRIE_TEST.as_bytes(), false).unwrap();
+
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert!(result.passed(), "{} failed", result.name);
+        }
+    }
+
+    #[test]
+    fn trie_any_order() {
+        let results = run_fixtures::<Keccak256>(TRIE_ANY_ORDER.as_bytes(), false).unwrap();
+
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert!(result.passed(), "{} failed", result.name);
+        }
+    }
+
+    #[test]
+    fn secure_changes_root() {
+        let results = run_fixtures::<Keccak256>(TRIE_ANY_ORDER.as_bytes(), true).unwrap();
+
+        assert!(results.iter().all(|x| !x.passed()));
+    }
+
+    #[test]
+    fn parse_removals() {
+        let tests = parse_fixtures(TRIE_TEST.as_bytes()).unwrap();
+
+        assert_eq!(tests[0].name, "emptyValues");
+        assert_eq!(tests[0].ops[5], (b"ether".to_vec(), None));
+    }
+
+    #[test]
+    fn invalid_hex() {
+        let fixture = r#"{ "bad": { "in": [["0xzz", "a"]], "root": "0x00" } }"#;
+
+        assert!(matches!(
+            parse_fixtures(fixture.as_bytes()),
+            Err(EthTestsError::InvalidHex(name)) if name == "bad",
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{codec::Decode, hex, rlp::DecodeError, Encode};
+use std::{
+    borrow::Cow,
+    fmt::{self, Debug},
+};
+
+/// A path of exactly `N` bytes, stored inline.
+///
+/// Unlike `Vec<u8>` paths, they don't allocate when stored in the tree (nor when encoded), which
+/// is worth it for fixed-length paths such as 20-byte addresses or 32-byte hashes.
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FixedPath<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> FixedPath<N> {
+    /// The path's length in nibbles.
+    pub const NIBBLES: usize = 2 * N;
+
+    pub const fn new(bytes: [u8; N]) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+
+    /// Return the nibble at `index`.
+    ///
+    /// Panics if `index` is not less than [`NIBBLES`](Self::NIBBLES).
+    pub const fn nibble(&self, index: usize) -> u8 {
+        match index & 1 {
+            0 => self.0[index >> 1] >> 4,
+            _ => self.0[index >> 1] & 0x0F,
+        }
+    }
+
+    /// Return the number of leading nibbles both paths have in common.
+    pub fn common_prefix_len(&self, other: &Self) -> usize {
+        match self.0.iter().zip(&other.0).position(|(a, b)| a != b) {
+            Some(index) => 2 * index + ((self.0[index] ^ other.0[index]) < 0x10) as usize,
+            None => Self::NIBBLES,
+        }
+    }
+
+    /// Return whether `prefix` (in nibbles) is a prefix of the path.
+    pub fn starts_with_nibbles(&self, prefix: &[u8]) -> bool {
+        prefix.len() <= Self::NIBBLES
+            && prefix
+                .iter()
+                .enumerate()
+                .all(|(index, nibble)| self.nibble(index) == *nibble)
+    }
+}
+
+impl<const N: usize> Default for FixedPath<N> {
+    fn default() -> Self {
+        Self([0; N])
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for FixedPath<N> {
+    fn from(value: [u8; N]) -> Self {
+        Self(value)
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for FixedPath<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<const N: usize> Debug for FixedPath<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FixedPath(0x{})", hex::encode(&self.0))
+    }
+}
+
+impl<const N: usize> Encode for FixedPath<N> {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+}
+
+impl<const N: usize> Decode for FixedPath<N> {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        Decode::decode(data).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{pmt_tree, proof::to_nibbles, PatriciaMerkleTree};
+    use proptest::prelude::*;
+    use sha3::Keccak256;
+
+    #[test]
+    fn fixed_path_tree() {
+        let mut tree = PatriciaMerkleTree::<FixedPath<20>, Vec<u8>, Keccak256>::new();
+        let mut reference = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            let path = FixedPath::new([i; 20]);
+            tree.insert(path, vec![i]);
+            reference.insert(path.0.to_vec(), vec![i]);
+        }
+
+        assert_eq!(tree.get(&FixedPath::new([3; 20])), Some(&vec![3]));
+        assert_eq!(tree.get(&FixedPath::default()), Some(&vec![0]));
+        assert_eq!(tree.compute_hash(), reference.compute_hash());
+        assert_eq!(
+            FixedPath::<2>::decode(&[0x12, 0x34]),
+            Ok(FixedPath([0x12, 0x34]))
+        );
+        assert!(FixedPath::<2>::decode(&[0x12]).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_nibbles(a in any::<[u8; 4]>(), b in any::<[u8; 4]>(), len in 0..=8usize) {
+            let (a, b) = (FixedPath(a), FixedPath(b));
+            let (a_nibbles, b_nibbles) = (to_nibbles(&a.0), to_nibbles(&b.0));
+
+            let expected = a_nibbles.iter().zip(&b_nibbles).take_while(|(x, y)| x == y).count();
+            prop_assert_eq!(a.common_prefix_len(&b), expected);
+            for (index, nibble) in a_nibbles.iter().enumerate() {
+                prop_assert_eq!(a.nibble(index), *nibble);
+            }
+            prop_assert!(a.starts_with_nibbles(&a_nibbles[..len]));
+            prop_assert_eq!(
+                a.starts_with_nibbles(&b_nibbles[..len]),
+                a_nibbles[..len] == b_nibbles[..len]
+            );
+        }
+    }
+}
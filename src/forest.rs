@@ -0,0 +1,691 @@
+use crate::{
+    account::{left_pad, Account, EMPTY_STORAGE_ROOT},
+    db::{load_tree, AtomicDb, LoadError, MemoryDb, NodeDb, WriteBatch},
+    hex,
+    rlp::{decode_item, DecodeError, RlpItem},
+    witness::{Address, StorageKey},
+    PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    mem::replace,
+};
+use thiserror::Error;
+
+type Tree<H> = PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>;
+
+/// Errors found while reading a storage slot through [`TrieForest::get_storage`].
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum StorageError {
+    #[error("no account trie has been designated")]
+    NoAccountTrie,
+    #[error("account {} doesn't exist", hex::encode(.0))]
+    NoAccount(Address),
+    #[error("undecodable account or slot: {0}")]
+    Undecodable(#[from] DecodeError),
+    #[error("storage trie can't be loaded: {0}")]
+    Load(#[from] LoadError),
+}
+
+/// Errors found while committing a forest through [`TrieForest::commit_atomic`].
+#[derive(Debug, Error)]
+pub enum AtomicCommitError {
+    #[error("undecodable account: {0}")]
+    Undecodable(#[from] DecodeError),
+    #[error("the batch couldn't be written: {0}")]
+    Write(#[from] io::Error),
+}
+
+/// Many logical tries (for example an account trie and the storage tries of every contract)
+/// sharing a single node database.
+///
+/// Tries are identified by an arbitrary byte string and kept in memory once opened. Nodes are
+/// reference counted across tries, so nodes shared by several tries are stored once and only
+/// become garbage when no trie references them anymore.
+///
+/// When an account trie is designated (see [`set_account_trie`](Self::set_account_trie)), every
+/// other trie is the storage trie of the account at the path equal to its id, and the storage
+/// roots of modified storage tries are written back into their accounts on commit.
+#[derive(Debug)]
+pub struct TrieForest<H, D>
+where
+    H: Digest,
+    D: NodeDb,
+{
+    db: D,
+    tries: BTreeMap<Vec<u8>, ForestTrie<H>>,
+    /// The id of the trie holding the accounts, if any.
+    account_trie: Option<Vec<u8>>,
+    /// Storage tries removed since the last commit.
+    removed_storage: HashSet<Vec<u8>>,
+
+    /// Number of tries whose committed version contains each node.
+    refs: HashMap<Vec<u8>, usize>,
+    /// Nodes no longer referenced by any trie, pending removal from the database.
+    garbage: HashSet<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct ForestTrie<H>
+where
+    H: Digest,
+{
+    tree: Tree<H>,
+    /// The root as of the last commit.
+    root: Output<H>,
+    /// Hashes of the nodes stored in the database as of the last commit.
+    nodes: HashSet<Vec<u8>>,
+    dirty: bool,
+}
+
+impl<H, D> TrieForest<H, D>
+where
+    H: Digest,
+    D: NodeDb,
+{
+    /// Create an empty forest over a node database.
+    pub fn new(db: D) -> Self {
+        Self {
+            db,
+            tries: BTreeMap::new(),
+            account_trie: None,
+            removed_storage: HashSet::new(),
+            refs: HashMap::new(),
+            garbage: HashSet::new(),
+        }
+    }
+
+    /// Open a trie already stored in the database, loading it into memory.
+    ///
+    /// Replaces any trie with the same id, which will have its nodes collected if they aren't
+    /// referenced by the new one.
+    pub fn open(&mut self, id: Vec<u8>, root: &[u8]) -> Result<(), LoadError> {
+        let mut tree = load_tree::<H, D>(&self.db, root)?;
+
+        let (root, nodes) = stage(&mut tree);
+        let old_nodes = self.tries.remove(&id).map(|x| x.nodes).unwrap_or_default();
+        self.update_refs(&old_nodes, &nodes);
+
+        self.tries.insert(
+            id,
+            ForestTrie {
+                tree,
+                root,
+                nodes: nodes.into_keys().collect(),
+                dirty: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Designate the trie holding the accounts, making every other trie a storage trie.
+    ///
+    /// From then on, committing a modified (or removed) storage trie re-encodes the account at the
+    /// path equal to the storage trie's id with its new storage root. Accounts are created when a
+    /// non-empty storage trie has none.
+    pub fn set_account_trie(&mut self, id: Vec<u8>) {
+        self.account_trie = Some(id);
+    }
+
+    /// Return the ids of every trie in the forest.
+    pub fn ids(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.tries.keys()
+    }
+
+    /// Return the root of a trie as of the last commit.
+    pub fn root(&self, id: &[u8]) -> Option<&Output<H>> {
+        self.tries.get(id).map(|x| &x.root)
+    }
+
+    /// Return a trie.
+    pub fn trie(&self, id: &[u8]) -> Option<&Tree<H>> {
+        self.tries.get(id).map(|x| &x.tree)
+    }
+
+    /// Return a trie for modification, creating an empty one if it doesn't exist.
+    pub fn trie_mut(&mut self, id: &[u8]) -> &mut Tree<H> {
+        let trie = self.tries.entry(id.to_vec()).or_insert_with(|| ForestTrie {
+            tree: Tree::new(),
+            root: H::digest([0x80]),
+            nodes: HashSet::new(),
+            dirty: false,
+        });
+
+        trie.dirty = true;
+        &mut trie.tree
+    }
+
+    /// Retrieve a value from a trie given its path.
+    pub fn get(&self, id: &[u8], path: &Vec<u8>) -> Option<&Vec<u8>> {
+        self.trie(id)?.get(path)
+    }
+
+    /// Insert a value into a trie, creating it if it doesn't exist.
+    pub fn insert(&mut self, id: &[u8], path: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.trie_mut(id).insert(path, value)
+    }
+
+    /// Remove a value from a trie.
+    pub fn remove(&mut self, id: &[u8], path: Vec<u8>) -> Option<Vec<u8>> {
+        if !self.tries.contains_key(id) {
+            return None;
+        }
+
+        self.trie_mut(id).remove(path)
+    }
+
+    /// Read a storage slot of an account, given their preimages.
+    ///
+    /// The account is looked up by hashed address within the account trie, and the slot by
+    /// hashed key within the account's storage trie, which is opened from its storage root if
+    /// it isn't in memory yet. Returns `None` if the slot is empty, and an error if the account
+    /// doesn't exist.
+    pub fn get_storage(
+        &mut self,
+        address: &Address,
+        slot: &StorageKey,
+    ) -> Result<Option<[u8; 32]>, StorageError> {
+        let account_id = self
+            .account_trie
+            .as_ref()
+            .ok_or(StorageError::NoAccountTrie)?;
+        let id = H::digest(address).to_vec();
+        let encoded = self
+            .get(account_id, &id)
+            .ok_or(StorageError::NoAccount(*address))?;
+        let account = Account::decode(encoded)?;
+
+        if !self.tries.contains_key(&id) {
+            if account.storage_root == EMPTY_STORAGE_ROOT {
+                return Ok(None);
+            }
+            self.open(id.clone(), &account.storage_root)?;
+        }
+
+        let Some(encoded) = self.get(&id, &H::digest(slot).to_vec()) else {
+            return Ok(None);
+        };
+        match decode_item(encoded)? {
+            (RlpItem::Bytes(value), _, []) => Ok(Some(left_pad(value)?)),
+            (RlpItem::Bytes(_), _, _) => Err(DecodeError::TrailingBytes.into()),
+            (RlpItem::List(_), _, _) => Err(DecodeError::ExpectedBytes.into()),
+        }
+    }
+
+    /// Drop a whole trie from the forest. Its nodes become garbage unless other tries reference
+    /// them.
+    pub fn remove_trie(&mut self, id: &[u8]) -> bool {
+        match self.tries.remove(id) {
+            Some(trie) => {
+                if self.account_trie.as_deref().is_some_and(|x| x != id) {
+                    self.removed_storage.insert(id.to_vec());
+                }
+
+                self.update_refs(&trie.nodes, &HashMap::new());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Write every modified trie into the database and return the ids of the committed tries.
+    ///
+    /// The nodes of every trie are written before any root is updated, so the committed roots
+    /// always refer to complete tries.
+    ///
+    /// Fails without writing anything if an account whose storage root needs updating can't be
+    /// decoded.
+    pub fn commit(&mut self) -> Result<Vec<Vec<u8>>, DecodeError> {
+        let staged = self.stage_dirty()?;
+        for (_, _, nodes) in &staged {
+            for (hash, encoded) in nodes {
+                self.db.insert(hash.clone(), encoded.clone());
+            }
+        }
+
+        Ok(self.finish_commit(staged))
+    }
+
+    /// Encode the modified tries, after writing the roots of the modified storage tries into
+    /// their accounts.
+    fn stage_dirty(&mut self) -> Result<Vec<StagedTrie<H>>, DecodeError> {
+        self.propagate_storage_roots()?;
+
+        let mut staged = Vec::new();
+        for (id, trie) in self.tries.iter_mut().filter(|(_, x)| x.dirty) {
+            let (root, nodes) = stage(&mut trie.tree);
+            staged.push((id.clone(), root, nodes));
+        }
+
+        Ok(staged)
+    }
+
+    /// Record the staged tries as committed, once their nodes are in the database.
+    fn finish_commit(&mut self, staged: Vec<StagedTrie<H>>) -> Vec<Vec<u8>> {
+        let mut committed = Vec::with_capacity(staged.len());
+        for (id, root, nodes) in staged {
+            let trie = self.tries.get_mut(&id).unwrap();
+            let old_nodes = replace(&mut trie.nodes, nodes.keys().cloned().collect());
+            trie.root = root;
+            trie.dirty = false;
+
+            self.update_refs(&old_nodes, &nodes);
+            committed.push(id);
+        }
+
+        committed
+    }
+
+    /// Remove every node no longer referenced by any trie from the database and return how many
+    /// were removed.
+    ///
+    /// Only nodes committed through the forest are tracked; other nodes in the database are left
+    /// untouched.
+    pub fn gc(&mut self) -> usize {
+        let count = self.garbage.len();
+        for hash in self.garbage.drain() {
+            self.db.remove(&hash);
+        }
+
+        count
+    }
+
+    /// Return the node database.
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Consume the forest, returning its node database.
+    pub fn into_db(self) -> D {
+        self.db
+    }
+
+    /// Write the roots of the modified storage tries into their accounts.
+    fn propagate_storage_roots(&mut self) -> Result<(), DecodeError> {
+        let account_id = match &self.account_trie {
+            Some(x) => x.clone(),
+            None => return Ok(()),
+        };
+
+        let mut storage_roots = self
+            .removed_storage
+            .iter()
+            .map(|id| (id.clone(), EMPTY_STORAGE_ROOT))
+            .collect::<BTreeMap<_, _>>();
+        for (id, trie) in self.tries.iter_mut() {
+            if trie.dirty && *id != account_id {
+                let root = trie.tree.compute_hash()[..]
+                    .try_into()
+                    .expect("storage roots must be 32 bytes long");
+                storage_roots.insert(id.clone(), root);
+            }
+        }
+
+        let account_tree = self.trie(&account_id);
+        let mut accounts = Vec::new();
+        for (id, storage_root) in storage_roots {
+            let mut account = match account_tree.and_then(|x| x.get(&id)) {
+                Some(encoded) => Account::decode(encoded)?,
+                None if storage_root == EMPTY_STORAGE_ROOT => continue,
+                None => Account::default(),
+            };
+
+            if account.storage_root != storage_root {
+                account.storage_root = storage_root;
+                accounts.push((id, account.encode()));
+            }
+        }
+
+        if !accounts.is_empty() {
+            let account_tree = self.trie_mut(&account_id);
+            for (id, encoded) in accounts {
+                account_tree.insert(id, encoded);
+            }
+        }
+        self.removed_storage.clear();
+
+        Ok(())
+    }
+
+    fn update_refs(&mut self, old_nodes: &HashSet<Vec<u8>>, new_nodes: &HashMap<Vec<u8>, Vec<u8>>) {
+        for hash in new_nodes.keys().filter(|x| !old_nodes.contains(*x)) {
+            *self.refs.entry(hash.clone()).or_default() += 1;
+            self.garbage.remove(hash);
+        }
+
+        for hash in old_nodes.iter().filter(|x| !new_nodes.contains_key(*x)) {
+            let count = self
+                .refs
+                .get_mut(hash)
+                .expect("inconsistent forest reference counts");
+            *count -= 1;
+            if *count == 0 {
+                self.refs.remove(hash);
+                self.garbage.insert(hash.clone());
+            }
+        }
+    }
+}
+
+impl<H, D> TrieForest<H, D>
+where
+    H: Digest,
+    D: AtomicDb,
+{
+    /// Like [`commit`](Self::commit), but writing the nodes of every modified trie along with
+    /// their new roots as a single atomic batch, so that a crash can't persist the new storage of
+    /// an account without its updated storage root (or vice versa).
+    ///
+    /// The roots are recorded under the tries' ids, and can be retrieved through
+    /// [`AtomicDb::trie_root`] to [`open`](Self::open) the tries again.
+    ///
+    /// If the database fails to write the batch, the tries are left modified (and not committed),
+    /// so that the commit can be retried.
+    pub fn commit_atomic(&mut self) -> Result<Vec<Vec<u8>>, AtomicCommitError> {
+        let staged = self.stage_dirty()?;
+
+        let mut batch = WriteBatch::default();
+        for (id, root, nodes) in &staged {
+            batch
+                .nodes
+                .extend(nodes.iter().map(|(h, e)| (h.clone(), e.clone())));
+            batch.roots.push((id.clone(), root.to_vec()));
+        }
+        self.db.write_batch(batch)?;
+
+        Ok(self.finish_commit(staged))
+    }
+}
+
+/// A modified trie's id, root and nodes, ready to be written.
+type StagedTrie<H> = (Vec<u8>, Output<H>, HashMap<Vec<u8>, Vec<u8>>);
+
+/// Encode every node of the tree, returning its root and its nodes indexed by hash.
+fn stage<H>(tree: &mut Tree<H>) -> (Output<H>, HashMap<Vec<u8>, Vec<u8>>)
+where
+    H: Digest,
+{
+    let mut db = MemoryDb::new();
+    let root = tree.commit(&mut db);
+
+    (root, db.into_iter().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sha3::Keccak256;
+
+    type Forest = TrieForest<Keccak256, MemoryDb>;
+
+    fn fill(forest: &mut Forest, id: &[u8], count: u8) {
+        for i in 0..count {
+            forest.insert(id, vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+    }
+
+    #[test]
+    fn commit_roots() {
+        let mut forest = Forest::new(MemoryDb::new());
+        fill(&mut forest, b"a", 16);
+        fill(&mut forest, b"b", 32);
+
+        assert_eq!(forest.root(b"a"), Some(&Keccak256::digest([0x80])));
+        assert_eq!(forest.commit().unwrap(), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert!(forest.commit().unwrap().is_empty());
+
+        let expected = *forest.trie_mut(b"a").compute_hash();
+        assert_eq!(forest.root(b"a"), Some(&expected));
+        assert_ne!(forest.root(b"a"), forest.root(b"b"));
+    }
+
+    #[test]
+    fn shared_nodes() {
+        let mut forest = Forest::new(MemoryDb::new());
+        fill(&mut forest, b"a", 32);
+        forest.commit().unwrap();
+        let len = forest.db().len();
+
+        // An identical trie shares every node.
+        fill(&mut forest, b"b", 32);
+        forest.commit().unwrap();
+        assert_eq!(forest.db().len(), len);
+
+        // Removing one of them keeps the nodes alive.
+        forest.remove_trie(b"a");
+        forest.commit().unwrap();
+        assert_eq!(forest.gc(), 0);
+        assert_eq!(forest.db().len(), len);
+
+        forest.remove_trie(b"b");
+        assert_eq!(forest.gc(), len);
+        assert!(forest.db().is_empty());
+    }
+
+    #[test]
+    fn gc_old_versions() {
+        let mut forest = Forest::new(MemoryDb::new());
+        fill(&mut forest, b"a", 32);
+        forest.commit().unwrap();
+
+        forest.insert(b"a", vec![0xFF], vec![0xFF; 32]);
+        forest.commit().unwrap();
+        assert!(forest.gc() > 0);
+
+        let root = *forest.root(b"a").unwrap();
+        let report = crate::check_completeness::<Keccak256, _>(forest.db(), &root);
+        assert!(report.is_complete());
+    }
+
+    #[test]
+    fn open() {
+        let mut forest = Forest::new(MemoryDb::new());
+        fill(&mut forest, b"a", 32);
+        forest.commit().unwrap();
+        let root = *forest.root(b"a").unwrap();
+
+        let mut reopened = Forest::new(forest.into_db());
+        reopened.open(b"a".to_vec(), &root).unwrap();
+        assert_eq!(reopened.get(b"a", &vec![3, 21]), Some(&vec![3; 24]));
+        assert_eq!(reopened.root(b"a"), Some(&root));
+
+        reopened.remove_trie(b"a");
+        assert!(reopened.gc() > 0);
+        assert!(reopened.db().is_empty());
+        assert!(reopened.open(b"a".to_vec(), &root).is_err());
+    }
+
+    const ACCOUNTS: &[u8] = b"accounts";
+
+    fn account_forest() -> Forest {
+        let mut forest = Forest::new(MemoryDb::new());
+        forest.set_account_trie(ACCOUNTS.to_vec());
+        forest.insert(
+            ACCOUNTS,
+            vec![0xAA; 32],
+            Account {
+                nonce: 1,
+                ..Default::default()
+            }
+            .encode(),
+        );
+
+        forest
+    }
+
+    fn storage_root(forest: &Forest, id: &[u8]) -> [u8; 32] {
+        let encoded = forest.get(ACCOUNTS, &id.to_vec()).unwrap();
+        Account::decode(encoded).unwrap().storage_root
+    }
+
+    #[test]
+    fn propagate_storage_root() {
+        let mut forest = account_forest();
+        fill(&mut forest, &[0xAA; 32], 8);
+        forest.commit().unwrap();
+
+        let expected = *forest.root(&[0xAA; 32]).unwrap();
+        assert_eq!(storage_root(&forest, &[0xAA; 32]), expected[..]);
+
+        // The state root includes the updated account.
+        let mut state = Tree::<Keccak256>::new();
+        state.insert(
+            vec![0xAA; 32],
+            Account {
+                nonce: 1,
+                storage_root: expected.into(),
+                ..Default::default()
+            }
+            .encode(),
+        );
+        assert_eq!(forest.root(ACCOUNTS), Some(state.compute_hash()));
+
+        forest.remove_trie(&[0xAA; 32]);
+        forest.commit().unwrap();
+        assert_eq!(storage_root(&forest, &[0xAA; 32]), EMPTY_STORAGE_ROOT);
+    }
+
+    #[test]
+    fn propagate_new_account() {
+        let mut forest = account_forest();
+        fill(&mut forest, &[0xBB; 32], 8);
+        forest.commit().unwrap();
+
+        let encoded = forest.get(ACCOUNTS, &vec![0xBB; 32]).unwrap();
+        let account = Account::decode(encoded).unwrap();
+        assert_eq!(account.nonce, 0);
+        assert_eq!(account.storage_root, forest.root(&[0xBB; 32]).unwrap()[..]);
+    }
+
+    #[test]
+    fn propagate_undecodable_account() {
+        let mut forest = account_forest();
+        forest.insert(ACCOUNTS, vec![0xCC; 32], vec![0x01]);
+        fill(&mut forest, &[0xCC; 32], 8);
+
+        assert_eq!(forest.commit(), Err(DecodeError::ExpectedList));
+        assert!(forest.db().is_empty());
+    }
+
+    #[test]
+    fn get_storage() {
+        let address = [0x11; 20];
+        let id = Keccak256::digest(address).to_vec();
+        let mut forest = account_forest();
+        assert_eq!(
+            forest.get_storage(&address, &[0; 32]),
+            Err(StorageError::NoAccount(address))
+        );
+
+        // Storage values are RLP strings of the trimmed words.
+        forest.insert(ACCOUNTS, id.clone(), Account::default().encode());
+        assert_eq!(forest.get_storage(&address, &[0; 32]), Ok(None));
+        let slot = |i: u8| Keccak256::digest([i; 32]).to_vec();
+        forest.insert(&id, slot(1), vec![0x2A]);
+        forest.insert(&id, slot(2), vec![0x82, 0x12, 0x34]);
+        forest.insert(&id, slot(3), vec![0xC0]);
+        forest.commit().unwrap();
+
+        // The storage trie is opened from the committed storage root.
+        let account_root = *forest.root(ACCOUNTS).unwrap();
+        let mut reopened = Forest::new(forest.into_db());
+        assert_eq!(
+            reopened.get_storage(&address, &[1; 32]),
+            Err(StorageError::NoAccountTrie)
+        );
+        reopened.set_account_trie(ACCOUNTS.to_vec());
+        reopened.open(ACCOUNTS.to_vec(), &account_root).unwrap();
+
+        let mut word = [0; 32];
+        word[31] = 0x2A;
+        assert_eq!(reopened.get_storage(&address, &[1; 32]), Ok(Some(word)));
+        assert!(reopened.trie(&id).is_some());
+        word[30..].copy_from_slice(&[0x12, 0x34]);
+        assert_eq!(reopened.get_storage(&address, &[2; 32]), Ok(Some(word)));
+        assert_eq!(
+            reopened.get_storage(&address, &[3; 32]),
+            Err(StorageError::Undecodable(DecodeError::ExpectedBytes))
+        );
+        assert_eq!(reopened.get_storage(&address, &[4; 32]), Ok(None));
+    }
+
+    #[test]
+    fn commit_atomic() {
+        let mut forest = account_forest();
+        fill(&mut forest, &[0xAA; 32], 8);
+        fill(&mut forest, b"other", 8);
+        let committed = forest.commit_atomic().unwrap();
+        assert_eq!(committed.len(), 3);
+
+        for id in &committed {
+            assert_eq!(
+                forest.db().trie_root(id),
+                forest.root(id).map(|x| x.to_vec())
+            );
+        }
+
+        let account_root = forest.db().trie_root(ACCOUNTS).unwrap();
+        let storage = storage_root(&forest, &[0xAA; 32]);
+        let mut reopened = Forest::new(forest.into_db());
+        reopened.open(ACCOUNTS.to_vec(), &account_root).unwrap();
+        reopened.open(vec![0xAA; 32], &storage).unwrap();
+        assert_eq!(reopened.get(&[0xAA; 32], &vec![3, 21]), Some(&vec![3; 24]));
+        assert_eq!(reopened.db().trie_root(&[0xAA; 32]), Some(storage.to_vec()));
+    }
+
+    /// A database whose batch writes fail until allowed.
+    struct FailingDb(MemoryDb, bool);
+
+    impl NodeDb for FailingDb {
+        fn get(&self, hash: &[u8]) -> Option<Vec<u8>> {
+            self.0.get(hash)
+        }
+
+        fn insert(&mut self, hash: Vec<u8>, encoded: Vec<u8>) {
+            self.0.insert(hash, encoded);
+        }
+
+        fn remove(&mut self, hash: &[u8]) {
+            NodeDb::remove(&mut self.0, hash);
+        }
+    }
+
+    impl AtomicDb for FailingDb {
+        fn write_batch(&mut self, batch: WriteBatch) -> io::Result<()> {
+            if self.1 {
+                return Err(io::ErrorKind::Other.into());
+            }
+
+            self.0.write_batch(batch)
+        }
+
+        fn trie_root(&self, id: &[u8]) -> Option<Vec<u8>> {
+            self.0.trie_root(id)
+        }
+    }
+
+    #[test]
+    fn commit_atomic_failure() {
+        let mut forest = TrieForest::<Keccak256, _>::new(FailingDb(MemoryDb::new(), true));
+        for i in 0..8u8 {
+            forest.insert(b"a", vec![i], vec![i; 24]);
+        }
+
+        assert!(matches!(
+            forest.commit_atomic(),
+            Err(AtomicCommitError::Write(_))
+        ));
+        assert_eq!(forest.root(b"a"), Some(&Keccak256::digest([0x80])));
+        assert!(forest.db().0.is_empty());
+
+        forest.db.1 = false;
+        assert_eq!(forest.commit_atomic().unwrap(), vec![b"a".to_vec()]);
+        assert_eq!(
+            forest.db().trie_root(b"a"),
+            forest.root(b"a").map(|x| x.to_vec())
+        );
+    }
+}
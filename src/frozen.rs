@@ -0,0 +1,376 @@
+use crate::{node::Node, proof::to_nibbles, Encode, NodeRef, PatriciaMerkleTree};
+use digest::{Digest, Output};
+use std::{collections::VecDeque, mem::size_of};
+
+const BRANCH: u8 = 0;
+const EXTENSION: u8 = 1;
+const LEAF: u8 = 2;
+/// Set on branches which hold a value.
+const HAS_VALUE: u8 = 0x04;
+
+/// Number of nodes between child index samples.
+const SAMPLE_RATE: usize = 64;
+
+/// Compact read-only form of a tree, for lookup-heavy workloads over tries which don't change.
+///
+/// Nodes are laid out in breadth-first order (LOUDS-style): every node takes a kind byte, a
+/// 16-bit occupancy bitmap and an offset into a packed buffer holding the paths and values. A
+/// node's children are found by counting the children of the nodes before it, so no child
+/// references are stored.
+#[derive(Clone, Debug)]
+pub struct FrozenTrie<H>
+where
+    H: Digest,
+{
+    root_hash: Output<H>,
+
+    kinds: Vec<u8>,
+    /// The occupied choices of each branch (zero for other nodes).
+    masks: Vec<u16>,
+    /// The offset of each node's payload within `data`.
+    offsets: Vec<u32>,
+    /// The index of the first child of every `SAMPLE_RATE`-th node's children.
+    samples: Vec<u32>,
+
+    /// Packed paths (as a nibble count followed by the nibbles, two per byte) and values (as a
+    /// length followed by the bytes). Lengths are LEB128-encoded.
+    data: Vec<u8>,
+}
+
+impl<H> FrozenTrie<H>
+where
+    H: Digest,
+{
+    /// Build the compact form of a tree.
+    pub fn from_tree<P, V>(tree: &mut PatriciaMerkleTree<P, V, H>) -> Self
+    where
+        P: Encode,
+        V: Encode,
+    {
+        let mut frozen = Self {
+            root_hash: tree.compute_hash().clone(),
+            kinds: Vec::new(),
+            masks: Vec::new(),
+            offsets: Vec::new(),
+            samples: Vec::new(),
+            data: Vec::new(),
+        };
+
+        let mut queue = VecDeque::new();
+        if tree.root_ref.is_valid() {
+            queue.push_back((tree.root_ref, 0));
+        }
+
+        let mut next_child = 1;
+        while let Some((node_ref, depth)) = queue.pop_front() {
+            if frozen.kinds.len() == SAMPLE_RATE * frozen.samples.len() {
+                frozen.samples.push(next_child as u32);
+            }
+            frozen.offsets.push(
+                frozen
+                    .data
+                    .len()
+                    .try_into()
+                    .expect("frozen tries are limited to 4 GiB"),
+            );
+
+            let (kind, mask) = match lookup(tree, node_ref) {
+                Node::Branch(branch_node) => {
+                    let mut mask = 0;
+                    for (choice, child_ref) in branch_node.choices.iter().enumerate() {
+                        if child_ref.is_valid() {
+                            mask |= 1 << choice;
+                            queue.push_back((*child_ref, depth + 1));
+                        }
+                    }
+
+                    match branch_node.value_ref.is_valid() {
+                        true => {
+                            let (_, value) = &tree.values[*branch_node.value_ref];
+                            write_bytes(&mut frozen.data, &value.encode());
+                            (BRANCH | HAS_VALUE, mask)
+                        }
+                        false => (BRANCH, mask),
+                    }
+                }
+                Node::Extension(extension_node) => {
+                    let prefix = extension_node
+                        .prefix
+                        .iter()
+                        .map(u8::from)
+                        .collect::<Vec<_>>();
+                    write_nibbles(&mut frozen.data, &prefix);
+                    queue.push_back((
+                        extension_node.child_ref,
+                        depth + extension_node.prefix.len(),
+                    ));
+                    (EXTENSION, 0)
+                }
+                Node::Leaf(leaf_node) => {
+                    let (path, value) = &tree.values[*leaf_node.value_ref];
+                    write_nibbles(&mut frozen.data, &to_nibbles(&path.encode())[depth..]);
+                    write_bytes(&mut frozen.data, &value.encode());
+                    (LEAF, 0)
+                }
+                // Stubbed subtrees have no values in memory, like an empty branch.
+                Node::Stub(_) => (BRANCH, 0),
+            };
+
+            next_child += degree(kind, mask);
+            frozen.kinds.push(kind);
+            frozen.masks.push(mask);
+        }
+
+        frozen
+    }
+
+    /// Return the root hash of the tree it was built from.
+    pub fn root_hash(&self) -> &Output<H> {
+        &self.root_hash
+    }
+
+    /// Return whether the trie is empty.
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    /// Retrieve a value given its encoded path.
+    pub fn get(&self, path: &[u8]) -> Option<&[u8]> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let path = to_nibbles(path);
+        let mut path = path.as_slice();
+        let mut index = 0;
+        loop {
+            let kind = self.kinds[index];
+            let mut data = &self.data[self.offsets[index] as usize..];
+
+            match kind & 0x03 {
+                BRANCH => match path.split_first() {
+                    Some((nibble, rest)) => {
+                        let mask = self.masks[index];
+                        if mask & (1 << nibble) == 0 {
+                            return None;
+                        }
+
+                        let rank = (mask & ((1 << nibble) - 1)).count_ones() as usize;
+                        index = self.first_child(index) + rank;
+                        path = rest;
+                    }
+                    None if kind & HAS_VALUE != 0 => return Some(read_bytes(&mut data)),
+                    None => return None,
+                },
+                EXTENSION => {
+                    path = path.strip_prefix(read_nibbles(&mut data).as_slice())?;
+                    index = self.first_child(index);
+                }
+                _ => {
+                    let leaf_path = read_nibbles(&mut data);
+                    return (path == leaf_path).then(|| read_bytes(&mut data));
+                }
+            }
+        }
+    }
+
+    /// Iterate over every `(encoded path, encoded value)` pair, in ascending path order.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, &[u8])> {
+        let mut stack = Vec::new();
+        if !self.is_empty() {
+            stack.push((0, Vec::new()));
+        }
+
+        std::iter::from_fn(move || {
+            while let Some((index, mut prefix)) = stack.pop() {
+                let kind = self.kinds[index];
+                let mut data = &self.data[self.offsets[index] as usize..];
+
+                match kind & 0x03 {
+                    BRANCH => {
+                        let mask = self.masks[index];
+                        let first_child = self.first_child(index);
+                        for choice in (0..16u8).rev().filter(|x| mask & (1 << x) != 0) {
+                            let rank = (mask & ((1 << choice) - 1)).count_ones() as usize;
+                            let mut child_prefix = prefix.clone();
+                            child_prefix.push(choice);
+                            stack.push((first_child + rank, child_prefix));
+                        }
+
+                        if kind & HAS_VALUE != 0 {
+                            return Some((from_nibbles(&prefix), read_bytes(&mut data)));
+                        }
+                    }
+                    EXTENSION => {
+                        prefix.extend(read_nibbles(&mut data));
+                        stack.push((self.first_child(index), prefix));
+                    }
+                    _ => {
+                        prefix.extend(read_nibbles(&mut data));
+                        return Some((from_nibbles(&prefix), read_bytes(&mut data)));
+                    }
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Rebuild the mutable form of the tree.
+    pub fn to_tree(&self) -> PatriciaMerkleTree<Vec<u8>, Vec<u8>, H> {
+        PatriciaMerkleTree::from_sorted_iter(
+            self.iter().map(|(path, value)| (path, value.to_vec())),
+        )
+    }
+
+    /// Calculate approximated memory usage (allocated).
+    pub fn memory_usage(&self) -> usize {
+        self.kinds.capacity()
+            + size_of::<u16>() * self.masks.capacity()
+            + size_of::<u32>() * (self.offsets.capacity() + self.samples.capacity())
+            + self.data.capacity()
+    }
+
+    /// Return the index of the node's first child.
+    fn first_child(&self, index: usize) -> usize {
+        let block_start = index - index % SAMPLE_RATE;
+        let preceding = (block_start..index)
+            .map(|x| degree(self.kinds[x], self.masks[x]))
+            .sum::<usize>();
+
+        self.samples[index / SAMPLE_RATE] as usize + preceding
+    }
+}
+
+/// Return the number of children of a node.
+fn degree(kind: u8, mask: u16) -> usize {
+    match kind & 0x03 {
+        BRANCH => mask.count_ones() as usize,
+        EXTENSION => 1,
+        _ => 0,
+    }
+}
+
+fn lookup<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>, node_ref: NodeRef) -> &Node<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    tree.nodes
+        .get(*node_ref)
+        .expect("inconsistent internal tree structure")
+}
+
+fn write_len(data: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        data.push(0x80 | (len & 0x7F) as u8);
+        len >>= 7;
+    }
+    data.push(len as u8);
+}
+
+fn read_len(data: &mut &[u8]) -> usize {
+    let mut len = 0;
+    for (index, byte) in data.iter().enumerate() {
+        len |= ((byte & 0x7F) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            *data = &data[index + 1..];
+            return len;
+        }
+    }
+
+    unreachable!("lengths are always terminated")
+}
+
+fn write_bytes(data: &mut Vec<u8>, value: &[u8]) {
+    write_len(data, value.len());
+    data.extend_from_slice(value);
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8]) -> &'a [u8] {
+    let len = read_len(data);
+    let (value, rest) = data.split_at(len);
+    *data = rest;
+    value
+}
+
+fn write_nibbles(data: &mut Vec<u8>, nibbles: &[u8]) {
+    write_len(data, nibbles.len());
+    data.extend(
+        nibbles
+            .chunks(2)
+            .map(|x| (x[0] << 4) | x.get(1).unwrap_or(&0)),
+    );
+}
+
+fn read_nibbles(data: &mut &[u8]) -> Vec<u8> {
+    let len = read_len(data);
+    let (packed, rest) = data.split_at(len.div_ceil(2));
+    *data = rest;
+
+    packed
+        .iter()
+        .flat_map(|x| [x >> 4, x & 0x0F])
+        .take(len)
+        .collect()
+}
+
+fn from_nibbles(nibbles: &[u8]) -> Vec<u8> {
+    nibbles.chunks(2).map(|x| (x[0] << 4) | x[1]).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+    use sha3::Keccak256;
+
+    #[test]
+    fn empty() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let frozen = FrozenTrie::from_tree(&mut tree);
+
+        assert!(frozen.is_empty());
+        assert_eq!(frozen.get(&[]), None);
+        assert_eq!(frozen.to_tree().compute_hash(), tree.compute_hash());
+    }
+
+    #[test]
+    fn memory_usage() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..4096u32 {
+            let path = Keccak256::digest(i.to_be_bytes()).to_vec();
+            tree.insert(path, vec![0xAA; 32]);
+        }
+
+        let frozen = FrozenTrie::from_tree(&mut tree);
+        assert!(2 * frozen.memory_usage() < tree.memory_usage().1);
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_get(
+            data in btree_map(vec(any::<u8>(), 0..4), vec(any::<u8>(), 1..40), 1..256),
+            missing in vec(vec(any::<u8>(), 0..4), 0..32),
+        ) {
+            let mut tree = pmt_tree!(Vec<u8>);
+            for (path, value) in &data {
+                tree.insert(path.clone(), value.clone());
+            }
+
+            let frozen = FrozenTrie::from_tree(&mut tree);
+            for path in data.keys().chain(&missing) {
+                prop_assert_eq!(frozen.get(path), tree.get(path).map(Vec::as_slice));
+            }
+
+            prop_assert!(frozen.iter().eq(data.iter().map(|(x, y)| (x.clone(), y.as_slice()))));
+            let mut thawed = frozen.to_tree();
+            prop_assert_eq!(thawed.compute_hash(), frozen.root_hash());
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{
+    db::{to_nibble_hex, HashMismatch, MissingNode, NodeDb, UndecodableNode},
+    hex,
+    rlp::{ChildRef, RawNode},
+};
+use digest::Digest;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    marker::PhantomData,
+};
+
+/// A root to check, along with the number of values its trie is recorded to have (if known).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FsckRoot {
+    pub hash: Vec<u8>,
+    pub expected_len: Option<usize>,
+}
+
+impl FsckRoot {
+    pub fn new(hash: Vec<u8>) -> Self {
+        Self {
+            hash,
+            expected_len: None,
+        }
+    }
+
+    /// Also check the trie has `len` values.
+    pub fn with_len(mut self, len: usize) -> Self {
+        self.expected_len = Some(len);
+        self
+    }
+}
+
+/// A trie whose number of values doesn't match the recorded one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LenMismatch {
+    pub root: Vec<u8>,
+    pub expected: usize,
+    pub actual: usize,
+}
+
+/// Result of checking the tries stored in a node database with [`check`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FsckReport {
+    /// Number of distinct hashed nodes checked.
+    pub visited: usize,
+    /// Number of values of each root (in the order given) whose trie could be fully read.
+    pub lens: Vec<Option<usize>>,
+    pub missing: Vec<MissingNode>,
+    pub undecodable: Vec<UndecodableNode>,
+    pub hash_mismatches: Vec<HashMismatch>,
+    pub len_mismatches: Vec<LenMismatch>,
+}
+
+impl FsckReport {
+    /// Return whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.undecodable.is_empty()
+            && self.hash_mismatches.is_empty()
+            && self.len_mismatches.is_empty()
+    }
+}
+
+impl Display for FsckReport {
+    /// Write the report as one whitespace-separated record per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "visited {}", self.visited)?;
+        for entry in &self.missing {
+            writeln!(
+                f,
+                "missing {} {}",
+                hex::encode(&entry.hash),
+                to_nibble_hex(&entry.path_prefix)
+            )?;
+        }
+        for entry in &self.undecodable {
+            writeln!(
+                f,
+                "undecodable {} {} {}",
+                hex::encode(&entry.hash),
+                to_nibble_hex(&entry.path_prefix),
+                entry.error,
+            )?;
+        }
+        for entry in &self.hash_mismatches {
+            writeln!(
+                f,
+                "hash-mismatch {} {} {}",
+                hex::encode(&entry.hash),
+                to_nibble_hex(&entry.path_prefix),
+                hex::encode(&entry.actual),
+            )?;
+        }
+        for entry in &self.len_mismatches {
+            writeln!(
+                f,
+                "len-mismatch {} {} {}",
+                hex::encode(&entry.root),
+                entry.expected,
+                entry.actual,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the tries stored in the database under `roots`: every node reachable from them must be
+/// present, decode and hash to the key it's stored under, and tries with a recorded number of
+/// values must have that many.
+///
+/// Every problem found is collected into the report instead of stopping at the first one. Nodes
+/// shared between tries are only checked once.
+pub fn check<H, D>(store: &D, roots: &[FsckRoot]) -> FsckReport
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    let mut checker = Checker::<H, D> {
+        store,
+        lens: HashMap::new(),
+        report: FsckReport::default(),
+        _hasher: PhantomData,
+    };
+
+    for root in roots {
+        let len = if root.hash == H::digest([0x80])[..] {
+            Some(0)
+        } else {
+            checker.visit(ChildRef::Hash(root.hash.clone()), &mut Vec::new())
+        };
+
+        if let (Some(expected), Some(actual)) = (root.expected_len, len) {
+            if expected != actual {
+                checker.report.len_mismatches.push(LenMismatch {
+                    root: root.hash.clone(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+        checker.report.lens.push(len);
+    }
+
+    checker.report
+}
+
+struct Checker<'a, H, D>
+where
+    D: ?Sized,
+{
+    store: &'a D,
+    /// Number of values below each hashed node checked so far, if its subtree is sound.
+    lens: HashMap<Vec<u8>, Option<usize>>,
+    report: FsckReport,
+    _hasher: PhantomData<H>,
+}
+
+impl<H, D> Checker<'_, H, D>
+where
+    H: Digest,
+    D: NodeDb + ?Sized,
+{
+    /// Check a node and its subtree, returning its number of values if no problems were found.
+    fn visit(&mut self, child_ref: ChildRef, path_prefix: &mut Vec<u8>) -> Option<usize> {
+        let (hash, encoded) = match child_ref {
+            ChildRef::Hash(hash) => {
+                if let Some(len) = self.lens.get(&hash) {
+                    return *len;
+                }
+
+                let len = self.visit_hashed(&hash, path_prefix);
+                self.lens.insert(hash, len);
+                return len;
+            }
+            ChildRef::Inline(encoded) => (encoded.clone(), encoded),
+        };
+
+        self.visit_encoded(hash, &encoded, path_prefix)
+    }
+
+    fn visit_hashed(&mut self, hash: &[u8], path_prefix: &mut Vec<u8>) -> Option<usize> {
+        self.report.visited += 1;
+        let encoded = match self.store.get(hash) {
+            Some(encoded) => encoded,
+            None => {
+                self.report.missing.push(MissingNode {
+                    hash: hash.to_vec(),
+                    path_prefix: path_prefix.clone(),
+                });
+                return None;
+            }
+        };
+
+        let actual = H::digest(&encoded);
+        if actual[..] != *hash {
+            self.report.hash_mismatches.push(HashMismatch {
+                hash: hash.to_vec(),
+                path_prefix: path_prefix.clone(),
+                actual: actual.to_vec(),
+            });
+            return None;
+        }
+
+        self.visit_encoded(hash.to_vec(), &encoded, path_prefix)
+    }
+
+    fn visit_encoded(
+        &mut self,
+        hash: Vec<u8>,
+        encoded: &[u8],
+        path_prefix: &mut Vec<u8>,
+    ) -> Option<usize> {
+        let node = match RawNode::decode(encoded) {
+            Ok(node) => node,
+            Err(error) => {
+                self.report.undecodable.push(UndecodableNode {
+                    hash,
+                    path_prefix: path_prefix.clone(),
+                    error,
+                });
+                return None;
+            }
+        };
+
+        let mut len = match &node {
+            RawNode::Branch { value, .. } => Some(value.is_some() as usize),
+            RawNode::Extension { .. } => Some(0),
+            RawNode::Leaf { .. } => Some(1),
+        };
+        for (nibbles, child_ref) in node.children() {
+            path_prefix.extend_from_slice(nibbles);
+            let child_len = self.visit(child_ref.clone(), path_prefix);
+            path_prefix.truncate(path_prefix.len() - nibbles.len());
+
+            len = len.zip(child_len).map(|(a, b)| a + b);
+        }
+
+        len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{db::commit, pmt_tree, MemoryDb};
+    use sha3::Keccak256;
+
+    #[test]
+    fn fsck() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+        let mut db = MemoryDb::new();
+        let root1 = commit(&mut tree, &mut db).to_vec();
+        tree.insert(vec![0xFF], vec![0xFF; 24]);
+        let root2 = commit(&mut tree, &mut db).to_vec();
+
+        let roots = [
+            FsckRoot::new(root1.clone()).with_len(64),
+            FsckRoot::new(root2.clone()).with_len(65),
+            FsckRoot::new(Keccak256::digest([0x80]).to_vec()),
+        ];
+        let report = check::<Keccak256, _>(&db, &roots);
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.lens, [Some(64), Some(65), Some(0)]);
+        assert_eq!(report.visited, db.len());
+
+        let report = check::<Keccak256, _>(&db, &[FsckRoot::new(root1.clone()).with_len(63)]);
+        assert_eq!(
+            report.len_mismatches,
+            [LenMismatch {
+                root: root1.clone(),
+                expected: 63,
+                actual: 64,
+            }]
+        );
+
+        let mut hashes = db
+            .iter()
+            .map(|(hash, _)| hash.clone())
+            .filter(|hash| *hash != root1 && *hash != root2)
+            .collect::<Vec<_>>();
+        hashes.sort();
+        let mut corrupted = db.clone();
+        let swapped = db.get(&hashes[1]).unwrap();
+        corrupted.insert(hashes[0].clone(), swapped);
+        let report = check::<Keccak256, _>(&corrupted, &roots);
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.hash_mismatches,
+            [HashMismatch {
+                hash: hashes[0].clone(),
+                path_prefix: report.hash_mismatches[0].path_prefix.clone(),
+                actual: hashes[1].clone(),
+            }]
+        );
+        assert_eq!(report.lens, [None, None, Some(0)]);
+        assert!(report.len_mismatches.is_empty());
+        assert!(report
+            .to_string()
+            .contains(&format!("hash-mismatch {}", hex::encode(&hashes[0]))));
+
+        db.remove(&hashes[0]);
+        let report = check::<Keccak256, _>(&db, &roots);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].hash, hashes[0]);
+    }
+}
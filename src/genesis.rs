@@ -0,0 +1,249 @@
+//! Genesis state builder for chain-spec style genesis files (as accepted by `geth init`).
+
+use crate::{
+    account::{trim_zeros, Account},
+    hashing::{NodeEncoder, NodeWriter},
+    hex, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use serde::Deserialize;
+use std::{collections::BTreeMap, io::Read};
+use thiserror::Error;
+
+type Tree<H> = PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>;
+
+/// Errors found while building a genesis state.
+#[derive(Debug, Error)]
+pub enum GenesisError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid {field} in account {account}")]
+    InvalidField {
+        account: String,
+        field: &'static str,
+    },
+}
+
+/// The state and storage trees of a genesis allocation.
+pub struct GenesisState<H>
+where
+    H: Digest,
+{
+    /// The genesis state root.
+    pub root: Output<H>,
+    /// The state tree, indexed by hashed address.
+    pub state: Tree<H>,
+    /// The storage trees of every account with storage, indexed by hashed address.
+    pub storage: BTreeMap<Vec<u8>, Tree<H>>,
+}
+
+/// Either a whole genesis file or just its allocation.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GenesisJson {
+    Spec {
+        alloc: BTreeMap<String, AllocAccount>,
+    },
+    Alloc(BTreeMap<String, AllocAccount>),
+}
+
+#[derive(Deserialize)]
+struct AllocAccount {
+    balance: String,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    storage: BTreeMap<String, String>,
+}
+
+/// Build the genesis state from a genesis file, or from its `alloc` object alone (a map from
+/// addresses to their balance, and optionally their nonce, code and storage).
+///
+/// Quantities may be written in decimal or in `0x`-prefixed hex. Storage slots with a zero value
+/// are skipped, as they would be when executing.
+pub fn build_genesis_state<H>(reader: impl Read) -> Result<GenesisState<H>, GenesisError>
+where
+    H: Digest,
+{
+    let alloc = match serde_json::from_reader(reader)? {
+        GenesisJson::Spec { alloc } | GenesisJson::Alloc(alloc) => alloc,
+    };
+
+    let mut state = Tree::<H>::new();
+    let mut storage = BTreeMap::new();
+    for (address, alloc_account) in alloc {
+        let invalid = |field| GenesisError::InvalidField {
+            account: address.clone(),
+            field,
+        };
+
+        let hashed_address = match hex::decode(&address) {
+            Some(x) if x.len() == 20 => H::digest(x).to_vec(),
+            _ => return Err(invalid("address")),
+        };
+
+        let mut storage_tree = Tree::<H>::new();
+        for (slot, value) in &alloc_account.storage {
+            let slot =
+                hex::decode_u256(&hex_prefixed(slot)).ok_or_else(|| invalid("storage slot"))?;
+            let value =
+                hex::decode_u256(&hex_prefixed(value)).ok_or_else(|| invalid("storage value"))?;
+            if value == [0; 32] {
+                continue;
+            }
+
+            let mut encoder = NodeEncoder::default();
+            encoder.write_bytes(trim_zeros(&value));
+            storage_tree.insert(H::digest(slot).to_vec(), encoder.finalize());
+        }
+
+        let nonce = match &alloc_account.nonce {
+            Some(nonce) => {
+                let nonce = hex::decode_u256(nonce).ok_or_else(|| invalid("nonce"))?;
+                let (high, low) = nonce.split_at(24);
+                if high.iter().any(|x| *x != 0) {
+                    return Err(invalid("nonce"));
+                }
+                u64::from_be_bytes(low.try_into().unwrap())
+            }
+            None => 0,
+        };
+        let code = match &alloc_account.code {
+            Some(code) => hex::decode(code).ok_or_else(|| invalid("code"))?,
+            None => Vec::new(),
+        };
+
+        let account = Account {
+            nonce,
+            balance: hex::decode_u256(&alloc_account.balance).ok_or_else(|| invalid("balance"))?,
+            storage_root: storage_tree.compute_hash()[..]
+                .try_into()
+                .expect("storage roots must be 32 bytes long"),
+            code_hash: H::digest(code)[..]
+                .try_into()
+                .expect("code hashes must be 32 bytes long"),
+        };
+
+        if !storage_tree.is_empty() {
+            storage.insert(hashed_address.clone(), storage_tree);
+        }
+        state.insert(hashed_address, account.encode());
+    }
+
+    Ok(GenesisState {
+        root: state.compute_hash().clone(),
+        state,
+        storage,
+    })
+}
+
+/// Compute the state root of a genesis file (see [`build_genesis_state`]).
+pub fn genesis_state_root<H>(reader: impl Read) -> Result<Output<H>, GenesisError>
+where
+    H: Digest,
+{
+    build_genesis_state::<H>(reader).map(|x| x.root)
+}
+
+/// Storage slots and values are always hex, even without the `0x` prefix.
+fn hex_prefixed(value: &str) -> String {
+    match value.starts_with("0x") {
+        true => value.to_string(),
+        false => format!("0x{value}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::{EMPTY_CODE_HASH, EMPTY_STORAGE_ROOT};
+    use sha3::Keccak256;
+
+    const ADDRESS: &str = "0x000000000000000000000000000000000000000a";
+
+    #[test]
+    fn build() {
+        let genesis = serde_json::json!({
+            "config": { "chainId": 1337 },
+            "alloc": {
+                ADDRESS: {
+                    "balance": "1000",
+                    "nonce": "0x1",
+                    "code": "0x6000",
+                    "storage": {
+                        "0x00": "0x1234",
+                        "01": "0x00",
+                    },
+                },
+                "000000000000000000000000000000000000000b": { "balance": "0x03e8" },
+            },
+        });
+        let built = build_genesis_state::<Keccak256>(genesis.to_string().as_bytes()).unwrap();
+        assert_eq!(built.state.len(), 2);
+        assert_eq!(built.storage.len(), 1);
+
+        let mut storage_tree = Tree::<Keccak256>::new();
+        storage_tree.insert(
+            Keccak256::digest([0u8; 32]).to_vec(),
+            vec![0x82, 0x12, 0x34],
+        );
+
+        let mut balance = [0; 32];
+        balance[30..].copy_from_slice(&[0x03, 0xE8]);
+        let hashed_address = Keccak256::digest(hex::decode(ADDRESS).unwrap()).to_vec();
+        let account = Account::decode(built.state.get(&hashed_address).unwrap()).unwrap();
+        assert_eq!(
+            account,
+            Account {
+                nonce: 1,
+                balance,
+                storage_root: storage_tree.compute_hash()[..].try_into().unwrap(),
+                code_hash: Keccak256::digest([0x60, 0x00]).into(),
+            }
+        );
+
+        let other = Keccak256::digest(hex::decode(&ADDRESS.replace('a', "b")).unwrap()).to_vec();
+        let account = Account::decode(built.state.get(&other).unwrap()).unwrap();
+        assert_eq!(
+            (account.storage_root, account.code_hash),
+            (EMPTY_STORAGE_ROOT, EMPTY_CODE_HASH)
+        );
+
+        // The allocation alone gives the same root.
+        let alloc = genesis["alloc"].to_string();
+        let root = genesis_state_root::<Keccak256>(alloc.as_bytes()).unwrap();
+        assert_eq!(root, built.root);
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for (account, field) in [
+            (serde_json::json!({ "balance": "0x1g" }), "balance"),
+            (
+                serde_json::json!({ "balance": "0", "nonce": "0x010000000000000000" }),
+                "nonce",
+            ),
+            (
+                serde_json::json!({ "balance": "0", "code": "0x6g" }),
+                "code",
+            ),
+        ] {
+            let genesis = serde_json::json!({ ADDRESS: account }).to_string();
+            assert!(matches!(
+                build_genesis_state::<Keccak256>(genesis.as_bytes()),
+                Err(GenesisError::InvalidField { field: x, .. }) if x == field,
+            ));
+        }
+
+        let genesis = serde_json::json!({ "0x0a": { "balance": "0" } }).to_string();
+        assert!(matches!(
+            build_genesis_state::<Keccak256>(genesis.as_bytes()),
+            Err(GenesisError::InvalidField {
+                field: "address",
+                ..
+            }),
+        ));
+    }
+}
@@ -0,0 +1,293 @@
+//! Importer for geth's JSON state dumps (`geth dump` and `debug_dumpBlock`).
+
+use crate::{
+    account::{trim_zeros, Account},
+    hashing::{NodeEncoder, NodeWriter},
+    hex, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use serde::Deserialize;
+use std::{collections::BTreeMap, io::Read};
+use thiserror::Error;
+
+type Tree<H> = PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>;
+
+/// Errors found while importing a state dump.
+#[derive(Debug, Error)]
+pub enum GethDumpError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid {field} in account {account}")]
+    InvalidField {
+        account: String,
+        field: &'static str,
+    },
+    #[error("invalid state root")]
+    InvalidRoot,
+    #[error("storage root mismatch in account {account}")]
+    StorageRootMismatch {
+        account: String,
+        expected: Vec<u8>,
+        computed: Vec<u8>,
+    },
+    #[error("state root mismatch")]
+    StateRootMismatch {
+        expected: Vec<u8>,
+        computed: Vec<u8>,
+    },
+}
+
+/// The state and storage trees rebuilt from a dump.
+pub struct StateDump<H>
+where
+    H: Digest,
+{
+    /// The state root, already verified against the dump's.
+    pub root: Output<H>,
+    /// The state tree, indexed by hashed address.
+    pub state: Tree<H>,
+    /// The storage trees of every account with storage, indexed by hashed address.
+    pub storage: BTreeMap<Vec<u8>, Tree<H>>,
+}
+
+#[derive(Deserialize)]
+struct DumpJson {
+    root: String,
+    accounts: BTreeMap<String, DumpAccount>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DumpAccount {
+    balance: String,
+    nonce: u64,
+    root: String,
+    code_hash: String,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    storage: BTreeMap<String, String>,
+    /// The hashed address, present when the address' preimage is unknown.
+    #[serde(default)]
+    key: Option<String>,
+}
+
+/// Build the state and storage trees from a geth JSON state dump, verifying every storage root
+/// and the state root against the ones stated in the dump.
+///
+/// Storage slots must be keyed by their preimages (as geth does when preimages are available).
+pub fn import_geth_dump<H>(reader: impl Read) -> Result<StateDump<H>, GethDumpError>
+where
+    H: Digest,
+{
+    let dump: DumpJson = serde_json::from_reader(reader)?;
+
+    let mut state = Tree::<H>::new();
+    let mut storage = BTreeMap::new();
+    for (name, dump_account) in dump.accounts {
+        let invalid = |field| GethDumpError::InvalidField {
+            account: name.clone(),
+            field,
+        };
+
+        let hashed_address = match &dump_account.key {
+            Some(key) => hex::decode(key).ok_or_else(|| invalid("key"))?,
+            None => {
+                let address = name
+                    .strip_prefix("pre(")
+                    .and_then(|x| x.strip_suffix(')'))
+                    .unwrap_or(&name);
+                H::digest(hex::decode(address).ok_or_else(|| invalid("address"))?).to_vec()
+            }
+        };
+
+        let mut storage_tree = Tree::<H>::new();
+        for (slot, value) in &dump_account.storage {
+            let slot = hex::decode(slot).ok_or_else(|| invalid("storage slot"))?;
+            let value = hex::decode(value).ok_or_else(|| invalid("storage value"))?;
+
+            let mut encoder = NodeEncoder::default();
+            encoder.write_bytes(trim_zeros(&value));
+            storage_tree.insert(H::digest(slot).to_vec(), encoder.finalize());
+        }
+
+        let account = Account {
+            nonce: dump_account.nonce,
+            balance: hex::decode_u256(&dump_account.balance).ok_or_else(|| invalid("balance"))?,
+            storage_root: hex::decode(&dump_account.root)
+                .and_then(|x| x.try_into().ok())
+                .ok_or_else(|| invalid("root"))?,
+            code_hash: hex::decode(&dump_account.code_hash)
+                .and_then(|x| x.try_into().ok())
+                .ok_or_else(|| invalid("codeHash"))?,
+        };
+
+        if let Some(code) = &dump_account.code {
+            let code = hex::decode(code).ok_or_else(|| invalid("code"))?;
+            if H::digest(code)[..] != account.code_hash {
+                return Err(invalid("code"));
+            }
+        }
+
+        let storage_root = storage_tree.compute_hash();
+        if storage_root[..] != account.storage_root {
+            return Err(GethDumpError::StorageRootMismatch {
+                account: name,
+                expected: account.storage_root.to_vec(),
+                computed: storage_root.to_vec(),
+            });
+        }
+
+        if !storage_tree.is_empty() {
+            storage.insert(hashed_address.clone(), storage_tree);
+        }
+        state.insert(hashed_address, account.encode());
+    }
+
+    let expected = hex::decode(&dump.root).ok_or(GethDumpError::InvalidRoot)?;
+    let root = state.compute_hash().clone();
+    if root[..] != expected[..] {
+        return Err(GethDumpError::StateRootMismatch {
+            expected,
+            computed: root.to_vec(),
+        });
+    }
+
+    Ok(StateDump {
+        root,
+        state,
+        storage,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sha3::Keccak256;
+
+    const ADDRESS_A: &str = "0x000000000000000000000000000000000000000a";
+    const ADDRESS_B: &str = "0x000000000000000000000000000000000000000b";
+
+    /// Build a dump with two accounts (one of them with storage) and compute its roots using
+    /// the tree directly.
+    fn build_dump() -> serde_json::Value {
+        let mut storage_tree = Tree::<Keccak256>::new();
+        storage_tree.insert(
+            Keccak256::digest([0u8; 32]).to_vec(),
+            vec![0x82, 0x12, 0x34],
+        );
+        let storage_root = storage_tree.compute_hash().to_vec();
+
+        let mut balance = [0u8; 32];
+        balance[24..].copy_from_slice(&1_000_000_000_000_000_000u64.to_be_bytes());
+
+        let account_a = Account {
+            nonce: 1,
+            balance,
+            ..Default::default()
+        };
+        let account_b = Account {
+            storage_root: storage_root.clone().try_into().unwrap(),
+            code_hash: Keccak256::digest([0x60, 0x00]).into(),
+            ..Default::default()
+        };
+
+        let mut state = Tree::<Keccak256>::new();
+        for (address, account) in [(ADDRESS_A, &account_a), (ADDRESS_B, &account_b)] {
+            state.insert(
+                Keccak256::digest(hex::decode(address).unwrap()).to_vec(),
+                account.encode(),
+            );
+        }
+
+        serde_json::json!({
+            "root": to_hex(state.compute_hash()),
+            "accounts": {
+                ADDRESS_A: {
+                    "balance": "1000000000000000000",
+                    "nonce": 1,
+                    "root": to_hex(&crate::account::EMPTY_STORAGE_ROOT),
+                    "codeHash": to_hex(&crate::account::EMPTY_CODE_HASH),
+                },
+                ADDRESS_B: {
+                    "balance": "0",
+                    "nonce": 0,
+                    "root": to_hex(&storage_root),
+                    "codeHash": to_hex(&account_b.code_hash),
+                    "code": "0x6000",
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000000": "1234",
+                    },
+                },
+            },
+        })
+    }
+
+    fn to_hex(data: &[u8]) -> String {
+        data.iter()
+            .fold("0x".to_string(), |acc, x| format!("{acc}{x:02x}"))
+    }
+
+    #[test]
+    fn import() {
+        let dump = build_dump().to_string();
+        let imported = import_geth_dump::<Keccak256>(dump.as_bytes()).unwrap();
+
+        assert_eq!(imported.state.len(), 2);
+        assert_eq!(imported.storage.len(), 1);
+    }
+
+    #[test]
+    fn storage_root_mismatch() {
+        let mut dump = build_dump();
+        dump["accounts"][ADDRESS_B]["storage"]["0x00"] = "01".into();
+
+        assert!(matches!(
+            import_geth_dump::<Keccak256>(dump.to_string().as_bytes()),
+            Err(GethDumpError::StorageRootMismatch { account, .. }) if account == ADDRESS_B,
+        ));
+    }
+
+    #[test]
+    fn state_root_mismatch() {
+        let mut dump = build_dump();
+        dump["accounts"][ADDRESS_A]["nonce"] = 2.into();
+
+        assert!(matches!(
+            import_geth_dump::<Keccak256>(dump.to_string().as_bytes()),
+            Err(GethDumpError::StateRootMismatch { .. }),
+        ));
+    }
+
+    #[test]
+    fn invalid_code() {
+        let mut dump = build_dump();
+        dump["accounts"][ADDRESS_B]["code"] = "0x6001".into();
+
+        assert!(matches!(
+            import_geth_dump::<Keccak256>(dump.to_string().as_bytes()),
+            Err(GethDumpError::InvalidField { field: "code", .. }),
+        ));
+    }
+
+    #[test]
+    fn balances() {
+        // Geth writes balances in decimal (older versions used hexadecimal).
+        assert_eq!(hex::decode_u256("0"), Some([0; 32]));
+        assert_eq!(hex::decode_u256("256").unwrap()[30..], [0x01, 0x00]);
+        assert_eq!(hex::decode_u256("0x0100").unwrap()[30..], [0x01, 0x00]);
+        assert_eq!(
+            hex::decode_u256(
+                "115792089237316195423570985008687907853269984665640564039457584007913129639935"
+            ),
+            Some([0xFF; 32]),
+        );
+        assert_eq!(
+            hex::decode_u256(
+                "115792089237316195423570985008687907853269984665640564039457584007913129639936"
+            ),
+            None,
+        );
+        assert_eq!(hex::decode_u256("12a"), None);
+    }
+}
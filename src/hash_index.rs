@@ -0,0 +1,153 @@
+use crate::{hashing::NodeHashRef, node::Node, storage::NodeRef, Encode, PatriciaMerkleTree};
+use digest::Digest;
+use std::collections::HashMap;
+
+/// Where the tree's nodes referenced by hash are, when enabled.
+///
+/// The index is collected lazily on the first lookup after the tree changes, so that batches of
+/// mutations are followed by a single walk of the tree instead of updating it after each one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HashIndex {
+    /// Nodes along with their path offset, by hash.
+    entries: Option<HashMap<Vec<u8>, (NodeRef, usize)>>,
+    /// The tree's generation when the entries were collected, if they're still valid.
+    generation: Option<u64>,
+}
+
+impl HashIndex {
+    pub fn is_enabled(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    pub fn configure(&mut self, enabled: bool) {
+        self.entries = enabled.then(HashMap::new);
+        self.generation = None;
+    }
+
+    /// Return the number of indexed nodes, as of the last lookup.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.as_ref().map_or(0, HashMap::len)
+    }
+
+    /// Forget every entry, after the nodes are moved.
+    pub fn invalidate(&mut self) {
+        self.generation = None;
+    }
+}
+
+/// Find the node with the given hash (and its path offset) using the tree's index, collecting it
+/// first if out of date. Returns `None` if the index is disabled.
+pub(crate) fn lookup<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    hash: &[u8],
+) -> Option<Option<(NodeRef, usize)>>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    if !tree.hash_index.is_enabled() {
+        return None;
+    }
+
+    if tree.hash_index.generation != Some(tree.generation) {
+        collect(tree);
+    }
+
+    let entries = tree.hash_index.entries.as_ref()?;
+    Some(entries.get(hash).copied())
+}
+
+fn collect<P, V, H>(tree: &mut PatriciaMerkleTree<P, V, H>)
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut entries = HashMap::new();
+    if tree.root_ref.is_valid() {
+        let root_hash = tree.compute_hash().to_vec();
+        entries.insert(root_hash, (tree.root_ref, 0));
+
+        let mut stack = vec![(tree.root_ref, 0)];
+        while let Some((node_ref, path_offset)) = stack.pop() {
+            let node = tree
+                .nodes
+                .get(*node_ref)
+                .expect("inconsistent internal tree structure");
+
+            // Hashes are cached after computing the root hash, so this doesn't rehash anything.
+            if node_ref != tree.root_ref {
+                if let NodeHashRef::Hashed(hash) =
+                    node.compute_hash(&tree.nodes, &tree.values, path_offset)
+                {
+                    entries.insert(hash.to_vec(), (node_ref, path_offset));
+                }
+            }
+
+            match node {
+                Node::Branch(branch_node) => stack.extend(
+                    branch_node
+                        .choices
+                        .iter()
+                        .filter(|x| x.is_valid())
+                        .map(|x| (*x, path_offset + 1)),
+                ),
+                Node::Extension(extension_node) => stack.push((
+                    extension_node.child_ref,
+                    path_offset + extension_node.prefix.len(),
+                )),
+                Node::Leaf(_) | Node::Stub(_) => {}
+            }
+        }
+    }
+
+    tree.hash_index.entries = Some(entries);
+    tree.hash_index.generation = Some(tree.generation);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{pmt_tree, MemoryDb};
+
+    #[test]
+    fn hash_index() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+        let mut expected = tree.clone();
+        tree.enable_hash_index();
+
+        let mut db = MemoryDb::new();
+        expected.commit(&mut db);
+        for (hash, encoded) in db.iter() {
+            assert!(tree.contains_node_hash(hash));
+            assert_eq!(tree.get_node_by_hash(hash).as_ref(), Some(encoded));
+        }
+        assert_eq!(tree.hash_index.len(), db.len());
+        assert!(!tree.contains_node_hash(&[0xAA; 32]));
+
+        // Mutations are picked up on the next lookup.
+        let old_root = *tree.compute_hash();
+        for i in 0..32u8 {
+            tree.remove(vec![i, i.wrapping_mul(7)]);
+        }
+        assert!(!tree.contains_node_hash(&old_root));
+        let root = *tree.compute_hash();
+        assert!(tree.contains_node_hash(&root));
+
+        // Compaction moves the nodes into the vacant slots.
+        tree.compact();
+        let mut db = MemoryDb::new();
+        tree.clone().commit(&mut db);
+        for (hash, encoded) in db.iter() {
+            assert_eq!(tree.get_node_by_hash(hash).as_ref(), Some(encoded));
+        }
+
+        tree.disable_hash_index();
+        assert!(tree.contains_node_hash(&root));
+        assert_eq!(tree.hash_index.len(), 0);
+    }
+}
@@ -1,10 +1,9 @@
-use crate::nibble::{NibbleSlice, NibbleVec};
-use digest::{Digest, Output};
-use std::{
-    cell::{Cell, Ref, RefCell},
-    cmp::min,
-    mem::size_of,
+use crate::{
+    nibble::{Nibble, NibbleSlice, NibbleVec},
+    Encode,
 };
+use digest::{Digest, Output, OutputSizeUser};
+use std::{cmp::min, mem::size_of, sync::OnceLock};
 
 #[derive(Debug)]
 pub struct DelimitedHash<H>(pub Output<H>, pub usize)
@@ -39,48 +38,46 @@ where
     }
 }
 
+/// A node's cached hash (or encoding, if it's shorter than a hash).
+///
+/// Empty while the node is dirty. Filling it only needs a shared reference, and is safe to do
+/// from multiple threads since every thread would compute the same value.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct NodeHash<H>
 where
-    H: Digest,
+    H: OutputSizeUser,
 {
-    length: Cell<usize>,
-    hash_ref: RefCell<Output<H>>,
+    hash: OnceLock<(Output<H>, usize)>,
 }
 
 impl<H> NodeHash<H>
 where
-    H: Digest,
+    H: OutputSizeUser,
 {
     pub fn mark_as_dirty(&mut self) {
-        self.length.set(0);
+        self.hash.take();
     }
 
-    pub fn extract_ref(&self) -> Option<NodeHashRef<H>> {
-        let length = self.length.get();
-        let hash_ref = self.hash_ref.borrow();
-
-        match length {
-            0 => None,
-            32 => Some(NodeHashRef::Hashed(hash_ref)),
-            l => Some(NodeHashRef::Inline(Ref::map(hash_ref, |x| &x[..l]))),
-        }
+    pub fn extract_ref(&self) -> Option<NodeHashRef<'_, H>> {
+        self.hash.get().map(|(hash, length)| match length {
+            32 => NodeHashRef::Hashed(hash),
+            l => NodeHashRef::Inline(&hash[..*l]),
+        })
     }
 
     #[warn(warnings)]
     pub fn into_inner(self) -> (Output<H>, usize) {
-        (self.hash_ref.into_inner(), self.length.into_inner())
+        self.hash.into_inner().unwrap_or_default()
     }
 }
 
 impl<H> Default for NodeHash<H>
 where
-    H: Digest,
+    H: OutputSizeUser,
 {
     fn default() -> Self {
         Self {
-            length: Cell::new(0),
-            hash_ref: Default::default(),
+            hash: OnceLock::new(),
         }
     }
 }
@@ -88,15 +85,15 @@ where
 #[derive(Debug)]
 pub enum NodeHashRef<'a, H>
 where
-    H: Digest,
+    H: OutputSizeUser,
 {
-    Inline(Ref<'a, [u8]>),
-    Hashed(Ref<'a, Output<H>>),
+    Inline(&'a [u8]),
+    Hashed(&'a Output<H>),
 }
 
 impl<'a, H> AsRef<[u8]> for NodeHashRef<'a, H>
 where
-    H: Digest,
+    H: OutputSizeUser,
 {
     fn as_ref(&self) -> &[u8] {
         match self {
@@ -112,6 +109,10 @@ where
 {
     parent: &'a NodeHash<H>,
     hasher: Option<H>,
+
+    /// Data not yet fed into the hasher (the whole encoding, while it's shorter than a hash).
+    buffer: Output<H>,
+    length: usize,
 }
 
 impl<'a, H> NodeHasher<'a, H>
@@ -119,29 +120,29 @@ where
     H: 'a + Digest,
 {
     pub fn new(parent: &'a NodeHash<H>) -> Self {
-        parent.length.set(0);
-
         Self {
             parent,
             hasher: None,
+            buffer: Default::default(),
+            length: 0,
         }
     }
 
     pub fn finalize(mut self) -> NodeHashRef<'a, H> {
-        match self.hasher {
-            Some(_) => {
-                {
-                    let mut hash_ref = self.parent.hash_ref.borrow_mut();
-                    self.push_hash_update(&hash_ref[..self.parent.length.get()]);
-                    self.hasher.take().unwrap().finalize_into(&mut hash_ref);
-                }
-                self.parent.length.set(32);
-                NodeHashRef::Hashed(self.parent.hash_ref.borrow())
+        let length = match self.hasher.take() {
+            Some(mut hasher) => {
+                hasher.update(&self.buffer[..self.length]);
+                hasher.finalize_into(&mut self.buffer);
+                32
             }
-            None => NodeHashRef::Inline(Ref::map(self.parent.hash_ref.borrow(), |x| {
-                &x[..self.parent.length.get()]
-            })),
-        }
+            None => self.length,
+        };
+
+        // Another thread may have cached the same hash in the meantime.
+        let _ = self.parent.hash.set((self.buffer, length));
+        self.parent
+            .extract_ref()
+            .expect("the hash has just been cached")
     }
 
     pub const fn path_len(value_len: usize) -> usize {
@@ -156,55 +157,47 @@ where
         }
     }
 
-    pub fn write_path_vec(&mut self, value: &NibbleVec, kind: PathKind) {
-        let mut flag = kind.into_flag();
+    /// Like [`bytes_len`](Self::bytes_len), for a value's encoding.
+    pub fn value_len(value: &(impl Encode + ?Sized)) -> usize {
+        match value.encoded_len() {
+            1 => Self::bytes_len(1, value.encode()[0]),
+            l => Self::bytes_len(l, 0),
+        }
+    }
+}
 
-        // TODO: Do not use iterators.
-        let nibble_count = value.len();
-        let nibble_iter = if nibble_count & 0x01 != 0 {
-            let mut iter = value.iter();
-            flag |= 0x10;
-            flag |= iter.next().unwrap() as u8;
-            iter
-        } else {
-            value.iter()
-        };
+/// RLP writing primitives shared by the node hasher and encoder.
+pub trait NodeWriter {
+    fn write_raw(&mut self, value: &[u8]);
 
-        let i2 = nibble_iter.clone().skip(1).step_by(2);
-        if nibble_count > 1 {
-            self.write_len(0x80, 0xB7, (nibble_count >> 1) + 1);
-        }
-        self.write_raw(&[flag]);
-        for (a, b) in nibble_iter.step_by(2).zip(i2) {
-            self.write_raw(&[((a as u8) << 4) | (b as u8)]);
-        }
+    fn write_path_vec(&mut self, value: &NibbleVec, kind: PathKind) {
+        self.write_path(value.len(), |index| value.get(index), kind);
     }
 
-    pub fn write_path_slice(&mut self, value: &NibbleSlice, kind: PathKind) {
-        let mut flag = kind.into_flag();
+    fn write_path_slice(&mut self, value: &NibbleSlice, kind: PathKind) {
+        self.write_path(value.len(), |index| value.get(index), kind);
+    }
 
-        // TODO: Do not use iterators.
-        let nibble_count = value.clone().count();
-        let nibble_iter = if nibble_count & 0x01 != 0 {
-            let mut iter = value.clone();
-            flag |= 0x10;
-            flag |= iter.next().unwrap() as u8;
-            iter
-        } else {
-            value.clone()
-        };
+    /// Write a path of `len` nibbles in its compact (hex-prefix) encoding, given the nibble at
+    /// each index.
+    fn write_path(&mut self, len: usize, nibble: impl Fn(usize) -> Option<Nibble>, kind: PathKind) {
+        let nibble = |index| nibble(index).map(u8::from).expect("index within the path");
 
-        let i2 = nibble_iter.clone().skip(1).step_by(2);
-        if nibble_count > 1 {
-            self.write_len(0x80, 0xB7, (nibble_count >> 1) + 1);
+        let mut flag = kind.into_flag();
+        if len & 0x01 != 0 {
+            flag |= 0x10 | nibble(0);
+        }
+
+        if len > 1 {
+            self.write_len(0x80, 0xB7, (len >> 1) + 1);
         }
         self.write_raw(&[flag]);
-        for (a, b) in nibble_iter.step_by(2).zip(i2) {
-            self.write_raw(&[((a as u8) << 4) | (b as u8)]);
+        for index in (len & 0x01..len).step_by(2) {
+            self.write_raw(&[(nibble(index) << 4) | nibble(index + 1)]);
         }
     }
 
-    pub fn write_bytes(&mut self, value: &[u8]) {
+    fn write_bytes(&mut self, value: &[u8]) {
         if value.len() == 1 && value[0] < 128 {
             self.write_raw(&[value[0]]);
         } else {
@@ -213,7 +206,18 @@ where
         }
     }
 
-    pub fn write_list_header(&mut self, children_len: usize) {
+    /// Write a value's encoding as a byte string, one piece at a time.
+    fn write_value(&mut self, value: &(impl Encode + ?Sized)) {
+        match value.encoded_len() {
+            1 => self.write_bytes(&value.encode()),
+            l => {
+                self.write_len(0x80, 0xB7, l);
+                value.encode_chunks(&mut |chunk| self.write_raw(chunk));
+            }
+        }
+    }
+
+    fn write_list_header(&mut self, children_len: usize) {
         self.write_len(0xC0, 0xF7, children_len);
     }
 
@@ -227,34 +231,46 @@ where
             }
         }
     }
+}
 
-    pub fn write_raw(&mut self, value: &[u8]) {
-        let mut length = self.parent.length.get();
-        let mut hash_ref = self.parent.hash_ref.borrow_mut();
-
+impl<'a, H> NodeWriter for NodeHasher<'a, H>
+where
+    H: 'a + Digest,
+{
+    fn write_raw(&mut self, value: &[u8]) {
         let mut current_pos = 0;
         while current_pos < value.len() {
-            let copy_len = min(32 - length, value.len() - current_pos);
+            let copy_len = min(32 - self.length, value.len() - current_pos);
 
-            let target_slice = &mut hash_ref[length..length + copy_len];
+            let target_slice = &mut self.buffer[self.length..self.length + copy_len];
             let source_slice = &value[current_pos..current_pos + copy_len];
             target_slice.copy_from_slice(source_slice);
 
             current_pos += copy_len;
-            length += copy_len;
+            self.length += copy_len;
 
-            if length == 32 {
-                self.push_hash_update(&hash_ref);
-                length = 0;
+            if self.length == 32 {
+                let hasher = self.hasher.get_or_insert_with(H::new);
+                hasher.update(&self.buffer);
+                self.length = 0;
             }
         }
+    }
+}
 
-        self.parent.length.set(length);
+/// Writes a node's RLP encoding into a buffer instead of hashing it.
+#[derive(Clone, Debug, Default)]
+pub struct NodeEncoder(Vec<u8>);
+
+impl NodeEncoder {
+    pub fn finalize(self) -> Vec<u8> {
+        self.0
     }
+}
 
-    fn push_hash_update(&mut self, data: &[u8]) {
-        let hasher = self.hasher.get_or_insert_with(H::new);
-        hasher.update(data);
+impl NodeWriter for NodeEncoder {
+    fn write_raw(&mut self, value: &[u8]) {
+        self.0.extend_from_slice(value);
     }
 }
 
@@ -0,0 +1,66 @@
+use std::fmt::Write;
+
+/// Encode bytes as a lowercase hex string (without prefix).
+pub(crate) fn encode(data: &[u8]) -> String {
+    data.iter()
+        .fold(String::with_capacity(2 * data.len()), |mut acc, x| {
+            write!(acc, "{x:02x}").unwrap();
+            acc
+        })
+}
+
+/// Decode an hex string, with or without the `0x` prefix. Odd lengths are left-padded.
+#[cfg(any(
+    feature = "eth-tests",
+    feature = "execution-witness",
+    feature = "genesis",
+    feature = "geth-dump",
+))]
+pub(crate) fn decode(value: &str) -> Option<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    let padding = value.len() & 1;
+
+    (0..value.len() + padding)
+        .step_by(2)
+        .map(|i| {
+            let byte = value.get(i.saturating_sub(padding)..i + 2 - padding)?;
+            u8::from_str_radix(byte, 16).ok()
+        })
+        .collect()
+}
+
+/// Parse a 256-bit big-endian integer, written either in decimal or in `0x`-prefixed hex.
+#[cfg(any(feature = "genesis", feature = "geth-dump"))]
+pub(crate) fn decode_u256(value: &str) -> Option<[u8; 32]> {
+    if value.starts_with("0x") {
+        let bytes = decode(value)?;
+        let bytes = crate::account::trim_zeros(&bytes);
+        if bytes.len() > 32 {
+            return None;
+        }
+
+        let mut output = [0; 32];
+        output[32 - bytes.len()..].copy_from_slice(bytes);
+        return Some(output);
+    }
+
+    if value.is_empty() {
+        return None;
+    }
+
+    let mut output = [0u8; 32];
+    for digit in value.chars() {
+        let mut carry = digit.to_digit(10)?;
+        for byte in output.iter_mut().rev() {
+            let x = *byte as u32 * 10 + carry;
+            *byte = x as u8;
+            carry = x >> 8;
+        }
+
+        if carry != 0 {
+            return None;
+        }
+    }
+
+    Some(output)
+}
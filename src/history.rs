@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+/// A value written at some point, identified by the sequence number of the write.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValueVersion {
+    pub version: u64,
+    /// The encoded value written (`None` if it was removed).
+    pub value: Option<Vec<u8>>,
+}
+
+/// Every value written under each path (encoded), when enabled.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct History {
+    enabled: bool,
+    /// Sequence number of the last write recorded.
+    version: u64,
+    versions: HashMap<Vec<u8>, Vec<ValueVersion>>,
+}
+
+impl History {
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, path: &[u8]) -> &[ValueVersion] {
+        self.versions.get(path).map_or(&[], Vec::as_slice)
+    }
+
+    /// Record a write if enabled.
+    pub fn record(&mut self, path: &[u8], value: Option<Vec<u8>>) {
+        if !self.enabled {
+            return;
+        }
+
+        self.version += 1;
+        self.versions
+            .entry(path.to_vec())
+            .or_default()
+            .push(ValueVersion {
+                version: self.version,
+                value,
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn versions() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(b"dog".to_vec(), b"puppy".to_vec());
+        tree.enable_history();
+
+        tree.insert(b"dog".to_vec(), b"hound".to_vec());
+        tree.insert(b"horse".to_vec(), b"stallion".to_vec());
+        tree.remove(b"dog".to_vec());
+        tree.remove(b"doge".to_vec());
+
+        assert_eq!(
+            tree.get_versions(&b"dog".to_vec()),
+            [
+                ValueVersion {
+                    version: 1,
+                    value: Some(b"hound".to_vec()),
+                },
+                ValueVersion {
+                    version: 3,
+                    value: None,
+                },
+            ],
+        );
+        assert_eq!(tree.get_versions(&b"horse".to_vec()).len(), 1);
+        assert!(tree.get_versions(&b"doge".to_vec()).is_empty());
+        assert_eq!(tree.version(), 3);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{
+    corruption::CorruptionError, nibble::NibbleSlice, node::Node, Encode, NodeRef,
+    PatriciaMerkleTree, ValueRef,
+};
+use digest::Digest;
+
+/// Check every node of the tree (see [`check_node`]).
+pub(crate) fn check_tree<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>) -> Result<(), CorruptionError>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    if !tree.root_ref.is_valid() {
+        return Ok(());
+    }
+
+    let mut stack = vec![(tree.root_ref, Vec::new())];
+    while let Some((node_ref, path_prefix)) = stack.pop() {
+        let node = check_node(tree, node_ref, &path_prefix)?;
+        match node {
+            Node::Branch(branch_node) => {
+                for (choice, child_ref) in branch_node.choices.iter().enumerate() {
+                    if child_ref.is_valid() {
+                        stack.push((
+                            *child_ref,
+                            [path_prefix.as_slice(), &[choice as u8]].concat(),
+                        ));
+                    }
+                }
+            }
+            Node::Extension(extension_node) => {
+                let prefix = extension_node.prefix.iter().map(u8::from);
+                let path_prefix = path_prefix.iter().copied().chain(prefix).collect();
+                stack.push((extension_node.child_ref, path_prefix));
+            }
+            Node::Leaf(_) | Node::Stub(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the nodes along an encoded path, from the root down to where it ends or diverges from
+/// the tree. Used after every mutation when the `debug-invariants` feature is enabled, since only
+/// the nodes along the mutated path can have changed.
+#[cfg_attr(not(feature = "debug-invariants"), allow(dead_code))]
+pub(crate) fn check_path<P, V, H>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    path: &[u8],
+) -> Result<(), CorruptionError>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    if !tree.root_ref.is_valid() {
+        return Ok(());
+    }
+
+    let mut path = NibbleSlice::new(path);
+    let mut path_prefix = Vec::new();
+    let mut node_ref = tree.root_ref;
+    loop {
+        node_ref = match check_node(tree, node_ref, &path_prefix)? {
+            Node::Branch(branch_node) => match path.next() {
+                Some(nibble) if branch_node.choices[nibble as usize].is_valid() => {
+                    path_prefix.push(nibble as u8);
+                    branch_node.choices[nibble as usize]
+                }
+                _ => return Ok(()),
+            },
+            Node::Extension(extension_node) => {
+                if !path.skip_prefix(&extension_node.prefix) {
+                    return Ok(());
+                }
+
+                path_prefix.extend(extension_node.prefix.iter().map(u8::from));
+                extension_node.child_ref
+            }
+            Node::Leaf(_) | Node::Stub(_) => return Ok(()),
+        };
+    }
+}
+
+/// Check a single node, reached through `path_prefix` (in nibbles):
+///   - Its children and values must be stored.
+///   - Branches must have at least two children, or a child and a value.
+///   - Extensions must have a non-empty prefix, and be followed by a branch.
+///   - The paths of its values must match the path leading to it.
+fn check_node<'a, P, V, H>(
+    tree: &'a PatriciaMerkleTree<P, V, H>,
+    node_ref: NodeRef,
+    path_prefix: &[u8],
+) -> Result<&'a Node<P, V, H>, CorruptionError>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let node = tree
+        .nodes
+        .get(*node_ref)
+        .ok_or_else(|| CorruptionError::missing(node_ref, path_prefix.to_vec()))?;
+    let error = |kind| CorruptionError::within(kind, path_prefix.to_vec()).at(node_ref);
+
+    // Return whether the value is stored, and its path matches the node's (fully if `exact`).
+    let is_valid_value = |value_ref: ValueRef, exact: bool| {
+        tree.values.get(*value_ref).is_some_and(|(path, _)| {
+            let path = path.encode();
+            let nibbles = path
+                .as_ref()
+                .iter()
+                .flat_map(|x| [x >> 4, x & 0x0F])
+                .collect::<Vec<_>>();
+            nibbles.starts_with(path_prefix) && (!exact || nibbles.len() == path_prefix.len())
+        })
+    };
+
+    let is_valid = match node {
+        Node::Branch(branch_node) => {
+            let children = branch_node.choices.iter().filter(|x| x.is_valid());
+            let has_value = branch_node.value_ref.is_valid();
+
+            children.clone().count() + has_value as usize >= 2
+                && children.clone().all(|x| tree.nodes.get(**x).is_some())
+                && (!has_value || is_valid_value(branch_node.value_ref, true))
+        }
+        Node::Extension(extension_node) => {
+            !extension_node.prefix.is_empty()
+                && matches!(
+                    tree.nodes.get(*extension_node.child_ref),
+                    Some(Node::Branch(_))
+                )
+        }
+        Node::Leaf(leaf_node) => is_valid_value(leaf_node.value_ref, false),
+        Node::Stub(_) => true,
+    };
+
+    match is_valid {
+        true => Ok(node),
+        false => Err(error(node.kind())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::NodeKind, pmt_tree};
+
+    #[test]
+    fn verify_integrity() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        assert_eq!(tree.verify_integrity(), Ok(()));
+        for i in 0..64u8 {
+            tree.insert(vec![i >> 2, i], vec![i]);
+        }
+        tree.insert(vec![0x01], vec![0xFF]);
+        assert_eq!(tree.verify_integrity(), Ok(()));
+        assert_eq!(check_path(&tree, &[0x01, 0x05]), Ok(()));
+
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x00], vec![0]);
+        tree.insert(vec![0x10], vec![1]);
+        let root_ref = tree.root_ref;
+        let Some(Node::Branch(branch_node)) = tree.nodes.get(*root_ref) else {
+            panic!("the root should be a branch");
+        };
+        let (first, second) = (branch_node.choices[0], branch_node.choices[1]);
+
+        // Leaves under the wrong paths.
+        let mut corrupted = tree.clone();
+        let Some(Node::Branch(branch_node)) = corrupted.nodes.get_mut(*root_ref) else {
+            unreachable!();
+        };
+        branch_node.choices.swap(0, 1);
+        assert_eq!(
+            check_path(&corrupted, &[0x00]),
+            Err(CorruptionError {
+                node_ref: *second,
+                node_kind: Some(NodeKind::Leaf),
+                path_prefix: vec![0],
+            })
+        );
+        assert!(corrupted.verify_integrity().is_err());
+        assert_eq!(check_path(&corrupted, &[0x80]), Ok(()));
+
+        // A dangling reference.
+        let mut corrupted = tree.clone();
+        corrupted.nodes.remove(*first);
+        assert_eq!(
+            corrupted.verify_integrity(),
+            Err(CorruptionError {
+                node_ref: *root_ref,
+                node_kind: Some(NodeKind::Branch),
+                path_prefix: vec![],
+            })
+        );
+    }
+}
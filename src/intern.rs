@@ -0,0 +1,149 @@
+use std::{collections::HashMap, hash::Hash, mem::size_of, sync::Arc};
+
+/// The values shared by the entries of a [`ValuesStorage`](crate::ValuesStorage), along with how
+/// many entries hold each of them.
+///
+/// Storages keep it as a trait object, so that only those with interning enabled require values
+/// to be hashable.
+pub(crate) trait Interner<V>: Send + Sync {
+    /// Return a shared copy of `value`, reusing an equal one if it was already interned.
+    fn intern(&mut self, value: V) -> Arc<V>;
+
+    /// Give back a shared copy returned by [`intern`](Self::intern), returning the value (copied
+    /// if other entries still hold it). Values are dropped once no entry holds them.
+    fn release(&mut self, value: Arc<V>) -> V;
+
+    /// Return the number of distinct values interned.
+    #[cfg(test)]
+    fn len(&self) -> usize;
+
+    /// Calculate approximated memory usage (both used and allocated) of the interned values.
+    fn memory_usage(&self) -> (usize, usize);
+
+    fn boxed_clone(&self) -> Box<dyn Interner<V>>;
+}
+
+#[derive(Clone)]
+pub(crate) struct ValueInterner<V> {
+    ref_counts: HashMap<Arc<V>, usize>,
+}
+
+impl<V> Default for ValueInterner<V> {
+    fn default() -> Self {
+        Self {
+            ref_counts: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Interner<V> for ValueInterner<V>
+where
+    V: Clone + Eq + Hash + Send + Sync + 'static,
+{
+    fn intern(&mut self, value: V) -> Arc<V> {
+        if let Some(ref_count) = self.ref_counts.get_mut(&value) {
+            *ref_count += 1;
+            let (shared, _) = self.ref_counts.get_key_value(&value).expect("just found");
+            return shared.clone();
+        }
+
+        let shared = Arc::new(value);
+        self.ref_counts.insert(shared.clone(), 1);
+        shared
+    }
+
+    fn release(&mut self, value: Arc<V>) -> V {
+        let ref_count = self
+            .ref_counts
+            .get_mut(&*value)
+            .expect("the value was interned");
+        *ref_count -= 1;
+        if *ref_count == 0 {
+            self.ref_counts.remove(&*value);
+        }
+
+        Arc::try_unwrap(value).unwrap_or_else(|shared| V::clone(&shared))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.ref_counts.len()
+    }
+
+    fn memory_usage(&self) -> (usize, usize) {
+        // Every value is stored along with its atomic reference counts.
+        let value_size = size_of::<V>() + 2 * size_of::<usize>();
+        let slot_size = size_of::<(Arc<V>, usize)>();
+
+        (
+            (slot_size + value_size) * self.ref_counts.len(),
+            slot_size * self.ref_counts.capacity() + value_size * self.ref_counts.len(),
+        )
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Interner<V>> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+
+    #[test]
+    fn shared_values() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let mut plain_tree = pmt_tree!(Vec<u8>);
+        tree.enable_value_interning();
+        for i in 0..64u8 {
+            tree.insert(vec![i], vec![0xAA; 32]);
+            plain_tree.insert(vec![i], vec![0xAA; 32]);
+        }
+        tree.insert(vec![0xFF], vec![0xBB; 32]);
+        plain_tree.insert(vec![0xFF], vec![0xBB; 32]);
+
+        assert_eq!(tree.values.interned_len(), 2);
+        assert_eq!(tree.get(&vec![0x10]), Some(&vec![0xAA; 32]));
+        assert_eq!(tree.compute_hash(), plain_tree.compute_hash());
+
+        // The shared values are counted once, on top of the entries.
+        let (used, allocated) = tree.memory_usage();
+        assert!(used > plain_tree.memory_usage().0);
+        assert!(allocated >= used);
+
+        assert_eq!(
+            tree.insert(vec![0xFF], vec![0xAA; 32]),
+            Some(vec![0xBB; 32])
+        );
+        assert_eq!(tree.values.interned_len(), 1);
+        for i in 0..64u8 {
+            assert_eq!(tree.remove(vec![i]), Some(vec![0xAA; 32]));
+        }
+        assert_eq!(tree.values.interned_len(), 1);
+        tree.remove(vec![0xFF]);
+        assert_eq!(tree.values.interned_len(), 0);
+    }
+
+    #[test]
+    fn enable_after_insertions() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i], vec![i % 4]);
+        }
+        let root = *tree.compute_hash();
+
+        tree.enable_value_interning();
+        assert_eq!(tree.values.interned_len(), 4);
+        assert_eq!(*tree.compute_hash(), root);
+
+        let mut other = tree.clone();
+        other.map_values_in_place(&[0x01], |_, value| value.push(0));
+        assert_eq!(other.get(&vec![0x01]), Some(&vec![0x01, 0x00]));
+        assert_eq!(tree.get(&vec![0x01]), Some(&vec![0x01]));
+        assert_eq!(tree.values.interned_len(), 4);
+
+        tree.compact();
+        assert_eq!(tree.values.interned_len(), 4);
+        assert_eq!(*tree.compute_hash(), root);
+    }
+}
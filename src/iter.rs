@@ -0,0 +1,508 @@
+use crate::{
+    node::Node,
+    proof::{prove, to_nibbles},
+    storage::ValueRef,
+    Encode, NodeRef, NodesStorage, PatriciaMerkleTree, ValuesStorage,
+};
+use digest::{Digest, OutputSizeUser};
+use thiserror::Error;
+
+/// The token doesn't come from [`TreeIterator::cursor_token`].
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("invalid cursor token")]
+pub struct InvalidCursorToken;
+
+/// The tree was modified after the cursor was taken (see
+/// [`iter_from_cursor_checked`](PatriciaMerkleTree::iter_from_cursor_checked)).
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("the tree was modified after the cursor was taken (generation {cursor:?}, now {tree})")]
+pub struct StaleCursor {
+    /// The tree's generation when the cursor was taken, if known.
+    pub cursor: Option<u64>,
+    pub tree: u64,
+}
+
+/// An opaque position within an iteration, from which it can be resumed later on (even by another
+/// process) with [`iter_from_cursor`](PatriciaMerkleTree::iter_from_cursor).
+///
+/// Tokens store the path of the last value returned, so they remain valid when the tree is
+/// modified: the resumed iteration returns the values after that path at that time. They also
+/// store the tree's [`generation`](PatriciaMerkleTree::generation), so that resuming can fail
+/// instead if the tree was modified in between.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CursorToken(Vec<u8>);
+
+/// A page of values in ascending path order, as returned by
+/// [`get_page`](PatriciaMerkleTree::get_page).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Page<'a, P, V> {
+    pub entries: Vec<(&'a P, &'a V)>,
+    /// Where to resume to get the next page, or `None` if this one is the last.
+    pub next: Option<CursorToken>,
+}
+
+/// Version of the token's encoding.
+const CURSOR_VERSION: u8 = 2;
+/// Version of the encoding without the tree's generation, which is still accepted.
+const CURSOR_VERSION_WITHOUT_GENERATION: u8 = 1;
+
+impl CursorToken {
+    /// Return the token's encoding.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Decode a token from its encoding.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, InvalidCursorToken> {
+        match data {
+            [CURSOR_VERSION_WITHOUT_GENERATION, 0] | [CURSOR_VERSION_WITHOUT_GENERATION, 1, ..] => {
+                Ok(Self(data.to_vec()))
+            }
+            [CURSOR_VERSION, _, _, _, _, _, _, _, _, 0]
+            | [CURSOR_VERSION, _, _, _, _, _, _, _, _, 1, ..] => Ok(Self(data.to_vec())),
+            _ => Err(InvalidCursorToken),
+        }
+    }
+
+    pub(crate) fn new(last: Option<&[u8]>, generation: u64) -> Self {
+        let header = [&[CURSOR_VERSION][..], &generation.to_be_bytes()].concat();
+        match last {
+            Some(path) => Self([&header, &[1][..], path].concat()),
+            None => Self([&header, &[0][..]].concat()),
+        }
+    }
+
+    /// Return the tree's generation when the token was taken, unless it comes from a version
+    /// which didn't store it.
+    pub fn generation(&self) -> Option<u64> {
+        (self.0[0] == CURSOR_VERSION)
+            .then(|| u64::from_be_bytes(self.0[1..9].try_into().expect("checked when decoding")))
+    }
+
+    /// Return the encoded path of the last value returned before the token was taken, if any.
+    pub(crate) fn last_path(&self) -> Option<&[u8]> {
+        let offset = match self.0[0] {
+            CURSOR_VERSION => 9,
+            _ => 1,
+        };
+        match self.0[offset] {
+            0 => None,
+            _ => Some(&self.0[offset + 1..]),
+        }
+    }
+}
+
+/// Iterator over the values of a tree, in ascending path order.
+pub struct TreeIterator<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
+{
+    nodes: &'a NodesStorage<P, V, H>,
+    values: &'a ValuesStorage<P, V>,
+
+    /// Pending nodes (along with the nibbles leading to them) and values, in reverse order.
+    stack: Vec<StackItem>,
+    /// Values whose path is lower than this one are skipped (as nibbles and encoded).
+    start: Option<(Vec<u8>, Vec<u8>)>,
+    /// Whether the value at the starting path is skipped too.
+    exclusive: bool,
+    /// Encoded path of the last value returned.
+    last: Option<Vec<u8>>,
+    /// The tree's generation, for cursor tokens.
+    generation: u64,
+}
+
+enum StackItem {
+    Node(NodeRef, Vec<u8>),
+    Value(ValueRef),
+}
+
+impl<'a, P, V, H> TreeIterator<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    pub(crate) fn new(tree: &'a PatriciaMerkleTree<P, V, H>, start: Option<&P>) -> Self {
+        let start = start.map(|x| x.encode());
+        let mut iter =
+            Self::with_storage(tree.root_ref, &tree.nodes, &tree.values, start.as_deref());
+        iter.generation = tree.generation;
+
+        iter
+    }
+
+    /// Create an iterator returning the values after the cursor's position.
+    pub(crate) fn from_cursor(tree: &'a PatriciaMerkleTree<P, V, H>, cursor: &CursorToken) -> Self {
+        let mut iter = Self::new(tree, None);
+        if let Some(path) = cursor.last_path() {
+            iter.start = Some((to_nibbles(path), path.to_vec()));
+            iter.exclusive = true;
+            iter.last = Some(path.to_vec());
+        }
+
+        iter
+    }
+}
+
+impl<'a, P, V, H> TreeIterator<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
+{
+    /// Create an iterator over the tree rooted at `root_ref`, starting at the encoded path
+    /// `start` (inclusive).
+    pub(crate) fn with_storage(
+        root_ref: NodeRef,
+        nodes: &'a NodesStorage<P, V, H>,
+        values: &'a ValuesStorage<P, V>,
+        start: Option<&[u8]>,
+    ) -> Self {
+        let mut stack = Vec::new();
+        if root_ref.is_valid() {
+            stack.push(StackItem::Node(root_ref, Vec::new()));
+        }
+
+        Self {
+            nodes,
+            values,
+            stack,
+            start: start.map(|x| (to_nibbles(x), x.to_vec())),
+            exclusive: false,
+            last: None,
+            generation: 0,
+        }
+    }
+
+    /// Return a token from which the iteration can be resumed, after the values returned so far.
+    pub fn cursor_token(&self) -> CursorToken {
+        CursorToken::new(self.last.as_deref(), self.generation)
+    }
+
+    /// Return a reference to the next value, the same one [`next`](Iterator::next) would return.
+    pub(crate) fn next_ref(&mut self) -> Option<ValueRef> {
+        while let Some(item) = self.stack.pop() {
+            let (node_ref, prefix) = match item {
+                StackItem::Node(node_ref, prefix) => (node_ref, prefix),
+                StackItem::Value(value_ref) => {
+                    let (path, _) = self
+                        .values
+                        .get(*value_ref)
+                        .expect("inconsistent internal tree structure");
+
+                    let encoded = path.encode();
+                    match &self.start {
+                        Some((_, start)) if encoded.as_ref() < start.as_slice() => continue,
+                        Some((_, start)) if self.exclusive && encoded.as_ref() == start => continue,
+                        _ => {
+                            self.last = Some(encoded.into_owned());
+                            return Some(value_ref);
+                        }
+                    }
+                }
+            };
+
+            if self.is_before_start(&prefix) {
+                continue;
+            }
+
+            match self
+                .nodes
+                .get(*node_ref)
+                .expect("inconsistent internal tree structure")
+            {
+                Node::Branch(branch_node) => {
+                    for (choice, child_ref) in branch_node.choices.iter().enumerate().rev() {
+                        if child_ref.is_valid() {
+                            let mut child_prefix = prefix.clone();
+                            child_prefix.push(choice as u8);
+                            self.stack.push(StackItem::Node(*child_ref, child_prefix));
+                        }
+                    }
+
+                    if branch_node.value_ref.is_valid() {
+                        self.stack.push(StackItem::Value(branch_node.value_ref));
+                    }
+                }
+                Node::Extension(extension_node) => {
+                    let mut child_prefix = prefix;
+                    child_prefix.extend(extension_node.prefix.iter().map(u8::from));
+                    self.stack
+                        .push(StackItem::Node(extension_node.child_ref, child_prefix));
+                }
+                Node::Leaf(leaf_node) => self.stack.push(StackItem::Value(leaf_node.value_ref)),
+                // Stubbed subtrees have no values in memory.
+                Node::Stub(_) => {}
+            }
+        }
+
+        None
+    }
+
+    /// Return whether every path starting with `prefix` is lower than the starting path.
+    fn is_before_start(&self, prefix: &[u8]) -> bool {
+        match &self.start {
+            Some((start, _)) => prefix < start.as_slice() && !start.starts_with(prefix),
+            None => false,
+        }
+    }
+}
+
+impl<'a, P, V, H> Iterator for TreeIterator<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
+{
+    type Item = (&'a P, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let values = self.values;
+        let (path, value) = &values[*self.next_ref()?];
+        Some((path, value))
+    }
+}
+
+/// Iterator over the values of a tree along with their proofs, in ascending path order.
+pub struct ProofIterator<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    tree: &'a PatriciaMerkleTree<P, V, H>,
+    inner: TreeIterator<'a, P, V, H>,
+}
+
+impl<'a, P, V, H> ProofIterator<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    pub(crate) fn new(tree: &'a PatriciaMerkleTree<P, V, H>) -> Self {
+        Self {
+            tree,
+            inner: TreeIterator::new(tree, None),
+        }
+    }
+}
+
+impl<'a, P, V, H> Iterator for ProofIterator<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    type Item = (&'a P, &'a V, Vec<Vec<u8>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, value) = self.inner.next()?;
+        Some((path, value, prove(self.tree, path)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{pmt_tree, verify_proof};
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+    use sha3::Keccak256;
+
+    #[test]
+    fn iter_empty() {
+        let tree = pmt_tree!(Vec<u8>);
+
+        assert_eq!(tree.iter().next(), None);
+    }
+
+    #[test]
+    fn iter_sorted() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for path in [&b"doge"[..], b"do", b"horse", b"dog", b"d"] {
+            tree.insert(path.to_vec(), path.to_vec());
+        }
+
+        let paths = tree
+            .iter()
+            .map(|(path, _)| path.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&b"d"[..], b"do", b"dog", b"doge", b"horse"]);
+
+        let paths = tree
+            .iter_from(&b"dog".to_vec())
+            .map(|(path, _)| path.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&b"dog"[..], b"doge", b"horse"]);
+
+        let paths = tree
+            .iter_from(&b"e".to_vec())
+            .map(|(path, _)| path.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&b"horse"[..]]);
+    }
+
+    #[test]
+    fn iter_with_proofs() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+        let root = *tree.compute_hash();
+
+        let mut count = 0;
+        for (path, value, proof) in tree.iter_with_proofs() {
+            assert_eq!(
+                verify_proof::<Keccak256>(&root, path, proof),
+                Some(Some(value.clone()))
+            );
+            count += 1;
+        }
+        assert_eq!(count, 64);
+    }
+
+    #[test]
+    fn cursor_token() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for path in [&b"doge"[..], b"do", b"horse", b"dog", b"d"] {
+            tree.insert(path.to_vec(), path.to_vec());
+        }
+
+        let mut iter = tree.iter();
+        let token = iter.cursor_token();
+        assert_eq!(iter.nth(1), Some((&b"do".to_vec(), &b"do".to_vec())));
+        let after_do = CursorToken::from_bytes(iter.cursor_token().as_bytes()).unwrap();
+
+        let resumed = CursorToken::from_bytes(token.as_bytes()).unwrap();
+        assert_eq!(tree.iter_from_cursor(&resumed).count(), 5);
+
+        tree.insert(b"dob".to_vec(), b"dob".to_vec());
+        tree.remove(b"dog".to_vec());
+        let mut iter = tree.iter_from_cursor(&after_do);
+        let paths = iter
+            .by_ref()
+            .map(|(path, _)| path.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&b"dob"[..], b"doge", b"horse"]);
+        assert_eq!(tree.iter_from_cursor(&iter.cursor_token()).next(), None);
+
+        assert_eq!(CursorToken::from_bytes(&[0xFF, 1]), Err(InvalidCursorToken));
+        assert_eq!(CursorToken::from_bytes(&[]), Err(InvalidCursorToken));
+        assert_eq!(CursorToken::from_bytes(&[2, 0, 1]), Err(InvalidCursorToken));
+
+        // Tokens without the tree's generation are still accepted.
+        let legacy = CursorToken::from_bytes(&[1, 1, b'd', b'o', b'g']).unwrap();
+        assert_eq!(legacy.generation(), None);
+        assert_eq!(tree.iter_from_cursor(&legacy).count(), 2);
+    }
+
+    #[test]
+    fn stale_cursor() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..10u8 {
+            tree.insert(vec![i], vec![i]);
+        }
+        assert_eq!(tree.generation(), 10);
+
+        let page = tree.get_page(None, 4);
+        let next = page.next.unwrap();
+        assert_eq!(next.generation(), Some(10));
+        assert_eq!(tree.iter_from_cursor_checked(&next).unwrap().count(), 6);
+
+        // Neither failed removals nor compactions modify the tree's contents.
+        tree.remove(vec![0xFF]);
+        tree.remove_range(vec![0xF0]..);
+        tree.compact();
+        assert!(tree.iter_from_cursor_checked(&next).is_ok());
+
+        tree.insert(vec![2], vec![0xFF]);
+        assert_eq!(
+            tree.iter_from_cursor_checked(&next).err(),
+            Some(StaleCursor {
+                cursor: Some(10),
+                tree: 11
+            })
+        );
+        assert_eq!(tree.iter_from_cursor(&next).count(), 6);
+
+        let legacy = CursorToken::from_bytes(&[1, 0]).unwrap();
+        assert!(tree.iter_from_cursor_checked(&legacy).is_err());
+    }
+
+    #[test]
+    fn get_page() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..10u8 {
+            tree.insert(vec![i], vec![i]);
+        }
+
+        let page = tree.get_page(None, 4);
+        assert_eq!(page.entries.len(), 4);
+        assert_eq!(page.entries[0], (&vec![0], &vec![0]));
+
+        let page = tree.get_page(Some(&vec![3]), 4);
+        let paths = page.entries.iter().map(|(p, _)| p[0]).collect::<Vec<_>>();
+        assert_eq!(paths, [4, 5, 6, 7]);
+
+        let next = page.next.unwrap();
+        let rest = tree
+            .iter_from_cursor(&next)
+            .map(|(p, _)| p[0])
+            .collect::<Vec<_>>();
+        assert_eq!(rest, [8, 9]);
+
+        let page = tree.get_page(Some(&vec![5]), 4);
+        assert_eq!(page.entries.len(), 4);
+        assert_eq!(page.next, None);
+        assert_eq!(tree.get_page(Some(&vec![9]), 4).entries, []);
+        assert_eq!(
+            tree.get_page(None, 0).next,
+            Some(CursorToken::new(None, tree.generation()))
+        );
+        assert_eq!(
+            tree.get_page(Some(&vec![3]), 0).next,
+            Some(CursorToken::new(Some(&[3]), tree.generation()))
+        );
+    }
+
+    #[test]
+    fn to_vec() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in (0..64u8).rev() {
+            tree.insert(vec![i % 8, i], vec![i]);
+        }
+        tree.remove(vec![0, 0]);
+
+        let entries = tree.to_vec();
+        assert_eq!(entries.len(), 63);
+        assert!(entries.windows(2).all(|x| x[0].0 < x[1].0));
+        assert!(entries.iter().map(|(p, v)| (p, v)).eq(tree.iter()));
+
+        // Consuming a clone leaves the original untouched.
+        assert_eq!(tree.clone().into_sorted_vec(), entries);
+        assert_eq!(tree.into_sorted_vec(), entries);
+        assert_eq!(pmt_tree!(Vec<u8>).into_sorted_vec(), []);
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_iter_from(
+            data in btree_map(vec(any::<u8>(), 0..4), vec(any::<u8>(), 1..4), 0..64),
+            start in vec(any::<u8>(), 0..4),
+        ) {
+            let mut tree = pmt_tree!(Vec<u8>);
+            for (path, value) in &data {
+                tree.insert(path.clone(), value.clone());
+            }
+
+            prop_assert!(tree.iter().eq(data.iter()));
+            prop_assert!(tree.iter_from(&start).eq(data.range(start.clone()..)));
+        }
+    }
+}
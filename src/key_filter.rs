@@ -0,0 +1,157 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// Minimum number of paths a filter is sized for.
+const MIN_CAPACITY: usize = 1024;
+
+/// Bloom filter over the encoded paths of a tree's values, when enabled.
+///
+/// Removed paths can't be taken out of the filter, so they're still counted until the filter is
+/// rebuilt, which happens whenever the number of paths added exceeds its capacity.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct KeyFilter {
+    bloom: Option<Bloom>,
+}
+
+#[derive(Clone, Debug)]
+struct Bloom {
+    bits: Vec<u64>,
+    num_hashes: u32,
+    false_positive_rate: f64,
+    capacity: usize,
+    /// Number of paths added since the filter was built.
+    added: usize,
+}
+
+impl KeyFilter {
+    pub fn is_enabled(&self) -> bool {
+        self.bloom.is_some()
+    }
+
+    /// Return whether the filter is disabled or `path` may have been added to it.
+    pub fn may_contain(&self, path: &[u8]) -> bool {
+        match &self.bloom {
+            Some(bloom) => bloom.contains(path),
+            None => true,
+        }
+    }
+
+    /// Build the filter from every path currently in the tree.
+    pub fn rebuild(
+        &mut self,
+        false_positive_rate: f64,
+        len: usize,
+        paths: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) {
+        let mut bloom = Bloom::new(false_positive_rate, (2 * len).max(MIN_CAPACITY));
+        for path in paths {
+            bloom.insert(path.as_ref());
+        }
+
+        self.bloom = Some(bloom);
+    }
+
+    /// Add a path to the filter, returning the false positive rate to rebuild it with if it's full.
+    pub fn insert(&mut self, path: &[u8]) -> Option<f64> {
+        let bloom = self.bloom.as_mut()?;
+        bloom.insert(path);
+
+        (bloom.added > bloom.capacity).then_some(bloom.false_positive_rate)
+    }
+}
+
+impl Bloom {
+    fn new(false_positive_rate: f64, capacity: usize) -> Self {
+        let false_positive_rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let num_hashes = (num_bits / capacity as f64 * ln2).round().max(1.0);
+
+        Self {
+            bits: vec![0; (num_bits as usize).div_ceil(64)],
+            num_hashes: num_hashes as u32,
+            false_positive_rate,
+            capacity,
+            added: 0,
+        }
+    }
+
+    /// Return the bit positions for a path, using double hashing.
+    fn positions(&self, path: &[u8]) -> impl Iterator<Item = usize> {
+        positions(path, self.bits.len() as u64 * 64, self.num_hashes)
+    }
+
+    fn insert(&mut self, path: &[u8]) {
+        for bit in positions(path, self.bits.len() as u64 * 64, self.num_hashes) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.added += 1;
+    }
+
+    fn contains(&self, path: &[u8]) -> bool {
+        self.positions(path)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+fn positions(path: &[u8], num_bits: u64, num_hashes: u32) -> impl Iterator<Item = usize> {
+    let hash = |seed: u8| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        path.hash(&mut hasher);
+        hasher.finish()
+    };
+
+    let (h1, h2) = (hash(0), hash(1) | 1);
+    (0..u64::from(num_hashes))
+        .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+
+    #[test]
+    fn key_filter() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..512u16 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![1]);
+        }
+        tree.enable_key_filter(0.01);
+
+        for i in 512..4096u16 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![2]);
+        }
+        tree.remove(vec![0x00, 0x07]);
+
+        for i in 0..4096u16 {
+            let expected = match i {
+                7 => None,
+                0..=511 => Some(&vec![1]),
+                _ => Some(&vec![2]),
+            };
+            assert_eq!(tree.get(&i.to_be_bytes().to_vec()), expected);
+        }
+
+        let false_positives = (0..10_000u32)
+            .map(|i| ((i + 1) << 16).to_be_bytes().to_vec())
+            .filter(|path| tree.key_filter.may_contain(path))
+            .count();
+        assert!(false_positives < 200, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn key_filter_patch() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.enable_key_filter(0.01);
+
+        let mut new = tree.clone();
+        new.insert(vec![0x34], vec![2]);
+        let patch = tree.create_patch(&mut new);
+        tree.apply_patch(&patch).unwrap();
+        assert_eq!(tree.get(&vec![0x34]), Some(&vec![2]));
+    }
+}
@@ -2,32 +2,180 @@
 
 #![deny(warnings)]
 
-pub use self::codec::Encode;
+#[cfg(feature = "alloc-stats")]
+pub use self::alloc::AllocStats;
+#[cfg(feature = "node-ids")]
+pub use self::node_id::NodeId;
+#[cfg(feature = "paranoid")]
+use self::shadow::ShadowMap;
+#[cfg(feature = "execution-witness")]
+pub use self::witness::WitnessJsonError;
+pub use self::{
+    account::{Account, EMPTY_CODE_HASH, EMPTY_STORAGE_ROOT},
+    account_proof::{AccountProof, AccountProofError, StorageProof, VerifiedAccount},
+    backed::{BackedTrie, EvictionConfig, EvictionPolicy},
+    batch::{Batch, RootChanged, StateChange},
+    cache::{CacheConfig, CacheCounters, CacheStats},
+    checksum::ContentDigest,
+    chunked::ChunkedBytes,
+    codec::{Decode, Encode, FieldDecoder, FieldEncoder, Rlp, RlpIndex, ValueDecode, ValueEncode},
+    compaction::{
+        collect_garbage, spawn_compaction, CompactableDb, CompactionConfig, CompactionHandle,
+        CompactionProgress,
+    },
+    corruption::CorruptionError,
+    db::{
+        check_completeness, compute_root_from_store, compute_root_from_store_bounded,
+        from_node_stream, get_proof_from_db, load_tree, AtomicDb, CompletenessReport, HashMismatch,
+        LoadError, MemoryDb, MissingNode, NodeDb, NodeStreamError, ReadPanicked, RootAuditError,
+        RootedDb, StatsDb, StorageStats, UndecodableNode, WriteBatch, DEFAULT_WORKING_SET,
+    },
+    db_iter::{
+        iter_db, iter_db_prefetching, scan_prefix_db, DbIterator, DbTrie, LenEstimate,
+        PrefetchConfig, Quarantine,
+    },
+    diff::Difference,
+    divergence::{Divergence, DivergenceReason, NodeSummary},
+    fixed_path::FixedPath,
+    forest::{AtomicCommitError, StorageError, TrieForest},
+    frozen::FrozenTrie,
+    fsck::{check, FsckReport, FsckRoot, LenMismatch},
+    history::ValueVersion,
+    iter::{CursorToken, InvalidCursorToken, Page, ProofIterator, StaleCursor, TreeIterator},
+    lifecycle::{CollapseEvent, RootChangeEvent, SplitEvent},
+    map::{MapIter, MapPrefixes, PatriciaMap},
+    merge::Conflict,
+    namespace::{Namespace, NamespaceIter},
+    node::NodeKind,
+    node_changes::NodeChanges,
+    ops::{apply_ops, check_ops, minimize_ops, OpsFailure, Reproducer, TreeOp},
+    partial::{PartialTrie, PartialTrieError},
+    patch::{apply_patch, create_patch, Patch, PatchError},
+    persistent::PersistentTrie,
+    pin::{PinError, PinHandle},
+    proof::{
+        explain_proof, update_proof, verify_gap_proof, verify_proof, verify_range_proof,
+        ProofFailure, RangeProofError, RemovalProof,
+    },
+    rlp::{ChildRef, DecodeError, RawNode},
+    secure::SecureTree,
+    set_ops::{Intersection, SymmetricDifference},
+    snap::{get_account_range, verify_storage_ranges, AccountRange, StorageRanges},
+    snapshot::{load_snapshot, read_snapshot_chain, SnapshotError, SnapshotWriter},
+    sparse::{SparseKey, SparseMerkleTree, SparseProof},
+    subtrie::{SubTrie, SubTrieIter},
+    telemetry::{Operation, SlowOpEvent, SLOW_OP_KEY_PREFIX_LEN},
+    test_tree::TestTreeBuilder,
+    util::compute_hash_from_sorted_iter,
+    value_codec::{CodecTree, ValueCodec},
+    verifier::{verify_proof_in_place, VerifyError},
+    watch::ChangeEvent,
+    witness::{AccessList, AccessListItem, Address, ExecutionWitness, StorageKey, WitnessRecorder},
+};
 use self::{
-    nibble::NibbleSlice,
-    node::{InsertAction, Node},
-    nodes::LeafNode,
-    storage::{NodeRef, NodesStorage, ValueRef, ValuesStorage},
+    alloc::AllocTracker,
+    cache::{LeafCache, NegativeCache},
+    hash_index::HashIndex,
+    history::History,
+    key_filter::KeyFilter,
+    lifecycle::LifecycleHooks,
+    node::Node,
+    node_changes::PathSnapshot,
+    pin::Pins,
+    storage::{NodeRef, NodesStorage, StoredValue, ValueRef, ValuesStorage},
+    telemetry::SlowOps,
+    watch::Watchers,
 };
 use digest::{Digest, Output};
-use hashing::NodeHashRef;
-use slab::Slab;
 use std::{
     fmt::Debug,
-    mem::{replace, size_of},
+    hash::Hash,
+    mem::{size_of, take},
+    ops::RangeBounds,
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
 };
 
+mod account;
+mod account_proof;
+mod alloc;
+mod backed;
+mod batch;
+mod bounds;
+mod cache;
+mod checksum;
+mod chunked;
 mod codec;
+mod compact;
+mod compaction;
+mod cooperative;
+mod corruption;
+mod db;
+mod db_iter;
+mod diff;
+mod divergence;
 #[cfg(feature = "tree-dump")]
 pub mod dump;
+#[cfg(feature = "eth-tests")]
+pub mod eth_tests;
+mod fixed_path;
+mod forest;
+mod frozen;
+mod fsck;
+#[cfg(feature = "genesis")]
+pub mod genesis;
+#[cfg(feature = "geth-dump")]
+pub mod geth_dump;
+mod hash_index;
 mod hashing;
-mod nibble;
+mod hex;
+mod history;
+mod integrity;
+mod intern;
+mod iter;
+mod key_filter;
+mod lifecycle;
+mod map;
+mod map_values;
+mod merge;
+mod namespace;
+pub mod nibble;
 mod node;
+mod node_changes;
+#[cfg(feature = "node-ids")]
+mod node_id;
 mod nodes;
+mod ops;
+mod partial;
+mod patch;
+mod persistent;
+mod pin;
+mod proof;
+mod range;
+mod rlp;
+mod secure;
+mod set_ops;
+#[cfg(feature = "paranoid")]
+mod shadow;
+mod snap;
+mod snapshot;
+mod sparse;
 mod storage;
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies;
+mod subtrie;
+mod telemetry;
+mod test_tree;
 mod util;
+mod value_codec;
+mod verifier;
+mod watch;
+mod witness;
 
 /// Patricia Merkle Tree implementation.
+///
+/// Cloning a tree is O(1): clones share their nodes and values, and modifying one of them only
+/// copies the few chunks of storage holding the nodes and values it changes.
 #[derive(Clone, Debug, Default)]
 pub struct PatriciaMerkleTree<P, V, H>
 where
@@ -44,6 +192,24 @@ where
     values: ValuesStorage<P, V>,
 
     hash: (bool, Output<H>),
+    /// Number of mutations which changed the tree's contents.
+    generation: u64,
+    /// The root and number of values as of the last commit (or load).
+    committed: Option<(Vec<u8>, usize)>,
+
+    watchers: Watchers,
+    slow_ops: SlowOps,
+    lifecycle: LifecycleHooks,
+    pins: Pins,
+    history: History,
+    key_filter: KeyFilter,
+    negative_cache: NegativeCache,
+    leaf_cache: LeafCache,
+    hash_index: HashIndex,
+    allocations: AllocTracker,
+    /// A copy of the contents, checked against the tree after every mutation.
+    #[cfg(feature = "paranoid")]
+    shadow: ShadowMap,
 }
 
 impl<P, V, H> PatriciaMerkleTree<P, V, H>
@@ -56,17 +222,66 @@ where
     pub fn new() -> Self {
         Self {
             root_ref: NodeRef::default(),
-            nodes: Slab::new(),
-            values: Slab::new(),
+            nodes: NodesStorage::new(),
+            values: ValuesStorage::new(),
             hash: (false, Default::default()),
+            generation: 0,
+            committed: None,
+            watchers: Watchers::default(),
+            slow_ops: SlowOps::default(),
+            lifecycle: LifecycleHooks::default(),
+            pins: Pins::default(),
+            history: History::default(),
+            key_filter: KeyFilter::default(),
+            negative_cache: NegativeCache::default(),
+            leaf_cache: LeafCache::default(),
+            hash_index: HashIndex::default(),
+            allocations: AllocTracker::default(),
+            #[cfg(feature = "paranoid")]
+            shadow: ShadowMap::default(),
         }
     }
 
+    /// Create an empty tree with the given lookup caches.
+    pub fn with_cache_config(config: &CacheConfig) -> Self {
+        let mut tree = Self::new();
+        tree.set_cache_config(config);
+        tree
+    }
+
     /// Return whether the tree is empty.
     pub fn is_empty(&self) -> bool {
         self.nodes.is_empty()
     }
 
+    /// Check the tree's internal structure: every node and value referenced must be stored, paths
+    /// must lead to the values stored under them, and nodes must have the shape the tree always
+    /// leaves them in (for example, extensions are always followed by a branch).
+    ///
+    /// It visits the whole tree. With the `debug-invariants` feature, the nodes along the path of
+    /// every insertion and removal are checked right after it, panicking on failure.
+    pub fn verify_integrity(&self) -> Result<(), CorruptionError> {
+        integrity::check_tree(self)
+    }
+
+    /// Return the identifier of every node (see [`NodeId`]), along with the nibbles from the root
+    /// up to it, in pre-order.
+    #[cfg(feature = "node-ids")]
+    pub fn node_ids(&self) -> Vec<(Vec<u8>, NodeId)> {
+        node_id::collect(self)
+    }
+
+    /// Return the nibbles from the root up to the node with the given identifier, or `None` if
+    /// it's no longer in the tree.
+    ///
+    /// It visits the whole tree.
+    #[cfg(feature = "node-ids")]
+    pub fn find_node(&self, id: NodeId) -> Option<Vec<u8>> {
+        node_id::collect(self)
+            .into_iter()
+            .find_map(|(path_prefix, x)| (x == id).then_some(path_prefix))
+    }
+
     /// Return the number of values in the tree.
     pub fn len(&self) -> usize {
         self.values.len()
@@ -74,133 +289,727 @@ where
 
     /// Retrieve a value from the tree given its path.
     pub fn get(&self, path: &P) -> Option<&V> {
-        if !self.root_ref.is_valid() {
-            return None;
+        self.try_get(path).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`get`](Self::get), but returns an error instead of panicking if the tree's
+    /// internal structure is found to be inconsistent.
+    pub fn try_get(&self, path: &P) -> Result<Option<&V>, CorruptionError> {
+        let start = self.slow_ops.start();
+        let value = self.get_value(path);
+        if start.is_some() {
+            self.finish_op(start, Operation::Get, path.encode().as_ref());
         }
 
-        let root_node = self
-            .nodes
-            .get(*self.root_ref)
-            .expect("inconsistent internal tree structure");
+        value
+    }
 
+    fn get_value(&self, path: &P) -> Result<Option<&V>, CorruptionError> {
         let encoded_path = path.encode();
-        root_node.get(
+        if !self.root_ref.is_valid()
+            || !self.key_filter.may_contain(encoded_path.as_ref())
+            || self.negative_cache.get(encoded_path.as_ref()).is_some()
+        {
+            return Ok(None);
+        }
+
+        // Value slots are reused, so the cached one may hold another path's value by now.
+        let cached = self.leaf_cache.get(encoded_path.as_ref(), |value_ref| {
+            self.values
+                .get(*value_ref)
+                .is_some_and(|(path, _)| path.encode().as_ref() == encoded_path.as_ref())
+        });
+        if let Some(value_ref) = cached {
+            return Ok(Some(&self.values[*value_ref].1));
+        }
+
+        let value_ref = node::get_value_ref(
+            self.root_ref,
             &self.nodes,
             &self.values,
-            NibbleSlice::new(encoded_path.as_ref()),
-        )
+            encoded_path.as_ref(),
+        )?;
+        match value_ref {
+            Some(value_ref) => {
+                self.leaf_cache.insert(encoded_path.as_ref(), value_ref);
+                Ok(Some(&self.values[*value_ref].1))
+            }
+            None => {
+                self.negative_cache.insert(encoded_path.as_ref(), ());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Iterate over the tree's values in ascending path order.
+    pub fn iter(&self) -> TreeIterator<'_, P, V, H> {
+        TreeIterator::new(self, None)
+    }
+
+    /// Iterate over the tree's values in ascending path order, starting at `start` (inclusive).
+    pub fn iter_from(&self, start: &P) -> TreeIterator<'_, P, V, H> {
+        TreeIterator::new(self, Some(start))
+    }
+
+    /// Resume an iteration in ascending path order, after the values returned before `cursor`
+    /// was taken (see [`TreeIterator::cursor_token`]).
+    pub fn iter_from_cursor(&self, cursor: &CursorToken) -> TreeIterator<'_, P, V, H> {
+        TreeIterator::from_cursor(self, cursor)
+    }
+
+    /// Same as [`iter_from_cursor`](Self::iter_from_cursor), but fails if the tree was modified
+    /// since the cursor was taken (or its generation is unknown), instead of resuming from the
+    /// values there at this time.
+    pub fn iter_from_cursor_checked(
+        &self,
+        cursor: &CursorToken,
+    ) -> Result<TreeIterator<'_, P, V, H>, StaleCursor> {
+        match cursor.generation() {
+            Some(generation) if generation == self.generation => {
+                Ok(TreeIterator::from_cursor(self, cursor))
+            }
+            generation => Err(StaleCursor {
+                cursor: generation,
+                tree: self.generation,
+            }),
+        }
+    }
+
+    /// Return the tree's generation: the number of mutations which changed its contents.
+    ///
+    /// Generations are only comparable within the same tree, since its clones keep counting
+    /// independently.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Return the value with the lowest path whose encoding starts with `prefix`, if any.
+    ///
+    /// Only the nodes along the prefix and the leftmost ones below it are visited.
+    pub fn first_under_prefix(&self, prefix: &[u8]) -> Option<(&P, &V)> {
+        bounds::find_under_prefix(self, prefix, false)
+    }
+
+    /// Return the value with the highest path whose encoding starts with `prefix`, if any.
+    ///
+    /// Only the nodes along the prefix and the rightmost ones below it are visited.
+    pub fn last_under_prefix(&self, prefix: &[u8]) -> Option<(&P, &V)> {
+        bounds::find_under_prefix(self, prefix, true)
+    }
+
+    /// Return a copy of every value in ascending path order.
+    pub fn to_vec(&self) -> Vec<(P, V)>
+    where
+        P: Clone,
+        V: Clone,
+    {
+        let mut entries = Vec::with_capacity(self.len());
+        entries.extend(
+            self.iter()
+                .map(|(path, value)| (path.clone(), value.clone())),
+        );
+        entries
+    }
+
+    /// Consume the tree, returning every value in ascending path order.
+    ///
+    /// Values are moved out of the tree, unless it shares them with a clone.
+    pub fn into_sorted_vec(mut self) -> Vec<(P, V)> {
+        let mut iter = self.iter();
+        let mut value_refs = Vec::with_capacity(self.len());
+        while let Some(value_ref) = iter.next_ref() {
+            value_refs.push(value_ref);
+        }
+
+        value_refs
+            .into_iter()
+            .map(|value_ref| self.values.remove(*value_ref))
+            .collect()
+    }
+
+    /// Return up to `limit` values in ascending path order, strictly after `after` (or from the
+    /// first one if `None`), along with the token from which to continue if there are more.
+    pub fn get_page(&self, after: Option<&P>, limit: usize) -> Page<'_, P, V> {
+        let cursor = CursorToken::new(after.map(|x| x.encode()).as_deref(), self.generation);
+        let mut iter = self.iter_from_cursor(&cursor).peekable();
+        let entries = iter.by_ref().take(limit).collect::<Vec<_>>();
+        let last = entries.last().map(|(path, _)| *path).or(after);
+        let next = iter
+            .peek()
+            .map(|_| CursorToken::new(last.map(|x| x.encode()).as_deref(), self.generation));
+
+        Page { entries, next }
+    }
+
+    /// Call `f` with every value in ascending path order, yielding to the executor every
+    /// `yield_every` values so that iterating over a large tree from an async task doesn't starve
+    /// the others.
+    pub async fn for_each_yielding(&self, yield_every: usize, f: impl FnMut(&P, &V)) {
+        cooperative::for_each(self, yield_every, f).await
+    }
+
+    /// Iterate over the tree's values in ascending path order, along with the proof of each one
+    /// against the root hash (as returned by [`get_proof`](Self::get_proof)).
+    pub fn iter_with_proofs(&self) -> ProofIterator<'_, P, V, H> {
+        ProofIterator::new(self)
     }
 
     /// Insert a value into the tree.
     pub fn insert(&mut self, path: P, value: V) -> Option<V> {
-        // Mark hash as dirty.
-        self.hash.0 = false;
+        self.try_insert(path, value)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
 
-        if let Some(root_node) = self.nodes.try_remove(*self.root_ref) {
-            // If the tree is not empty, call the root node's insertion logic.
-            let encoded_path = path.encode();
-            let (root_node, insert_action) = root_node.insert(
-                &mut self.nodes,
-                &mut self.values,
-                NibbleSlice::new(encoded_path.as_ref()),
-            );
-            self.root_ref = NodeRef::new(self.nodes.insert(root_node));
-
-            match insert_action.quantize_self(self.root_ref) {
-                InsertAction::Insert(node_ref) => {
-                    let value_ref = ValueRef::new(self.values.insert((path, value)));
-                    match self
-                        .nodes
-                        .get_mut(*node_ref)
-                        .expect("inconsistent internal tree structure")
-                    {
-                        Node::Leaf(leaf_node) => leaf_node.update_value_ref(value_ref),
-                        Node::Branch(branch_node) => branch_node.update_value_ref(value_ref),
-                        _ => panic!("inconsistent internal tree structure"),
-                    };
-
-                    None
-                }
-                InsertAction::Replace(value_ref) => {
-                    let (_, old_value) = self
-                        .values
-                        .get_mut(*value_ref)
-                        .expect("inconsistent internal tree structure");
-
-                    Some(replace(old_value, value))
-                }
-                _ => unreachable!(),
-            }
-        } else {
-            // If the tree is empty, just add a leaf.
-            let value_ref = ValueRef::new(self.values.insert((path, value)));
-            self.root_ref = NodeRef::new(self.nodes.insert(LeafNode::new(value_ref).into()));
+    /// Same as [`insert`](Self::insert), but returns an error instead of panicking if the tree's
+    /// internal structure is found to be inconsistent.
+    pub fn try_insert(&mut self, path: P, value: V) -> Result<Option<V>, CorruptionError> {
+        let start = self.slow_ops.start();
+        let slow_path = start.map(|_| path.encode().into_owned());
+        let change = self
+            .is_tracked(path.encode().as_ref())
+            .then(|| (path.encode().into_owned(), value.encode().into_owned()));
+        let lifecycle = (!self.lifecycle.is_empty()).then(|| {
+            let path = path.encode().into_owned();
+            let snapshot = PathSnapshot::take(self, &path);
+            (path, snapshot)
+        });
+
+        let old_value = self.insert_value(path, value)?;
+        self.allocations.update(self.memory_usage().1);
+        if let Some((path, value)) = change {
+            let old_value = old_value.as_ref().map(|x| x.encode().into_owned());
+            self.track_change(path, old_value, Some(value));
+        }
+        if let Some((path, before)) = lifecycle {
+            let after = PathSnapshot::take(self, &path);
+            self.lifecycle.notify_splits(&path, &before, &after);
+        }
+        if let Some(path) = slow_path {
+            self.finish_op(start, Operation::Insert, &path);
+        }
 
-            None
+        Ok(old_value)
+    }
+
+    fn insert_value(&mut self, path: P, value: V) -> Result<Option<V>, CorruptionError> {
+        #[cfg(any(feature = "debug-invariants", feature = "paranoid"))]
+        let encoded_path = path.encode().into_owned();
+        #[cfg(feature = "paranoid")]
+        let encoded_value = value.encode().into_owned();
+        if self.negative_cache.is_enabled() {
+            self.negative_cache.remove(path.encode().as_ref());
+        }
+        let filter_path = self
+            .key_filter
+            .is_enabled()
+            .then(|| path.encode().into_owned());
+        let dirty_path = self
+            .lifecycle
+            .has_root_change_hooks()
+            .then(|| path.encode().into_owned());
+
+        let old_value = self.insert_node(path, value)?;
+        if let (Some(path), None) = (filter_path, &old_value) {
+            if let Some(false_positive_rate) = self.key_filter.insert(&path) {
+                self.enable_key_filter(false_positive_rate);
+            }
         }
+        if let Some(path) = dirty_path {
+            let node_refs = lifecycle::dirty_path_refs(self, &path);
+            self.lifecycle.mark_dirty(node_refs);
+        }
+        self.generation += 1;
+        self.update_pins();
+        #[cfg(feature = "debug-invariants")]
+        if let Err(error) = integrity::check_path(self, &encoded_path) {
+            panic!("{error} after insertion");
+        }
+        #[cfg(feature = "paranoid")]
+        {
+            self.shadow.insert(encoded_path.clone(), encoded_value);
+            self.shadow.check(self, "insertion", &encoded_path);
+        }
+
+        Ok(old_value)
+    }
+
+    fn insert_node(&mut self, path: P, value: V) -> Result<Option<V>, CorruptionError> {
+        // Mark hash as dirty.
+        self.hash.0 = false;
+
+        node::insert_value(
+            &mut self.root_ref,
+            &mut self.nodes,
+            &mut self.values,
+            path,
+            value,
+        )
     }
 
     /// Remove a value from the tree.
     pub fn remove(&mut self, path: P) -> Option<V> {
-        if !self.root_ref.is_valid() {
-            return None;
+        self.try_remove(path).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`remove`](Self::remove), but returns an error instead of panicking if the tree's
+    /// internal structure is found to be inconsistent.
+    pub fn try_remove(&mut self, path: P) -> Result<Option<V>, CorruptionError> {
+        let start = self.slow_ops.start();
+        let slow_path = start.map(|_| path.encode().into_owned());
+        let tracked_path = self
+            .is_tracked(path.encode().as_ref())
+            .then(|| path.encode().into_owned());
+        let lifecycle = (!self.lifecycle.is_empty()).then(|| {
+            let path = path.encode().into_owned();
+            let snapshot = PathSnapshot::take(self, &path);
+            (path, snapshot)
+        });
+
+        let old_value = self.remove_value(path)?;
+        if let Some(path) = tracked_path {
+            let old_value = old_value.as_ref().map(|x| x.encode().into_owned());
+            self.track_change(path, old_value, None);
+        }
+        if let Some((path, before)) = lifecycle {
+            let after = PathSnapshot::take(self, &path);
+            self.lifecycle.notify_collapses(&path, &before, &after);
+        }
+        if let Some(path) = slow_path {
+            self.finish_op(start, Operation::Remove, &path);
+        }
+
+        Ok(old_value)
+    }
+
+    /// Report an operation on `path` (encoded) started at `start` if it was slow.
+    fn finish_op(&self, start: Option<Instant>, operation: Operation, path: &[u8]) {
+        self.slow_ops.finish(start, |duration| {
+            let nodes_visited = telemetry::path_depth(self, path);
+            SlowOpEvent::new(operation, path, duration, nodes_visited, 0)
+        });
+    }
+
+    /// Same as [`insert`](Self::insert), but also returns the node references created, modified
+    /// and freed by the insertion.
+    pub fn insert_tracked(&mut self, path: P, value: V) -> (Option<V>, NodeChanges) {
+        let encoded_path = path.encode().into_owned();
+        let before = PathSnapshot::take(self, &encoded_path);
+        let old_value = self.insert(path, value);
+        let after = PathSnapshot::take(self, &encoded_path);
+
+        (old_value, before.diff(&after, true))
+    }
+
+    /// Same as [`remove`](Self::remove), but also returns the node references created, modified
+    /// and freed by the removal.
+    pub fn remove_tracked(&mut self, path: P) -> (Option<V>, NodeChanges) {
+        let encoded_path = path.encode().into_owned();
+        let before = PathSnapshot::take(self, &encoded_path);
+        let old_value = self.remove(path);
+        let after = PathSnapshot::take(self, &encoded_path);
+        let changes = before.diff(&after, old_value.is_some());
+
+        (old_value, changes)
+    }
+
+    /// Remove a value from the tree, returning it along with the proof that it was in the tree
+    /// against the root hash before the removal.
+    pub fn remove_with_proof(&mut self, path: P) -> Option<RemovalProof<V>> {
+        self.get(&path)?;
+        let old_root = self.compute_hash().to_vec();
+        let proof = self.get_proof(&path);
+        let value = self.remove(path)?;
+
+        Some(RemovalProof {
+            value,
+            old_root,
+            proof,
+        })
+    }
+
+    /// Return whether changes at `path` (encoded) have to be recorded or notified.
+    fn is_tracked(&self, path: &[u8]) -> bool {
+        self.history.is_enabled() || self.watchers.is_watched(path)
+    }
+
+    /// Record a change in the history and notify it to the watchers (everything encoded).
+    fn track_change(
+        &mut self,
+        path: Vec<u8>,
+        old_value: Option<Vec<u8>>,
+        new_value: Option<Vec<u8>>,
+    ) {
+        let event = match (old_value, new_value.clone()) {
+            (None, Some(value)) => ChangeEvent::Insert { path, value },
+            (Some(old_value), Some(new_value)) => ChangeEvent::Update {
+                path,
+                old_value,
+                new_value,
+            },
+            (Some(old_value), None) => ChangeEvent::Remove { path, old_value },
+            (None, None) => return,
+        };
+
+        self.history.record(event.path(), new_value);
+        self.watchers.notify(event);
+    }
+
+    fn remove_value(&mut self, path: P) -> Result<Option<V>, CorruptionError> {
+        #[cfg(any(feature = "debug-invariants", feature = "paranoid"))]
+        let encoded_path = path.encode().into_owned();
+        if self.leaf_cache.is_enabled() {
+            self.leaf_cache.remove(path.encode().as_ref());
+        }
+        let dirty_path = self
+            .lifecycle
+            .has_root_change_hooks()
+            .then(|| path.encode().into_owned());
+
+        let old_value = self.remove_node(path)?;
+        if old_value.is_some() {
+            self.generation += 1;
+        }
+        if let (Some(path), Some(_)) = (dirty_path, &old_value) {
+            let node_refs = lifecycle::dirty_path_refs(self, &path);
+            self.lifecycle.mark_dirty(node_refs);
+        }
+        self.update_pins();
+        #[cfg(feature = "debug-invariants")]
+        if let Err(error) = integrity::check_path(self, &encoded_path) {
+            panic!("{error} after removal");
+        }
+        #[cfg(feature = "paranoid")]
+        {
+            self.shadow.remove(&encoded_path);
+            self.shadow.check(self, "removal", &encoded_path);
         }
 
-        let root_node = self
-            .nodes
-            .try_remove(*self.root_ref)
-            .expect("inconsistent internal tree structure");
-        let (root_node, old_value) = root_node.remove(
+        Ok(old_value)
+    }
+
+    fn remove_node(&mut self, path: P) -> Result<Option<V>, CorruptionError> {
+        let old_value = node::remove_value(
+            &mut self.root_ref,
             &mut self.nodes,
             &mut self.values,
-            NibbleSlice::new(path.encode().as_ref()),
-        );
-        self.root_ref = match root_node {
-            Some(root_node) => NodeRef::new(self.nodes.insert(root_node)),
-            None => Default::default(),
-        };
+            path.encode().as_ref(),
+        )?;
+
+        // Mark hash as dirty.
+        if old_value.is_some() {
+            self.hash.0 = false;
+        }
+
+        Ok(old_value)
+    }
+
+    /// Remove every value whose path is within `range`, returning how many were removed.
+    ///
+    /// Paths are compared by their encoding, the same order used by [`iter`](Self::iter). Whole
+    /// subtrees within the range are dropped at once, which makes it much cheaper than removing
+    /// each value, for example when expiring time-bucketed paths stored big-endian.
+    pub fn remove_range(&mut self, range: impl RangeBounds<P>) -> usize {
+        let removed = range::remove_range(self, range).unwrap_or_else(|e| panic!("{e}"));
+        self.update_pins();
+        if !removed.is_empty() {
+            self.generation += 1;
+            self.hash.0 = false;
+            self.leaf_cache.invalidate();
+            self.mark_dirty_nodes();
+        }
+
+        for (path, old_value) in &removed {
+            let path = path.encode();
+            #[cfg(feature = "paranoid")]
+            self.shadow.remove(path.as_ref());
+            if self.is_tracked(path.as_ref()) {
+                let old_value = old_value.encode().into_owned();
+                self.track_change(path.into_owned(), Some(old_value), None);
+            }
+        }
+
+        #[cfg(feature = "paranoid")]
+        self.shadow.check(self, "range removal", &[]);
+
+        removed.len()
+    }
+
+    /// Call `f` with every value whose encoded path starts with `prefix`, in ascending path order,
+    /// allowing to modify them in place. Returns how many values were visited.
+    ///
+    /// Only the hashes of the subtree holding those values (and the nodes leading to it) are
+    /// marked as dirty, whether the values were actually modified or not.
+    pub fn map_values_in_place(&mut self, prefix: &[u8], mut f: impl FnMut(&P, &mut V)) -> usize {
+        let value_refs = map_values::mark_prefix(self, prefix);
+        if value_refs.is_empty() {
+            return 0;
+        }
+
+        self.hash.0 = false;
+        self.generation += 1;
+        for value_ref in &value_refs {
+            let (path, value) = &self.values[**value_ref];
+            let encoded_path = path.encode().into_owned();
+            let old_value = self
+                .is_tracked(&encoded_path)
+                .then(|| value.encode().into_owned());
+
+            let (path, value) = self
+                .values
+                .get_mut(**value_ref)
+                .expect("inconsistent internal tree structure");
+            f(path, value);
+
+            #[cfg(feature = "paranoid")]
+            self.shadow
+                .insert(encoded_path.clone(), value.encode().into_owned());
+            if let Some(old_value) = old_value {
+                let new_value = value.encode().into_owned();
+                self.track_change(encoded_path, Some(old_value), Some(new_value));
+            }
+        }
+        self.mark_dirty_nodes();
+        #[cfg(feature = "paranoid")]
+        self.shadow.check(self, "value mapping", prefix);
+
+        value_refs.len()
+    }
+
+    /// Return the number of values whose path is within `range`, compared by their encoding like
+    /// in [`remove_range`](Self::remove_range).
+    ///
+    /// Only the nodes along both boundaries of the range are compared against it: the subtrees in
+    /// between are counted without reading their values. Nodes don't cache how many values are
+    /// below them though, so those subtrees are still walked, which makes counting O(n) in the
+    /// number of values within the range.
+    pub fn count_range(&self, range: impl RangeBounds<P>) -> usize {
+        range::count_range(self, range)
+    }
+
+    /// Start a batch of writes which are only applied to the tree when applying the batch, and
+    /// are visible to reads through it in the meantime.
+    pub fn batch(&mut self) -> Batch<'_, P, V, H> {
+        Batch::new(self)
+    }
+
+    /// Subscribe to the changes of values whose encoded path starts with `prefix`.
+    ///
+    /// Events are sent as values are inserted, updated or removed. Subscriptions end when their
+    /// receiver is dropped, and aren't carried over to clones of the tree.
+    pub fn watch(&mut self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        self.watchers.add(prefix)
+    }
+
+    /// Report the reads, insertions and removals which take longer than `threshold`.
+    ///
+    /// Only one subscription is kept: a new one replaces the previous one. Like with
+    /// [`watch`](Self::watch), it isn't carried over to clones of the tree.
+    pub fn report_slow_ops(&mut self, threshold: Duration) -> Receiver<SlowOpEvent> {
+        self.slow_ops.enable(threshold)
+    }
+
+    /// Call `callback` whenever an insertion splits a leaf or an extension, creating a branch
+    /// (see [`SplitEvent`]).
+    ///
+    /// Only insertions and removals of single values are traced, not patches nor range removals.
+    /// Callbacks aren't carried over to clones of the tree.
+    pub fn on_split(&mut self, callback: impl FnMut(&SplitEvent) + Send + Sync + 'static) {
+        self.lifecycle.on_split(Box::new(callback));
+    }
+
+    /// Call `callback` whenever a removal collapses a branch left with a single child or value
+    /// (see [`CollapseEvent`]). The same restrictions as with [`on_split`](Self::on_split) apply.
+    pub fn on_collapse(&mut self, callback: impl FnMut(&CollapseEvent) + Send + Sync + 'static) {
+        self.lifecycle.on_collapse(Box::new(callback));
+    }
+
+    /// Call `callback` whenever a commit (see [`commit`](Self::commit)) changes the committed
+    /// root (see [`RootChangeEvent`]). Callbacks aren't carried over to clones of the tree.
+    pub fn on_root_change(
+        &mut self,
+        callback: impl FnMut(&RootChangeEvent) + Send + Sync + 'static,
+    ) {
+        // Nodes modified before are only known while they're dirty.
+        let is_first = !self.lifecycle.has_root_change_hooks();
+        self.lifecycle.on_root_change(Box::new(callback));
+        if is_first {
+            self.mark_dirty_nodes();
+        }
+    }
+
+    /// Record every dirty node as modified since the last commit, for the root change callbacks.
+    /// It visits every dirty node, so it's only done after bulk mutations.
+    fn mark_dirty_nodes(&mut self) {
+        if self.lifecycle.has_root_change_hooks() {
+            let node_refs = lifecycle::dirty_node_refs(self);
+            self.lifecycle.mark_dirty(node_refs);
+        }
+    }
+
+    /// Pin the node holding the value at `path`, returning a handle to it or `None` if there's no
+    /// such value.
+    ///
+    /// The handle keeps track of the node while it's moved within the storage, until it's removed
+    /// or replaced by another node (see [`PinError`]). Pins aren't carried over to clones of the
+    /// tree, and are dropped along with their handles.
+    pub fn pin(&mut self, path: &P) -> Option<PinHandle> {
+        let mut pins = take(&mut self.pins);
+        let handle = pins.pin(self, path.encode().into_owned());
+        self.pins = pins;
+
+        handle
+    }
+
+    fn update_pins(&mut self) {
+        if !self.pins.is_empty() {
+            let mut pins = take(&mut self.pins);
+            pins.update(self);
+            self.pins = pins;
+        }
+    }
+
+    /// Start keeping every value written, so that past values can be retrieved using
+    /// [`get_versions`](Self::get_versions).
+    ///
+    /// Writes are numbered with a sequence shared by every path. Writes done before enabling
+    /// the history aren't recorded.
+    pub fn enable_history(&mut self) {
+        self.history.enable();
+    }
+
+    /// Return every value written at `path` (oldest first) since the history was enabled.
+    pub fn get_versions(&self, path: &P) -> &[ValueVersion] {
+        self.history.get(path.encode().as_ref())
+    }
+
+    /// Keep a bloom filter over the tree's paths, so that looking up paths which aren't in the tree
+    /// usually returns without walking it, with the given rate of false positives.
+    ///
+    /// The filter is rebuilt (with the same rate) as it fills up, and calling it again rebuilds it
+    /// with the new rate.
+    pub fn enable_key_filter(&mut self, false_positive_rate: f64) {
+        let paths = self.values.iter().map(|(_, (path, _))| path.encode());
+        self.key_filter
+            .rebuild(false_positive_rate, self.values.len(), paths);
+    }
+
+    /// Store equal values once, shared by every path holding them (including the values already
+    /// in the tree), so that trees holding the same value under many paths keep a single copy.
+    ///
+    /// Values modified in place by [`map_values_in_place`](Self::map_values_in_place) stop being
+    /// shared.
+    pub fn enable_value_interning(&mut self)
+    where
+        V: Clone + Eq + Hash + Send + Sync + 'static,
+    {
+        self.values.enable_interning();
+    }
+
+    /// Remember where the values of up to `capacity` recently read paths are, so that repeated
+    /// lookups of them return without walking the tree.
+    pub fn enable_leaf_cache(&mut self, capacity: usize) {
+        self.leaf_cache.configure(Some(capacity));
+    }
+
+    /// Remember up to `capacity` paths recently looked up and found not to be in the tree, so that
+    /// repeated lookups of them return without walking it. Paths are forgotten when inserted.
+    pub fn enable_negative_cache(&mut self, capacity: usize) {
+        self.negative_cache.enable(capacity);
+    }
+
+    /// Index the nodes referenced by hash, so that [`get_node_by_hash`](Self::get_node_by_hash) and
+    /// [`contains_node_hash`](Self::contains_node_hash) don't walk the tree.
+    ///
+    /// The index is brought up to date by the first lookup after the tree is modified, which walks
+    /// it once.
+    pub fn enable_hash_index(&mut self) {
+        self.hash_index.configure(true);
+    }
+
+    /// Drop the node hash index, if enabled.
+    pub fn disable_hash_index(&mut self) {
+        self.hash_index.configure(false);
+    }
+
+    /// Resize, enable or disable the lookup caches. Resized caches start empty.
+    pub fn set_cache_config(&mut self, config: &CacheConfig) {
+        self.leaf_cache.configure(config.leaf_cache);
+        self.negative_cache.configure(config.negative_cache);
+    }
+
+    /// Return the current sizes of the lookup caches.
+    pub fn cache_config(&self) -> CacheConfig {
+        CacheConfig {
+            leaf_cache: self.leaf_cache.capacity(),
+            negative_cache: self.negative_cache.capacity(),
+        }
+    }
+
+    /// Return the hit, miss and eviction counters of the lookup caches.
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            leaf_cache: self.leaf_cache.counters(),
+            negative_cache: self.negative_cache.counters(),
+        }
+    }
+
+    /// Return the sequence number of the last write recorded in the history.
+    pub fn version(&self) -> u64 {
+        self.history.version()
+    }
+
+    /// Return a digest of the tree's contents which doesn't depend on its structure, so that it
+    /// can be compared against data sets stored differently. See [`ContentDigest`].
+    pub fn content_digest(&self) -> Output<H> {
+        let mut digest = ContentDigest::<H>::new();
+        for (path, value) in self.iter() {
+            digest.add(path.encode().as_ref(), value.encode().as_ref());
+        }
 
-        old_value
+        digest.finalize()
     }
 
     /// Return the root hash of the tree (or recompute if needed).
     pub fn compute_hash(&mut self) -> &Output<H> {
         if !self.hash.0 {
-            if self.root_ref.is_valid() {
-                let root_node = self
-                    .nodes
-                    .get(*self.root_ref)
-                    .expect("inconsistent internal tree structure");
-
-                match root_node.compute_hash(&self.nodes, &self.values, 0) {
-                    NodeHashRef::Inline(x) => {
-                        H::new().chain_update(&*x).finalize_into(&mut self.hash.1)
-                    }
-                    NodeHashRef::Hashed(x) => self.hash.1.copy_from_slice(&x),
-                }
-            } else {
-                H::new()
-                    .chain_update([0x80])
-                    .finalize_into(&mut self.hash.1);
-            }
+            self.hash.1 = node::compute_root_hash(self.root_ref, &self.nodes, &self.values)
+                .unwrap_or_else(|e| panic!("{e}"));
             self.hash.0 = true;
         }
         &self.hash.1
     }
 
+    /// Same as [`compute_hash`](Self::compute_hash), but hashes the dirty nodes bottom-up and
+    /// yields to the executor every `yield_every` nodes, so that hashing a large tree from an
+    /// async task doesn't starve the others.
+    pub async fn compute_hash_yielding(&mut self, yield_every: usize) -> &Output<H> {
+        cooperative::compute_hash(self, yield_every).await
+    }
+
     /// Generate a tree from a sorted items iterator.
     ///
     /// Panics if the iterator is not sorted.
     pub fn from_sorted_iter(iter: impl IntoIterator<Item = (P, V)>) -> Self {
         let mut tree = Self::new();
+        let mut last_path = Vec::new();
         for (path, value) in iter {
+            let encoded_path = path.encode();
+            assert!(
+                tree.is_empty() || encoded_path.as_ref() > last_path.as_slice(),
+                "paths must be strictly ascending"
+            );
+            last_path.clear();
+            last_path.extend_from_slice(encoded_path.as_ref());
+            drop(encoded_path);
+
             tree.insert(path, value);
         }
 
         tree
     }
 
-    /// Compute the root hash of a tree given a ascending sorted iterator to its items.
+    /// Compute the root hash of a tree given a ascending sorted iterator to its items, without
+    /// building it (see [`compute_hash_from_sorted_iter`]).
     ///
     /// Panics if the iterator is not sorted.
     pub fn compute_hash_from_sorted_iter<'a>(
@@ -210,29 +1019,225 @@ where
         P: 'a,
         V: 'a,
     {
-        util::compute_hash_from_sorted_iter::<P, V, H>(iter)
+        compute_hash_from_sorted_iter::<P, V, H>(iter)
+    }
+
+    /// Write every node needed to rebuild the tree into a node database and return the root hash.
+    ///
+    /// The stored nodes can be checked using [`check_completeness`].
+    pub fn commit(&mut self, db: &mut impl NodeDb) -> Output<H> {
+        db::commit(self, db)
+    }
+
+    /// Build a compact read-only copy of the tree.
+    pub fn freeze(&mut self) -> FrozenTrie<H> {
+        FrozenTrie::from_tree(self)
+    }
+
+    /// Return the RLP encoding of the node with the given hash, as needed to answer node data
+    /// requests.
+    pub fn get_node_by_hash(&mut self, hash: &[u8]) -> Option<Vec<u8>> {
+        db::get_node_by_hash(self, hash)
+    }
+
+    /// Return whether the tree has a node with the given hash (see
+    /// [`get_node_by_hash`](Self::get_node_by_hash)).
+    pub fn contains_node_hash(&mut self, hash: &[u8]) -> bool {
+        match hash_index::lookup(self, hash) {
+            Some(found) => found.is_some(),
+            None => db::get_node_by_hash(self, hash).is_some(),
+        }
+    }
+
+    /// Return the encoded nodes needed to prove the value at `path` (or its absence), from the
+    /// root down.
+    ///
+    /// The proof can be checked using [`verify_proof`].
+    pub fn get_proof(&mut self, path: &P) -> Vec<Vec<u8>> {
+        proof::get_proof(self, path)
+    }
+
+    /// Return the encoded nodes needed to prove that there are no values strictly between `left`
+    /// and `right`.
+    ///
+    /// The proof can be checked using [`verify_gap_proof`].
+    pub fn get_gap_proof(&mut self, left: &P, right: &P) -> Vec<Vec<u8>> {
+        proof::get_gap_proof(self, left, right)
+    }
+
+    /// Walk both trees in lockstep and report the first structural difference between them.
+    ///
+    /// Subtrees with matching hashes are skipped. Returns `None` when both trees are identical.
+    pub fn find_divergence(&self, other: &Self) -> Option<Divergence> {
+        divergence::find_divergence(self, other)
+    }
+
+    /// Return every path whose value differs from the ones in `other`, in ascending path order.
+    ///
+    /// Subtrees with matching hashes are skipped, so the cost depends on the size of the
+    /// differences rather than on the size of the trees.
+    pub fn diff<'a>(&'a self, other: &'a Self) -> Vec<Difference<'a, P, V>> {
+        diff::diff(self, other)
+    }
+
+    /// Iterate over the paths present in exactly one of this tree and `other` (whatever their
+    /// values), in ascending path order.
+    ///
+    /// Like with [`diff`](Self::diff), subtrees with matching hashes are skipped.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Self) -> SymmetricDifference<'a, P, V, H> {
+        SymmetricDifference::new(self, other)
+    }
+
+    /// Iterate over the paths present in both this tree and `other`, along with their values in
+    /// each one (this tree's first), in ascending path order.
+    ///
+    /// Both trees are walked in lockstep, so only the subtrees they have in common are visited.
+    pub fn intersection<'a>(&'a self, other: &'a Self) -> Intersection<'a, P, V, H> {
+        Intersection::new(self, other)
+    }
+
+    /// Merge the values of `other` into this tree, calling `resolve` with the path and both
+    /// values (this tree's first) for every path whose values differ. Subtrees with matching
+    /// hashes are skipped.
+    pub fn merge_with(&mut self, other: &Self, resolve: impl FnMut(&P, &V, &V) -> V)
+    where
+        P: Clone,
+        V: Clone,
+    {
+        merge::merge_with(self, other, resolve)
+    }
+
+    /// Merge the changes this tree and `theirs` made relative to `ancestor`, returning the
+    /// merged tree along with the paths changed differently by both (which keep our value).
+    pub fn three_way_merge<'a>(
+        &'a self,
+        ancestor: &'a Self,
+        theirs: &'a Self,
+    ) -> (Self, Vec<Conflict<'a, P, V>>)
+    where
+        P: Clone,
+        V: Clone,
+        H: Clone,
+    {
+        merge::three_way_merge(ancestor, self, theirs)
+    }
+
+    /// Build the patch which brings this tree to `target`. See [`Patch`].
+    pub fn create_patch(&mut self, target: &mut Self) -> Patch {
+        patch::create_patch(self, target)
+    }
+
+    /// Apply a patch, verifying the root hash both before and after it. The tree is left
+    /// untouched if any of them doesn't match.
+    pub fn apply_patch(&mut self, patch: &Patch) -> Result<(), PatchError>
+    where
+        P: Decode,
+        V: Decode,
+    {
+        patch::apply_patch(self, patch)
     }
 
     /// Calculate approximated memory usage (both used and allocated).
+    ///
+    /// Values shared by several paths (see
+    /// [`enable_value_interning`](Self::enable_value_interning)) are counted once.
     pub fn memory_usage(&self) -> (usize, usize) {
-        let mem_consumed = size_of::<Node<P, V, H>>() * self.nodes.len()
-            + size_of::<(P, Output<H>, V)>() * self.values.len();
-        let mem_reserved = size_of::<Node<P, V, H>>() * self.nodes.capacity()
-            + size_of::<(P, Output<H>, V)>() * self.values.capacity();
+        let (values_consumed, values_reserved) =
+            self.values
+                .memory_usage(size_of::<(P, Output<H>, StoredValue<V>)>());
+        let mem_consumed = size_of::<Node<P, V, H>>() * self.nodes.len() + values_consumed;
+        let mem_reserved = size_of::<Node<P, V, H>>() * self.nodes.capacity() + values_reserved;
 
         (mem_consumed, mem_reserved)
     }
 
-    /// Use after a `.clone()` to reserve the capacity the slabs would have if they hadn't been
-    /// cloned.
+    /// Return the memory allocated by the tree, along with its peak.
     ///
-    /// Note: Used by the benchmark to mimic real conditions.
-    #[doc(hidden)]
-    pub fn reserve_next_power_of_two(&mut self) {
-        self.nodes
-            .reserve(self.nodes.capacity().next_power_of_two());
-        self.values
-            .reserve(self.values.capacity().next_power_of_two());
+    /// Nodes and values are kept in slabs, so insertions only allocate when they grow. The
+    /// memory used by long extension prefixes is found by walking every node.
+    #[cfg(feature = "alloc-stats")]
+    pub fn alloc_stats(&self) -> AllocStats {
+        AllocStats {
+            nodes: size_of::<Node<P, V, H>>() * self.nodes.capacity(),
+            values: self
+                .values
+                .memory_usage(size_of::<(P, Output<H>, StoredValue<V>)>())
+                .1,
+            nibbles: self
+                .nodes
+                .iter()
+                .map(|(_, node)| match node {
+                    Node::Extension(extension_node) => extension_node.prefix.heap_size(),
+                    _ => 0,
+                })
+                .sum(),
+            peak: self.allocations.peak(),
+        }
+    }
+
+    /// Move the nodes and values into densely packed storage, returning the amount of memory
+    /// reclaimed (as reported by [`memory_usage`](Self::memory_usage)).
+    ///
+    /// Removing values leaves vacant slots behind, which are reused by later insertions but never
+    /// released. Compacting after heavy churn releases them and improves locality.
+    pub fn compact(&mut self) -> usize {
+        let (_, allocated) = self.memory_usage();
+        self.lifecycle.forget_dirty_refs(&self.nodes);
+        compact::compact(self);
+        self.update_pins();
+        self.leaf_cache.invalidate();
+        self.hash_index.invalidate();
+        #[cfg(feature = "paranoid")]
+        self.shadow.check(self, "compaction", &[]);
+
+        allocated - self.memory_usage().1
+    }
+}
+
+impl<V, H> PatriciaMerkleTree<Vec<u8>, V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    /// Return a handle to the values whose path starts with `prefix`, which prepends and strips
+    /// it automatically.
+    pub fn namespace(&mut self, prefix: &[u8]) -> Namespace<'_, V, H> {
+        Namespace::new(self, prefix)
+    }
+}
+
+impl<H> PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>
+where
+    H: Digest,
+{
+    /// Assemble a trie from its `(hash, encoded node)` pairs, received in any order. See
+    /// [`from_node_stream`](crate::from_node_stream).
+    pub fn from_node_stream(
+        root: &[u8],
+        nodes: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<Self, NodeStreamError> {
+        db::from_node_stream(root, nodes)
+    }
+
+    /// Iterate over the accounts of a state tree in ascending path order, along with their
+    /// hashed addresses (the paths).
+    ///
+    /// Entries which aren't accounts (paths other than 32 bytes long, or values which can't be
+    /// decoded) are returned as errors, without stopping the iteration.
+    pub fn iter_accounts(
+        &self,
+    ) -> impl Iterator<Item = Result<([u8; 32], Account), DecodeError>> + '_ {
+        account::iter_accounts(self)
+    }
+
+    /// Return a typed view of the values whose path starts with `prefix`, which encodes and
+    /// decodes keys and values automatically.
+    pub fn sub_trie<K, V>(&mut self, prefix: &[u8]) -> SubTrie<'_, K, V, H>
+    where
+        K: Encode,
+        V: Encode + Decode,
+    {
+        SubTrie::new(self.namespace(prefix))
     }
 }
 
@@ -246,6 +1251,48 @@ mod test {
     use proptest::prelude::*;
     use sha3::Keccak256;
 
+    #[test]
+    fn clone_on_write() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..1024u16 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![i as u8; 32]);
+        }
+        let hash = *tree.compute_hash();
+        let shared_nodes = |a: &PatriciaMerkleTree<_, _, _>, b: &PatriciaMerkleTree<_, _, _>| {
+            let keys = 0..a.nodes.capacity();
+            keys.filter(|&x| a.nodes.shares_entry(&b.nodes, x)).count()
+        };
+
+        let mut cloned = tree.clone();
+        assert_eq!(shared_nodes(&tree, &cloned), tree.nodes.capacity());
+        assert_eq!(cloned.compute_hash(), &hash);
+
+        // Only the nodes near the modified paths are copied.
+        cloned.insert(vec![0x04, 0x00], vec![0xFF; 32]);
+        cloned.remove(vec![0x00, 0x00]);
+        assert!(shared_nodes(&tree, &cloned) > tree.nodes.capacity() / 2);
+        assert!(shared_nodes(&tree, &cloned) < tree.nodes.capacity());
+        assert_eq!(tree.get(&vec![0x00, 0x00]), Some(&vec![0x00; 32]));
+        assert_eq!(tree.get(&vec![0x04, 0x00]), None);
+        assert_eq!(tree.compute_hash(), &hash);
+        assert_ne!(cloned.compute_hash(), &hash);
+    }
+
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256>>();
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly ascending")]
+    fn from_unsorted_iter() {
+        PatriciaMerkleTree::<_, _, Keccak256>::from_sorted_iter([
+            (vec![2], vec![0]),
+            (vec![1], vec![0]),
+        ]);
+    }
+
     #[test]
     fn compute_hash() {
         let mut tree = PatriciaMerkleTree::<&[u8], &[u8], Keccak256>::new();
@@ -0,0 +1,369 @@
+use crate::{
+    node::Node, node_changes::PathSnapshot, Encode, NodeRef, NodesStorage, PatriciaMerkleTree,
+};
+use digest::Digest;
+use std::{collections::HashSet, fmt};
+
+/// A branch created by an insertion where its path diverges from an existing leaf or extension
+/// (or continues past an existing leaf), splitting it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SplitEvent {
+    pub branch_ref: usize,
+    /// Nibbles from the root up to the branch.
+    pub path_prefix: Vec<u8>,
+    /// References of the branch's children, in nibble order.
+    pub children: Vec<usize>,
+}
+
+/// A branch removed by a removal which left it with a single child or value, which took its
+/// place.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CollapseEvent {
+    pub branch_ref: usize,
+    /// Nibbles from the root up to the branch.
+    pub path_prefix: Vec<u8>,
+    /// Reference of the remaining child (before the collapse), or `None` if only the branch's
+    /// value remained.
+    pub remaining_child: Option<usize>,
+}
+
+/// A commit which changed the tree's committed root.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RootChangeEvent {
+    /// The root as of the previous commit (or load), or the empty tree's root if none.
+    pub old_root: Vec<u8>,
+    pub new_root: Vec<u8>,
+    /// Change in the number of values since the previous commit.
+    pub entry_delta: isize,
+    /// Number of nodes created or modified since the previous commit (or since the first root
+    /// change callback was registered, if later).
+    pub dirty_nodes: usize,
+}
+
+type Callback<T> = Box<dyn FnMut(&T) + Send + Sync>;
+
+/// Callbacks to be notified of structural changes.
+///
+/// Clones of a tree are independent from it, so they start without callbacks.
+#[derive(Default)]
+pub(crate) struct LifecycleHooks {
+    split: Vec<Callback<SplitEvent>>,
+    collapse: Vec<Callback<CollapseEvent>>,
+    root_change: Vec<Callback<RootChangeEvent>>,
+
+    /// Nodes created or modified since the last commit, tracked while there are root change
+    /// callbacks.
+    dirty: HashSet<NodeRef>,
+    /// Dirty nodes which were moved to other references by a compaction.
+    moved_dirty: usize,
+}
+
+impl Clone for LifecycleHooks {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Debug for LifecycleHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LifecycleHooks")
+            .field("split", &self.split.len())
+            .field("collapse", &self.collapse.len())
+            .field("root_change", &self.root_change.len())
+            .field("dirty", &self.dirty.len())
+            .finish()
+    }
+}
+
+impl LifecycleHooks {
+    pub fn on_split(&mut self, callback: Callback<SplitEvent>) {
+        self.split.push(callback);
+    }
+
+    pub fn on_collapse(&mut self, callback: Callback<CollapseEvent>) {
+        self.collapse.push(callback);
+    }
+
+    pub fn on_root_change(&mut self, callback: Callback<RootChangeEvent>) {
+        self.root_change.push(callback);
+    }
+
+    /// Return whether there are no callbacks for structural changes.
+    pub fn is_empty(&self) -> bool {
+        self.split.is_empty() && self.collapse.is_empty()
+    }
+
+    pub fn has_root_change_hooks(&self) -> bool {
+        !self.root_change.is_empty()
+    }
+
+    /// Record nodes as created or modified since the last commit.
+    pub fn mark_dirty(&mut self, node_refs: impl IntoIterator<Item = NodeRef>) {
+        if self.has_root_change_hooks() {
+            self.dirty.extend(node_refs);
+        }
+    }
+
+    /// Keep counting the dirty nodes after a compaction moved them to other references.
+    pub fn forget_dirty_refs<P, V, H>(&mut self, nodes: &NodesStorage<P, V, H>)
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        self.moved_dirty += self.dirty.drain().filter(|x| nodes.contains(**x)).count();
+    }
+
+    /// Return the number of nodes created or modified since the last commit, and start counting
+    /// them again.
+    pub fn take_dirty_count<P, V, H>(&mut self, nodes: &NodesStorage<P, V, H>) -> usize
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        // Freed references which weren't reused since are no longer in the tree.
+        let count = self.dirty.drain().filter(|x| nodes.contains(**x)).count();
+        count + std::mem::take(&mut self.moved_dirty)
+    }
+
+    pub fn notify_root_change(&mut self, event: &RootChangeEvent) {
+        self.root_change
+            .iter_mut()
+            .for_each(|callback| callback(event));
+    }
+
+    // Nodes are moved to other slots while mutating the tree, so branches are identified by their
+    // position along the path instead of their references.
+
+    /// Notify the splits done by an insertion at `path` (encoded), given snapshots of the nodes
+    /// along it before and after.
+    pub fn notify_splits(&mut self, path: &[u8], before: &PathSnapshot, after: &PathSnapshot) {
+        let existing = before.branch_offsets();
+        for node_ref in &after.path {
+            let shape = &after.shapes[node_ref];
+            if !shape.is_branch || existing.contains(&shape.path_offset) {
+                continue;
+            }
+
+            let event = SplitEvent {
+                branch_ref: **node_ref,
+                path_prefix: nibbles(path, shape.path_offset),
+                children: (shape.children.iter())
+                    .filter(|x| x.is_valid())
+                    .map(|x| **x)
+                    .collect(),
+            };
+            self.split.iter_mut().for_each(|callback| callback(&event));
+        }
+    }
+
+    /// Notify the collapses done by a removal at `path` (encoded), given snapshots of the nodes
+    /// along it before and after.
+    pub fn notify_collapses(&mut self, path: &[u8], before: &PathSnapshot, after: &PathSnapshot) {
+        let remaining = after.branch_offsets();
+        for (index, node_ref) in before.path.iter().enumerate() {
+            let shape = &before.shapes[node_ref];
+            if !shape.is_branch || remaining.contains(&shape.path_offset) {
+                continue;
+            }
+
+            // The child along the path is the one which was removed (if any).
+            let removed = before.path.get(index + 1);
+            let event = CollapseEvent {
+                branch_ref: **node_ref,
+                path_prefix: nibbles(path, shape.path_offset),
+                remaining_child: (shape.children.iter())
+                    .find(|x| x.is_valid() && Some(*x) != removed)
+                    .map(|x| **x),
+            };
+            self.collapse
+                .iter_mut()
+                .for_each(|callback| callback(&event));
+        }
+    }
+}
+
+/// Return the references of every node whose hash is dirty.
+pub(crate) fn dirty_node_refs<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>) -> Vec<NodeRef>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    // The subtrees of hashed nodes are hashed too, so they're skipped.
+    let (mut stack, mut dirty) = (vec![tree.root_ref], Vec::new());
+    while let Some(node_ref) = stack.pop() {
+        let node = match tree.nodes.get(*node_ref) {
+            Some(node) if !node.is_hashed() => node,
+            _ => continue,
+        };
+
+        match node {
+            Node::Branch(branch_node) => {
+                stack.extend(branch_node.choices.iter().filter(|x| x.is_valid()))
+            }
+            Node::Extension(extension_node) => stack.push(extension_node.child_ref),
+            Node::Leaf(_) | Node::Stub(_) => {}
+        }
+        dirty.push(node_ref);
+    }
+
+    dirty
+}
+
+/// Return the references of the dirty nodes along an encoded path and their children, which
+/// are the ones a mutation at that path can have created or modified.
+pub(crate) fn dirty_path_refs<P, V, H>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    path: &[u8],
+) -> Vec<NodeRef>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let snapshot = PathSnapshot::take(tree, path);
+    snapshot
+        .shapes
+        .into_keys()
+        .filter(|x| tree.nodes.get(**x).is_some_and(|x| !x.is_hashed()))
+        .collect()
+}
+
+/// Return the first `len` nibbles of an encoded path.
+fn nibbles(path: &[u8], len: usize) -> Vec<u8> {
+    path.iter()
+        .flat_map(|x| [x >> 4, x & 0x0F])
+        .take(len)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{load_tree, pmt_tree, MemoryDb, SnapshotWriter};
+    use sha3::{Digest, Keccak256};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn lifecycle_hooks() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let (split_sender, splits) = channel();
+        let (collapse_sender, collapses) = channel();
+        tree.on_split(move |x| split_sender.send(x.clone()).unwrap());
+        tree.on_collapse(move |x| collapse_sender.send(x.clone()).unwrap());
+
+        tree.insert(vec![0x12, 0x34], vec![1]);
+        assert_eq!(splits.try_recv().ok(), None);
+
+        // The leaf splits into an extension followed by a branch.
+        tree.insert(vec![0x12, 0x56], vec![2]);
+        let split = splits.try_recv().unwrap();
+        assert_eq!(split.path_prefix, [1, 2]);
+        assert_eq!(split.children.len(), 2);
+        assert_eq!(splits.try_recv().ok(), None);
+
+        // Another branch along the path continuing past a leaf.
+        tree.insert(vec![0x12, 0x56, 0x78], vec![3]);
+        assert_eq!(splits.try_recv().unwrap().path_prefix, [1, 2, 5, 6]);
+
+        // Insertions into an existing branch don't split anything.
+        tree.insert(vec![0x12, 0x9A], vec![4]);
+        assert_eq!(splits.try_recv().ok(), None);
+
+        tree.remove(vec![0x12, 0x56, 0x78]);
+        let collapse = collapses.try_recv().unwrap();
+        assert_eq!(collapse.path_prefix, [1, 2, 5, 6]);
+        assert_eq!(collapse.remaining_child, None);
+
+        tree.remove(vec![0x12, 0x9A]);
+        assert_eq!(collapses.try_recv().ok(), None);
+        tree.remove(vec![0x12, 0x56]);
+        let collapse = collapses.try_recv().unwrap();
+        assert_eq!(collapse.path_prefix, split.path_prefix);
+        assert!(collapse.remaining_child.is_some());
+        assert!(tree.clone().lifecycle.is_empty());
+    }
+
+    #[test]
+    fn root_change_hook() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..16u8 {
+            tree.insert(vec![i << 4], vec![i; 32]);
+        }
+        let (sender, events) = channel();
+        tree.on_root_change(move |x| sender.send(x.clone()).unwrap());
+
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.old_root, Keccak256::digest([0x80]).to_vec());
+        assert_eq!(event.new_root, root.to_vec());
+        assert_eq!(event.entry_delta, 16);
+        assert_eq!(event.dirty_nodes, 17);
+
+        // Unchanged roots aren't notified.
+        tree.commit(&mut db);
+        assert_eq!(events.try_recv().ok(), None);
+
+        // Only the nodes along the modified paths are dirty.
+        tree.remove(vec![0x00]);
+        tree.remove(vec![0x10]);
+        tree.insert(vec![0x20], vec![0xFF]);
+        let new_root = tree.commit(&mut db);
+        let event = events.try_recv().unwrap();
+        assert_eq!(
+            (event.old_root, event.new_root),
+            (root.to_vec(), new_root.to_vec())
+        );
+        assert_eq!((event.entry_delta, event.dirty_nodes), (-2, 2));
+
+        // Loaded trees start from the root they were loaded from.
+        let mut loaded = load_tree::<Keccak256, _>(&db, &root).unwrap();
+        let (sender, events) = channel();
+        loaded.on_root_change(move |x| sender.send(x.clone()).unwrap());
+        loaded.insert(vec![0x01], vec![1]);
+        loaded.commit(&mut db);
+        let event = events.try_recv().unwrap();
+        assert_eq!((event.old_root, event.entry_delta), (root.to_vec(), 1));
+    }
+
+    #[test]
+    fn root_change_dirty_nodes() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..16u8 {
+            tree.insert(vec![i << 4], vec![i; 32]);
+        }
+        let (sender, events) = channel();
+        tree.on_root_change(move |x| sender.send(x.clone()).unwrap());
+        let mut snapshots = SnapshotWriter::<Keccak256>::new();
+        let root = snapshots.checkpoint(&mut tree, Vec::new()).unwrap();
+        assert_eq!(events.try_recv().unwrap().dirty_nodes, 17);
+
+        // Hashing between mutations doesn't hide them.
+        tree.remove(vec![0x00]);
+        tree.compute_hash();
+        tree.remove(vec![0x10]);
+        tree.compute_hash();
+        tree.insert(vec![0x20], vec![0xFF]);
+
+        // Failed writes aren't commits.
+        let mut buffer = [0; 96];
+        assert!(snapshots.checkpoint(&mut tree, &mut buffer[..]).is_err());
+        assert_eq!(events.try_recv().ok(), None);
+
+        let new_root = snapshots.checkpoint(&mut tree, Vec::new()).unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!(
+            (event.old_root, event.new_root),
+            (root.to_vec(), new_root.to_vec())
+        );
+        assert_eq!((event.entry_delta, event.dirty_nodes), (-2, 2));
+
+        // Compacting moves the nodes, but they're still counted.
+        tree.insert(vec![0x30], vec![0xFF]);
+        tree.compact();
+        tree.commit(&mut MemoryDb::new());
+        assert_eq!(events.try_recv().unwrap().dirty_nodes, 2);
+    }
+}
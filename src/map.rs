@@ -0,0 +1,406 @@
+use crate::{
+    iter::TreeIterator,
+    node::{self, Node},
+    proof::to_nibbles,
+    storage::StoredValue,
+    Encode, NodeRef, NodesStorage, ValuesStorage,
+};
+use digest::{typenum::U0, OutputSizeUser};
+use std::{borrow::Cow, mem::size_of};
+
+/// Patricia tree used as a plain prefix-aware map, without any hash bookkeeping.
+///
+/// Shares the node structure of [`PatriciaMerkleTree`](crate::PatriciaMerkleTree), but none of
+/// its hooks, caches or filters. Node hashes are zero-sized and can't be computed (the map's hash
+/// type isn't a [`Digest`](digest::Digest)), which makes nodes smaller and inserts faster. Values
+/// don't need to implement [`Encode`].
+#[derive(Clone, Debug)]
+pub struct PatriciaMap<P, V>
+where
+    P: Encode,
+{
+    root_ref: NodeRef,
+    nodes: NodesStorage<P, Opaque<V>, NoHash>,
+    values: ValuesStorage<P, Opaque<V>>,
+}
+
+impl<P, V> PatriciaMap<P, V>
+where
+    P: Encode,
+{
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self {
+            root_ref: Default::default(),
+            nodes: NodesStorage::new(),
+            values: ValuesStorage::new(),
+        }
+    }
+
+    /// Return whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        !self.root_ref.is_valid()
+    }
+
+    /// Return the number of values in the map.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Retrieve a value from the map given its path.
+    pub fn get(&self, path: &P) -> Option<&V> {
+        let value_ref = node::get_value_ref(
+            self.root_ref,
+            &self.nodes,
+            &self.values,
+            path.encode().as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("{e}"))?;
+        Some(&self.values[*value_ref].1 .0)
+    }
+
+    /// Insert a value into the map.
+    pub fn insert(&mut self, path: P, value: V) -> Option<V> {
+        node::insert_value(
+            &mut self.root_ref,
+            &mut self.nodes,
+            &mut self.values,
+            path,
+            Opaque(value),
+        )
+        .unwrap_or_else(|e| panic!("{e}"))
+        .map(|x| x.0)
+    }
+
+    /// Remove a value from the map.
+    pub fn remove(&mut self, path: P) -> Option<V> {
+        node::remove_value(
+            &mut self.root_ref,
+            &mut self.nodes,
+            &mut self.values,
+            path.encode().as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("{e}"))
+        .map(|x| x.0)
+    }
+
+    /// Iterate over the map's values in ascending path order.
+    pub fn iter(&self) -> MapIter<'_, P, V> {
+        self.iter_prefix(&[])
+    }
+
+    /// Iterate over the map's values whose path starts with `prefix` (as encoded bytes), in
+    /// ascending path order.
+    ///
+    /// It only visits the nodes along the prefix and the subtree below it.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> MapIter<'_, P, V> {
+        MapIter {
+            inner: TreeIterator::with_storage(
+                self.root_ref,
+                &self.nodes,
+                &self.values,
+                Some(prefix),
+            ),
+            prefix: prefix.to_vec(),
+        }
+    }
+
+    /// Iterate over the map's values whose path (as encoded bytes) is a prefix of `path`,
+    /// shortest first.
+    ///
+    /// It only visits the nodes along `path`.
+    pub fn prefixes_of(&self, path: &[u8]) -> MapPrefixes<'_, P, V> {
+        MapPrefixes {
+            nodes: &self.nodes,
+            values: &self.values,
+            node_ref: self.root_ref,
+            path: path.to_vec(),
+            nibbles: to_nibbles(path),
+            offset: 0,
+        }
+    }
+
+    /// Return the value with the longest path (as encoded bytes) which is a prefix of `path`, if
+    /// any.
+    pub fn longest_prefix_of(&self, path: &[u8]) -> Option<(&P, &V)> {
+        self.prefixes_of(path).last()
+    }
+
+    /// Calculate approximated memory usage (both used and allocated).
+    pub fn memory_usage(&self) -> (usize, usize) {
+        let node_size = size_of::<Node<P, Opaque<V>, NoHash>>();
+        let (values_consumed, values_reserved) = self
+            .values
+            .memory_usage(size_of::<(P, StoredValue<Opaque<V>>)>());
+
+        (
+            node_size * self.nodes.len() + values_consumed,
+            node_size * self.nodes.capacity() + values_reserved,
+        )
+    }
+}
+
+impl<P, V> Default for PatriciaMap<P, V>
+where
+    P: Encode,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Iterator over the values of a map, in ascending path order (see
+/// [`iter_prefix`](PatriciaMap::iter_prefix)).
+pub struct MapIter<'a, P, V>
+where
+    P: Encode,
+{
+    inner: TreeIterator<'a, P, Opaque<V>, NoHash>,
+    /// Every path yielded starts with this one.
+    prefix: Vec<u8>,
+}
+
+impl<'a, P, V> Iterator for MapIter<'a, P, V>
+where
+    P: Encode,
+{
+    type Item = (&'a P, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, value) = self.inner.next()?;
+        path.encode()
+            .as_ref()
+            .starts_with(&self.prefix)
+            .then_some((path, &value.0))
+    }
+}
+
+/// Iterator over the values of a map whose path is a prefix of another one, shortest first (see
+/// [`prefixes_of`](PatriciaMap::prefixes_of)).
+pub struct MapPrefixes<'a, P, V>
+where
+    P: Encode,
+{
+    nodes: &'a NodesStorage<P, Opaque<V>, NoHash>,
+    values: &'a ValuesStorage<P, Opaque<V>>,
+
+    /// The next node along the path, if any.
+    node_ref: NodeRef,
+    /// The path, both encoded and as nibbles.
+    path: Vec<u8>,
+    nibbles: Vec<u8>,
+    /// Number of nibbles leading to the next node.
+    offset: usize,
+}
+
+impl<'a, P, V> Iterator for MapPrefixes<'a, P, V>
+where
+    P: Encode,
+{
+    type Item = (&'a P, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let value_ref = match self.nodes.get(*self.node_ref)? {
+                Node::Branch(branch_node) => {
+                    // A branch's value has exactly the path leading to it.
+                    self.node_ref = match self.nibbles.get(self.offset) {
+                        Some(&choice) => branch_node.choices[choice as usize],
+                        None => Default::default(),
+                    };
+                    self.offset += 1;
+
+                    if !branch_node.value_ref.is_valid() {
+                        continue;
+                    }
+                    branch_node.value_ref
+                }
+                Node::Extension(extension_node) => {
+                    let prefix = extension_node.prefix.iter().map(u8::from);
+                    let prefix = prefix.collect::<Vec<_>>();
+                    self.node_ref = match self.nibbles[self.offset..].starts_with(&prefix) {
+                        true => extension_node.child_ref,
+                        false => Default::default(),
+                    };
+                    self.offset += prefix.len();
+                    continue;
+                }
+                Node::Leaf(leaf_node) => {
+                    self.node_ref = Default::default();
+
+                    // Leaves hold the whole path, which has to be checked.
+                    let (path, _) = &self.values[*leaf_node.value_ref];
+                    if !self.path.starts_with(path.encode().as_ref()) {
+                        return None;
+                    }
+                    leaf_node.value_ref
+                }
+                // Maps are never backed by a database.
+                Node::Stub(_) => return None,
+            };
+
+            let (path, value) = &self.values[*value_ref];
+            return Some((path, &value.0));
+        }
+    }
+}
+
+/// Value wrapper which lets the map store values without an encoding (they are never hashed).
+#[derive(Clone, Debug)]
+struct Opaque<V>(V);
+
+impl<V> Encode for Opaque<V> {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&[])
+    }
+}
+
+/// Hash type with an empty output, so that node hashes take no space.
+///
+/// It isn't a [`Digest`](digest::Digest), so the hashing code can't be instantiated for maps.
+#[derive(Clone, Debug)]
+struct NoHash;
+
+impl OutputSizeUser for NoHash {
+    type OutputSize = U0;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PatriciaMerkleTree;
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+    use sha3::Keccak256;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut map = PatriciaMap::<&[u8], i32>::new();
+        assert!(map.is_empty());
+
+        assert_eq!(map.insert(b"first", 1), None);
+        assert_eq!(map.insert(b"firstly", 2), None);
+        assert_eq!(map.insert(b"first", 3), Some(1));
+        assert_eq!(map.len(), 2);
+
+        assert_eq!(map.get(&&b"first"[..]), Some(&3));
+        assert_eq!(map.get(&&b"firstly"[..]), Some(&2));
+        assert_eq!(map.get(&&b"firs"[..]), None);
+
+        assert_eq!(map.remove(b"first"), Some(3));
+        assert_eq!(map.remove(b"first"), None);
+        assert_eq!(map.get(&&b"firstly"[..]), Some(&2));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn smaller_nodes() {
+        let mut map = PatriciaMap::<Vec<u8>, Vec<u8>>::new();
+        let mut tree = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, Keccak256>::new();
+        for i in 0..64u8 {
+            map.insert(vec![i, i], vec![i]);
+            tree.insert(vec![i, i], vec![i]);
+        }
+
+        assert!(map.memory_usage().0 < tree.memory_usage().0);
+    }
+
+    #[test]
+    fn iter_prefix() {
+        let mut map = PatriciaMap::<&[u8], i32>::new();
+        for (i, path) in [&b"do"[..], b"dog", b"doge", b"horse", b"d", b"dot"]
+            .into_iter()
+            .enumerate()
+        {
+            map.insert(path, i as i32);
+        }
+
+        let paths = |prefix: &[u8]| {
+            map.iter_prefix(prefix)
+                .map(|(path, _)| *path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(b"do"), [&b"do"[..], b"dog", b"doge", b"dot"]);
+        assert_eq!(paths(b"dog"), [&b"dog"[..], b"doge"]);
+        assert_eq!(paths(b"dogs"), Vec::<&[u8]>::new());
+        assert_eq!(paths(b"h"), [&b"horse"[..]]);
+        assert_eq!(
+            paths(b""),
+            [&b"d"[..], b"do", b"dog", b"doge", b"dot", b"horse"]
+        );
+        assert_eq!(map.iter().count(), 6);
+    }
+
+    #[test]
+    fn prefixes_of() {
+        let mut map = PatriciaMap::<&[u8], i32>::new();
+        for (i, path) in [&b"do"[..], b"dog", b"doge", b"horse", b"d", b"dot"]
+            .into_iter()
+            .enumerate()
+        {
+            map.insert(path, i as i32);
+        }
+
+        let paths = |path: &[u8]| {
+            map.prefixes_of(path)
+                .map(|(path, _)| *path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(b"doges"), [&b"d"[..], b"do", b"dog", b"doge"]);
+        assert_eq!(paths(b"dot"), [&b"d"[..], b"do", b"dot"]);
+        assert_eq!(paths(b"horses"), [&b"horse"[..]]);
+        assert_eq!(paths(b"hors"), Vec::<&[u8]>::new());
+        assert_eq!(paths(b""), Vec::<&[u8]>::new());
+
+        assert_eq!(map.longest_prefix_of(b"dogma"), Some((&&b"dog"[..], &1)));
+        assert_eq!(map.longest_prefix_of(b"cat"), None);
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_prefix_apis(
+            data in btree_map(vec(0..4u8, 0..6), any::<i32>(), 0..64),
+            prefix in vec(0..4u8, 0..4),
+        ) {
+            let mut map = PatriciaMap::new();
+            for (path, value) in &data {
+                map.insert(path.clone(), *value);
+            }
+
+            let values = map.iter().collect::<Vec<_>>();
+            prop_assert_eq!(values, data.iter().collect::<Vec<_>>());
+
+            let values = map.iter_prefix(&prefix).collect::<Vec<_>>();
+            let expected = data.iter().filter(|(path, _)| path.starts_with(&prefix));
+            prop_assert_eq!(values, expected.collect::<Vec<_>>());
+
+            let values = map.prefixes_of(&prefix).collect::<Vec<_>>();
+            let expected = data.iter().filter(|(path, _)| prefix.starts_with(path));
+            prop_assert_eq!(values, expected.collect::<Vec<_>>());
+        }
+
+        #[test]
+        fn proptest_compare_btree_map(
+            data in btree_map(vec(any::<u8>(), 0..8), any::<i32>(), 0..64),
+            removed in vec(vec(any::<u8>(), 0..8), 0..16),
+        ) {
+            let mut map = PatriciaMap::new();
+            let mut model = BTreeMap::new();
+
+            for (path, value) in &data {
+                prop_assert_eq!(map.insert(path.clone(), *value), model.insert(path.clone(), *value));
+            }
+            for path in removed.iter().chain(data.keys().step_by(2)) {
+                prop_assert_eq!(map.remove(path.clone()), model.remove(path));
+            }
+
+            prop_assert_eq!(map.len(), model.len());
+            for (path, value) in &model {
+                prop_assert_eq!(map.get(path), Some(value));
+            }
+        }
+    }
+}
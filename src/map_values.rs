@@ -0,0 +1,156 @@
+use crate::{node::Node, proof::to_nibbles, Encode, NodeRef, PatriciaMerkleTree, ValueRef};
+use digest::Digest;
+
+/// Return the values whose encoded path starts with `prefix`, in ascending path order, marking
+/// the hashes of every node which depends on them as dirty: the subtree holding them and the
+/// nodes leading to it.
+pub(crate) fn mark_prefix<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    prefix: &[u8],
+) -> Vec<ValueRef>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    // Find the subtree holding the values, along with the nodes leading to it.
+    let nibbles = to_nibbles(prefix);
+    let mut rest = nibbles.as_slice();
+    let mut ancestors = Vec::new();
+    let mut node_ref = tree.root_ref;
+    while !rest.is_empty() {
+        let Some(node) = tree.nodes.get(*node_ref) else {
+            return Vec::new();
+        };
+
+        let child_ref = match node {
+            Node::Branch(branch_node) => {
+                let child_ref = branch_node.choices[rest[0] as usize];
+                rest = &rest[1..];
+                child_ref
+            }
+            Node::Extension(extension_node) => {
+                let extension_prefix = extension_node.prefix.iter().map(u8::from);
+                let extension_prefix = extension_prefix.collect::<Vec<_>>();
+                if extension_prefix.starts_with(rest) {
+                    break;
+                }
+                let Some(next) = rest.strip_prefix(extension_prefix.as_slice()) else {
+                    return Vec::new();
+                };
+
+                rest = next;
+                extension_node.child_ref
+            }
+            Node::Leaf(_) | Node::Stub(_) => break,
+        };
+        ancestors.push(node_ref);
+        node_ref = child_ref;
+    }
+
+    let mut value_refs = Vec::new();
+    let mut stack = Vec::new();
+    if node_ref.is_valid() {
+        stack.push(node_ref);
+    }
+    let mut subtree = Vec::new();
+    while let Some(node_ref) = stack.pop() {
+        subtree.push(node_ref);
+        match tree
+            .nodes
+            .get(*node_ref)
+            .expect("inconsistent internal tree structure")
+        {
+            Node::Branch(branch_node) => {
+                if branch_node.value_ref.is_valid() {
+                    value_refs.push(branch_node.value_ref);
+                }
+                let children = branch_node.choices.iter().rev();
+                stack.extend(children.filter(|x| x.is_valid()));
+            }
+            Node::Extension(extension_node) => stack.push(extension_node.child_ref),
+            Node::Leaf(leaf_node) => value_refs.push(leaf_node.value_ref),
+            // Stubbed subtrees have no values in memory.
+            Node::Stub(_) => {}
+        }
+    }
+
+    // A leaf found before reaching the end of the prefix may not match it.
+    value_refs.retain(|value_ref| {
+        let (path, _) = tree
+            .values
+            .get(**value_ref)
+            .expect("inconsistent internal tree structure");
+        path.encode().as_ref().starts_with(prefix)
+    });
+    if !value_refs.is_empty() {
+        for node_ref in ancestors.into_iter().chain(subtree) {
+            mark_as_dirty(tree, node_ref);
+        }
+    }
+
+    value_refs
+}
+
+fn mark_as_dirty<P, V, H>(tree: &mut PatriciaMerkleTree<P, V, H>, node_ref: NodeRef)
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    match tree
+        .nodes
+        .get_mut(*node_ref)
+        .expect("inconsistent internal tree structure")
+    {
+        Node::Branch(branch_node) => branch_node.hash.mark_as_dirty(),
+        Node::Extension(extension_node) => extension_node.hash.mark_as_dirty(),
+        Node::Leaf(leaf_node) => leaf_node.hash.mark_as_dirty(),
+        Node::Stub(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+
+    #[test]
+    fn map_values_in_place() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..=255u8 {
+            tree.insert(vec![0x12, i], vec![i]);
+            tree.insert(vec![0x34, i], vec![i]);
+        }
+        tree.insert(vec![0x12], vec![0]);
+        tree.compute_hash();
+
+        let receiver = tree.watch(&[0x12, 0xFF]);
+        let mut paths = Vec::new();
+        let count = tree.map_values_in_place(&[0x12], |path, value| {
+            paths.push(path.clone());
+            value.push(0xAA);
+        });
+        assert_eq!(count, 257);
+        assert!(paths.windows(2).all(|x| x[0] < x[1]));
+        assert_eq!(tree.get(&vec![0x12, 0x05]), Some(&vec![0x05, 0xAA]));
+        assert_eq!(tree.get(&vec![0x34, 0x05]), Some(&vec![0x05]));
+        assert_eq!(receiver.try_iter().count(), 1);
+
+        // Only the hashes depending on the mapped values were recomputed.
+        let Some(crate::node::Node::Branch(root)) = tree.nodes.get(*tree.root_ref) else {
+            panic!("the root should be a branch");
+        };
+        let other_ref = root.choices[3];
+        assert!(tree.nodes[*other_ref].is_hashed());
+
+        let mut expected = pmt_tree!(Vec<u8>);
+        for (path, value) in tree.iter() {
+            expected.insert(path.clone(), value.clone());
+        }
+        assert_eq!(tree.compute_hash(), expected.compute_hash());
+
+        assert_eq!(tree.map_values_in_place(&[0x12, 0x34, 0x56], |_, _| {}), 0);
+        assert_eq!(tree.map_values_in_place(&[0x56], |_, _| {}), 0);
+        assert_eq!(tree.map_values_in_place(&[], |_, _| {}), 513);
+    }
+}
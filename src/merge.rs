@@ -0,0 +1,154 @@
+use crate::{Encode, PatriciaMerkleTree};
+use digest::Digest;
+
+/// Merge the values of `other` into `tree`, calling `resolve` for every path present in both
+/// with different values.
+///
+/// Both trees are walked in lockstep (see [`diff`](PatriciaMerkleTree::diff)), so identical
+/// subtrees are skipped without visiting their values.
+pub fn merge_with<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    other: &PatriciaMerkleTree<P, V, H>,
+    mut resolve: impl FnMut(&P, &V, &V) -> V,
+) where
+    P: Clone + Encode,
+    V: Clone + Encode,
+    H: Digest,
+{
+    let changes = tree
+        .diff(other)
+        .into_iter()
+        .filter_map(|x| match (x.old_value, x.new_value) {
+            (Some(mine), Some(theirs)) => Some((x.path.clone(), resolve(x.path, mine, theirs))),
+            (None, Some(theirs)) => Some((x.path.clone(), theirs.clone())),
+            (_, None) => None,
+        })
+        .collect::<Vec<_>>();
+
+    for (path, value) in changes {
+        tree.insert(path, value);
+    }
+}
+
+/// A path changed differently by both sides of a three-way merge.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Conflict<'a, P, V> {
+    pub path: &'a P,
+    /// The values at each tree (`None` where it's missing).
+    pub ancestor: Option<&'a V>,
+    pub ours: Option<&'a V>,
+    pub theirs: Option<&'a V>,
+}
+
+/// Merge the changes both `ours` and `theirs` made relative to `ancestor`, returning the merged
+/// tree along with the paths which were changed differently by both.
+///
+/// The merged tree starts as a (cheap) clone of `ours`, and every change from `theirs` which
+/// doesn't conflict is applied to it. Conflicting paths keep our value. Only the paths changed by
+/// `theirs` are visited, since subtrees matching the ancestor's are skipped by their hashes.
+pub fn three_way_merge<'a, P, V, H>(
+    ancestor: &'a PatriciaMerkleTree<P, V, H>,
+    ours: &'a PatriciaMerkleTree<P, V, H>,
+    theirs: &'a PatriciaMerkleTree<P, V, H>,
+) -> (PatriciaMerkleTree<P, V, H>, Vec<Conflict<'a, P, V>>)
+where
+    P: Clone + Encode,
+    V: Clone + Encode,
+    H: Clone + Digest,
+{
+    let encode = |x: Option<&V>| x.map(|x| x.encode().into_owned());
+
+    let mut merged = ours.clone();
+    let mut conflicts = Vec::new();
+    for difference in ancestor.diff(theirs) {
+        let our_value = ours.get(difference.path);
+        let (ancestor_encoded, our_encoded) = (encode(difference.old_value), encode(our_value));
+
+        if our_encoded == ancestor_encoded {
+            match difference.new_value {
+                Some(value) => merged.insert(difference.path.clone(), value.clone()),
+                None => merged.remove(difference.path.clone()),
+            };
+        } else if our_encoded != encode(difference.new_value) {
+            conflicts.push(Conflict {
+                path: difference.path,
+                ancestor: difference.old_value,
+                ours: our_value,
+                theirs: difference.new_value,
+            });
+        }
+    }
+
+    (merged, conflicts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn merge_with() {
+        let mut mine = pmt_tree!(Vec<u8>);
+        mine.insert(b"counter:a".to_vec(), vec![3]);
+        mine.insert(b"counter:b".to_vec(), vec![1]);
+        mine.insert(b"mine".to_vec(), vec![0]);
+
+        let mut theirs = pmt_tree!(Vec<u8>);
+        theirs.insert(b"counter:a".to_vec(), vec![5]);
+        theirs.insert(b"counter:b".to_vec(), vec![1]);
+        theirs.insert(b"theirs".to_vec(), vec![0]);
+
+        let mut conflicts = Vec::new();
+        mine.merge_with(&theirs, |path, mine, theirs| {
+            conflicts.push(path.clone());
+            mine.iter().zip(theirs).map(|(x, y)| *x.max(y)).collect()
+        });
+
+        assert_eq!(conflicts, [b"counter:a".to_vec()]);
+        assert_eq!(mine.len(), 4);
+        assert_eq!(mine.get(&b"counter:a".to_vec()), Some(&vec![5]));
+        assert_eq!(mine.get(&b"theirs".to_vec()), Some(&vec![0]));
+
+        // Merging is idempotent.
+        let root = *mine.compute_hash();
+        mine.merge_with(&theirs, |_, _, _| unreachable!());
+        assert_eq!(*mine.compute_hash(), root);
+    }
+
+    #[test]
+    fn three_way_merge() {
+        let mut ancestor = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            ancestor.insert(vec![i], vec![i; 4]);
+        }
+
+        let mut ours = ancestor.clone();
+        ours.insert(vec![1], vec![0xAA]);
+        ours.insert(vec![2], vec![0xAA]);
+        ours.insert(vec![3], vec![0xAA]);
+        ours.remove(vec![4]);
+
+        let mut theirs = ancestor.clone();
+        theirs.insert(vec![2], vec![0xAA]);
+        theirs.insert(vec![3], vec![0xBB]);
+        theirs.remove(vec![5]);
+        theirs.insert(vec![64], vec![0xBB]);
+
+        let (mut merged, conflicts) = ours.three_way_merge(&ancestor, &theirs);
+        assert_eq!(
+            conflicts,
+            [Conflict {
+                path: &vec![3],
+                ancestor: Some(&vec![3; 4]),
+                ours: Some(&vec![0xAA]),
+                theirs: Some(&vec![0xBB]),
+            }]
+        );
+
+        let mut expected = ours.clone();
+        expected.remove(vec![5]);
+        expected.insert(vec![64], vec![0xBB]);
+        assert_eq!(merged.compute_hash(), expected.compute_hash());
+    }
+}
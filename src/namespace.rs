@@ -0,0 +1,167 @@
+use crate::{iter::TreeIterator, Encode, PatriciaMerkleTree};
+use digest::Digest;
+
+/// A handle to the values of a tree whose paths start with a given prefix.
+///
+/// Paths passed to and returned from the handle don't include the prefix, which is prepended and
+/// stripped automatically. Since prefixes are plain bytes, there's no need to care about nibble
+/// alignment when scanning.
+#[derive(Debug)]
+pub struct Namespace<'a, V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    tree: &'a mut PatriciaMerkleTree<Vec<u8>, V, H>,
+    prefix: Vec<u8>,
+}
+
+impl<'a, V, H> Namespace<'a, V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    pub(crate) fn new(tree: &'a mut PatriciaMerkleTree<Vec<u8>, V, H>, prefix: &[u8]) -> Self {
+        Self {
+            tree,
+            prefix: prefix.to_vec(),
+        }
+    }
+
+    /// Return the prefix of the namespace.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Return a handle to a nested namespace, whose prefix is appended to this one's.
+    pub fn namespace(&mut self, prefix: &[u8]) -> Namespace<'_, V, H> {
+        let prefix = self.path(prefix);
+        Namespace {
+            tree: self.tree,
+            prefix,
+        }
+    }
+
+    /// Retrieve a value from the namespace given its path.
+    pub fn get(&self, path: &[u8]) -> Option<&V> {
+        self.tree.get(&self.path(path))
+    }
+
+    /// Insert a value into the namespace.
+    pub fn insert(&mut self, path: &[u8], value: V) -> Option<V> {
+        self.tree.insert(self.path(path), value)
+    }
+
+    /// Remove a value from the namespace.
+    pub fn remove(&mut self, path: &[u8]) -> Option<V> {
+        self.tree.remove(self.path(path))
+    }
+
+    /// Iterate over the namespace's values whose path starts with `prefix`, in ascending path
+    /// order.
+    pub fn scan(&self, prefix: &[u8]) -> NamespaceIter<'_, V, H> {
+        let start = self.path(prefix);
+        NamespaceIter {
+            inner: self.tree.iter_from(&start),
+            prefix_len: self.prefix.len(),
+            start,
+        }
+    }
+
+    /// Remove every value from the namespace, returning how many were removed.
+    pub fn clear(&mut self) -> usize {
+        let start = self.prefix.clone();
+        match prefix_end(&self.prefix) {
+            Some(end) => self.tree.remove_range(start..end),
+            None => self.tree.remove_range(start..),
+        }
+    }
+
+    fn path(&self, path: &[u8]) -> Vec<u8> {
+        let mut full_path = Vec::with_capacity(self.prefix.len() + path.len());
+        full_path.extend_from_slice(&self.prefix);
+        full_path.extend_from_slice(path);
+        full_path
+    }
+}
+
+/// Iterator over the values of a namespace, with their paths stripped of the namespace's prefix.
+pub struct NamespaceIter<'a, V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    inner: TreeIterator<'a, Vec<u8>, V, H>,
+    prefix_len: usize,
+    /// Every path yielded starts with this one (which includes the namespace's prefix).
+    start: Vec<u8>,
+}
+
+impl<'a, V, H> Iterator for NamespaceIter<'a, V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    type Item = (&'a [u8], &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, value) = self.inner.next()?;
+        path.starts_with(&self.start)
+            .then(|| (&path[self.prefix_len..], value))
+    }
+}
+
+/// Return the lowest path greater than every path starting with `prefix`, if any.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let len = prefix.iter().rposition(|&x| x != 0xFF)?;
+
+    let mut end = prefix[..=len].to_vec();
+    end[len] += 1;
+    Some(end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn namespace() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(b"contract".to_vec(), b"outside".to_vec());
+        tree.insert(b"contract;a".to_vec(), b"outside".to_vec());
+
+        let mut namespace = tree.namespace(b"contract:");
+        assert_eq!(namespace.insert(b"a", b"1".to_vec()), None);
+        assert_eq!(namespace.insert(b"ab", b"2".to_vec()), None);
+        assert_eq!(namespace.insert(b"b", b"3".to_vec()), None);
+        assert_eq!(namespace.get(b"a"), Some(&b"1".to_vec()));
+        assert_eq!(namespace.get(b"contract"), None);
+
+        let paths = namespace
+            .scan(b"")
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&b"a"[..], b"ab", b"b"]);
+        let paths = namespace
+            .scan(b"a")
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&b"a"[..], b"ab"]);
+
+        namespace.namespace(b"a").insert(b"c", b"4".to_vec());
+        assert_eq!(namespace.remove(b"b"), Some(b"3".to_vec()));
+        assert_eq!(tree.get(&b"contract:ac".to_vec()), Some(&b"4".to_vec()));
+
+        assert_eq!(tree.namespace(b"contract:").clear(), 3);
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn namespace_prefix_end() {
+        assert_eq!(prefix_end(b""), None);
+        assert_eq!(prefix_end(&[0xFF, 0xFF]), None);
+        assert_eq!(prefix_end(&[0x01, 0xFF]), Some(vec![0x02]));
+        assert_eq!(prefix_end(&[0x01, 0x02]), Some(vec![0x01, 0x03]));
+    }
+}
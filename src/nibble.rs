@@ -1,5 +1,13 @@
+//! Nibble (half-byte) paths, as used by the tree to walk its nodes.
+//!
+//! Paths are split into nibbles high half first, so `[0x12, 0x34]` is `1, 2, 3, 4`. Both
+//! [`NibbleSlice`] and [`NibbleVec`] may start or end in the middle of a byte, which is why some
+//! of their methods only accept values with a matching alignment. The ones documented as
+//! alignment-agnostic work on any pair of values.
+
 use smallvec::SmallVec;
 
+/// A single nibble, converted from and into its `u8` value (`0x00` to `0x0F`).
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum Nibble {
     V0 = 0,
@@ -58,6 +66,19 @@ impl From<Nibble> for usize {
     }
 }
 
+/// Return the nibble at `index` within `data` (high nibbles first).
+fn nibble_at(data: &[u8], index: usize) -> Nibble {
+    let byte = match index & 1 {
+        0 => data[index >> 1] >> 4,
+        _ => data[index >> 1] & 0x0F,
+    };
+
+    Nibble::try_from(byte).unwrap_or_else(|_| unreachable!())
+}
+
+/// A borrowed path, along with a cursor (the offset) which is advanced as the path is consumed.
+///
+/// Iterating it yields the nibbles after the offset, advancing it.
 #[derive(Clone, Debug)]
 pub struct NibbleSlice<'a> {
     data: &'a [u8],
@@ -65,6 +86,7 @@ pub struct NibbleSlice<'a> {
 }
 
 impl<'a> NibbleSlice<'a> {
+    /// Create a slice over every nibble of `inner`, with its offset at the start.
     pub const fn new(inner: &'a [u8]) -> Self {
         Self {
             data: inner,
@@ -72,42 +94,85 @@ impl<'a> NibbleSlice<'a> {
         }
     }
 
+    /// Return the number of nibbles after the offset.
     pub const fn len(&self) -> usize {
         2 * self.data.len() - self.offset
     }
 
+    /// Return whether there are no nibbles after the offset.
+    pub const fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return how many nibbles have been consumed.
     pub const fn offset(&self) -> usize {
         self.offset
     }
 
+    /// Return the nibble `index` positions after the offset (without advancing).
+    pub fn get(&self, index: usize) -> Option<Nibble> {
+        let index = self.offset + index;
+        (index >> 1 < self.data.len()).then(|| nibble_at(self.data, index))
+    }
+
+    /// Return the nibbles before the current offset.
+    pub fn consumed(&self) -> Vec<u8> {
+        NibbleSlice::new(self.data)
+            .take(self.offset)
+            .map(u8::from)
+            .collect()
+    }
+
+    /// Copy the next `offset` nibbles into a [`NibbleVec`] (with the same alignment), without
+    /// advancing.
+    ///
+    /// Panics if there are fewer nibbles left.
     pub fn split_to_vec(&self, offset: usize) -> NibbleVec {
         NibbleVec {
             data: SmallVec::from_slice(
                 &self.data[self.offset >> 1..(self.offset + offset + 1) >> 1],
             ),
-            first_is_half: self.offset % 2 != 0,
-            last_is_half: (self.offset + offset) % 2 != 0,
+            first_is_half: self.offset & 1 != 0,
+            last_is_half: (self.offset + offset) & 1 != 0,
         }
     }
 
+    /// Advance the offset by `delta` nibbles.
     pub fn offset_add(&mut self, delta: usize) {
         self.offset += delta;
     }
 
+    /// Return whether the nibbles after the offset start with `prefix` (alignment-agnostic).
+    pub fn starts_with(&self, prefix: &NibbleVec) -> bool {
+        prefix.len() <= self.len() && self.common_prefix_len(prefix) == prefix.len()
+    }
+
+    /// Return how many of the nibbles after the offset match the start of `other`
+    /// (alignment-agnostic).
+    pub fn common_prefix_len(&self, other: &NibbleVec) -> usize {
+        if (self.offset & 1 != 0) == other.first_is_half {
+            return self.count_prefix_vec(other);
+        }
+
+        (0..self.len().min(other.len()))
+            .take_while(|index| self.get(*index) == other.get(*index))
+            .count()
+    }
+
     /// If `prefix` is a prefix of itself (with the correct nibble alignment), move the offset after
     /// the prefix and return true, otherwise return false.
     ///
     /// Unaligned comparations are bugs (panic).
-    pub fn skip_prefix(&mut self, prefix: &NibbleVec) -> bool {
+    pub(crate) fn skip_prefix(&mut self, prefix: &NibbleVec) -> bool {
         // Check alignment.
         assert_eq!(
-            (self.offset % 2 != 0),
+            (self.offset & 1 != 0),
             prefix.first_is_half,
             "inconsistent internal tree structure",
         );
 
         // Prefix can only be a prefix if self.len() >= prefix.len()
-        if self.data.len() < prefix.data.len() {
+        if self.len() < prefix.len() {
             return false;
         }
 
@@ -157,12 +222,12 @@ impl<'a> NibbleSlice<'a> {
     }
 
     /// Compare the rest of the data in self with the data in `other` after the offset in self.
-    pub fn cmp_rest(&self, other: &[u8]) -> bool {
+    pub(crate) fn cmp_rest(&self, other: &[u8]) -> bool {
         // Prepare slices.
         let mut othr_slice = &other[self.offset >> 1..];
         let mut self_slice = &self.data[self.offset >> 1..];
 
-        if self.offset % 2 != 0 {
+        if self.offset & 1 != 0 {
             if (othr_slice[0] & 0x0F) != (self_slice[0] & 0x0F) {
                 return false;
             }
@@ -174,18 +239,19 @@ impl<'a> NibbleSlice<'a> {
         self_slice == othr_slice
     }
 
-    pub fn count_prefix_vec(&mut self, other: &NibbleVec) -> usize {
+    /// Return how many of the nibbles after the offset match the start of `other`.
+    pub(crate) fn count_prefix_vec(&self, other: &NibbleVec) -> usize {
         if other.data.is_empty() {
             return 0;
         }
 
         // Check alignment and length.
-        assert_eq!(self.offset % 2 != 0, other.first_is_half);
+        assert_eq!(self.offset & 1 != 0, other.first_is_half);
 
         // Compare first nibble (if not byte-aligned).
         let mut eq_count = 0;
         if other.first_is_half {
-            if self.next().map(u8::from) == Some(other.data[0] & 0x0F) {
+            if self.get(0).map(u8::from) == Some(other.data[0] & 0x0F) {
                 eq_count += 1;
             } else {
                 return 0;
@@ -193,30 +259,26 @@ impl<'a> NibbleSlice<'a> {
         }
 
         // Compare middle bytes.
-        let mut byte_nibble_count = 0;
         let mut check_last_half = true;
-        for (a, b) in self.data[(self.offset + 1) >> 1..].iter().zip(
+        for (a, b) in self.data[(self.offset + eq_count) >> 1..].iter().zip(
             &other.data
                 [other.first_is_half as usize..other.data.len() - (other.last_is_half as usize)],
         ) {
             if a == b {
-                byte_nibble_count += 2;
-            } else if (a & 0xF0) == (b & 0xF0) {
-                byte_nibble_count += 1;
-                check_last_half = false;
-                break;
+                eq_count += 2;
             } else {
+                if (a & 0xF0) == (b & 0xF0) {
+                    eq_count += 1;
+                }
                 check_last_half = false;
                 break;
             }
         }
-        eq_count += byte_nibble_count;
-        self.offset += byte_nibble_count;
 
         // Compare last nibble (if not byte-aligned).
         if check_last_half
             && other.last_is_half
-            && self.clone().next().map(u8::from) == other.data.last().map(|x| x >> 4)
+            && self.get(eq_count).map(u8::from) == other.data.last().map(|x| x >> 4)
         {
             eq_count += 1;
         }
@@ -224,13 +286,13 @@ impl<'a> NibbleSlice<'a> {
         eq_count
     }
 
-    pub fn count_prefix_slice(&self, other: &NibbleSlice) -> usize {
+    pub(crate) fn count_prefix_slice(&self, other: &NibbleSlice) -> usize {
         // Check offset (and therefore alignment implicitly).
         assert_eq!(self.offset, other.offset);
 
         // Check first nibble (if not byte-aligned).
         let mut eq_count = 0;
-        if self.offset % 2 != 0 {
+        if self.offset & 1 != 0 {
             if (self.data[self.offset >> 1] & 0x0F) == (other.data[self.offset >> 1] & 0x0F) {
                 eq_count += 1;
             } else {
@@ -268,7 +330,7 @@ impl<'a> Iterator for NibbleSlice<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.data.get(self.offset >> 1).map(|byte| {
-            let byte = if self.offset % 2 != 0 {
+            let byte = if self.offset & 1 != 0 {
                 byte & 0x0F
             } else {
                 byte >> 4
@@ -280,16 +342,26 @@ impl<'a> Iterator for NibbleSlice<'a> {
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+/// Bytes stored inline by a [`NibbleVec`] before spilling to the heap.
+///
+/// Extension prefixes are rarely longer than a few nibbles, so they almost never allocate. The
+/// capacity is the largest one which doesn't make extension nodes bigger than branch nodes (and
+/// therefore doesn't grow `Node`).
+const INLINE_CAPACITY: usize = 111;
+
+/// An owned path, such as an extension node's prefix.
+///
+/// Short paths are stored inline. Equality compares the nibbles, regardless of their alignment.
+#[derive(Clone, Debug, Default, Eq)]
 pub struct NibbleVec {
-    data: SmallVec<[u8; 111]>,
+    data: SmallVec<[u8; INLINE_CAPACITY]>,
 
     first_is_half: bool,
     last_is_half: bool,
 }
 
 impl NibbleVec {
-    #[cfg(test)]
+    /// Create an empty path.
     pub fn new() -> Self {
         NibbleVec {
             data: Default::default(),
@@ -329,7 +401,16 @@ impl NibbleVec {
         }
     }
 
-    pub fn from_single(nibble: Nibble, is_right_half: bool) -> Self {
+    /// Copy the first `len` nibbles of `bytes`.
+    ///
+    /// Panics if `bytes` is shorter than that.
+    pub fn from_prefix(bytes: &[u8], len: usize) -> Self {
+        let mut vec = NibbleSlice::new(bytes).split_to_vec(len);
+        vec.normalize();
+        vec
+    }
+
+    pub(crate) fn from_single(nibble: Nibble, is_right_half: bool) -> Self {
         Self {
             data: SmallVec::from_elem(
                 if is_right_half {
@@ -348,23 +429,41 @@ impl NibbleVec {
         self.data.is_empty()
     }
 
+    /// Return the number of nibbles.
     pub fn len(&self) -> usize {
         2 * self.data.len() - self.first_is_half as usize - self.last_is_half as usize
     }
 
-    pub const fn iter(&self) -> NibbleVecIter {
+    /// Return the size of the heap buffer, which is only allocated for long prefixes.
+    pub(crate) fn heap_size(&self) -> usize {
+        match self.data.spilled() {
+            true => self.data.capacity(),
+            false => 0,
+        }
+    }
+
+    /// Return the nibble at `index`.
+    pub fn get(&self, index: usize) -> Option<Nibble> {
+        (index < self.len()).then(|| nibble_at(&self.data, index + self.first_is_half as usize))
+    }
+
+    pub const fn iter(&self) -> NibbleVecIter<'_> {
         NibbleVecIter {
             inner: self,
             pos: self.first_is_half as usize,
         }
     }
 
+    /// Split the path around the nibble at `index`, returning the nibbles before it, the nibble
+    /// itself and the ones after it.
+    ///
+    /// Panics if `index` is out of bounds.
     pub fn split_extract_at(self, index: usize) -> (NibbleVec, Nibble, NibbleVec) {
         let offset = (index + 1 + self.first_is_half as usize) >> 1;
         let mut left_vec = NibbleVec {
             data: SmallVec::from_slice(&self.data[..offset]),
             first_is_half: self.first_is_half,
-            last_is_half: (index + self.first_is_half as usize) % 2 != 0,
+            last_is_half: (index + self.first_is_half as usize) & 1 != 0,
         };
         left_vec.normalize();
 
@@ -374,7 +473,7 @@ impl NibbleVec {
             ((offset + self.last_is_half as usize) >> 1) < self.data.len(),
             "out of bounds"
         );
-        let value = if offset % 2 != 0 {
+        let value = if offset & 1 != 0 {
             self.data[offset >> 1] & 0x0F
         } else {
             self.data[offset >> 1] >> 4
@@ -388,7 +487,7 @@ impl NibbleVec {
             } else {
                 SmallVec::from_slice(&self.data[offset..])
             },
-            first_is_half: (index + self.first_is_half as usize) % 2 == 0,
+            first_is_half: (index + self.first_is_half as usize) & 1 == 0,
             last_is_half: self.last_is_half,
         };
         right_vec.normalize();
@@ -396,6 +495,7 @@ impl NibbleVec {
         (left_vec, value, right_vec)
     }
 
+    /// Insert a nibble at the start.
     pub fn prepend(&mut self, nibble: Nibble) {
         if self.first_is_half {
             self.data[0] = (self.data[0] & 0x0F) | ((nibble as u8) << 4);
@@ -405,9 +505,21 @@ impl NibbleVec {
         self.first_is_half = !self.first_is_half;
     }
 
+    /// Append a nibble at the end.
+    pub fn push(&mut self, nibble: Nibble) {
+        match self.last_is_half {
+            true => *self.data.last_mut().unwrap() |= nibble as u8,
+            false => self.data.push((nibble as u8) << 4),
+        }
+        self.last_is_half = !self.last_is_half;
+    }
+
+    /// Append the nibbles of `other` at the end (alignment-agnostic).
     pub fn extend(&mut self, other: &Self) {
-        // Ensure alignment.
-        assert_eq!(self.last_is_half, other.first_is_half);
+        if self.last_is_half != other.first_is_half {
+            other.iter().for_each(|x| self.push(x));
+            return;
+        }
 
         // Copy half-byte (if misaligned).
         if self.last_is_half {
@@ -434,6 +546,21 @@ impl NibbleVec {
     }
 }
 
+impl PartialEq for NibbleVec {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl FromIterator<Nibble> for NibbleVec {
+    fn from_iter<T: IntoIterator<Item = Nibble>>(iter: T) -> Self {
+        let mut vec = Self::new();
+        iter.into_iter().for_each(|x| vec.push(x));
+        vec
+    }
+}
+
+/// Iterator over the nibbles of a [`NibbleVec`].
 #[derive(Clone)]
 pub struct NibbleVecIter<'a> {
     inner: &'a NibbleVec,
@@ -446,13 +573,13 @@ impl<'a> Iterator for NibbleVecIter<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         self.inner.data.get(self.pos >> 1).and_then(|byte| {
             if (self.pos >> 1) + 1 == self.inner.data.len()
-                && self.pos % 2 == 1
+                && self.pos & 1 == 1
                 && self.inner.last_is_half
             {
                 return None;
             }
 
-            let byte = if self.pos % 2 != 0 {
+            let byte = if self.pos & 1 != 0 {
                 byte & 0x0F
             } else {
                 byte >> 4
@@ -705,7 +832,7 @@ mod test {
         };
 
         let (l, c, r) = vec.split_extract_at(0);
-        assert_eq!(l.data.as_slice(), &[]);
+        assert_eq!(l.data.as_slice(), &[] as &[u8]);
         assert!(!l.first_is_half);
         assert!(!l.last_is_half);
         assert_eq!(c, Nibble::V1);
@@ -723,7 +850,7 @@ mod test {
         };
 
         let (l, c, r) = vec.split_extract_at(0);
-        assert_eq!(l.data.as_slice(), &[]);
+        assert_eq!(l.data.as_slice(), &[] as &[u8]);
         assert!(!l.first_is_half);
         assert!(!l.last_is_half);
         assert_eq!(c, Nibble::V2);
@@ -741,7 +868,7 @@ mod test {
         };
 
         let (l, c, r) = vec.split_extract_at(0);
-        assert_eq!(l.data.as_slice(), &[]);
+        assert_eq!(l.data.as_slice(), &[] as &[u8]);
         assert!(!l.first_is_half);
         assert!(!l.last_is_half);
         assert_eq!(c, Nibble::V1);
@@ -759,7 +886,7 @@ mod test {
         };
 
         let (l, c, r) = vec.split_extract_at(0);
-        assert_eq!(l.data.as_slice(), &[]);
+        assert_eq!(l.data.as_slice(), &[] as &[u8]);
         assert!(!l.first_is_half);
         assert!(!l.last_is_half);
         assert_eq!(c, Nibble::V2);
@@ -853,7 +980,7 @@ mod test {
         assert!(!l.first_is_half);
         assert!(l.last_is_half);
         assert_eq!(c, Nibble::V6);
-        assert_eq!(r.data.as_slice(), &[]);
+        assert_eq!(r.data.as_slice(), &[] as &[u8]);
         assert!(!r.first_is_half);
         assert!(!r.last_is_half);
     }
@@ -871,7 +998,7 @@ mod test {
         assert!(l.first_is_half);
         assert!(l.last_is_half);
         assert_eq!(c, Nibble::V6);
-        assert_eq!(r.data.as_slice(), &[]);
+        assert_eq!(r.data.as_slice(), &[] as &[u8]);
         assert!(!r.first_is_half);
         assert!(!r.last_is_half);
     }
@@ -889,7 +1016,7 @@ mod test {
         assert!(!l.first_is_half);
         assert!(!l.last_is_half);
         assert_eq!(c, Nibble::V5);
-        assert_eq!(r.data.as_slice(), &[]);
+        assert_eq!(r.data.as_slice(), &[] as &[u8]);
         assert!(!r.first_is_half);
         assert!(!r.last_is_half);
     }
@@ -907,7 +1034,7 @@ mod test {
         assert!(l.first_is_half);
         assert!(!l.last_is_half);
         assert_eq!(c, Nibble::V5);
-        assert_eq!(r.data.as_slice(), &[]);
+        assert_eq!(r.data.as_slice(), &[] as &[u8]);
         assert!(!r.first_is_half);
         assert!(!r.last_is_half);
     }
@@ -1019,4 +1146,101 @@ mod test {
         assert_eq!(vec_iter.next(), None);
         assert_eq!(vec_iter.pos, 5);
     }
+
+    #[test]
+    fn nibble_vec_inline() {
+        use crate::nodes::{BranchNode, ExtensionNode};
+        use sha3::Keccak256;
+        use std::mem::size_of;
+
+        assert!(
+            size_of::<ExtensionNode<Vec<u8>, Vec<u8>, Keccak256>>()
+                <= size_of::<BranchNode<Vec<u8>, Vec<u8>, Keccak256>>()
+        );
+
+        // Splitting and merging a whole 32-byte path's worth of nibbles doesn't allocate.
+        let vec = NibbleVec::from_nibbles(
+            (0..64).map(|x| Nibble::try_from(x as u8 & 0x0F).unwrap()),
+            false,
+        );
+        let (mut left, choice, right) = vec.split_extract_at(19);
+        assert_eq!(choice, Nibble::V3);
+        left.extend(&NibbleVec::from_single(choice, true));
+        left.extend(&right);
+        assert_eq!(left.len(), 64);
+        assert!(!left.data.spilled() && !right.data.spilled());
+    }
+
+    #[test]
+    fn nibble_get() {
+        let mut slice = NibbleSlice::new(&[0x12, 0x34]);
+        slice.offset_add(1);
+        assert_eq!(slice.get(0), Some(Nibble::V2));
+        assert_eq!(slice.get(2), Some(Nibble::V4));
+        assert_eq!(slice.get(3), None);
+
+        let vec = NibbleVec {
+            data: SmallVec::from_slice(&[0x12, 0x34, 0x56]),
+            first_is_half: true,
+            last_is_half: true,
+        };
+        assert_eq!(
+            (0..5).map(|x| vec.get(x)).collect::<Vec<_>>(),
+            vec.iter().map(Some).chain([None]).collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn nibble_slice_count_prefix_vec() {
+        let vec = NibbleVec {
+            data: SmallVec::from_slice(&[0x02, 0x34, 0x50]),
+            first_is_half: true,
+            last_is_half: true,
+        };
+
+        let mut slice = NibbleSlice::new(&[0x12, 0x34, 0x56]);
+        slice.offset_add(1);
+        assert_eq!(slice.count_prefix_vec(&vec), 4);
+        assert_eq!(slice.offset(), 1);
+
+        let slice = NibbleSlice::new(&[0x12, 0x35, 0x56]);
+        let vec = NibbleVec::from_nibbles([Nibble::V1, Nibble::V2, Nibble::V3].into_iter(), false);
+        assert_eq!(slice.count_prefix_vec(&vec), 3);
+        let vec = NibbleVec::from_nibbles([Nibble::V1, Nibble::V3].into_iter(), false);
+        assert_eq!(slice.count_prefix_vec(&vec), 1);
+    }
+
+    #[test]
+    fn nibble_public_api() {
+        let nibbles = |x: &[u8]| {
+            x.iter()
+                .map(|x| Nibble::try_from(*x).unwrap())
+                .collect::<NibbleVec>()
+        };
+
+        // Equality doesn't depend on the alignment.
+        let mut slice = NibbleSlice::new(&[0x12, 0x34, 0x56]);
+        slice.offset_add(1);
+        let vec = slice.split_to_vec(3);
+        assert_eq!(vec, nibbles(&[2, 3, 4]));
+        assert_eq!(
+            NibbleVec::from_prefix(&[0x12, 0x34], 3),
+            nibbles(&[1, 2, 3])
+        );
+
+        // So do comparisons.
+        assert!(slice.starts_with(&nibbles(&[2, 3])));
+        assert!(slice.starts_with(&vec));
+        assert!(!slice.starts_with(&nibbles(&[2, 4])));
+        assert!(!slice.starts_with(&nibbles(&[2, 3, 4, 5, 6, 7])));
+        assert_eq!(slice.common_prefix_len(&nibbles(&[2, 3, 4, 6])), 3);
+
+        // And extending.
+        let mut vec = nibbles(&[1]);
+        vec.extend(&nibbles(&[2, 3]));
+        vec.extend(&NibbleVec::from_prefix(&[0x45], 1));
+        vec.push(Nibble::V5);
+        assert_eq!(vec, nibbles(&[1, 2, 3, 4, 5]));
+        assert!(vec.get(4).is_some() && vec.get(5).is_none());
+    }
 }
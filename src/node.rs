@@ -1,10 +1,14 @@
+#[cfg(feature = "node-ids")]
+use crate::NodeId;
 use crate::{
+    corruption::CorruptionError,
     hashing::NodeHashRef,
     nibble::NibbleSlice,
-    nodes::{BranchNode, ExtensionNode, LeafNode},
+    nodes::{BranchNode, ExtensionNode, LeafNode, StubNode},
+    proof::to_nibbles,
     Encode, NodeRef, NodesStorage, ValueRef, ValuesStorage,
 };
-use digest::Digest;
+use digest::{Digest, Output, OutputSizeUser};
 
 /// A node within the Patricia Merkle tree.
 ///
@@ -12,34 +16,47 @@ use digest::Digest;
 ///   - The `Branch` variant havs an optional value.
 ///   - Extension nodes are only used when followed by a branch, and never with other extensions
 ///     (they are combined) or leaves (they are removed).
+///   - The `Stub` variant only exists within a [`BackedTrie`](crate::BackedTrie).
 #[derive(Clone, Debug)]
 pub enum Node<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     Branch(BranchNode<P, V, H>),
     Extension(ExtensionNode<P, V, H>),
     Leaf(LeafNode<P, V, H>),
+    Stub(StubNode<H>),
 }
 
 impl<P, V, H> Node<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
-    pub fn get<'a>(
-        &'a self,
-        nodes: &'a NodesStorage<P, V, H>,
-        values: &'a ValuesStorage<P, V>,
+    pub const fn kind(&self) -> NodeKind {
+        match self {
+            Node::Branch(_) => NodeKind::Branch,
+            Node::Extension(_) => NodeKind::Extension,
+            Node::Leaf(_) => NodeKind::Leaf,
+            Node::Stub(_) => NodeKind::Stub,
+        }
+    }
+
+    /// Return a reference to the value at `path` in the storage, if any.
+    pub(crate) fn get_ref(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
         path: NibbleSlice,
-    ) -> Option<&V> {
+    ) -> Result<Option<ValueRef>, CorruptionError> {
         match self {
-            Node::Branch(branch_node) => branch_node.get(nodes, values, path),
-            Node::Extension(extension_node) => extension_node.get(nodes, values, path),
-            Node::Leaf(leaf_node) => leaf_node.get(nodes, values, path),
+            Node::Branch(branch_node) => branch_node.get_ref(nodes, values, path),
+            Node::Extension(extension_node) => extension_node.get_ref(nodes, values, path),
+            Node::Leaf(leaf_node) => leaf_node.get_ref(nodes, values, path),
+            Node::Stub(_) => Err(CorruptionError::within(NodeKind::Stub, path.consumed())),
         }
     }
 
@@ -48,11 +65,12 @@ where
         nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         path: NibbleSlice,
-    ) -> (Self, InsertAction) {
+    ) -> Result<(Self, InsertAction), CorruptionError> {
         match self {
             Node::Branch(branch_node) => branch_node.insert(nodes, values, path),
             Node::Extension(extension_node) => extension_node.insert(nodes, values, path),
             Node::Leaf(leaf_node) => leaf_node.insert(nodes, values, path),
+            Node::Stub(_) => Err(CorruptionError::within(NodeKind::Stub, path.consumed())),
         }
     }
 
@@ -61,35 +79,232 @@ where
         nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         path: NibbleSlice,
-    ) -> (Option<Self>, Option<V>) {
+    ) -> RemoveResult<P, V, H> {
         match self {
             Node::Branch(branch_node) => branch_node.remove(nodes, values, path),
             Node::Extension(extension_node) => extension_node.remove(nodes, values, path),
             Node::Leaf(leaf_node) => leaf_node.remove(nodes, values, path),
+            Node::Stub(_) => Err(CorruptionError::within(NodeKind::Stub, path.consumed())),
         }
     }
+}
 
+impl<P, V, H> Node<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
     pub fn compute_hash(
         &self,
         nodes: &NodesStorage<P, V, H>,
         values: &ValuesStorage<P, V>,
         path_offset: usize,
-    ) -> NodeHashRef<H> {
+    ) -> NodeHashRef<'_, H> {
         match self {
             Node::Branch(branch_node) => branch_node.compute_hash(nodes, values, path_offset),
             Node::Extension(extension_node) => {
                 extension_node.compute_hash(nodes, values, path_offset)
             }
             Node::Leaf(leaf_node) => leaf_node.compute_hash(nodes, values, path_offset),
+            Node::Stub(stub_node) => stub_node.compute_hash(),
         }
     }
+
+    /// Return whether the node's hash is cached, in which case so are the hashes of its subtree.
+    pub fn is_hashed(&self) -> bool {
+        match self {
+            Node::Branch(branch_node) => branch_node.hash.extract_ref().is_some(),
+            Node::Extension(extension_node) => extension_node.hash.extract_ref().is_some(),
+            Node::Leaf(leaf_node) => leaf_node.hash.extract_ref().is_some(),
+            Node::Stub(_) => true,
+        }
+    }
+
+    #[cfg(feature = "node-ids")]
+    pub fn id(&self) -> NodeId {
+        match self {
+            Node::Branch(branch_node) => branch_node.id,
+            Node::Extension(extension_node) => extension_node.id,
+            Node::Leaf(leaf_node) => leaf_node.id,
+            Node::Stub(stub_node) => stub_node.id,
+        }
+    }
+
+    /// Return the node's RLP encoding, or `None` for stubs (whose encoding is stored elsewhere).
+    pub fn encode(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
+        path_offset: usize,
+    ) -> Option<Vec<u8>> {
+        Some(match self {
+            Node::Branch(branch_node) => branch_node.encode(nodes, values, path_offset),
+            Node::Extension(extension_node) => extension_node.encode(nodes, values, path_offset),
+            Node::Leaf(leaf_node) => leaf_node.encode(nodes, values, path_offset),
+            Node::Stub(_) => return None,
+        })
+    }
 }
 
-impl<P, V, H> From<BranchNode<P, V, H>> for Node<P, V, H>
+/// Return a reference to the value at `path` in the tree rooted at `root_ref`, if any.
+pub(crate) fn get_value_ref<P, V, H>(
+    root_ref: NodeRef,
+    nodes: &NodesStorage<P, V, H>,
+    values: &ValuesStorage<P, V>,
+    path: &[u8],
+) -> Result<Option<ValueRef>, CorruptionError>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
+{
+    if !root_ref.is_valid() {
+        return Ok(None);
+    }
+
+    let root_node = nodes
+        .get(*root_ref)
+        .ok_or_else(|| CorruptionError::missing(root_ref, Vec::new()))?;
+    root_node
+        .get_ref(nodes, values, NibbleSlice::new(path))
+        .map_err(|e| e.at(root_ref))
+}
+
+/// Insert a value into the tree rooted at `root_ref`, returning the one it replaced (if any).
+pub(crate) fn insert_value<P, V, H>(
+    root_ref: &mut NodeRef,
+    nodes: &mut NodesStorage<P, V, H>,
+    values: &mut ValuesStorage<P, V>,
+    path: P,
+    value: V,
+) -> Result<Option<V>, CorruptionError>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
+{
+    let Some(root_node) = nodes.try_remove(**root_ref) else {
+        // If the tree is empty, just add a leaf.
+        let value_ref = ValueRef::new(values.insert((path, value)));
+        *root_ref = NodeRef::new(nodes.insert(LeafNode::new(value_ref).into()));
+
+        return Ok(None);
+    };
+
+    // If the tree is not empty, call the root node's insertion logic.
+    let encoded_path = path.encode();
+    let (root_node, insert_action) = root_node
+        .insert(nodes, values, NibbleSlice::new(encoded_path.as_ref()))
+        .map_err(|e| e.at(*root_ref))?;
+    *root_ref = NodeRef::new(nodes.insert(root_node));
+
+    match insert_action.quantize_self(*root_ref) {
+        InsertAction::Insert(node_ref) => {
+            let node = nodes.get_mut(*node_ref).ok_or_else(|| {
+                CorruptionError::missing(node_ref, to_nibbles(encoded_path.as_ref()))
+            })?;
+            if !matches!(node, Node::Leaf(_) | Node::Branch(_)) {
+                return Err(CorruptionError::within(
+                    node.kind(),
+                    to_nibbles(encoded_path.as_ref()),
+                )
+                .at(node_ref));
+            }
+
+            let value_ref = ValueRef::new(values.insert((path, value)));
+            match node {
+                Node::Leaf(leaf_node) => leaf_node.update_value_ref(value_ref),
+                Node::Branch(branch_node) => branch_node.update_value_ref(value_ref),
+                _ => unreachable!(),
+            };
+
+            Ok(None)
+        }
+        InsertAction::Replace(value_ref) => {
+            // Nodes check that the value is stored before asking to replace it.
+            let old_value = values
+                .replace(*value_ref, value)
+                .ok_or_else(|| CorruptionError::missing(*root_ref, Vec::new()))?;
+
+            Ok(Some(old_value))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Remove the value at `path` from the tree rooted at `root_ref`, returning it (if any).
+pub(crate) fn remove_value<P, V, H>(
+    root_ref: &mut NodeRef,
+    nodes: &mut NodesStorage<P, V, H>,
+    values: &mut ValuesStorage<P, V>,
+    path: &[u8],
+) -> Result<Option<V>, CorruptionError>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
+{
+    if !root_ref.is_valid() {
+        return Ok(None);
+    }
+
+    let root_node = nodes
+        .try_remove(**root_ref)
+        .ok_or_else(|| CorruptionError::missing(*root_ref, Vec::new()))?;
+    let (root_node, old_value) = root_node
+        .remove(nodes, values, NibbleSlice::new(path))
+        .map_err(|e| e.at(*root_ref))?;
+    *root_ref = match root_node {
+        Some(root_node) => NodeRef::new(nodes.insert(root_node)),
+        None => Default::default(),
+    };
+
+    Ok(old_value)
+}
+
+/// Return the root hash of the tree rooted at `root_ref`.
+pub(crate) fn compute_root_hash<P, V, H>(
+    root_ref: NodeRef,
+    nodes: &NodesStorage<P, V, H>,
+    values: &ValuesStorage<P, V>,
+) -> Result<Output<H>, CorruptionError>
 where
     P: Encode,
     V: Encode,
     H: Digest,
+{
+    if !root_ref.is_valid() {
+        return Ok(H::new().chain_update([0x80]).finalize());
+    }
+
+    let root_node = nodes
+        .get(*root_ref)
+        .ok_or_else(|| CorruptionError::missing(root_ref, Vec::new()))?;
+    Ok(match root_node.compute_hash(nodes, values, 0) {
+        NodeHashRef::Inline(x) => H::new().chain_update(x).finalize(),
+        NodeHashRef::Hashed(x) => x.clone(),
+    })
+}
+
+/// The node left after a removal (if any) along with the removed value.
+pub(crate) type RemoveResult<P, V, H> = Result<(Option<Node<P, V, H>>, Option<V>), CorruptionError>;
+
+/// The kind of a node, without its contents.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NodeKind {
+    Branch,
+    Extension,
+    Leaf,
+    /// A subtree known only by its hash (see [`BackedTrie`](crate::BackedTrie)).
+    Stub,
+}
+
+impl<P, V, H> From<BranchNode<P, V, H>> for Node<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
 {
     fn from(value: BranchNode<P, V, H>) -> Self {
         Self::Branch(value)
@@ -100,7 +315,7 @@ impl<P, V, H> From<ExtensionNode<P, V, H>> for Node<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     fn from(value: ExtensionNode<P, V, H>) -> Self {
         Self::Extension(value)
@@ -111,13 +326,24 @@ impl<P, V, H> From<LeafNode<P, V, H>> for Node<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     fn from(value: LeafNode<P, V, H>) -> Self {
         Self::Leaf(value)
     }
 }
 
+impl<P, V, H> From<StubNode<H>> for Node<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: OutputSizeUser,
+{
+    fn from(value: StubNode<H>) -> Self {
+        Self::Stub(value)
+    }
+}
+
 /// Returned by .insert() to update the values' storage.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub(crate) enum InsertAction {
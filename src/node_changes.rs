@@ -0,0 +1,246 @@
+use crate::{nibble::NibbleSlice, node::Node, Encode, NodeRef, PatriciaMerkleTree};
+use digest::Digest;
+use std::collections::{HashMap, HashSet};
+
+/// The node references (storage indices) affected by an insertion or removal, each list in
+/// ascending order.
+///
+/// Nodes whose contents changed in any way, including the hashes of their children, are
+/// reported as modified. A reference may be reported as freed by one mutation and created by a
+/// later one, since storage slots are reused.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NodeChanges {
+    pub created: Vec<usize>,
+    pub modified: Vec<usize>,
+    pub freed: Vec<usize>,
+}
+
+impl NodeChanges {
+    /// Return whether no nodes were affected.
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.modified.is_empty() && self.freed.is_empty()
+    }
+}
+
+/// A node's contents, without its cached hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Shape {
+    pub children: Vec<NodeRef>,
+    pub value_ref: Option<usize>,
+    /// The extension's prefix, in nibbles.
+    pub prefix: Vec<u8>,
+    /// Number of nibbles from the root to the node, which leaves' encodings depend on.
+    pub path_offset: usize,
+    pub is_branch: bool,
+}
+
+/// The nodes a mutation at some path can affect: the ones along the path and their children.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PathSnapshot {
+    pub shapes: HashMap<NodeRef, Shape>,
+    /// The nodes along the path, from the root down.
+    pub path: Vec<NodeRef>,
+}
+
+impl PathSnapshot {
+    /// Take a snapshot of the nodes along an encoded path and their children.
+    pub fn take<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>, path: &[u8]) -> Self
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        let mut snapshot = Self::default();
+        let mut path = NibbleSlice::new(path);
+        let mut node_ref = tree.root_ref;
+        let mut path_offset = 0;
+        while let Some(node) = tree.nodes.get(*node_ref) {
+            snapshot.path.push(node_ref);
+            snapshot.add(tree, node_ref, path_offset);
+            let children = &snapshot.shapes[&node_ref].children;
+            for child_ref in children.clone() {
+                let child_offset = path_offset + snapshot.child_offset(node_ref);
+                snapshot.add(tree, child_ref, child_offset);
+            }
+
+            (node_ref, path_offset) = match node {
+                Node::Branch(branch_node) => match path.next() {
+                    Some(nibble) if branch_node.choices[nibble as usize].is_valid() => {
+                        (branch_node.choices[nibble as usize], path_offset + 1)
+                    }
+                    _ => break,
+                },
+                Node::Extension(extension_node) => {
+                    if !path.skip_prefix(&extension_node.prefix) {
+                        break;
+                    }
+
+                    (
+                        extension_node.child_ref,
+                        path_offset + extension_node.prefix.len(),
+                    )
+                }
+                Node::Leaf(_) | Node::Stub(_) => break,
+            };
+        }
+
+        snapshot
+    }
+
+    /// Return the changes from `self` (taken before a mutation) to `after`, given whether the
+    /// mutation changed the tree's contents.
+    pub fn diff(&self, after: &Self, changed: bool) -> NodeChanges {
+        let on_path = after.path.iter().collect::<HashSet<_>>();
+        let mut changes = NodeChanges::default();
+        for (node_ref, shape) in &after.shapes {
+            match self.shapes.get(node_ref) {
+                None => changes.created.push(**node_ref),
+                // Every node along the path of a changed value has a different encoding.
+                Some(old_shape)
+                    if *old_shape != *shape || (changed && on_path.contains(node_ref)) =>
+                {
+                    changes.modified.push(**node_ref)
+                }
+                Some(_) => {}
+            }
+        }
+        changes.freed = self
+            .shapes
+            .keys()
+            .filter(|x| !after.shapes.contains_key(x))
+            .map(|x| **x)
+            .collect();
+
+        changes.created.sort_unstable();
+        changes.modified.sort_unstable();
+        changes.freed.sort_unstable();
+        changes
+    }
+
+    /// Return the path offsets of the branches along the path.
+    pub fn branch_offsets(&self) -> HashSet<usize> {
+        (self.path.iter())
+            .map(|x| &self.shapes[x])
+            .filter(|x| x.is_branch)
+            .map(|x| x.path_offset)
+            .collect()
+    }
+
+    fn add<P, V, H>(
+        &mut self,
+        tree: &PatriciaMerkleTree<P, V, H>,
+        node_ref: NodeRef,
+        path_offset: usize,
+    ) where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        let Some(node) = tree.nodes.get(*node_ref) else {
+            return;
+        };
+
+        let shape = match node {
+            Node::Branch(branch_node) => Shape {
+                children: branch_node.choices.to_vec(),
+                value_ref: branch_node
+                    .value_ref
+                    .is_valid()
+                    .then_some(*branch_node.value_ref),
+                prefix: Vec::new(),
+                path_offset,
+                is_branch: true,
+            },
+            Node::Extension(extension_node) => Shape {
+                children: vec![extension_node.child_ref],
+                value_ref: None,
+                prefix: extension_node.prefix.iter().map(u8::from).collect(),
+                path_offset,
+                is_branch: false,
+            },
+            Node::Leaf(leaf_node) => Shape {
+                children: Vec::new(),
+                value_ref: Some(*leaf_node.value_ref),
+                prefix: Vec::new(),
+                path_offset,
+                is_branch: false,
+            },
+            Node::Stub(_) => Shape {
+                children: Vec::new(),
+                value_ref: None,
+                prefix: Vec::new(),
+                path_offset,
+                is_branch: false,
+            },
+        };
+        self.shapes.insert(node_ref, shape);
+    }
+
+    /// Return the number of nibbles between a node and its children.
+    fn child_offset(&self, node_ref: NodeRef) -> usize {
+        let shape = &self.shapes[&node_ref];
+        match shape.is_branch {
+            true => 1,
+            false => shape.prefix.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{node::Node, pmt_tree};
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn node_changes() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let (_, changes) = tree.insert_tracked(vec![0x12], vec![1]);
+        assert_eq!(changes.created, [*tree.root_ref]);
+        assert!(changes.modified.is_empty() && changes.freed.is_empty());
+
+        // Check the changes against the hashes of every node, before and after each mutation.
+        let hashes = |tree: &mut crate::PatriciaMerkleTree<Vec<u8>, Vec<u8>, _>| {
+            tree.compute_hash();
+            tree.nodes
+                .iter()
+                .map(|(node_ref, node)| {
+                    let hash = match node {
+                        Node::Branch(x) => x.hash.extract_ref(),
+                        Node::Extension(x) => x.hash.extract_ref(),
+                        Node::Leaf(x) => x.hash.extract_ref(),
+                        Node::Stub(_) => None,
+                    };
+                    (node_ref, hash.map(|x| x.as_ref().to_vec()))
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        let mut live = HashSet::from([*tree.root_ref]);
+        let mut before = hashes(&mut tree);
+        for i in 0..512u16 {
+            let path = vec![(i.wrapping_mul(97) % 61) as u8, (i % 3) as u8];
+            let changes = match i % 3 {
+                0 => tree.remove_tracked(path).1,
+                _ => tree.insert_tracked(path, i.to_be_bytes().to_vec()).1,
+            };
+            let after = hashes(&mut tree);
+
+            for node_ref in &changes.freed {
+                assert!(live.remove(node_ref));
+            }
+            for node_ref in &changes.created {
+                assert!(live.insert(*node_ref));
+            }
+            assert_eq!(live, after.keys().copied().collect());
+            for (node_ref, hash) in &after {
+                if !changes.created.contains(node_ref) && !changes.modified.contains(node_ref) {
+                    assert_eq!(before[node_ref], *hash, "node {node_ref} changed");
+                }
+            }
+            before = after;
+        }
+
+        let (old_value, changes) = tree.remove_tracked(vec![0xFF]);
+        assert_eq!((old_value, changes.is_empty()), (None, true));
+    }
+}
@@ -0,0 +1,105 @@
+//! Stable node identities, when the `node-ids` feature is enabled.
+
+use crate::{node::Node, Encode, PatriciaMerkleTree};
+use digest::Digest;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// An identifier assigned to every node when it's created, which unlike its storage index is
+/// never reused.
+///
+/// Nodes keep their identifier while they're moved within the storage or their children change.
+/// Splitting, merging or replacing them creates new nodes, with new identifiers. Identifiers are
+/// unique within the process, except that clones of a tree share them.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NodeId(u64);
+
+impl NodeId {
+    pub(crate) fn next() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Return the identifier of every node, along with the nibbles from the root up to it, in
+/// pre-order.
+pub(crate) fn collect<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>) -> Vec<(Vec<u8>, NodeId)>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut ids = Vec::with_capacity(tree.nodes.len());
+    let mut stack = vec![(tree.root_ref, Vec::new())];
+    while let Some((node_ref, path_prefix)) = stack.pop() {
+        let Some(node) = tree.nodes.get(*node_ref) else {
+            continue;
+        };
+
+        match node {
+            Node::Branch(branch_node) => {
+                let children = branch_node.choices.iter().enumerate().rev();
+                for (choice, child_ref) in children.filter(|(_, x)| x.is_valid()) {
+                    stack.push((
+                        *child_ref,
+                        [path_prefix.as_slice(), &[choice as u8]].concat(),
+                    ));
+                }
+            }
+            Node::Extension(extension_node) => {
+                let prefix = extension_node.prefix.iter().map(u8::from);
+                let child_prefix = path_prefix.iter().copied().chain(prefix).collect();
+                stack.push((extension_node.child_ref, child_prefix));
+            }
+            Node::Leaf(_) | Node::Stub(_) => {}
+        }
+        ids.push((path_prefix, node.id()));
+    }
+
+    ids
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+    use std::collections::HashMap;
+
+    #[test]
+    fn node_ids() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.insert(vec![0x34], vec![2]);
+        tree.insert(vec![0x35], vec![3]);
+
+        let ids = tree.node_ids().into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(ids.len(), 5);
+        let mut sorted = tree.node_ids();
+        sorted.sort();
+        assert_eq!(tree.node_ids(), sorted);
+
+        // The root and the untouched leaf keep their identifiers even though they're moved.
+        let root_ref = tree.root_ref;
+        tree.insert(vec![0x36], vec![4]);
+        tree.insert(vec![0x56], vec![5]);
+        assert_ne!(tree.root_ref, root_ref);
+        assert_eq!(tree.find_node(ids[&vec![]]), Some(vec![]));
+        assert_eq!(tree.find_node(ids[&vec![1]]), Some(vec![1]));
+        assert_eq!(tree.find_node(ids[&vec![3, 4]]), Some(vec![3, 4]));
+
+        // The branch is replaced by a leaf when collapsed, and its slot reused.
+        tree.remove(vec![0x34]);
+        tree.remove(vec![0x35]);
+        tree.insert(vec![0x78], vec![6]);
+        assert_eq!(tree.find_node(ids[&vec![3]]), None);
+        assert!(tree.node_ids().iter().all(|(_, id)| *id != ids[&vec![3]]));
+    }
+}
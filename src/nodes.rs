@@ -2,11 +2,13 @@ pub use self::{
     branch::{compute_branch_hash, BranchNode},
     extension::{compute_extension_hash, ExtensionNode},
     leaf::{compute_leaf_hash, LeafNode},
+    stub::StubNode,
 };
 
 mod branch;
 mod extension;
 mod leaf;
+mod stub;
 
 #[cfg(test)]
 #[macro_export]
@@ -88,7 +90,7 @@ macro_rules! pmt_node {
             offset
         );
 
-        let offset = offset  ^ (prefix.len() % 2 != 0);
+        let offset = offset  ^ (prefix.len() & 1 != 0);
         $crate::nodes::ExtensionNode::<Vec<u8>, _, sha3::Keccak256>::new(
             prefix,
             {
@@ -116,7 +118,7 @@ macro_rules! pmt_node {
 #[macro_export]
 macro_rules! pmt_path {
     ( $path:literal ) => {{
-        assert!($path.len() % 2 == 1);
+        assert!($path.len() & 1 == 1);
         $path
             .as_bytes()
             .chunks(2)
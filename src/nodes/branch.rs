@@ -1,11 +1,14 @@
 use super::{ExtensionNode, LeafNode};
+#[cfg(feature = "node-ids")]
+use crate::NodeId;
 use crate::{
-    hashing::{DelimitedHash, NodeHash, NodeHashRef, NodeHasher},
+    corruption::CorruptionError,
+    hashing::{DelimitedHash, NodeEncoder, NodeHash, NodeHashRef, NodeHasher, NodeWriter},
     nibble::{Nibble, NibbleSlice, NibbleVec},
-    node::{InsertAction, Node},
+    node::{InsertAction, Node, NodeKind, RemoveResult},
     Encode, NodeRef, NodesStorage, ValueRef, ValuesStorage,
 };
-use digest::{Digest, Output};
+use digest::{Digest, Output, OutputSizeUser};
 use std::marker::PhantomData;
 
 #[derive(Clone, Debug)]
@@ -13,13 +16,15 @@ pub struct BranchNode<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     // The node zero is always the root, which cannot be a child.
     pub(crate) choices: [NodeRef; 16],
     pub(crate) value_ref: ValueRef,
 
-    hash: NodeHash<H>,
+    #[cfg(feature = "node-ids")]
+    pub(crate) id: NodeId,
+    pub(crate) hash: NodeHash<H>,
     phantom: PhantomData<(P, V, H)>,
 }
 
@@ -27,12 +32,14 @@ impl<P, V, H> BranchNode<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     pub(crate) fn new(choices: [NodeRef; 16]) -> Self {
         Self {
             choices,
             value_ref: Default::default(),
+            #[cfg(feature = "node-ids")]
+            id: NodeId::next(),
             hash: Default::default(),
             phantom: PhantomData,
         }
@@ -42,42 +49,56 @@ where
         self.value_ref = new_value_ref;
     }
 
+    #[cfg(test)]
     pub fn get<'a>(
         &self,
-        nodes: &'a NodesStorage<P, V, H>,
+        nodes: &NodesStorage<P, V, H>,
         values: &'a ValuesStorage<P, V>,
+        path: NibbleSlice,
+    ) -> Result<Option<&'a V>, CorruptionError> {
+        let value_ref = self.get_ref(nodes, values, path)?;
+        Ok(value_ref.map(|x| &*values[*x].1))
+    }
+
+    /// Return a reference to the value at `path` in the storage, if any.
+    pub(crate) fn get_ref(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
         mut path: NibbleSlice,
-    ) -> Option<&'a V> {
+    ) -> Result<Option<ValueRef>, CorruptionError> {
         // If path is at the end, return to its own value if present.
         // Otherwise, check the corresponding choice and delegate accordingly if present.
 
-        path.next()
-            .map(usize::from)
-            .and_then(|choice| {
+        match path.next() {
+            Some(choice) => {
                 // Delegate to children if present.
-                let child_ref = self.choices[choice];
+                let child_ref = self.choices[choice as usize];
                 if child_ref.is_valid() {
                     let child_node = nodes
                         .get(*child_ref)
-                        .expect("inconsistent internal tree structure");
+                        .ok_or_else(|| CorruptionError::missing(child_ref, path.consumed()))?;
 
-                    child_node.get(nodes, values, path)
+                    child_node
+                        .get_ref(nodes, values, path)
+                        .map_err(|e| e.at(child_ref))
                 } else {
-                    None
+                    Ok(None)
                 }
-            })
-            .or_else(|| {
+            }
+            None => {
                 // Return internal value if present.
                 if self.value_ref.is_valid() {
-                    let (_, value) = values
-                        .get(*self.value_ref)
-                        .expect("inconsistent internal tree structure");
+                    if !values.contains(*self.value_ref) {
+                        return Err(CorruptionError::within(NodeKind::Branch, path.consumed()));
+                    }
 
-                    Some(value)
+                    Ok(Some(self.value_ref))
                 } else {
-                    None
+                    Ok(None)
                 }
-            })
+            }
+        }
     }
 
     pub(crate) fn insert(
@@ -85,7 +106,7 @@ where
         nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         mut path: NibbleSlice,
-    ) -> (Node<P, V, H>, InsertAction) {
+    ) -> Result<(Node<P, V, H>, InsertAction), CorruptionError> {
         // If path is at the end, insert or replace its own value.
         // Otherwise, check the corresponding choice and insert or delegate accordingly.
 
@@ -102,9 +123,11 @@ where
                 choice_ref => {
                     let child_node = nodes
                         .try_remove(**choice_ref)
-                        .expect("inconsistent internal tree structure");
+                        .ok_or_else(|| CorruptionError::missing(*choice_ref, path.consumed()))?;
 
-                    let (child_node, insert_action) = child_node.insert(nodes, values, path);
+                    let (child_node, insert_action) = child_node
+                        .insert(nodes, values, path)
+                        .map_err(|e| e.at(*choice_ref))?;
                     *choice_ref = NodeRef::new(nodes.insert(child_node));
 
                     insert_action.quantize_self(*choice_ref)
@@ -112,6 +135,10 @@ where
             },
             None => {
                 if self.value_ref.is_valid() {
+                    if !values.contains(*self.value_ref) {
+                        return Err(CorruptionError::within(NodeKind::Branch, path.consumed()));
+                    }
+
                     InsertAction::Replace(self.value_ref)
                 } else {
                     InsertAction::InsertSelf
@@ -119,7 +146,7 @@ where
             }
         };
 
-        (self.into(), insert_action)
+        Ok((self.into(), insert_action))
     }
 
     pub fn remove(
@@ -127,7 +154,7 @@ where
         nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         mut path: NibbleSlice,
-    ) -> (Option<Node<P, V, H>>, Option<V>) {
+    ) -> RemoveResult<P, V, H> {
         // Possible flow paths:
         //   branch { 2 choices } -> leaf/extension { ... }
         //   branch { 3+ choices } -> branch { ... }
@@ -135,154 +162,213 @@ where
         //   branch { 1 choice } with value -> leaf/extension { ... }
         //   branch { 2+ choices } with value -> branch { ... }
 
-        let path_offset = path.offset();
+        let (path_offset, branch_path) = (path.offset(), path.clone());
         let value = match path.next() {
-            Some(choice_index) => self.choices[choice_index as usize]
-                .is_valid()
-                .then(|| {
+            Some(choice_index) => {
+                let child_ref = self.choices[choice_index as usize];
+                if child_ref.is_valid() {
                     let child_node = nodes
-                        .try_remove(*self.choices[choice_index as usize])
-                        .expect("inconsistent internal tree structure");
+                        .try_remove(*child_ref)
+                        .ok_or_else(|| CorruptionError::missing(child_ref, path.consumed()))?;
 
-                    let (child_node, old_value) = child_node.remove(nodes, values, path);
+                    let (child_node, old_value) = child_node
+                        .remove(nodes, values, path)
+                        .map_err(|e| e.at(child_ref))?;
                     self.choices[choice_index as usize] = child_node
                         .map(|x| NodeRef::new(nodes.insert(x)))
                         .unwrap_or_default();
 
                     old_value
-                })
-                .flatten(),
-            None => self.value_ref.is_valid().then(|| {
+                } else {
+                    None
+                }
+            }
+            None if self.value_ref.is_valid() => {
                 let (_, value) = values
                     .try_remove(*self.value_ref)
-                    .expect("inconsistent internal tree structure");
+                    .ok_or_else(|| CorruptionError::within(NodeKind::Branch, path.consumed()))?;
 
                 self.value_ref = Default::default();
-                value
-            }),
+                Some(value)
+            }
+            None => None,
         };
 
+        if value.is_some() {
+            self.hash.mark_as_dirty();
+        }
+
+        let path_prefix = || branch_path.consumed();
+        Ok((self.collapse(nodes, path_offset, path_prefix)?, value))
+    }
+
+    /// Turn the branch into a simpler node if it's left with fewer than two items (choices or
+    /// value), or into nothing if it's left empty. `path_offset` is the nibble offset of the
+    /// branch, and `path_prefix` returns its nibbles for errors.
+    ///
+    /// Fails if the only child left is a stub, whose kind isn't known until it's expanded.
+    pub(crate) fn collapse(
+        self,
+        nodes: &mut NodesStorage<P, V, H>,
+        path_offset: usize,
+        path_prefix: impl FnOnce() -> Vec<u8>,
+    ) -> Result<Option<Node<P, V, H>>, CorruptionError> {
         // An `Err(_)` means more than one choice. `Ok(Some(_))` and `Ok(None)` mean a single and no
         // choices respectively.
         let choice_count = self
             .choices
-            .iter_mut()
+            .iter()
             .enumerate()
             .try_fold(None, |acc, (i, x)| {
                 Ok(match (acc, x.is_valid()) {
-                    (None, true) => Some((i, x)),
+                    (None, true) => Some((i, *x)),
                     (None, false) => None,
                     (Some(_), true) => return Err(()),
                     (Some((i, x)), false) => Some((i, x)),
                 })
             });
 
-        let child_ref = match choice_count {
-            Ok(Some((choice_index, child_ref))) => {
+        // Nodes moved up lose a nibble of path offset, which invalidates their cached hashes.
+        match (choice_count, self.value_ref.is_valid()) {
+            (Ok(Some((choice_index, child_ref))), false) => {
                 let choice_index = Nibble::try_from(choice_index as u8).unwrap();
                 let child_node = nodes
-                    .get_mut(**child_ref)
+                    .try_remove(*child_ref)
                     .expect("inconsistent internal tree structure");
 
-                match child_node {
-                    Node::Branch(_) => {
-                        *child_ref = NodeRef::new(
-                            nodes.insert(
-                                ExtensionNode::new(
-                                    NibbleVec::from_single(choice_index, path_offset % 2 != 0),
-                                    *child_ref,
-                                )
-                                .into(),
-                            ),
-                        );
-                    }
-                    Node::Extension(extension_node) => {
+                Ok(Some(match child_node {
+                    Node::Branch(_) => ExtensionNode::new(
+                        NibbleVec::from_single(choice_index, path_offset & 1 != 0),
+                        NodeRef::new(nodes.insert(child_node)),
+                    )
+                    .into(),
+                    Node::Extension(mut extension_node) => {
                         extension_node.prefix.prepend(choice_index);
+                        extension_node.hash.mark_as_dirty();
+                        extension_node.into()
                     }
-                    _ => {}
-                }
-
-                Some(child_ref)
+                    Node::Leaf(mut leaf_node) => {
+                        leaf_node.hash.mark_as_dirty();
+                        leaf_node.into()
+                    }
+                    Node::Stub(_) => {
+                        let mut path_prefix = path_prefix();
+                        path_prefix.push(choice_index.into());
+                        return Err(
+                            CorruptionError::within(NodeKind::Stub, path_prefix).at(child_ref)
+                        );
+                    }
+                }))
             }
-            _ => None,
-        };
-
-        if value.is_some() {
-            self.hash.mark_as_dirty();
+            (Ok(None), true) => Ok(Some(LeafNode::new(self.value_ref).into())),
+            (Ok(None), false) => Ok(None),
+            _ => Ok(Some(self.into())),
         }
-
-        let new_node = match (child_ref, self.value_ref.is_valid()) {
-            (Some(_), true) => Some(self.into()),
-            (None, true) => Some(LeafNode::new(self.value_ref).into()),
-            (Some(x), false) => Some(
-                nodes
-                    .try_remove(**x)
-                    .expect("inconsistent internal tree structure"),
-            ),
-            (None, false) => Some(self.into()),
-        };
-
-        (new_node, value)
     }
+}
 
+impl<P, V, H> BranchNode<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
     pub fn compute_hash(
         &self,
         nodes: &NodesStorage<P, V, H>,
         values: &ValuesStorage<P, V>,
         path_offset: usize,
-    ) -> NodeHashRef<H> {
+    ) -> NodeHashRef<'_, H> {
         self.hash.extract_ref().unwrap_or_else(|| {
-            let children = self.choices.map(|node_ref| {
-                if node_ref.is_valid() {
-                    let child_node = nodes
-                        .get(*node_ref)
-                        .expect("inconsistent internal tree structure");
-
-                    let mut target = Output::<H>::default();
-                    let target_len = match child_node.compute_hash(nodes, values, path_offset + 1) {
-                        NodeHashRef::Inline(x) => {
-                            target[..x.len()].copy_from_slice(&x);
-                            x.len()
-                        }
-                        NodeHashRef::Hashed(x) => {
-                            target.copy_from_slice(&x);
-                            x.len()
-                        }
-                    };
-
-                    DelimitedHash(target, target_len)
-                } else {
-                    DelimitedHash(Output::<H>::default(), 0)
-                }
-            });
+            let children = self.compute_children_hashes(nodes, values, path_offset);
+            compute_branch_hash::<DelimitedHash<H>, _>(&self.hash, &children, self.value(values))
+        })
+    }
 
-            let encoded_value = if self.value_ref.is_valid() {
-                let (_, value) = values
-                    .get(*self.value_ref)
+    /// Return the node's RLP encoding.
+    pub fn encode(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
+        path_offset: usize,
+    ) -> Vec<u8> {
+        let children = self.compute_children_hashes(nodes, values, path_offset);
+        encode_branch::<DelimitedHash<H>, H>(&children, self.value(values))
+    }
+
+    fn compute_children_hashes(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
+        path_offset: usize,
+    ) -> [DelimitedHash<H>; 16] {
+        self.choices.map(|node_ref| {
+            if node_ref.is_valid() {
+                let child_node = nodes
+                    .get(*node_ref)
                     .expect("inconsistent internal tree structure");
 
-                Some(value.encode())
+                let mut target = Output::<H>::default();
+                let target_len = match child_node.compute_hash(nodes, values, path_offset + 1) {
+                    NodeHashRef::Inline(x) => {
+                        target[..x.len()].copy_from_slice(x);
+                        x.len()
+                    }
+                    NodeHashRef::Hashed(x) => {
+                        target.copy_from_slice(x);
+                        x.len()
+                    }
+                };
+
+                DelimitedHash(target, target_len)
             } else {
-                None
-            };
-
-            compute_branch_hash::<DelimitedHash<H>, _>(
-                &self.hash,
-                &children,
-                encoded_value.as_deref(),
-            )
+                DelimitedHash(Output::<H>::default(), 0)
+            }
         })
     }
+
+    fn value<'a>(&self, values: &'a ValuesStorage<P, V>) -> Option<&'a V> {
+        if self.value_ref.is_valid() {
+            let (_, value) = values
+                .get(*self.value_ref)
+                .expect("inconsistent internal tree structure");
+
+            Some(value)
+        } else {
+            None
+        }
+    }
 }
 
 pub fn compute_branch_hash<'a, T, H>(
     hash: &'a NodeHash<H>,
     choices: &[T; 16],
-    value: Option<&[u8]>,
+    value: Option<&(impl Encode + ?Sized)>,
 ) -> NodeHashRef<'a, H>
 where
     T: AsRef<[u8]>,
     H: Digest,
+{
+    let mut hasher = NodeHasher::new(hash);
+    write_branch::<_, _, H>(&mut hasher, choices, value);
+    hasher.finalize()
+}
+
+pub fn encode_branch<T, H>(choices: &[T; 16], value: Option<&(impl Encode + ?Sized)>) -> Vec<u8>
+where
+    T: AsRef<[u8]>,
+    H: Digest,
+{
+    let mut encoder = NodeEncoder::default();
+    write_branch::<_, _, H>(&mut encoder, choices, value);
+    encoder.finalize()
+}
+
+fn write_branch<W, T, H>(writer: &mut W, choices: &[T; 16], value: Option<&(impl Encode + ?Sized)>)
+where
+    W: NodeWriter,
+    T: AsRef<[u8]>,
+    H: Digest,
 {
     let mut children_len: usize = choices
         .iter()
@@ -294,24 +380,21 @@ where
         .sum();
 
     if let Some(value) = value {
-        children_len +=
-            NodeHasher::<H>::bytes_len(value.len(), value.first().copied().unwrap_or_default());
+        children_len += NodeHasher::<H>::value_len(value);
     } else {
         children_len += 1;
     }
 
-    let mut hasher = NodeHasher::new(hash);
-    hasher.write_list_header(children_len);
+    writer.write_list_header(children_len);
     choices.iter().for_each(|x| match x.as_ref().len() {
-        0 => hasher.write_bytes(&[]),
-        32 => hasher.write_bytes(x.as_ref()),
-        _ => hasher.write_raw(x.as_ref()),
+        0 => writer.write_bytes(&[]),
+        32 => writer.write_bytes(x.as_ref()),
+        _ => writer.write_raw(x.as_ref()),
     });
     match value {
-        Some(value) => hasher.write_bytes(value),
-        None => hasher.write_bytes(&[]),
+        Some(value) => writer.write_value(value),
+        None => writer.write_bytes(&[]),
     }
-    hasher.finalize()
 }
 
 #[cfg(test)]
@@ -367,11 +450,13 @@ mod test {
 
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x00]))
+                .unwrap()
                 .map(Vec::as_slice),
             Some([0x12, 0x34, 0x56, 0x78].as_slice()),
         );
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x10]))
+                .unwrap()
                 .map(Vec::as_slice),
             Some([0x34, 0x56, 0x78, 0x9A].as_slice()),
         );
@@ -390,6 +475,26 @@ mod test {
 
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x20]))
+                .unwrap()
+                .map(Vec::as_slice),
+            None,
+        );
+    }
+
+    #[test]
+    fn get_none_with_value() {
+        let (mut nodes, mut values) = pmt_state!(Vec<u8>);
+
+        let node = pmt_node! { @(nodes, values)
+            branch {
+                0 => leaf { vec![0x00] => vec![0x12, 0x34, 0x56, 0x78] },
+                1 => leaf { vec![0x10] => vec![0x34, 0x56, 0x78, 0x9A] },
+            } with_leaf { vec![] => vec![0xFF] }
+        };
+
+        assert_eq!(
+            node.get(&nodes, &values, NibbleSlice::new(&[0x20]))
+                .unwrap()
                 .map(Vec::as_slice),
             None,
         );
@@ -406,7 +511,9 @@ mod test {
             }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[]))
+            .unwrap();
         let _ = match node {
             Node::Branch(x) => x,
             _ => panic!("expected a branch node"),
@@ -427,7 +534,9 @@ mod test {
             }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x20]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x20]))
+            .unwrap();
         let _ = match node {
             Node::Branch(x) => x,
             _ => panic!("expected a branch node"),
@@ -449,11 +558,13 @@ mod test {
         };
 
         // The extension node is ignored since it's irrelevant in this test.
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, {
-            let mut nibble_slice = NibbleSlice::new(&[0x00]);
-            nibble_slice.offset_add(2);
-            nibble_slice
-        });
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, {
+                let mut nibble_slice = NibbleSlice::new(&[0x00]);
+                nibble_slice.offset_add(2);
+                nibble_slice
+            })
+            .unwrap();
         let _ = match node {
             Node::Branch(x) => x,
             _ => panic!("expected a branch node"),
@@ -474,7 +585,9 @@ mod test {
             }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Leaf(_))));
         assert_eq!(value, Some(vec![0x00]));
@@ -492,7 +605,9 @@ mod test {
             }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Branch(_))));
         assert_eq!(value, Some(vec![0x00]));
@@ -508,7 +623,9 @@ mod test {
             } with_leaf { vec![] => vec![0xFF] }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Leaf(_))));
         assert_eq!(value, Some(vec![0x00]));
@@ -524,7 +641,9 @@ mod test {
             } with_leaf { vec![] => vec![0xFF] }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Leaf(_))));
         assert_eq!(value, Some(vec![0xFF]));
@@ -541,7 +660,9 @@ mod test {
             } with_leaf { vec![] => vec![0xFF] }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Branch(_))));
         assert_eq!(value, Some(vec![0xFF]));
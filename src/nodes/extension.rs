@@ -1,12 +1,15 @@
 use super::BranchNode;
+#[cfg(feature = "node-ids")]
+use crate::NodeId;
 use crate::{
-    hashing::{NodeHash, NodeHashRef, NodeHasher, PathKind},
+    corruption::CorruptionError,
+    hashing::{NodeEncoder, NodeHash, NodeHashRef, NodeHasher, NodeWriter, PathKind},
     nibble::{NibbleSlice, NibbleVec},
-    node::{InsertAction, Node},
+    node::{InsertAction, Node, RemoveResult},
     nodes::LeafNode,
-    Encode, NodeRef, NodesStorage, ValuesStorage,
+    Encode, NodeRef, NodesStorage, ValueRef, ValuesStorage,
 };
-use digest::Digest;
+use digest::{Digest, OutputSizeUser};
 use std::marker::PhantomData;
 
 #[derive(Clone, Debug)]
@@ -14,14 +17,16 @@ pub struct ExtensionNode<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     pub(crate) prefix: NibbleVec,
     // The child node may only be a branch, but it's not included directly by value to avoid
     // inflating `Node`'s size too much.
     pub(crate) child_ref: NodeRef,
 
-    hash: NodeHash<H>,
+    #[cfg(feature = "node-ids")]
+    pub(crate) id: NodeId,
+    pub(crate) hash: NodeHash<H>,
     phantom: PhantomData<(P, V, H)>,
 }
 
@@ -29,35 +34,51 @@ impl<P, V, H> ExtensionNode<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     pub(crate) fn new(prefix: NibbleVec, child_ref: NodeRef) -> Self {
         Self {
             prefix,
             child_ref,
+            #[cfg(feature = "node-ids")]
+            id: NodeId::next(),
             hash: Default::default(),
             phantom: PhantomData,
         }
     }
 
+    #[cfg(test)]
     pub fn get<'a>(
         &self,
-        nodes: &'a NodesStorage<P, V, H>,
+        nodes: &NodesStorage<P, V, H>,
         values: &'a ValuesStorage<P, V>,
+        path: NibbleSlice,
+    ) -> Result<Option<&'a V>, CorruptionError> {
+        let value_ref = self.get_ref(nodes, values, path)?;
+        Ok(value_ref.map(|x| &*values[*x].1))
+    }
+
+    /// Return a reference to the value at `path` in the storage, if any.
+    pub(crate) fn get_ref(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
         mut path: NibbleSlice,
-    ) -> Option<&'a V> {
+    ) -> Result<Option<ValueRef>, CorruptionError> {
         // If the path is prefixed by this node's prefix, delegate to its child.
         // Otherwise, no value is present.
 
-        path.skip_prefix(&self.prefix)
-            .then(|| {
-                let child_node = nodes
-                    .get(*self.child_ref)
-                    .expect("inconsistent internal tree structure");
+        if !path.skip_prefix(&self.prefix) {
+            return Ok(None);
+        }
+
+        let child_node = nodes
+            .get(*self.child_ref)
+            .ok_or_else(|| CorruptionError::missing(self.child_ref, path.consumed()))?;
 
-                child_node.get(nodes, values, path)
-            })
-            .flatten()
+        child_node
+            .get_ref(nodes, values, path)
+            .map_err(|e| e.at(self.child_ref))
     }
 
     pub(crate) fn insert(
@@ -65,7 +86,7 @@ where
         nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         mut path: NibbleSlice,
-    ) -> (Node<P, V, H>, InsertAction) {
+    ) -> Result<(Node<P, V, H>, InsertAction), CorruptionError> {
         // Possible flow paths (there are duplicates between different prefix lengths):
         //   extension { [0], child } -> branch { 0 => child } with_value !
         //   extension { [0], child } -> extension { [0], child }
@@ -82,15 +103,17 @@ where
         if path.skip_prefix(&self.prefix) {
             let child_node = nodes
                 .try_remove(*self.child_ref)
-                .expect("inconsistent internal tree structure");
+                .ok_or_else(|| CorruptionError::missing(self.child_ref, path.consumed()))?;
 
-            let (child_node, insert_action) = child_node.insert(nodes, values, path);
+            let (child_node, insert_action) = child_node
+                .insert(nodes, values, path)
+                .map_err(|e| e.at(self.child_ref))?;
             self.child_ref = NodeRef::new(nodes.insert(child_node));
 
             let insert_action = insert_action.quantize_self(self.child_ref);
-            (self.into(), insert_action)
+            Ok((self.into(), insert_action))
         } else {
-            let offset = path.clone().count_prefix_vec(&self.prefix);
+            let offset = path.count_prefix_vec(&self.prefix);
             path.offset_add(offset);
             let (left_prefix, choice, right_prefix) = self.prefix.split_extract_at(offset);
 
@@ -118,7 +141,7 @@ where
             });
 
             // Prefix left node (if any, child is branch_node).
-            match left_prefix {
+            Ok(match left_prefix {
                 Some(left_prefix) => {
                     let branch_ref = NodeRef::new(nodes.insert(branch_node.into()));
 
@@ -131,7 +154,7 @@ where
                     Some(child_ref) => (branch_node.into(), InsertAction::Insert(child_ref)),
                     None => (branch_node.into(), InsertAction::InsertSelf),
                 },
-            }
+            })
         }
     }
 
@@ -140,7 +163,7 @@ where
         nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         mut path: NibbleSlice,
-    ) -> (Option<Node<P, V, H>>, Option<V>) {
+    ) -> RemoveResult<P, V, H> {
         // Possible flow paths:
         //   - extension { a, branch { ... } } -> extension { a, branch { ... }}
         //   - extension { a, branch { ... } } -> extension { a + b, branch { ... }}
@@ -149,37 +172,64 @@ where
         if path.skip_prefix(&self.prefix) {
             let child_node = nodes
                 .try_remove(*self.child_ref)
-                .expect("inconsistent internal tree structure");
+                .ok_or_else(|| CorruptionError::missing(self.child_ref, path.consumed()))?;
 
-            let (child_node, old_value) = child_node.remove(nodes, values, path);
+            let (child_node, old_value) = child_node
+                .remove(nodes, values, path)
+                .map_err(|e| e.at(self.child_ref))?;
             if old_value.is_some() {
                 self.hash.mark_as_dirty();
             }
 
-            let node = child_node.map(|x| match x {
-                Node::Branch(branch_node) => {
-                    self.child_ref = NodeRef::new(nodes.insert(branch_node.into()));
-                    self.into()
-                }
-                Node::Extension(extension_node) => {
-                    self.prefix.extend(&extension_node.prefix);
-                    self.into()
-                }
-                Node::Leaf(leaf_node) => leaf_node.into(),
-            });
-
-            (node, old_value)
+            Ok((self.with_child(nodes, child_node), old_value))
         } else {
-            (Some(self.into()), None)
+            Ok((Some(self.into()), None))
         }
     }
 
+    /// Attach the (already detached and possibly modified) child node back, merging it into the
+    /// extension when it's no longer a branch.
+    pub(crate) fn with_child(
+        mut self,
+        nodes: &mut NodesStorage<P, V, H>,
+        child_node: Option<Node<P, V, H>>,
+    ) -> Option<Node<P, V, H>> {
+        child_node.map(|x| match x {
+            Node::Branch(branch_node) => {
+                self.child_ref = NodeRef::new(nodes.insert(branch_node.into()));
+                self.into()
+            }
+            Node::Extension(extension_node) => {
+                self.prefix.extend(&extension_node.prefix);
+                self.child_ref = extension_node.child_ref;
+                self.into()
+            }
+            Node::Leaf(mut leaf_node) => {
+                // The leaf has moved up, therefore its cached hash is no longer valid.
+                leaf_node.hash.mark_as_dirty();
+                leaf_node.into()
+            }
+            // The child wasn't modified if it's still a stub, so it stays where it was.
+            Node::Stub(_) => {
+                self.child_ref = NodeRef::new(nodes.insert(x));
+                self.into()
+            }
+        })
+    }
+}
+
+impl<P, V, H> ExtensionNode<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
     pub fn compute_hash(
         &self,
         nodes: &NodesStorage<P, V, H>,
         values: &ValuesStorage<P, V>,
         path_offset: usize,
-    ) -> NodeHashRef<H> {
+    ) -> NodeHashRef<'_, H> {
         self.hash.extract_ref().unwrap_or_else(|| {
             let child_node = nodes
                 .get(*self.child_ref)
@@ -191,6 +241,22 @@ where
             compute_extension_hash(&self.hash, &self.prefix, child_hash_ref)
         })
     }
+
+    /// Return the node's RLP encoding.
+    pub fn encode(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
+        path_offset: usize,
+    ) -> Vec<u8> {
+        let child_node = nodes
+            .get(*self.child_ref)
+            .expect("inconsistent internal tree structure");
+
+        let child_hash_ref =
+            child_node.compute_hash(nodes, values, path_offset + self.prefix.len());
+        encode_extension(&self.prefix, child_hash_ref)
+    }
 }
 
 pub fn compute_extension_hash<'a, H>(
@@ -200,6 +266,25 @@ pub fn compute_extension_hash<'a, H>(
 ) -> NodeHashRef<'a, H>
 where
     H: Digest,
+{
+    let mut hasher = NodeHasher::new(hash);
+    write_extension(&mut hasher, prefix, child_hash_ref);
+    hasher.finalize()
+}
+
+pub fn encode_extension<H>(prefix: &NibbleVec, child_hash_ref: NodeHashRef<H>) -> Vec<u8>
+where
+    H: Digest,
+{
+    let mut encoder = NodeEncoder::default();
+    write_extension(&mut encoder, prefix, child_hash_ref);
+    encoder.finalize()
+}
+
+fn write_extension<W, H>(writer: &mut W, prefix: &NibbleVec, child_hash_ref: NodeHashRef<H>)
+where
+    W: NodeWriter,
+    H: Digest,
 {
     let prefix_len = NodeHasher::<H>::path_len(prefix.len());
     let child_len = match &child_hash_ref {
@@ -207,14 +292,12 @@ where
         NodeHashRef::Hashed(x) => NodeHasher::<H>::bytes_len(x.len(), x[0]),
     };
 
-    let mut hasher = NodeHasher::new(hash);
-    hasher.write_list_header(prefix_len + child_len);
-    hasher.write_path_vec(prefix, PathKind::Extension);
+    writer.write_list_header(prefix_len + child_len);
+    writer.write_path_vec(prefix, PathKind::Extension);
     match child_hash_ref {
-        NodeHashRef::Inline(x) => hasher.write_raw(&x),
-        NodeHashRef::Hashed(x) => hasher.write_bytes(&x),
+        NodeHashRef::Inline(x) => writer.write_raw(x),
+        NodeHashRef::Hashed(x) => writer.write_bytes(x),
     }
-    hasher.finalize()
 }
 
 #[cfg(test)]
@@ -245,11 +328,13 @@ mod test {
 
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x00]))
+                .unwrap()
                 .map(Vec::as_slice),
             Some([0x12, 0x34, 0x56, 0x78].as_slice()),
         );
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x01]))
+                .unwrap()
                 .map(Vec::as_slice),
             Some([0x34, 0x56, 0x78, 0x9A].as_slice()),
         );
@@ -268,6 +353,7 @@ mod test {
 
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x02]))
+                .unwrap()
                 .map(Vec::as_slice),
             None,
         );
@@ -284,7 +370,9 @@ mod test {
             } }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x02]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x02]))
+            .unwrap();
         let node = match node {
             Node::Extension(x) => x,
             _ => panic!("expected an extension node"),
@@ -306,7 +394,9 @@ mod test {
             } }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x10]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x10]))
+            .unwrap();
         let _ = match node {
             Node::Branch(x) => x,
             _ => panic!("expected a branch node"),
@@ -327,7 +417,9 @@ mod test {
             } }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x10]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x10]))
+            .unwrap();
         let _ = match node {
             Node::Branch(x) => x,
             _ => panic!("expected a branch node"),
@@ -348,7 +440,9 @@ mod test {
             } }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x01]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x01]))
+            .unwrap();
         let _ = match node {
             Node::Extension(x) => x,
             _ => panic!("expected an extension node"),
@@ -369,7 +463,9 @@ mod test {
             } }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x01]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x01]))
+            .unwrap();
         let _ = match node {
             Node::Extension(x) => x,
             _ => panic!("expected an extension node"),
@@ -390,7 +486,9 @@ mod test {
             } }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x02]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x02]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Extension(_))));
         assert_eq!(value, None);
@@ -407,7 +505,9 @@ mod test {
             } }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x01]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x01]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Leaf(_))));
         assert_eq!(value, Some(vec![0x01]));
@@ -427,7 +527,9 @@ mod test {
             } }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x00]))
+            .unwrap();
 
         assert!(matches!(node, Some(Node::Extension(_))));
         assert_eq!(value, Some(vec![0x00]));
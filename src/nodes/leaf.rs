@@ -1,11 +1,14 @@
 use super::{BranchNode, ExtensionNode};
+#[cfg(feature = "node-ids")]
+use crate::NodeId;
 use crate::{
-    hashing::{NodeHash, NodeHashRef, NodeHasher, PathKind},
+    corruption::CorruptionError,
+    hashing::{NodeEncoder, NodeHash, NodeHashRef, NodeHasher, NodeWriter, PathKind},
     nibble::NibbleSlice,
-    node::{InsertAction, Node},
+    node::{InsertAction, Node, NodeKind, RemoveResult},
     Encode, NodeRef, NodesStorage, ValueRef, ValuesStorage,
 };
-use digest::Digest;
+use digest::{Digest, OutputSizeUser};
 use std::marker::PhantomData;
 
 #[derive(Clone, Debug)]
@@ -13,11 +16,13 @@ pub struct LeafNode<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     pub(crate) value_ref: ValueRef,
 
-    hash: NodeHash<H>,
+    #[cfg(feature = "node-ids")]
+    pub(crate) id: NodeId,
+    pub(crate) hash: NodeHash<H>,
     phantom: PhantomData<(P, V, H)>,
 }
 
@@ -25,11 +30,13 @@ impl<P, V, H> LeafNode<P, V, H>
 where
     P: Encode,
     V: Encode,
-    H: Digest,
+    H: OutputSizeUser,
 {
     pub(crate) fn new(value_ref: ValueRef) -> Self {
         Self {
             value_ref,
+            #[cfg(feature = "node-ids")]
+            id: NodeId::next(),
             hash: Default::default(),
             phantom: PhantomData,
         }
@@ -39,21 +46,35 @@ where
         self.value_ref = new_value_ref;
     }
 
+    #[cfg(test)]
     pub fn get<'a>(
         &self,
-        _nodes: &NodesStorage<P, V, H>,
+        nodes: &NodesStorage<P, V, H>,
         values: &'a ValuesStorage<P, V>,
         path: NibbleSlice,
-    ) -> Option<&'a V> {
+    ) -> Result<Option<&'a V>, CorruptionError> {
+        let value_ref = self.get_ref(nodes, values, path)?;
+        Ok(value_ref.map(|x| &*values[*x].1))
+    }
+
+    /// Return a reference to the value at `path` in the storage, if any.
+    pub(crate) fn get_ref(
+        &self,
+        _nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
+        path: NibbleSlice,
+    ) -> Result<Option<ValueRef>, CorruptionError> {
         // If the remaining path (and offset) matches with the value's path, return the value.
         // Otherwise, no value is present.
 
-        let (value_path, value) = values
+        let (value_path, _) = values
             .get(*self.value_ref)
-            .expect("inconsistent internal tree structure");
+            .ok_or_else(|| CorruptionError::within(NodeKind::Leaf, path.consumed()))?;
 
         let encoded_value_path = value_path.encode();
-        path.cmp_rest(encoded_value_path.as_ref()).then_some(value)
+        Ok(path
+            .cmp_rest(encoded_value_path.as_ref())
+            .then_some(self.value_ref))
     }
 
     pub(crate) fn insert(
@@ -61,7 +82,7 @@ where
         nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         path: NibbleSlice,
-    ) -> (Node<P, V, H>, InsertAction) {
+    ) -> Result<(Node<P, V, H>, InsertAction), CorruptionError> {
         // Possible flow paths:
         //   leaf { path => value } -> leaf { path => value }
         //   leaf { path => value } -> branch { 0 => leaf { path => value }, 1 => leaf { path => value } }
@@ -73,14 +94,14 @@ where
 
         let (value_path, _) = values
             .get(*self.value_ref)
-            .expect("inconsistent internal tree structure");
+            .ok_or_else(|| CorruptionError::within(NodeKind::Leaf, path.consumed()))?;
 
         let encoded_value_path = value_path.encode();
         if path.cmp_rest(encoded_value_path.as_ref()) {
             let value_ref = self.value_ref;
-            (self.into(), InsertAction::Replace(value_ref))
+            Ok((self.into(), InsertAction::Replace(value_ref)))
         } else {
-            let offset = path.clone().count_prefix_slice(&{
+            let offset = path.count_prefix_slice(&{
                 let mut value_path = NibbleSlice::new(encoded_value_path.as_ref());
                 value_path.offset_add(path.offset());
                 value_path
@@ -94,9 +115,8 @@ where
                 (
                     BranchNode::new({
                         let mut choices = [Default::default(); 16];
-                        // TODO: Dedicated method.
                         choices[NibbleSlice::new(encoded_value_path.as_ref())
-                            .nth(absolute_offset)
+                            .get(absolute_offset)
                             .unwrap() as usize] = NodeRef::new(nodes.insert(self.into()));
                         choices
                     }),
@@ -118,9 +138,8 @@ where
                 (
                     BranchNode::new({
                         let mut choices = [Default::default(); 16];
-                        // TODO: Dedicated method.
                         choices[NibbleSlice::new(encoded_value_path.as_ref())
-                            .nth(absolute_offset)
+                            .get(absolute_offset)
                             .unwrap() as usize] = NodeRef::new(nodes.insert(self.into()));
                         choices[path_branch.next().unwrap() as usize] = NodeRef::new(child_ref);
                         choices
@@ -138,7 +157,7 @@ where
                 branch_node.into()
             };
 
-            (final_node, insert_action)
+            Ok((final_node, insert_action))
         }
     }
 
@@ -147,61 +166,102 @@ where
         _nodes: &mut NodesStorage<P, V, H>,
         values: &mut ValuesStorage<P, V>,
         path: NibbleSlice,
-    ) -> (Option<Node<P, V, H>>, Option<V>) {
+    ) -> RemoveResult<P, V, H> {
         let (value_path, _) = values
             .get(*self.value_ref)
-            .expect("inconsistent internal tree structure");
+            .ok_or_else(|| CorruptionError::within(NodeKind::Leaf, path.consumed()))?;
 
         let encoded_value_path = value_path.encode();
         if path.cmp_rest(encoded_value_path.as_ref()) {
             let (_, value) = values.remove(*self.value_ref);
-            (None, Some(value))
+            Ok((None, Some(value)))
         } else {
-            (Some(self.into()), None)
+            Ok((Some(self.into()), None))
         }
     }
+}
 
+impl<P, V, H> LeafNode<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
     pub fn compute_hash(
         &self,
         _nodes: &NodesStorage<P, V, H>,
         values: &ValuesStorage<P, V>,
         path_offset: usize,
-    ) -> NodeHashRef<H> {
+    ) -> NodeHashRef<'_, H> {
         self.hash.extract_ref().unwrap_or_else(|| {
             let (path, value) = values
                 .get(*self.value_ref)
                 .expect("inconsistent internal tree structure");
 
             let encoded_path = path.encode();
-            let encoded_value = value.encode();
 
             let mut path_slice = NibbleSlice::new(encoded_path.as_ref());
             path_slice.offset_add(path_offset);
 
-            compute_leaf_hash(&self.hash, path_slice, encoded_value.as_ref())
+            compute_leaf_hash(&self.hash, path_slice, &**value)
         })
     }
+
+    /// Return the node's RLP encoding.
+    pub fn encode(
+        &self,
+        _nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
+        path_offset: usize,
+    ) -> Vec<u8> {
+        let (path, value) = values
+            .get(*self.value_ref)
+            .expect("inconsistent internal tree structure");
+
+        let encoded_path = path.encode();
+
+        let mut path_slice = NibbleSlice::new(encoded_path.as_ref());
+        path_slice.offset_add(path_offset);
+
+        encode_leaf::<H>(path_slice, &**value)
+    }
 }
 
 pub fn compute_leaf_hash<'a, H>(
     hash: &'a NodeHash<H>,
     path: NibbleSlice,
-    value: &[u8],
+    value: &(impl Encode + ?Sized),
 ) -> NodeHashRef<'a, H>
 where
     H: Digest,
 {
-    let path_len = NodeHasher::<H>::path_len(path.len());
-    let value_len =
-        NodeHasher::<H>::bytes_len(value.len(), value.first().copied().unwrap_or_default());
-
     let mut hasher = NodeHasher::new(hash);
-    hasher.write_list_header(path_len + value_len);
-    hasher.write_path_slice(&path, PathKind::Leaf);
-    hasher.write_bytes(value);
+    write_leaf::<_, H>(&mut hasher, path, value);
     hasher.finalize()
 }
 
+pub fn encode_leaf<H>(path: NibbleSlice, value: &(impl Encode + ?Sized)) -> Vec<u8>
+where
+    H: Digest,
+{
+    let mut encoder = NodeEncoder::default();
+    write_leaf::<_, H>(&mut encoder, path, value);
+    encoder.finalize()
+}
+
+fn write_leaf<W, H>(writer: &mut W, path: NibbleSlice, value: &(impl Encode + ?Sized))
+where
+    W: NodeWriter,
+    H: Digest,
+{
+    let path_len = NodeHasher::<H>::path_len(path.len());
+    let value_len = NodeHasher::<H>::value_len(value);
+
+    writer.write_list_header(path_len + value_len);
+    writer.write_path_slice(&path, PathKind::Leaf);
+    writer.write_value(value);
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -224,6 +284,7 @@ mod test {
 
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x12]))
+                .unwrap()
                 .map(Vec::as_slice),
             Some([0x12, 0x34, 0x56, 0x78].as_slice()),
         );
@@ -239,6 +300,7 @@ mod test {
 
         assert_eq!(
             node.get(&nodes, &values, NibbleSlice::new(&[0x34]))
+                .unwrap()
                 .map(Vec::as_slice),
             None,
         );
@@ -252,7 +314,9 @@ mod test {
             leaf { vec![0x12] => vec![0x12, 0x34, 0x56, 0x78] }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x12]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x12]))
+            .unwrap();
         let node = match node {
             Node::Leaf(x) => x,
             _ => panic!("expected a leaf node"),
@@ -271,7 +335,9 @@ mod test {
             leaf { vec![0x12] => vec![0x12, 0x34, 0x56, 0x78] }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x22]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x22]))
+            .unwrap();
         let _ = match node {
             Node::Branch(x) => x,
             _ => panic!("expected a branch node"),
@@ -289,7 +355,9 @@ mod test {
             leaf { vec![0x12] => vec![0x12, 0x34, 0x56, 0x78] }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x13]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x13]))
+            .unwrap();
         let _ = match node {
             Node::Extension(x) => x,
             _ => panic!("expected an extension node"),
@@ -307,8 +375,9 @@ mod test {
             leaf { vec![0x12] => vec![0x12, 0x34, 0x56, 0x78] }
         };
 
-        let (node, insert_action) =
-            node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x12, 0x34]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x12, 0x34]))
+            .unwrap();
         let _ = match node {
             Node::Extension(x) => x,
             _ => panic!("expected an extension node"),
@@ -326,7 +395,9 @@ mod test {
             leaf { vec![0x12, 0x34] => vec![0x12, 0x34, 0x56, 0x78] }
         };
 
-        let (node, insert_action) = node.insert(&mut nodes, &mut values, NibbleSlice::new(&[0x12]));
+        let (node, insert_action) = node
+            .insert(&mut nodes, &mut values, NibbleSlice::new(&[0x12]))
+            .unwrap();
         let _ = match node {
             Node::Extension(x) => x,
             _ => panic!("expected an extension node"),
@@ -352,7 +423,9 @@ mod test {
             leaf { vec![0x12, 0x34] => vec![0x12, 0x34, 0x56, 0x78] }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x12, 0x34]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x12, 0x34]))
+            .unwrap();
 
         assert!(node.is_none());
         assert_eq!(value, Some(vec![0x12, 0x34, 0x56, 0x78]));
@@ -366,7 +439,9 @@ mod test {
             leaf { vec![0x12, 0x34] => vec![0x12, 0x34, 0x56, 0x78] }
         };
 
-        let (node, value) = node.remove(&mut nodes, &mut values, NibbleSlice::new(&[0x12]));
+        let (node, value) = node
+            .remove(&mut nodes, &mut values, NibbleSlice::new(&[0x12]))
+            .unwrap();
 
         assert!(node.is_some());
         assert_eq!(value, None);
@@ -0,0 +1,43 @@
+use crate::hashing::NodeHashRef;
+#[cfg(feature = "node-ids")]
+use crate::NodeId;
+use digest::{Digest, Output, OutputSizeUser};
+
+/// A subtree known only by its hash, whose nodes are stored elsewhere (see
+/// [`BackedTrie`](crate::BackedTrie)).
+///
+/// Stubs are expanded before the operations which need their contents. Anything else only sees
+/// the values in memory: traversals skip stubs, and operations which can't do without their
+/// contents fail with a [`CorruptionError`](crate::CorruptionError).
+#[derive(Clone, Debug)]
+pub struct StubNode<H>
+where
+    H: OutputSizeUser,
+{
+    pub(crate) hash: Output<H>,
+
+    #[cfg(feature = "node-ids")]
+    pub(crate) id: NodeId,
+}
+
+impl<H> StubNode<H>
+where
+    H: OutputSizeUser,
+{
+    pub(crate) fn new(hash: Output<H>) -> Self {
+        Self {
+            hash,
+            #[cfg(feature = "node-ids")]
+            id: NodeId::next(),
+        }
+    }
+}
+
+impl<H> StubNode<H>
+where
+    H: Digest,
+{
+    pub fn compute_hash(&self) -> NodeHashRef<'_, H> {
+        NodeHashRef::Hashed(&self.hash)
+    }
+}
@@ -0,0 +1,423 @@
+use crate::{
+    corruption::CorruptionError,
+    node::Node,
+    storage::{NodeRef, ValueRef},
+    PatriciaMerkleTree,
+};
+use digest::Digest;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    mem,
+};
+use thiserror::Error;
+
+/// A single operation on a tree, used to drive model-based fuzzing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum TreeOp {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Get(Vec<u8>),
+    ComputeHash,
+}
+
+/// Where and how a sequence of operations made a tree disagree with a `BTreeMap` model.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum OpsFailure {
+    #[error("operation {index} found the tree corrupt: {error}")]
+    Corruption {
+        index: usize,
+        error: CorruptionError,
+    },
+    #[error("operation {index} returned a different value than the model")]
+    ValueMismatch { index: usize },
+    #[error("tree length mismatch after operation {index}")]
+    LenMismatch { index: usize },
+    #[error("root hash mismatch after operation {index}")]
+    RootMismatch { index: usize },
+}
+
+impl OpsFailure {
+    /// Return the index of the operation after which the failure was found. Failures found by
+    /// the checks done once every operation was applied have the number of operations as index.
+    pub fn index(&self) -> usize {
+        match self {
+            OpsFailure::Corruption { index, .. }
+            | OpsFailure::ValueMismatch { index }
+            | OpsFailure::LenMismatch { index }
+            | OpsFailure::RootMismatch { index } => *index,
+        }
+    }
+
+    /// Return whether both failures are of the same kind, wherever they were found.
+    fn is_same_kind(&self, other: &Self) -> bool {
+        mem::discriminant(self) == mem::discriminant(other)
+    }
+}
+
+/// Apply a sequence of operations both to a tree and to a `BTreeMap` model, panicking as soon as
+/// they disagree.
+///
+/// Every operation's result is compared against the model, and the tree's root hash is checked
+/// against the one of a tree built from scratch using the model's contents. Intended to be called
+/// from fuzz targets and property tests.
+pub fn apply_ops<H>(ops: impl IntoIterator<Item = TreeOp>)
+where
+    H: Digest,
+{
+    if let Err(failure) = check_ops::<H>(&ops.into_iter().collect::<Vec<_>>()) {
+        panic!("{failure}");
+    }
+}
+
+/// Same as [`apply_ops`], but returns the first disagreement found instead of panicking.
+pub fn check_ops<H>(ops: &[TreeOp]) -> Result<(), OpsFailure>
+where
+    H: Digest,
+{
+    let mut tree = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, H>::new();
+    let mut model = BTreeMap::<Vec<u8>, Vec<u8>>::new();
+
+    for (index, op) in ops.iter().enumerate() {
+        let corruption = |error| OpsFailure::Corruption { index, error };
+        let matches = match op {
+            TreeOp::Insert(path, value) => {
+                tree.try_insert(path.clone(), value.clone())
+                    .map_err(corruption)?
+                    == model.insert(path.clone(), value.clone())
+            }
+            TreeOp::Remove(path) => {
+                tree.try_remove(path.clone()).map_err(corruption)? == model.remove(path)
+            }
+            TreeOp::Get(path) => tree.try_get(path).map_err(corruption)? == model.get(path),
+            TreeOp::ComputeHash => {
+                if !has_expected_hash(&mut tree, &model) {
+                    return Err(OpsFailure::RootMismatch { index });
+                }
+                true
+            }
+        };
+
+        if !matches {
+            return Err(OpsFailure::ValueMismatch { index });
+        }
+        if tree.len() != model.len() {
+            return Err(OpsFailure::LenMismatch { index });
+        }
+    }
+
+    let index = ops.len();
+    for (path, value) in &model {
+        let corruption = |error| OpsFailure::Corruption { index, error };
+        if tree.try_get(path).map_err(corruption)? != Some(value) {
+            return Err(OpsFailure::ValueMismatch { index });
+        }
+    }
+    if !has_expected_hash(&mut tree, &model) {
+        return Err(OpsFailure::RootMismatch { index });
+    }
+
+    Ok(())
+}
+
+fn has_expected_hash<H>(
+    tree: &mut PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>,
+    model: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> bool
+where
+    H: Digest,
+{
+    let mut expected = PatriciaMerkleTree::<_, _, H>::from_sorted_iter(
+        model.iter().map(|(p, v)| (p.clone(), v.clone())),
+    );
+
+    tree.compute_hash() == expected.compute_hash()
+}
+
+/// A minimal sequence of operations reproducing a failure, as found by [`minimize_ops`].
+///
+/// Its [`Display`] implementation writes a unit test calling [`apply_ops`], followed by the
+/// structure of the tree right before the failing operation in the syntax of the `pmt_node!`
+/// macro used by the crate's unit tests.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reproducer {
+    pub ops: Vec<TreeOp>,
+    pub failure: OpsFailure,
+    /// The tree before the failing operation, in `pmt_node!` syntax.
+    pub tree: String,
+}
+
+/// Shrink a sequence of operations which makes [`check_ops`] fail into a minimal one failing in
+/// the same way, or return `None` if it doesn't fail.
+///
+/// Operations after the failing one are dropped, then chunks of operations (halving their size
+/// down to single operations) are removed for as long as the failure is preserved. The result is
+/// 1-minimal: removing any single operation from it makes the failure go away (or change kind).
+pub fn minimize_ops<H>(ops: &[TreeOp]) -> Option<Reproducer>
+where
+    H: Digest,
+{
+    let (ops, failure) = shrink(ops, |ops| check_ops::<H>(ops).err())?;
+
+    let mut tree = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, H>::new();
+    for op in &ops[..failure.index().min(ops.len())] {
+        // Failures before this point would have been found first.
+        match op {
+            TreeOp::Insert(path, value) => {
+                tree.try_insert(path.clone(), value.clone()).ok();
+            }
+            TreeOp::Remove(path) => {
+                tree.try_remove(path.clone()).ok();
+            }
+            TreeOp::Get(_) | TreeOp::ComputeHash => {}
+        }
+    }
+
+    Some(Reproducer {
+        tree: to_pmt_node(&tree),
+        ops,
+        failure,
+    })
+}
+
+/// Shrink `ops` given a function returning how a sequence of operations fails, if it does.
+fn shrink(
+    ops: &[TreeOp],
+    mut check: impl FnMut(&[TreeOp]) -> Option<OpsFailure>,
+) -> Option<(Vec<TreeOp>, OpsFailure)> {
+    let mut failure = check(ops)?;
+    let mut ops = ops[..(failure.index() + 1).min(ops.len())].to_vec();
+
+    let mut chunk_len = ops.len().div_ceil(2).max(1);
+    loop {
+        let mut removed = false;
+        let mut start = 0;
+        while start < ops.len() {
+            let end = (start + chunk_len).min(ops.len());
+            let candidate = [&ops[..start], &ops[end..]].concat();
+            match check(&candidate) {
+                Some(new_failure) if new_failure.is_same_kind(&failure) => {
+                    ops = candidate;
+                    failure = new_failure;
+                    removed = true;
+                }
+                _ => start = end,
+            }
+        }
+
+        if chunk_len == 1 && !removed {
+            break;
+        }
+        if !removed {
+            chunk_len = chunk_len.div_ceil(2);
+        }
+    }
+
+    Some((ops, failure))
+}
+
+/// Describe the structure of a tree in the syntax of the `pmt_node!` macro.
+fn to_pmt_node<H>(tree: &PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>) -> String
+where
+    H: Digest,
+{
+    let mut output = String::new();
+    match tree.root_ref.is_valid() {
+        true => write_node(tree, tree.root_ref, 0, &mut output),
+        false => output.push_str("(empty)"),
+    }
+
+    output
+}
+
+fn write_node<H>(
+    tree: &PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>,
+    node_ref: NodeRef,
+    depth: usize,
+    output: &mut String,
+) where
+    H: Digest,
+{
+    let indent = "    ".repeat(depth + 1);
+    let leaf = |value_ref: ValueRef| match tree.values.get(*value_ref) {
+        Some((path, value)) => format!("{} => {}", to_vec_literal(path), to_vec_literal(value)),
+        None => "(missing value)".to_string(),
+    };
+
+    match tree.nodes.get(*node_ref) {
+        Some(Node::Branch(branch_node)) => {
+            output.push_str("branch {\n");
+            for (choice, child_ref) in branch_node.choices.iter().enumerate() {
+                if child_ref.is_valid() {
+                    output.push_str(&format!("{indent}{choice} => "));
+                    write_node(tree, *child_ref, depth + 1, output);
+                    output.push_str(",\n");
+                }
+            }
+            output.push_str(&"    ".repeat(depth));
+            output.push('}');
+            if branch_node.value_ref.is_valid() {
+                output.push_str(&format!(" with_leaf {{ {} }}", leaf(branch_node.value_ref)));
+            }
+        }
+        Some(Node::Extension(extension_node)) => {
+            let prefix = extension_node
+                .prefix
+                .iter()
+                .map(|x| u8::from(x).to_string())
+                .collect::<Vec<_>>();
+            output.push_str(&format!("extension {{ [{}], ", prefix.join(", ")));
+            write_node(tree, extension_node.child_ref, depth, output);
+            output.push_str(" }");
+        }
+        Some(Node::Leaf(leaf_node)) => {
+            output.push_str(&format!("leaf {{ {} }}", leaf(leaf_node.value_ref)));
+        }
+        Some(Node::Stub(stub_node)) => {
+            output.push_str(&format!("stub {{ {} }}", to_vec_literal(&stub_node.hash)));
+        }
+        None => output.push_str("(missing node)"),
+    }
+}
+
+fn to_vec_literal(bytes: &[u8]) -> String {
+    let bytes = bytes
+        .iter()
+        .map(|x| format!("0x{x:02X}"))
+        .collect::<Vec<_>>();
+    format!("vec![{}]", bytes.join(", "))
+}
+
+impl Display for Reproducer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "// {}", self.failure)?;
+        writeln!(f, "#[test]")?;
+        writeln!(f, "fn minimized() {{")?;
+        writeln!(f, "    apply_ops::<Keccak256>([")?;
+        for op in &self.ops {
+            match op {
+                TreeOp::Insert(path, value) => writeln!(
+                    f,
+                    "        TreeOp::Insert({}, {}),",
+                    to_vec_literal(path),
+                    to_vec_literal(value)
+                )?,
+                TreeOp::Remove(path) => {
+                    writeln!(f, "        TreeOp::Remove({}),", to_vec_literal(path))?
+                }
+                TreeOp::Get(path) => writeln!(f, "        TreeOp::Get({}),", to_vec_literal(path))?,
+                TreeOp::ComputeHash => writeln!(f, "        TreeOp::ComputeHash,")?,
+            }
+        }
+        writeln!(f, "    ]);")?;
+        writeln!(f, "}}")?;
+        writeln!(f)?;
+        writeln!(f, "// Tree before operation {}:", self.failure.index())?;
+        writeln!(f, "// pmt_node! {{ @(nodes, values)")?;
+        for line in self.tree.lines() {
+            writeln!(f, "//     {line}")?;
+        }
+        writeln!(f, "// }}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::strategies::{nibble_paths, tree_ops};
+    use proptest::prelude::*;
+    use sha3::Keccak256;
+
+    #[test]
+    fn remove_merges_extensions() {
+        apply_ops::<Keccak256>([
+            TreeOp::Insert(vec![0x00, 0x00], vec![0x01; 32]),
+            TreeOp::Insert(vec![0x00, 0x01], vec![0x02; 32]),
+            TreeOp::Insert(vec![0x01, 0x00, 0x00], vec![0x03; 32]),
+            TreeOp::Insert(vec![0x01, 0x00, 0x01], vec![0x04; 32]),
+            TreeOp::ComputeHash,
+            TreeOp::Remove(vec![0x00, 0x00]),
+            TreeOp::Remove(vec![0x00, 0x01]),
+            TreeOp::Get(vec![0x01, 0x00, 0x01]),
+        ]);
+    }
+
+    #[test]
+    fn minimize() {
+        let ops = (0..32u8)
+            .map(|i| TreeOp::Insert(vec![i], vec![i]))
+            .chain([TreeOp::ComputeHash, TreeOp::Remove(vec![0x12])])
+            .collect::<Vec<_>>();
+        assert_eq!(check_ops::<Keccak256>(&ops), Ok(()));
+        assert_eq!(minimize_ops::<Keccak256>(&ops), None);
+
+        // A failure which needs two inserts, followed by a removal of one of them.
+        let check = |ops: &[TreeOp]| {
+            let position = |op: &TreeOp| ops.iter().position(|x| x == op);
+            let first = position(&TreeOp::Insert(vec![3], vec![3]))?;
+            let second = position(&TreeOp::Insert(vec![20], vec![20]))?;
+            let index = position(&TreeOp::Remove(vec![3]))?;
+            (first.max(second) < index).then_some(OpsFailure::RootMismatch { index })
+        };
+        let mut ops = ops;
+        ops.insert(25, TreeOp::Remove(vec![3]));
+        let (minimized, failure) = shrink(&ops, check).unwrap();
+        assert_eq!(
+            minimized,
+            [
+                TreeOp::Insert(vec![3], vec![3]),
+                TreeOp::Insert(vec![20], vec![20]),
+                TreeOp::Remove(vec![3]),
+            ]
+        );
+        assert_eq!(failure, OpsFailure::RootMismatch { index: 2 });
+    }
+
+    #[test]
+    fn pmt_node_syntax() {
+        let mut tree = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, Keccak256>::new();
+        tree.insert(vec![0x00], vec![0x01]);
+        tree.insert(vec![0x01], vec![0x02]);
+        tree.insert(vec![0x00, 0x00], vec![0x03]);
+        assert_eq!(
+            to_pmt_node(&tree),
+            "extension { [0], branch {
+    0 => branch {
+        0 => leaf { vec![0x00, 0x00] => vec![0x03] },
+    } with_leaf { vec![0x00] => vec![0x01] },
+    1 => leaf { vec![0x01] => vec![0x02] },
+} }"
+        );
+
+        let reproducer = Reproducer {
+            ops: vec![TreeOp::Insert(vec![0x12], vec![]), TreeOp::ComputeHash],
+            failure: OpsFailure::RootMismatch { index: 1 },
+            tree: "leaf { vec![0x12] => vec![] }".to_string(),
+        };
+        assert_eq!(
+            reproducer.to_string(),
+            "// root hash mismatch after operation 1
+#[test]
+fn minimized() {
+    apply_ops::<Keccak256>([
+        TreeOp::Insert(vec![0x12], vec![]),
+        TreeOp::ComputeHash,
+    ]);
+}
+
+// Tree before operation 1:
+// pmt_node! { @(nodes, values)
+//     leaf { vec![0x12] => vec![] }
+// }
+"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_apply_ops(ops in tree_ops(nibble_paths(&[0x0, 0x1, 0xF], 1..6), 0..64)) {
+            apply_ops::<Keccak256>(ops);
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use crate::{
+    db::{MemoryDb, MissingNode, NodeDb, UndecodableNode},
+    hex,
+    nibble::NibbleSlice,
+    proof::{prefix_overlaps, prefix_within, to_nibbles},
+    rlp::{ChildRef, RawNode},
+};
+use digest::{Digest, Output};
+use std::collections::{hash_map::Entry, HashMap};
+use thiserror::Error;
+
+/// A hashed node's hash and path prefix.
+type Reference = (Vec<u8>, Vec<u8>);
+
+/// Errors found while building or merging partial tries.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum PartialTrieError {
+    #[error("the partial tries have different roots")]
+    RootMismatch,
+    #[error("node {} is not reachable from the root", hex::encode(.0))]
+    UnreachableNode(Vec<u8>),
+    #[error("node {} can't be decoded: {}", hex::encode(&.0.hash), .0.error)]
+    UndecodableNode(UndecodableNode),
+}
+
+/// A subset of a trie's nodes (for example, a witness), indexed by their hash.
+///
+/// Every node is checked to be reachable from the root, so paths which lead to nodes outside the
+/// subset fail with a [`MissingNode`] instead of returning wrong results.
+///
+/// The hashes referenced by the known nodes are kept, so that nodes added one by one are checked
+/// without visiting the others.
+#[derive(Clone, Debug)]
+pub struct PartialTrie<H>
+where
+    H: Digest,
+{
+    root: Output<H>,
+    nodes: MemoryDb,
+    /// The path prefix of every hashed node referenced by a known node (or the root), whether
+    /// it's known or not.
+    references: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl<H> PartialTrie<H>
+where
+    H: Digest,
+{
+    /// Create a partial trie which knows only its root hash.
+    ///
+    /// Nodes can be added as they're found missing (see [`add_node`](Self::add_node)).
+    pub fn new(root: Output<H>) -> Self {
+        Self {
+            references: HashMap::from([(root.to_vec(), Vec::new())]),
+            root,
+            nodes: MemoryDb::new(),
+        }
+    }
+
+    /// Build a partial trie from a root hash and the RLP encodings of some of its nodes, in any
+    /// order.
+    pub fn from_nodes(
+        root: Output<H>,
+        nodes: impl IntoIterator<Item = Vec<u8>>,
+    ) -> Result<Self, PartialTrieError> {
+        let mut db = MemoryDb::new();
+        for encoded in nodes {
+            db.insert(H::digest(&encoded).to_vec(), encoded);
+        }
+
+        let mut partial_trie = Self {
+            root,
+            nodes: db,
+            references: HashMap::new(),
+        };
+        partial_trie.references = partial_trie.collect_references()?;
+
+        Ok(partial_trie)
+    }
+
+    /// Return the trie's root hash.
+    pub fn root(&self) -> &Output<H> {
+        &self.root
+    }
+
+    /// Return the number of nodes known.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Return whether no nodes are known.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Return whether the node with the given hash is known.
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.nodes.get(hash).is_some()
+    }
+
+    /// Retrieve a value from the trie given its path.
+    ///
+    /// Fails if the path goes through a node which is not known.
+    pub fn get(&self, path: &[u8]) -> Result<Option<Vec<u8>>, MissingNode> {
+        if self.root == H::digest([0x80]) {
+            return Ok(None);
+        }
+
+        let mut path = NibbleSlice::new(path);
+        let mut path_prefix = Vec::new();
+        let mut child_ref = ChildRef::Hash(self.root.to_vec());
+        loop {
+            let encoded = self.resolve(child_ref, &path_prefix)?;
+
+            child_ref = match RawNode::decode(&encoded).expect("nodes are validated when inserted")
+            {
+                RawNode::Branch { mut choices, value } => match path.next() {
+                    Some(nibble) => match choices[nibble as usize].take() {
+                        Some(child_ref) => {
+                            path_prefix.push(nibble as u8);
+                            child_ref
+                        }
+                        None => return Ok(None),
+                    },
+                    None => return Ok(value),
+                },
+                RawNode::Extension { prefix, child } => {
+                    for nibble in &prefix {
+                        if path.next().map(|x| x as u8) != Some(*nibble) {
+                            return Ok(None);
+                        }
+                    }
+
+                    path_prefix.extend(prefix);
+                    child
+                }
+                RawNode::Leaf {
+                    path: leaf_path,
+                    value,
+                } => {
+                    let matches = path.clone().map(|x| x as u8).eq(leaf_path);
+                    return Ok(matches.then_some(value));
+                }
+            };
+        }
+    }
+
+    /// Return whether there are values strictly between `left` and `right`.
+    ///
+    /// Fails if the known nodes aren't enough to tell, which happens when a node overlapping the
+    /// range only partially is missing.
+    pub fn has_values_between(&self, left: &[u8], right: &[u8]) -> Result<bool, MissingNode> {
+        if self.root == H::digest([0x80]) {
+            return Ok(false);
+        }
+
+        let left = to_nibbles(left);
+        let right = to_nibbles(right);
+
+        let mut stack = vec![(ChildRef::Hash(self.root.to_vec()), Vec::new())];
+        while let Some((child_ref, path_prefix)) = stack.pop() {
+            if !prefix_overlaps(&path_prefix, &left, &right) {
+                continue;
+            }
+            // Every node has at least one value below it.
+            if prefix_within(&path_prefix, &left, &right) {
+                return Ok(true);
+            }
+
+            let encoded = self.resolve(child_ref, &path_prefix)?;
+
+            let node = RawNode::decode(&encoded).expect("nodes are validated when inserted");
+            let value_path = match &node {
+                RawNode::Branch { value, .. } => value.as_ref().map(|_| path_prefix.clone()),
+                RawNode::Extension { .. } => None,
+                RawNode::Leaf { path, .. } => Some([path_prefix.as_slice(), path].concat()),
+            };
+            if let Some(value_path) = value_path {
+                if value_path > left && value_path < right {
+                    return Ok(true);
+                }
+            }
+
+            for (nibbles, child_ref) in node.children() {
+                stack.push((
+                    child_ref.clone(),
+                    [path_prefix.as_slice(), nibbles].concat(),
+                ));
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Add a node, which must be referenced by one of the known nodes (or be the root).
+    ///
+    /// Meant to be used after an operation fails with a [`MissingNode`]: once the node is fetched
+    /// and added, the operation can be retried. Only the new node is checked, so adding `n` nodes
+    /// takes O(n).
+    pub fn add_node(&mut self, encoded: Vec<u8>) -> Result<(), PartialTrieError> {
+        let hash = H::digest(&encoded).to_vec();
+        if self.contains(&hash) {
+            return Ok(());
+        }
+
+        let path_prefix = match self.references.get(&hash) {
+            Some(path_prefix) => path_prefix.clone(),
+            None => return Err(PartialTrieError::UnreachableNode(hash)),
+        };
+        for (child_hash, child_prefix) in referenced_nodes(hash.clone(), &encoded, path_prefix)? {
+            self.references.entry(child_hash).or_insert(child_prefix);
+        }
+
+        self.nodes.insert(hash, encoded);
+        Ok(())
+    }
+
+    /// Add every node of `other` (another subset of the same trie) into this one.
+    ///
+    /// Both tries must have the same root. Since both node sets are reachable from it, their union
+    /// is a consistent subset of the trie too.
+    pub fn merge(&mut self, other: Self) -> Result<(), PartialTrieError> {
+        if self.root != other.root {
+            return Err(PartialTrieError::RootMismatch);
+        }
+
+        for (hash, encoded) in other.nodes.iter() {
+            if !self.contains(hash) {
+                self.nodes.insert(hash.clone(), encoded.clone());
+            }
+        }
+        for (hash, path_prefix) in other.references {
+            self.references.entry(hash).or_insert(path_prefix);
+        }
+
+        Ok(())
+    }
+
+    /// Iterate over every `(hash, encoded node)` pair known.
+    pub fn nodes(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.nodes.iter()
+    }
+
+    /// Return the encoding of the node behind a reference at `path_prefix` (in nibbles).
+    pub(crate) fn resolve(
+        &self,
+        child_ref: ChildRef,
+        path_prefix: &[u8],
+    ) -> Result<Vec<u8>, MissingNode> {
+        match child_ref {
+            ChildRef::Hash(hash) => self.nodes.get(&hash).ok_or_else(|| MissingNode {
+                hash,
+                path_prefix: path_prefix.to_vec(),
+            }),
+            ChildRef::Inline(encoded) => Ok(encoded),
+        }
+    }
+
+    /// Check that every node is decodable and reachable from the root.
+    ///
+    /// It visits every known node. Nodes are already checked when they're added, so this is only
+    /// needed to double-check the whole trie.
+    pub fn validate(&self) -> Result<(), PartialTrieError> {
+        self.collect_references().map(|_| ())
+    }
+
+    /// Check that every node is decodable and reachable from the root, and return the path prefix
+    /// of every hashed node referenced.
+    fn collect_references(&self) -> Result<HashMap<Vec<u8>, Vec<u8>>, PartialTrieError> {
+        let mut references = HashMap::from([(self.root.to_vec(), Vec::new())]);
+        let mut stack = vec![self.root.to_vec()];
+        while let Some(hash) = stack.pop() {
+            let Some(encoded) = self.nodes.get(&hash) else {
+                continue;
+            };
+
+            let path_prefix = references[&hash].clone();
+            for (child_hash, child_prefix) in referenced_nodes(hash, &encoded, path_prefix)? {
+                if let Entry::Vacant(entry) = references.entry(child_hash.clone()) {
+                    entry.insert(child_prefix);
+                    stack.push(child_hash);
+                }
+            }
+        }
+
+        if let Some((hash, _)) = self
+            .nodes
+            .iter()
+            .find(|(hash, _)| !references.contains_key(*hash))
+        {
+            return Err(PartialTrieError::UnreachableNode(hash.clone()));
+        }
+
+        Ok(references)
+    }
+}
+
+/// Decode a node at `path_prefix`, and return the hashed nodes it references (directly or through
+/// inline nodes) along with their path prefixes.
+fn referenced_nodes(
+    hash: Vec<u8>,
+    encoded: &[u8],
+    path_prefix: Vec<u8>,
+) -> Result<Vec<Reference>, PartialTrieError> {
+    let mut references = Vec::new();
+    let mut stack = vec![(hash, encoded.to_vec(), path_prefix)];
+    while let Some((hash, encoded, path_prefix)) = stack.pop() {
+        let node = RawNode::decode(&encoded).map_err(|error| {
+            PartialTrieError::UndecodableNode(UndecodableNode {
+                hash,
+                path_prefix: path_prefix.clone(),
+                error,
+            })
+        })?;
+        for (nibbles, child_ref) in node.children() {
+            let child_prefix = [path_prefix.as_slice(), nibbles].concat();
+            match child_ref {
+                ChildRef::Hash(hash) => references.push((hash.clone(), child_prefix)),
+                ChildRef::Inline(encoded) => {
+                    stack.push((encoded.clone(), encoded.clone(), child_prefix))
+                }
+            }
+        }
+    }
+
+    Ok(references)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{db::commit, pmt_tree};
+    use sha3::Keccak256;
+
+    /// Collect the hashed nodes on the way to `path`, as a proof would.
+    fn witness(db: &MemoryDb, root: &[u8], path: &[u8]) -> Vec<Vec<u8>> {
+        let nibbles = NibbleSlice::new(path).map(|x| x as u8).collect::<Vec<_>>();
+
+        let mut nodes = Vec::new();
+        let mut depth = 0;
+        let mut child_ref = ChildRef::Hash(root.to_vec());
+        loop {
+            let encoded = match child_ref {
+                ChildRef::Hash(hash) => {
+                    let encoded = db.get(&hash).unwrap();
+                    nodes.push(encoded.clone());
+                    encoded
+                }
+                ChildRef::Inline(encoded) => encoded,
+            };
+
+            child_ref = match RawNode::decode(&encoded).unwrap() {
+                RawNode::Branch { mut choices, .. } if depth < nibbles.len() => {
+                    depth += 1;
+                    match choices[nibbles[depth - 1] as usize].take() {
+                        Some(child_ref) => child_ref,
+                        None => break,
+                    }
+                }
+                RawNode::Extension { prefix, child } => {
+                    depth += prefix.len();
+                    child
+                }
+                _ => break,
+            };
+        }
+
+        nodes
+    }
+
+    fn build_db() -> (MemoryDb, Output<Keccak256>) {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+
+        let mut db = MemoryDb::new();
+        let root = commit(&mut tree, &mut db);
+
+        (db, root)
+    }
+
+    #[test]
+    fn get_from_witness() {
+        let (db, root) = build_db();
+        let partial =
+            PartialTrie::<Keccak256>::from_nodes(root, witness(&db, &root, &[3, 21])).unwrap();
+
+        assert_eq!(partial.get(&[3, 21]), Ok(Some(vec![3; 24])));
+        assert_eq!(partial.get(&[3, 22]), Ok(None));
+        assert!(partial.get(&[40, 24]).is_err());
+    }
+
+    #[test]
+    fn merge() {
+        let (db, root) = build_db();
+        let mut partial =
+            PartialTrie::<Keccak256>::from_nodes(root, witness(&db, &root, &[3, 21])).unwrap();
+        let other =
+            PartialTrie::<Keccak256>::from_nodes(root, witness(&db, &root, &[40, 24])).unwrap();
+
+        let len = partial.len();
+        partial.merge(other.clone()).unwrap();
+
+        assert!(partial.len() > len);
+        assert!(partial.len() < len + other.len());
+        assert_eq!(partial.get(&[3, 21]), Ok(Some(vec![3; 24])));
+        assert_eq!(partial.get(&[40, 24]), Ok(Some(vec![40; 24])));
+    }
+
+    #[test]
+    fn merge_root_mismatch() {
+        let (db, root) = build_db();
+        let mut partial =
+            PartialTrie::<Keccak256>::from_nodes(root, witness(&db, &root, &[3, 21])).unwrap();
+        let other = PartialTrie::<Keccak256>::from_nodes(Keccak256::digest([0x80]), []).unwrap();
+
+        assert_eq!(partial.merge(other), Err(PartialTrieError::RootMismatch));
+    }
+
+    #[test]
+    fn unreachable_node() {
+        let (db, root) = build_db();
+        let mut nodes = witness(&db, &root, &[3, 21]);
+
+        let mut other_tree = pmt_tree!(Vec<u8>);
+        other_tree.insert(vec![0x12], vec![0x34; 32]);
+        let mut other_db = MemoryDb::new();
+        let other_root = commit(&mut other_tree, &mut other_db);
+        let extra = other_db.get(&other_root).unwrap();
+        nodes.push(extra.clone());
+
+        assert_eq!(
+            PartialTrie::<Keccak256>::from_nodes(root, nodes).unwrap_err(),
+            PartialTrieError::UnreachableNode(Keccak256::digest(extra).to_vec()),
+        );
+    }
+
+    #[test]
+    fn undecodable_node() {
+        let encoded = vec![0xC1, 0x80];
+        let root = Keccak256::digest(&encoded);
+
+        assert!(matches!(
+            PartialTrie::<Keccak256>::from_nodes(root, [encoded]),
+            Err(PartialTrieError::UndecodableNode(_)),
+        ));
+    }
+
+    #[test]
+    fn fetch_on_demand() {
+        let (db, root) = build_db();
+        let mut partial = PartialTrie::<Keccak256>::new(root);
+
+        let mut fetched = 0;
+        let value = loop {
+            match partial.get(&[40, 24]) {
+                Ok(value) => break value,
+                Err(MissingNode { hash, .. }) => {
+                    partial.add_node(db.get(&hash).unwrap()).unwrap();
+                    fetched += 1;
+                }
+            }
+        };
+
+        assert_eq!(value, Some(vec![40; 24]));
+        assert_eq!(fetched, witness(&db, &root, &[40, 24]).len());
+        assert_eq!(partial.len(), fetched);
+        assert_eq!(partial.validate(), Ok(()));
+    }
+
+    #[test]
+    fn add_unreachable_node() {
+        let (db, root) = build_db();
+        let mut partial = PartialTrie::<Keccak256>::new(root);
+
+        let node = witness(&db, &root, &[40, 24]).pop().unwrap();
+        assert!(matches!(
+            partial.add_node(node),
+            Err(PartialTrieError::UnreachableNode(_)),
+        ));
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn add_undecodable_node() {
+        let encoded = vec![0xC1, 0x80];
+        let mut partial = PartialTrie::<Keccak256>::new(Keccak256::digest(&encoded));
+
+        assert!(matches!(
+            partial.add_node(encoded),
+            Err(PartialTrieError::UndecodableNode(_)),
+        ));
+        assert!(partial.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{
+    codec::Decode,
+    corruption::CorruptionError,
+    hashing::{NodeEncoder, NodeWriter},
+    rlp::{decode_list, expect_list, write_list, DecodeError, RlpItem},
+    Encode, PatriciaMerkleTree,
+};
+use digest::Digest;
+use thiserror::Error;
+
+/// The changes bringing a tree from one root to another, which can be shipped elsewhere and
+/// applied to a copy of the old tree.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Patch {
+    pub old_root: Vec<u8>,
+    pub new_root: Vec<u8>,
+    /// The changed paths along with their new values (`None` for removals), all encoded and in
+    /// ascending path order.
+    pub changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+/// Errors found while applying a patch.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum PatchError {
+    #[error("the tree's root doesn't match the patch's old root")]
+    OldRootMismatch,
+    #[error("the patched tree's root doesn't match the patch's new root")]
+    NewRootMismatch,
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Corruption(#[from] CorruptionError),
+}
+
+impl Patch {
+    /// Return the patch's RLP encoding.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut changes = NodeEncoder::default();
+        for (path, value) in &self.changes {
+            let mut change = NodeEncoder::default();
+            change.write_bytes(path);
+            if let Some(value) = value {
+                change.write_bytes(value);
+            }
+            write_list(&mut changes, &change.finalize());
+        }
+
+        let mut payload = NodeEncoder::default();
+        payload.write_bytes(&self.old_root);
+        payload.write_bytes(&self.new_root);
+        write_list(&mut payload, &changes.finalize());
+
+        let mut encoder = NodeEncoder::default();
+        write_list(&mut encoder, &payload.finalize());
+        encoder.finalize()
+    }
+
+    /// Decode a patch from its RLP encoding.
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let items = decode_list(expect_list(data)?)?;
+        let [(RlpItem::Bytes(old_root), _), (RlpItem::Bytes(new_root), _), (_, changes)] =
+            items[..]
+        else {
+            return match items.len() {
+                3 => Err(DecodeError::ExpectedBytes),
+                len => Err(DecodeError::InvalidItemCount(len)),
+            };
+        };
+
+        let changes = decode_list(expect_list(changes)?)?
+            .iter()
+            .map(|(_, raw)| {
+                let items = decode_list(expect_list(raw)?)?;
+                match items[..] {
+                    [(RlpItem::Bytes(path), _)] => Ok((path.to_vec(), None)),
+                    [(RlpItem::Bytes(path), _), (RlpItem::Bytes(value), _)] => {
+                        Ok((path.to_vec(), Some(value.to_vec())))
+                    }
+                    [_] | [_, _] => Err(DecodeError::ExpectedBytes),
+                    _ => Err(DecodeError::InvalidItemCount(items.len())),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            old_root: old_root.to_vec(),
+            new_root: new_root.to_vec(),
+            changes,
+        })
+    }
+}
+
+/// Build the patch which brings `old` to `new`.
+pub fn create_patch<P, V, H>(
+    old: &mut PatriciaMerkleTree<P, V, H>,
+    new: &mut PatriciaMerkleTree<P, V, H>,
+) -> Patch
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let old_root = old.compute_hash().to_vec();
+    let new_root = new.compute_hash().to_vec();
+
+    let changes = old
+        .diff(new)
+        .into_iter()
+        .map(|x| {
+            (
+                x.path.encode().into_owned(),
+                x.new_value.map(|x| x.encode().into_owned()),
+            )
+        })
+        .collect();
+
+    Patch {
+        old_root,
+        new_root,
+        changes,
+    }
+}
+
+/// Apply a patch to a tree, checking its root hash both before and after.
+///
+/// The tree is left untouched on errors (except for [`PatchError::Corruption`], after which it
+/// can't be relied upon anyway). Watchers and the history only see the changes once the
+/// resulting root hash has been verified.
+pub fn apply_patch<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    patch: &Patch,
+) -> Result<(), PatchError>
+where
+    P: Encode + Decode,
+    V: Encode + Decode,
+    H: Digest,
+{
+    if tree.compute_hash()[..] != patch.old_root[..] {
+        return Err(PatchError::OldRootMismatch);
+    }
+
+    let changes = patch
+        .changes
+        .iter()
+        .map(|(path, value)| {
+            Ok((
+                P::decode(path)?,
+                value.as_deref().map(V::decode).transpose()?,
+            ))
+        })
+        .collect::<Result<Vec<_>, DecodeError>>()?;
+
+    // Keep the previous values around to be able to revert the changes.
+    let old_values = changes
+        .into_iter()
+        .map(|(path, value)| match value {
+            Some(value) => tree.insert_value(path, value),
+            None => tree.remove_value(path),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    tree.allocations.update(tree.memory_usage().1);
+
+    if tree.compute_hash()[..] != patch.new_root[..] {
+        for ((path, _), old_value) in patch.changes.iter().zip(old_values).rev() {
+            let path = P::decode(path)?;
+            match old_value {
+                Some(old_value) => tree.insert_value(path, old_value),
+                None => tree.remove_value(path),
+            }?;
+        }
+
+        return Err(PatchError::NewRootMismatch);
+    }
+
+    for ((path, value), old_value) in patch.changes.iter().zip(old_values) {
+        if tree.is_tracked(path) {
+            let old_value = old_value.map(|x| x.encode().into_owned());
+            tree.track_change(path.clone(), old_value, value.clone());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use sha3::Keccak256;
+
+    type Tree = PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256>;
+
+    fn build_trees() -> (Tree, Tree) {
+        let mut old = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            old.insert(vec![i, i], vec![i; 8]);
+        }
+
+        let mut new = old.clone();
+        new.insert(vec![3, 3], vec![0; 8]);
+        new.insert(vec![3, 4], vec![1; 8]);
+        new.remove(vec![60, 60]);
+
+        (old, new)
+    }
+
+    #[test]
+    fn patch_roundtrip() {
+        let (mut old, mut new) = build_trees();
+        let patch = old.create_patch(&mut new);
+        assert_eq!(patch.changes.len(), 3);
+        assert_eq!(Patch::decode(&patch.encode()), Ok(patch.clone()));
+
+        let receiver = old.watch(&[3]);
+        assert_eq!(old.apply_patch(&patch), Ok(()));
+        assert_eq!(old.compute_hash(), new.compute_hash());
+        assert_eq!(receiver.try_iter().count(), 2);
+        assert_eq!(old.apply_patch(&patch), Err(PatchError::OldRootMismatch));
+    }
+
+    #[test]
+    fn patch_new_root_mismatch() {
+        let (mut old, mut new) = build_trees();
+        let mut patch = old.create_patch(&mut new);
+        patch.changes[0].1 = Some(vec![2; 8]);
+
+        let root = old.compute_hash().to_vec();
+        let receiver = old.watch(&[]);
+        assert_eq!(old.apply_patch(&patch), Err(PatchError::NewRootMismatch));
+        assert_eq!(old.compute_hash().to_vec(), root);
+        assert_eq!(old.get(&vec![3, 3]), Some(&vec![3; 8]));
+        assert_eq!(old.get(&vec![3, 4]), None);
+        assert!(receiver.try_recv().is_err());
+    }
+}
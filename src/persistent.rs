@@ -0,0 +1,252 @@
+use crate::{
+    node::{self, compute_root_hash},
+    Encode, NodeRef, NodesStorage, ValuesStorage,
+};
+use digest::{Digest, Output};
+
+/// Immutable Patricia Merkle Tree: `insert` and `remove` return a new tree which shares every
+/// untouched node with the old one, so that old versions remain valid and cheap to keep around.
+///
+/// It uses the same nodes and storage as [`PatriciaMerkleTree`](crate::PatriciaMerkleTree), whose
+/// clones share their storage chunk by chunk: a new version only copies the chunks holding the
+/// nodes and values it modifies. Nodes shared between versions never change, so their hashes are
+/// cached safely and trees can be read from multiple threads at once.
+#[derive(Clone, Debug)]
+pub struct PersistentTrie<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    root_ref: NodeRef,
+    nodes: NodesStorage<P, V, H>,
+    values: ValuesStorage<P, V>,
+}
+
+impl<P, V, H> PersistentTrie<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    /// Create an empty tree.
+    pub fn new() -> Self {
+        Self {
+            root_ref: Default::default(),
+            nodes: NodesStorage::new(),
+            values: ValuesStorage::new(),
+        }
+    }
+
+    /// Return whether the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        !self.root_ref.is_valid()
+    }
+
+    /// Return the number of values in the tree.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Retrieve a value from the tree given its path.
+    pub fn get(&self, path: &P) -> Option<&V> {
+        let value_ref = node::get_value_ref(
+            self.root_ref,
+            &self.nodes,
+            &self.values,
+            path.encode().as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("{e}"))?;
+        Some(&self.values[*value_ref].1)
+    }
+
+    /// Return a new tree with `value` inserted at `path`, replacing the previous value (if any).
+    pub fn insert(&self, path: P, value: V) -> Self
+    where
+        P: Clone,
+        V: Clone,
+        H: Clone,
+    {
+        let mut trie = self.clone();
+        node::insert_value(
+            &mut trie.root_ref,
+            &mut trie.nodes,
+            &mut trie.values,
+            path,
+            value,
+        )
+        .unwrap_or_else(|e| panic!("{e}"));
+
+        trie
+    }
+
+    /// Return a new tree without the value at `path`, along with the removed value. Returns
+    /// `None` if there's no such value.
+    pub fn remove(&self, path: &P) -> Option<(Self, &V)>
+    where
+        P: Clone,
+        V: Clone,
+        H: Clone,
+    {
+        let value = self.get(path)?;
+
+        let mut trie = self.clone();
+        node::remove_value(
+            &mut trie.root_ref,
+            &mut trie.nodes,
+            &mut trie.values,
+            path.encode().as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("{e}"));
+
+        Some((trie, value))
+    }
+
+    /// Return the root hash of the tree.
+    ///
+    /// Hashes are cached within the nodes, so only nodes created since the last call are hashed.
+    pub fn compute_hash(&self) -> Output<H> {
+        compute_root_hash(self.root_ref, &self.nodes, &self.values)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+}
+
+impl<P, V, H> Default for PersistentTrie<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P, V, H> FromIterator<(P, V)> for PersistentTrie<P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    fn from_iter<T: IntoIterator<Item = (P, V)>>(iter: T) -> Self {
+        let mut trie = Self::new();
+        for (path, value) in iter {
+            node::insert_value(
+                &mut trie.root_ref,
+                &mut trie.nodes,
+                &mut trie.values,
+                path,
+                value,
+            )
+            .unwrap_or_else(|e| panic!("{e}"));
+        }
+
+        trie
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+    use sha3::Keccak256;
+    use std::thread;
+
+    type Trie = PersistentTrie<Vec<u8>, Vec<u8>, Keccak256>;
+
+    #[test]
+    fn old_versions_remain_valid() {
+        let empty = Trie::new();
+        let one = empty.insert(b"dog".to_vec(), b"puppy".to_vec());
+        let two = one.insert(b"doge".to_vec(), b"coin".to_vec());
+        let (three, removed) = two.remove(&b"dog".to_vec()).unwrap();
+
+        assert_eq!(removed, b"puppy");
+        assert!(empty.is_empty());
+        assert_eq!(one.get(&b"dog".to_vec()), Some(&b"puppy".to_vec()));
+        assert_eq!(one.get(&b"doge".to_vec()), None);
+        assert_eq!(two.len(), 2);
+        assert_eq!(three.get(&b"dog".to_vec()), None);
+        assert_eq!(three.get(&b"doge".to_vec()), Some(&b"coin".to_vec()));
+        assert!(three.remove(&b"dog".to_vec()).is_none());
+
+        assert_eq!(empty.compute_hash(), Keccak256::digest([0x80]));
+        assert_ne!(one.compute_hash(), two.compute_hash());
+        assert_ne!(two.compute_hash(), three.compute_hash());
+    }
+
+    #[test]
+    fn versions_share_storage() {
+        let trie = (0..=255u8)
+            .map(|i| (vec![i], vec![i; 32]))
+            .collect::<Trie>();
+        let new_trie = trie.insert(vec![0x80], vec![0xFF; 32]);
+
+        // Only the chunks holding the nodes and values modified are copied.
+        let shared = (0..trie.values.capacity())
+            .filter(|&key| trie.values.shares_entry(&new_trie.values, key))
+            .count();
+        assert!(shared >= trie.values.capacity() - 32);
+        assert_eq!(trie.get(&vec![0x80]), Some(&vec![0x80; 32]));
+        assert_eq!(new_trie.get(&vec![0x80]), Some(&vec![0xFF; 32]));
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let trie = (0..64u8).map(|i| (vec![i], vec![i; 32])).collect::<Trie>();
+        let hash = trie.compute_hash();
+
+        thread::scope(|scope| {
+            for i in 0..4u8 {
+                let trie = trie.clone();
+                scope.spawn(move || {
+                    let trie = trie.insert(vec![i], vec![0xFF; 32]);
+                    assert_eq!(trie.get(&vec![i]), Some(&vec![0xFF; 32]));
+                    assert_ne!(trie.compute_hash(), hash);
+                });
+            }
+        });
+
+        assert_eq!(trie.compute_hash(), hash);
+        assert_eq!(trie.get(&vec![0]), Some(&vec![0; 32]));
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_compare_hashes(
+            data in btree_map(vec(any::<u8>(), 0..4), vec(any::<u8>(), 1..40), 1..64),
+            removed in vec(vec(any::<u8>(), 0..4), 0..32),
+        ) {
+            let mut tree = pmt_tree!(Vec<u8>);
+            let mut trie = Trie::new();
+            for (path, value) in &data {
+                tree.insert(path.clone(), value.clone());
+                trie = trie.insert(path.clone(), value.clone());
+            }
+            prop_assert_eq!(trie.compute_hash(), *tree.compute_hash());
+
+            for path in &removed {
+                let tree_value = tree.remove(path.clone());
+                trie = match trie.remove(path) {
+                    Some((new_trie, value)) => {
+                        prop_assert_eq!(Some(value), tree_value.as_ref());
+                        new_trie
+                    }
+                    None => {
+                        prop_assert_eq!(tree_value, None);
+                        trie
+                    }
+                };
+            }
+            prop_assert_eq!(trie.len(), tree.len());
+            prop_assert_eq!(trie.compute_hash(), *tree.compute_hash());
+            for path in data.keys() {
+                prop_assert_eq!(trie.get(path), tree.get(path));
+            }
+        }
+    }
+}
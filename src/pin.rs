@@ -0,0 +1,230 @@
+use crate::{
+    nibble::NibbleSlice,
+    node::{Node, NodeKind},
+    Encode, NodeRef, PatriciaMerkleTree, ValueRef,
+};
+use digest::Digest;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use thiserror::Error;
+
+/// Why a pinned node is no longer tracked.
+#[derive(Clone, Copy, Debug, Eq, Error, Hash, PartialEq)]
+pub enum PinError {
+    #[error("the pinned value was removed")]
+    Removed,
+    /// The value is still there, but held by another node: for example, a leaf was split into a
+    /// branch by an insertion below it, or moved up by the collapse of its parent.
+    #[error("the pinned node was replaced")]
+    Replaced,
+}
+
+/// A reference to the node holding the value at some path, kept up to date while the tree is
+/// mutated. See [`PatriciaMerkleTree::pin`].
+#[derive(Clone, Debug)]
+pub struct PinHandle(Arc<Mutex<PinState>>);
+
+#[derive(Debug)]
+struct PinState {
+    /// The encoded path.
+    path: Vec<u8>,
+    kind: NodeKind,
+    /// Number of nibbles from the root to the node.
+    path_offset: usize,
+    node_ref: Result<NodeRef, PinError>,
+}
+
+impl PinHandle {
+    /// Return the pinned value's encoded path.
+    pub fn path(&self) -> Vec<u8> {
+        self.lock().path.clone()
+    }
+
+    /// Return the pinned node's kind.
+    pub fn kind(&self) -> NodeKind {
+        self.lock().kind
+    }
+
+    /// Return the pinned node's current reference (storage index), or why it was unpinned.
+    ///
+    /// Once unpinned, a handle stays so even if the node is recreated later on.
+    pub fn node_ref(&self) -> Result<usize, PinError> {
+        self.lock().node_ref.map(|x| *x)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PinState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The pins of a tree.
+///
+/// Clones of a tree are independent from it, so they start without pins.
+#[derive(Debug, Default)]
+pub(crate) struct Pins(Vec<Weak<Mutex<PinState>>>);
+
+impl Clone for Pins {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Pins {
+    /// Pin the node holding the value at `path` (encoded), if any.
+    pub fn pin<P, V, H>(
+        &mut self,
+        tree: &PatriciaMerkleTree<P, V, H>,
+        path: Vec<u8>,
+    ) -> Option<PinHandle>
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        let (node_ref, kind, path_offset) = locate(tree, &path)?;
+        let state = Arc::new(Mutex::new(PinState {
+            path,
+            kind,
+            path_offset,
+            node_ref: Ok(node_ref),
+        }));
+        self.0.push(Arc::downgrade(&state));
+
+        Some(PinHandle(state))
+    }
+
+    /// Follow the pinned nodes after a mutation, dropping the pins which are no longer
+    /// tracked.
+    pub fn update<P, V, H>(&mut self, tree: &PatriciaMerkleTree<P, V, H>)
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        self.0.retain(|state| {
+            let Some(state) = state.upgrade() else {
+                return false;
+            };
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+
+            state.node_ref = match locate(tree, &state.path) {
+                Some((node_ref, kind, path_offset))
+                    if kind == state.kind && path_offset == state.path_offset =>
+                {
+                    Ok(node_ref)
+                }
+                Some(_) => Err(PinError::Replaced),
+                None => Err(PinError::Removed),
+            };
+            state.node_ref.is_ok()
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Find the node holding the value at an encoded path, returning its reference, kind and path
+/// offset.
+fn locate<P, V, H>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    path: &[u8],
+) -> Option<(NodeRef, NodeKind, usize)>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let has_path = |value_ref: ValueRef| {
+        tree.values
+            .get(*value_ref)
+            .is_some_and(|(value_path, _)| value_path.encode().as_ref() == path)
+    };
+
+    let mut nibbles = NibbleSlice::new(path);
+    let mut node_ref = tree.root_ref;
+    let mut path_offset = 0;
+    loop {
+        let node = tree.nodes.get(*node_ref)?;
+        (node_ref, path_offset) = match node {
+            Node::Branch(branch_node) => match nibbles.next() {
+                Some(nibble) => (branch_node.choices[nibble as usize], path_offset + 1),
+                None => {
+                    return has_path(branch_node.value_ref).then_some((
+                        node_ref,
+                        NodeKind::Branch,
+                        path_offset,
+                    ))
+                }
+            },
+            Node::Extension(extension_node) => {
+                if !nibbles.skip_prefix(&extension_node.prefix) {
+                    return None;
+                }
+
+                (
+                    extension_node.child_ref,
+                    path_offset + extension_node.prefix.len(),
+                )
+            }
+            Node::Leaf(leaf_node) => {
+                return has_path(leaf_node.value_ref).then_some((
+                    node_ref,
+                    NodeKind::Leaf,
+                    path_offset,
+                ))
+            }
+            Node::Stub(_) => return None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn pin() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.insert(vec![0x34], vec![2]);
+        assert!(tree.pin(&vec![0x56]).is_none());
+
+        let leaf = tree.pin(&vec![0x12]).unwrap();
+        let other_leaf = tree.pin(&vec![0x34]).unwrap();
+        assert_eq!(leaf.kind(), NodeKind::Leaf);
+        assert_eq!(leaf.path(), [0x12]);
+
+        // Nodes are moved around by unrelated mutations.
+        for i in 0..32 {
+            tree.insert(vec![0x80 | i], vec![i]);
+        }
+        tree.compact();
+        let node_ref = leaf.node_ref().unwrap();
+        assert!(matches!(tree.nodes.get(node_ref), Some(Node::Leaf(_))));
+        assert_eq!(locate(&tree, &[0x12]).map(|x| *x.0), Some(node_ref));
+
+        // The leaf is split by an insertion continuing its path.
+        tree.insert(vec![0x12, 0x00], vec![3]);
+        assert_eq!(leaf.node_ref(), Err(PinError::Replaced));
+        let branch = tree.pin(&vec![0x12]).unwrap();
+        assert_eq!(branch.kind(), NodeKind::Branch);
+        tree.remove(vec![0x12, 0x00]);
+        assert_eq!(branch.node_ref(), Err(PinError::Replaced));
+        assert_eq!(leaf.node_ref(), Err(PinError::Replaced));
+
+        tree.remove_range(vec![0x30]..vec![0x40]);
+        assert_eq!(other_leaf.node_ref(), Err(PinError::Removed));
+
+        // Clones don't update handles, and dropped handles are forgotten.
+        let handle = tree.pin(&vec![0x12]).unwrap();
+        let mut clone = tree.clone();
+        clone.remove(vec![0x12]);
+        assert!(handle.node_ref().is_ok());
+        assert_eq!(tree.pins.0.len(), 1);
+        drop(handle);
+        tree.remove(vec![0x80]);
+        assert!(tree.pins.is_empty());
+    }
+}
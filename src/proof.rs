@@ -0,0 +1,913 @@
+use crate::{
+    db::{to_nibble_hex, MissingNode},
+    hex,
+    nibble::NibbleSlice,
+    node::Node,
+    partial::{PartialTrie, PartialTrieError},
+    rlp::{ChildRef, DecodeError, RawNode},
+    Encode, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Errors found while verifying a range proof.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum RangeProofError {
+    #[error("the paths are not in strictly ascending order")]
+    UnsortedPaths,
+    #[error("a path is before the range's origin")]
+    PathBeforeOrigin,
+    #[error("the range is empty but there are values after its origin")]
+    MissingValues,
+    #[error("the range doesn't match the root")]
+    RootMismatch,
+    #[error(transparent)]
+    InvalidProof(#[from] PartialTrieError),
+    #[error(transparent)]
+    MissingNode(#[from] MissingNode),
+}
+
+/// The reason a proof failed to verify, as diagnosed by [`explain_proof`].
+///
+/// Path prefixes are in nibbles, and indices refer to the position of nodes within the proof.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ProofFailure {
+    /// The node expected at some position of the proof doesn't have the hash its parent (or the
+    /// root) refers to, and no other node in the proof does.
+    #[error(
+        "node {index} at path {} has hash {} but {} was expected",
+        to_nibble_hex(.path_prefix),
+        hex::encode(.actual),
+        hex::encode(.expected)
+    )]
+    HashMismatch {
+        index: usize,
+        path_prefix: Vec<u8>,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// The proof ends before reaching the path's value (or where it diverges from the tree).
+    #[error(
+        "missing node {} at path {}",
+        hex::encode(.expected),
+        to_nibble_hex(.path_prefix)
+    )]
+    MissingNode {
+        path_prefix: Vec<u8>,
+        expected: Vec<u8>,
+    },
+    /// A node (or a node embedded in it) has a valid hash but isn't a valid node.
+    #[error("undecodable node {index} at path {}: {error}", to_nibble_hex(.path_prefix))]
+    UndecodableNode {
+        index: usize,
+        path_prefix: Vec<u8>,
+        error: DecodeError,
+    },
+}
+
+/// Return the encoded nodes from the root down to where `path` ends (or diverges from the tree).
+///
+/// Nodes embedded within their parents are not included, since they're already part of their
+/// parent's encoding.
+pub fn get_proof<P, V, H>(tree: &mut PatriciaMerkleTree<P, V, H>, path: &P) -> Vec<Vec<u8>>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    tree.compute_hash();
+    prove(tree, path)
+}
+
+/// Same as [`get_proof`], for trees behind a shared reference (hashes not cached yet are computed
+/// along the way).
+pub(crate) fn prove<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>, path: &P) -> Vec<Vec<u8>>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    if !tree.root_ref.is_valid() {
+        return Vec::new();
+    }
+
+    let encoded_path = path.encode();
+    let mut path = NibbleSlice::new(encoded_path.as_ref());
+
+    let mut proof = Vec::new();
+    let mut node_ref = tree.root_ref;
+    loop {
+        let node = tree
+            .nodes
+            .get(*node_ref)
+            .expect("inconsistent internal tree structure");
+
+        // Stubbed subtrees aren't in memory, so the proof ends before them.
+        let Some(encoded) = node.encode(&tree.nodes, &tree.values, path.offset()) else {
+            break;
+        };
+        if node_ref == tree.root_ref || encoded.len() >= 32 {
+            proof.push(encoded);
+        }
+
+        node_ref = match node {
+            Node::Branch(branch_node) => match path.next() {
+                Some(nibble) if branch_node.choices[nibble as usize].is_valid() => {
+                    branch_node.choices[nibble as usize]
+                }
+                _ => break,
+            },
+            Node::Extension(extension_node) => {
+                if !path.skip_prefix(&extension_node.prefix) {
+                    break;
+                }
+
+                extension_node.child_ref
+            }
+            Node::Leaf(_) | Node::Stub(_) => break,
+        };
+    }
+
+    proof
+}
+
+/// Return the nodes proving that there are no values strictly between `left` and `right`, which
+/// are the proofs of both boundaries.
+pub fn get_gap_proof<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    left: &P,
+    right: &P,
+) -> Vec<Vec<u8>>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut proof = get_proof(tree, left);
+    for node in get_proof(tree, right) {
+        if !proof.contains(&node) {
+            proof.push(node);
+        }
+    }
+
+    proof
+}
+
+/// Update a proof generated by [`get_proof`](crate::PatriciaMerkleTree::get_proof) to be valid
+/// against `new_root`, given the nodes created since it was generated (for example, those written
+/// by the commits in between).
+///
+/// Nodes along the path which didn't change are taken from the old proof. Returns `None` if a node
+/// is found in neither set, in which case a new proof has to be generated.
+pub fn update_proof<H>(
+    new_root: &Output<H>,
+    path: &[u8],
+    proof: &[Vec<u8>],
+    changed_nodes: impl IntoIterator<Item = Vec<u8>>,
+) -> Option<Vec<Vec<u8>>>
+where
+    H: Digest,
+{
+    if *new_root == H::digest([0x80]) {
+        return Some(Vec::new());
+    }
+
+    let known = proof
+        .iter()
+        .cloned()
+        .chain(changed_nodes)
+        .map(|encoded| (H::digest(&encoded).to_vec(), encoded))
+        .collect::<HashMap<_, _>>();
+
+    let mut path = NibbleSlice::new(path);
+    let mut new_proof = Vec::new();
+    let mut child_ref = ChildRef::Hash(new_root.to_vec());
+    loop {
+        let encoded = match child_ref {
+            ChildRef::Hash(hash) => {
+                let encoded = known.get(&hash)?;
+                new_proof.push(encoded.clone());
+                encoded.clone()
+            }
+            ChildRef::Inline(encoded) => encoded,
+        };
+
+        child_ref = match RawNode::decode(&encoded).ok()? {
+            RawNode::Branch { mut choices, .. } => match path.next() {
+                Some(nibble) => match choices[nibble as usize].take() {
+                    Some(child_ref) => child_ref,
+                    None => break,
+                },
+                None => break,
+            },
+            RawNode::Extension { prefix, child } => {
+                if !prefix.iter().all(|x| path.next().map(u8::from) == Some(*x)) {
+                    break;
+                }
+
+                child
+            }
+            RawNode::Leaf { .. } => break,
+        };
+    }
+
+    Some(new_proof)
+}
+
+/// A value removed by [`remove_with_proof`](crate::PatriciaMerkleTree::remove_with_proof), along
+/// with the proof that it was in the tree right before.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RemovalProof<V> {
+    pub value: V,
+    /// The root hash before the removal.
+    pub old_root: Vec<u8>,
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl<V> RemovalProof<V>
+where
+    V: Encode,
+{
+    /// Return whether the proof shows the value was at `path` (encoded) in the tree with the old
+    /// root.
+    pub fn verify<H>(&self, path: &[u8]) -> bool
+    where
+        H: Digest,
+    {
+        let mut old_root = Output::<H>::default();
+        if old_root.len() != self.old_root.len() {
+            return false;
+        }
+        old_root.copy_from_slice(&self.old_root);
+
+        verify_proof::<H>(&old_root, path, self.proof.iter().cloned())
+            .is_some_and(|value| value.as_deref() == Some(self.value.encode().as_ref()))
+    }
+}
+
+/// Verify a proof generated by [`get_proof`](crate::PatriciaMerkleTree::get_proof), returning
+/// the value at `path` (or `None` if there is none).
+///
+/// Fails if the proof doesn't belong to the tree, or doesn't contain every node needed.
+pub fn verify_proof<H>(
+    root: &Output<H>,
+    path: &[u8],
+    proof: impl IntoIterator<Item = Vec<u8>>,
+) -> Option<Option<Vec<u8>>>
+where
+    H: Digest,
+{
+    PartialTrie::<H>::from_nodes(root.clone(), proof)
+        .ok()?
+        .get(path)
+        .ok()
+}
+
+/// Verify a proof like [`verify_proof`] does, but explain why it failed instead of just failing.
+///
+/// Nodes are looked up by their hash like when verifying, but they're expected in the order
+/// generated by [`get_proof`](crate::PatriciaMerkleTree::get_proof) (from the root down), so that
+/// a node with an unexpected hash can be told apart from a missing one.
+pub fn explain_proof<H>(
+    root: &Output<H>,
+    path: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, ProofFailure>
+where
+    H: Digest,
+{
+    if *root == H::digest([0x80]) {
+        return Ok(None);
+    }
+
+    let nodes = proof
+        .iter()
+        .enumerate()
+        .map(|(index, node)| (H::digest(node).to_vec(), index))
+        .collect::<HashMap<_, _>>();
+
+    let path = to_nibbles(path);
+    let mut path_offset = 0;
+    let mut child_ref = ChildRef::Hash(root.to_vec());
+    // Index of the node which embeds the current one (or the current one when hashed).
+    let mut index = 0;
+    // Number of hashed nodes visited so far.
+    let mut depth = 0;
+
+    loop {
+        let path_prefix = &path[..path_offset];
+        let encoded = match &child_ref {
+            ChildRef::Inline(encoded) => encoded.as_slice(),
+            ChildRef::Hash(hash) => {
+                index = match (nodes.get(hash), proof.get(depth)) {
+                    (Some(index), _) => *index,
+                    (None, Some(node)) => {
+                        return Err(ProofFailure::HashMismatch {
+                            index: depth,
+                            path_prefix: path_prefix.to_vec(),
+                            expected: hash.clone(),
+                            actual: H::digest(node).to_vec(),
+                        })
+                    }
+                    (None, None) => {
+                        return Err(ProofFailure::MissingNode {
+                            path_prefix: path_prefix.to_vec(),
+                            expected: hash.clone(),
+                        })
+                    }
+                };
+                depth += 1;
+
+                &proof[index]
+            }
+        };
+
+        let node = RawNode::decode(encoded).map_err(|error| ProofFailure::UndecodableNode {
+            index,
+            path_prefix: path_prefix.to_vec(),
+            error,
+        })?;
+        let rest = &path[path_offset..];
+        child_ref = match node {
+            RawNode::Branch { mut choices, value } => match rest.first() {
+                Some(choice) => match choices[*choice as usize].take() {
+                    Some(child_ref) => {
+                        path_offset += 1;
+                        child_ref
+                    }
+                    None => return Ok(None),
+                },
+                None => return Ok(value),
+            },
+            RawNode::Extension { prefix, child } if rest.starts_with(&prefix) => {
+                path_offset += prefix.len();
+                child
+            }
+            RawNode::Extension { .. } => return Ok(None),
+            RawNode::Leaf { path, value } => return Ok((rest == path).then_some(value)),
+        };
+    }
+}
+
+/// Verify a proof generated by [`get_gap_proof`](crate::PatriciaMerkleTree::get_gap_proof),
+/// returning whether there are no values strictly between `left` and `right`.
+pub fn verify_gap_proof<H>(
+    root: &Output<H>,
+    left: &[u8],
+    right: &[u8],
+    proof: impl IntoIterator<Item = Vec<u8>>,
+) -> bool
+where
+    H: Digest,
+{
+    match PartialTrie::<H>::from_nodes(root.clone(), proof) {
+        Ok(partial_trie) => partial_trie.has_values_between(left, right) == Ok(false),
+        Err(_) => false,
+    }
+}
+
+/// Verify that `entries` are every value in the tree from `origin` up to the last of them, using
+/// the proofs of both boundaries (as generated by
+/// [`get_gap_proof`](crate::PatriciaMerkleTree::get_gap_proof) between `origin` and the last path).
+/// Returns whether there are more values after the range.
+///
+/// An empty proof means that `entries` are the whole tree. An empty range proves that there are
+/// no values from `origin` onwards.
+pub fn verify_range_proof<H>(
+    root: &Output<H>,
+    origin: &[u8],
+    entries: &[(Vec<u8>, Vec<u8>)],
+    proof: impl IntoIterator<Item = Vec<u8>>,
+) -> Result<bool, RangeProofError>
+where
+    H: Digest,
+{
+    if entries.windows(2).any(|x| x[0].0 >= x[1].0) {
+        return Err(RangeProofError::UnsortedPaths);
+    }
+    if entries
+        .first()
+        .is_some_and(|(path, _)| path.as_slice() < origin)
+    {
+        return Err(RangeProofError::PathBeforeOrigin);
+    }
+
+    let entries = entries
+        .iter()
+        .map(|(path, value)| (to_nibbles(path), value.as_slice()))
+        .collect::<Vec<_>>();
+
+    let proof = proof.into_iter().collect::<Vec<_>>();
+    if proof.is_empty() {
+        let new_root = match entries.is_empty() {
+            true => H::digest([0x80]),
+            false => H::digest(build_range::<H>(&entries, 0)),
+        };
+
+        return match new_root == *root {
+            true => Ok(false),
+            false => Err(RangeProofError::RootMismatch),
+        };
+    }
+
+    let partial_trie = PartialTrie::<H>::from_nodes(root.clone(), proof)?;
+    let left = to_nibbles(origin);
+    let right = match entries.last() {
+        Some((path, _)) => path.clone(),
+        None => left.clone(),
+    };
+
+    let mut rebuilder = RangeRebuilder {
+        partial_trie: &partial_trie,
+        left,
+        right,
+        entries: &entries,
+        has_more: false,
+    };
+    let new_root = match rebuilder.rebuild(Some(ChildRef::Hash(root.to_vec())), Vec::new())? {
+        Some(ChildRef::Hash(hash)) => hash,
+        Some(ChildRef::Inline(encoded)) => H::digest(encoded).to_vec(),
+        None => H::digest([0x80]).to_vec(),
+    };
+
+    if new_root[..] != root[..] {
+        return Err(RangeProofError::RootMismatch);
+    }
+    if entries.is_empty() && rebuilder.has_more {
+        return Err(RangeProofError::MissingValues);
+    }
+
+    Ok(rebuilder.has_more)
+}
+
+/// Rebuilds the nodes overlapping a range from the range's values, leaving everything outside it
+/// as found in the proof.
+struct RangeRebuilder<'a, H>
+where
+    H: Digest,
+{
+    partial_trie: &'a PartialTrie<H>,
+
+    /// The boundaries of the range (inclusive, in nibbles).
+    left: Vec<u8>,
+    right: Vec<u8>,
+    /// The range's values (paths in nibbles), in ascending order.
+    entries: &'a [(Vec<u8>, &'a [u8])],
+
+    /// Whether any value after the range has been found.
+    has_more: bool,
+}
+
+impl<'a, H> RangeRebuilder<'a, H>
+where
+    H: Digest,
+{
+    /// Return the reference to the rebuilt node at `path_prefix` (in nibbles), if any.
+    fn rebuild(
+        &mut self,
+        child_ref: Option<ChildRef>,
+        path_prefix: Vec<u8>,
+    ) -> Result<Option<ChildRef>, RangeProofError> {
+        let start = self.entries.partition_point(|(x, _)| *x < path_prefix);
+        let end =
+            start + self.entries[start..].partition_point(|(x, _)| x.starts_with(&path_prefix));
+        let entries = &self.entries[start..end];
+
+        // Subtrees outside the range are kept as they are.
+        let all_below = path_prefix < self.left && !self.left.starts_with(&path_prefix);
+        let all_above = path_prefix > self.right;
+        if all_below || all_above {
+            self.has_more |= all_above && child_ref.is_some();
+            return Ok(child_ref);
+        }
+
+        // Subtrees within the range are made of the range's values only.
+        let all_within = path_prefix >= self.left
+            && path_prefix < self.right
+            && !self.right.starts_with(&path_prefix);
+        let child_ref = match child_ref {
+            Some(child_ref) if !all_within => child_ref,
+            _ => {
+                return Ok((!entries.is_empty())
+                    .then(|| ChildRef::new::<H>(build_range::<H>(entries, path_prefix.len()))))
+            }
+        };
+
+        let encoded = self.partial_trie.resolve(child_ref.clone(), &path_prefix)?;
+        let node = match RawNode::decode(&encoded).expect("nodes are validated when inserted") {
+            RawNode::Branch { mut choices, value } => {
+                let value = match path_prefix >= self.left && path_prefix <= self.right {
+                    true => entries
+                        .first()
+                        .filter(|(path, _)| *path == path_prefix)
+                        .map(|(_, value)| value.to_vec()),
+                    false => {
+                        self.has_more |= path_prefix > self.right && value.is_some();
+                        value
+                    }
+                };
+
+                for (choice, child_ref) in choices.iter_mut().enumerate() {
+                    let mut child_prefix = path_prefix.clone();
+                    child_prefix.push(choice as u8);
+                    *child_ref = self.rebuild(child_ref.take(), child_prefix)?;
+                }
+
+                RawNode::Branch { choices, value }
+            }
+            RawNode::Extension { prefix, child } => {
+                let child_prefix = [path_prefix.as_slice(), &prefix].concat();
+                if entries.iter().any(|(x, _)| !x.starts_with(&child_prefix)) {
+                    return Err(RangeProofError::RootMismatch);
+                }
+
+                match self.rebuild(Some(child), child_prefix)? {
+                    Some(child) => RawNode::Extension { prefix, child },
+                    None => return Err(RangeProofError::RootMismatch),
+                }
+            }
+            RawNode::Leaf { path, .. } => {
+                let leaf_path = [path_prefix.as_slice(), &path].concat();
+                if leaf_path >= self.left && leaf_path <= self.right {
+                    return Ok((!entries.is_empty()).then(|| {
+                        ChildRef::new::<H>(build_range::<H>(entries, path_prefix.len()))
+                    }));
+                }
+                if !entries.is_empty() {
+                    return Err(RangeProofError::RootMismatch);
+                }
+
+                self.has_more |= leaf_path > self.right;
+                return Ok(Some(child_ref));
+            }
+        };
+
+        Ok(Some(ChildRef::new::<H>(node.encode())))
+    }
+}
+
+/// Return the encoding of the node holding exactly `entries` (paths in nibbles, in ascending
+/// order, sharing their first `depth` nibbles), which must not be empty.
+fn build_range<H>(entries: &[(Vec<u8>, &[u8])], depth: usize) -> Vec<u8>
+where
+    H: Digest,
+{
+    let (first, value) = &entries[0];
+    if entries.len() == 1 {
+        return RawNode::Leaf {
+            path: first[depth..].to_vec(),
+            value: value.to_vec(),
+        }
+        .encode();
+    }
+
+    // Since the entries are sorted, the first and last ones share the shortest prefix.
+    let last = &entries[entries.len() - 1].0;
+    let common_len = first[depth..]
+        .iter()
+        .zip(&last[depth..])
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common_len > 0 {
+        return RawNode::Extension {
+            prefix: first[depth..depth + common_len].to_vec(),
+            child: ChildRef::new::<H>(build_range::<H>(entries, depth + common_len)),
+        }
+        .encode();
+    }
+
+    let (value, mut entries) = match first.len() == depth {
+        true => (Some(value.to_vec()), &entries[1..]),
+        false => (None, entries),
+    };
+
+    let mut choices = Box::<[Option<ChildRef>; 16]>::default();
+    while let Some((path, _)) = entries.first() {
+        let choice = path[depth];
+        let len = entries.partition_point(|(x, _)| x[depth] == choice);
+        choices[choice as usize] = Some(ChildRef::new::<H>(build_range::<H>(
+            &entries[..len],
+            depth + 1,
+        )));
+        entries = &entries[len..];
+    }
+
+    RawNode::Branch { choices, value }.encode()
+}
+
+/// Convert a path into nibbles.
+pub(crate) fn to_nibbles(path: &[u8]) -> Vec<u8> {
+    NibbleSlice::new(path).map(u8::from).collect()
+}
+
+/// Return whether there is some path starting with `prefix` which is strictly between `left` and
+/// `right` (all of them in nibbles).
+pub(crate) fn prefix_overlaps(prefix: &[u8], left: &[u8], right: &[u8]) -> bool {
+    let all_below = prefix < left && !left.starts_with(prefix);
+    let all_above = prefix >= right;
+
+    !all_below && !all_above
+}
+
+/// Return whether every path starting with `prefix` is strictly between `left` and `right` (all
+/// of them in nibbles).
+pub(crate) fn prefix_within(prefix: &[u8], left: &[u8], right: &[u8]) -> bool {
+    prefix > left && prefix < right && !right.starts_with(prefix)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        db::{MemoryDb, NodeDb},
+        pmt_tree,
+    };
+    use proptest::{
+        collection::{btree_map, btree_set, vec},
+        prelude::*,
+    };
+    use sha3::Keccak256;
+
+    fn build(paths: &[&[u8]]) -> PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256> {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for path in paths {
+            tree.insert(path.to_vec(), path.repeat(16));
+        }
+
+        tree
+    }
+
+    #[test]
+    fn membership() {
+        let mut tree = build(&[b"do", b"dog", b"doge", b"horse"]);
+        let root = *tree.compute_hash();
+
+        for path in [&b"do"[..], b"dog", b"doge", b"horse"] {
+            let proof = tree.get_proof(&path.to_vec());
+            assert_eq!(
+                verify_proof::<Keccak256>(&root, path, proof),
+                Some(Some(path.repeat(16))),
+            );
+        }
+
+        let proof = tree.get_proof(&b"dogs".to_vec());
+        assert_eq!(verify_proof::<Keccak256>(&root, b"dogs", proof), Some(None));
+    }
+
+    #[test]
+    fn incomplete_proof() {
+        let mut tree = build(&[b"do", b"dog", b"doge", b"horse"]);
+        let root = *tree.compute_hash();
+
+        let mut proof = tree.get_proof(&b"doge".to_vec());
+        proof.pop();
+        assert_eq!(verify_proof::<Keccak256>(&root, b"doge", proof), None);
+    }
+
+    #[test]
+    fn explain() {
+        let mut tree = build(&[b"do", b"dog", b"doge", b"horse"]);
+        let root = *tree.compute_hash();
+
+        let proof = tree.get_proof(&b"doge".to_vec());
+        assert!(proof.len() > 2);
+        assert_eq!(
+            explain_proof::<Keccak256>(&root, b"doge", &proof),
+            Ok(Some(b"doge".repeat(16))),
+        );
+        assert_eq!(explain_proof::<Keccak256>(&root, b"dogs", &proof), Ok(None));
+
+        let mut tampered = proof.clone();
+        *tampered[1].last_mut().unwrap() ^= 1;
+        let Err(ProofFailure::HashMismatch {
+            index,
+            path_prefix,
+            actual,
+            ..
+        }) = explain_proof::<Keccak256>(&root, b"doge", &tampered)
+        else {
+            panic!("expected a hash mismatch");
+        };
+        assert_eq!(index, 1);
+        assert!(to_nibbles(b"doge").starts_with(&path_prefix));
+        assert_eq!(actual, Keccak256::digest(&tampered[1]).to_vec());
+
+        let truncated = &proof[..proof.len() - 1];
+        assert!(matches!(
+            explain_proof::<Keccak256>(&root, b"doge", truncated),
+            Err(ProofFailure::MissingNode { .. }),
+        ));
+
+        let garbage = vec![0xC1, 0x80];
+        let root = Keccak256::digest(&garbage);
+        assert_eq!(
+            explain_proof::<Keccak256>(&root, b"doge", &[garbage]),
+            Err(ProofFailure::UndecodableNode {
+                index: 0,
+                path_prefix: Vec::new(),
+                error: DecodeError::InvalidItemCount(1),
+            }),
+        );
+    }
+
+    #[test]
+    fn gap() {
+        let mut tree = build(&[b"a", b"c", b"e"]);
+        let root = *tree.compute_hash();
+
+        let proof = tree.get_gap_proof(&b"a".to_vec(), &b"c".to_vec());
+        assert!(verify_gap_proof::<Keccak256>(
+            &root,
+            b"a",
+            b"c",
+            proof.clone()
+        ));
+        assert!(verify_gap_proof::<Keccak256>(&root, b"a", b"b", proof));
+
+        let proof = tree.get_gap_proof(&b"a".to_vec(), &b"e".to_vec());
+        assert!(!verify_gap_proof::<Keccak256>(&root, b"a", b"e", proof));
+
+        let proof = tree.get_gap_proof(&b"c".to_vec(), &b"c".to_vec());
+        assert!(verify_gap_proof::<Keccak256>(&root, b"c", b"c", proof));
+    }
+
+    #[test]
+    fn gap_missing_nodes() {
+        let mut tree = build(&[b"aa", b"ab", b"ba", b"bb", b"ca", b"cb"]);
+        let root = *tree.compute_hash();
+
+        // The proof of "aa" alone can't tell whether "ab" exists.
+        let proof = tree.get_proof(&b"aa".to_vec());
+        assert!(!verify_gap_proof::<Keccak256>(&root, b"aa", b"b", proof));
+    }
+
+    type Entry = (Vec<u8>, Vec<u8>);
+
+    fn range_of(
+        tree: &mut PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256>,
+        origin: &[u8],
+        count: usize,
+    ) -> (Vec<Entry>, Vec<Vec<u8>>) {
+        let entries = tree
+            .iter_from(&origin.to_vec())
+            .take(count)
+            .map(|(path, value)| (path.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        let proof = match entries.last() {
+            Some((last, _)) => tree.get_gap_proof(&origin.to_vec(), last),
+            None => tree.get_proof(&origin.to_vec()),
+        };
+
+        (entries, proof)
+    }
+
+    #[test]
+    fn range() {
+        let mut tree = build(&[b"a", b"b", b"ba", b"bb", b"c", b"d", b"e"]);
+        let root = *tree.compute_hash();
+
+        let (entries, proof) = range_of(&mut tree, b"b", 3);
+        assert_eq!(
+            verify_range_proof::<Keccak256>(&root, b"b", &entries, proof),
+            Ok(true)
+        );
+
+        let (entries, proof) = range_of(&mut tree, b"bc", 8);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            verify_range_proof::<Keccak256>(&root, b"bc", &entries, proof),
+            Ok(false)
+        );
+
+        let (entries, _) = range_of(&mut tree, b"", 8);
+        assert_eq!(
+            verify_range_proof::<Keccak256>(&root, b"", &entries, []),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn range_tampered() {
+        let mut tree = build(&[b"a", b"b", b"ba", b"bb", b"c", b"d", b"e"]);
+        let root = *tree.compute_hash();
+        let (entries, proof) = range_of(&mut tree, b"a", 5);
+
+        let mut missing = entries.clone();
+        missing.remove(2);
+        assert_eq!(
+            verify_range_proof::<Keccak256>(&root, b"a", &missing, proof.clone()),
+            Err(RangeProofError::RootMismatch),
+        );
+
+        let mut modified = entries.clone();
+        modified[1].1 = b"x".to_vec();
+        assert_eq!(
+            verify_range_proof::<Keccak256>(&root, b"a", &modified, proof.clone()),
+            Err(RangeProofError::RootMismatch),
+        );
+
+        let mut unsorted = entries;
+        unsorted.swap(0, 1);
+        assert_eq!(
+            verify_range_proof::<Keccak256>(&root, b"a", &unsorted, proof.clone()),
+            Err(RangeProofError::UnsortedPaths),
+        );
+
+        // An empty range can't hide values after its origin.
+        assert_eq!(
+            verify_range_proof::<Keccak256>(&root, b"aa", &[], tree.get_proof(&b"aa".to_vec())),
+            Err(RangeProofError::MissingValues),
+        );
+    }
+
+    #[test]
+    fn removal_proof() {
+        let mut tree = build(&[b"do", b"dog", b"doge", b"horse"]);
+        let old_root = *tree.compute_hash();
+        let value = tree.get(&b"dog".to_vec()).unwrap().clone();
+
+        let removal = tree.remove_with_proof(b"dog".to_vec()).unwrap();
+        assert_eq!(removal.value, value);
+        assert_eq!(removal.old_root, old_root.to_vec());
+        assert!(removal.verify::<Keccak256>(b"dog"));
+        assert!(!removal.verify::<Keccak256>(b"doge"));
+        assert_eq!(tree.get(&b"dog".to_vec()), None);
+        assert_ne!(*tree.compute_hash(), old_root);
+
+        let mut forged = removal.clone();
+        forged.value = b"cat".to_vec();
+        assert!(!forged.verify::<Keccak256>(b"dog"));
+        assert_eq!(tree.remove_with_proof(b"dog".to_vec()), None);
+    }
+
+    #[test]
+    fn update() {
+        let mut tree = build(&[b"do", b"dog", b"doge", b"horse"]);
+        let mut old_db = MemoryDb::new();
+        tree.commit(&mut old_db);
+        let proof = tree.get_proof(&b"dog".to_vec());
+
+        tree.insert(b"dot".to_vec(), b"dot".repeat(16));
+        tree.insert(b"horses".to_vec(), b"horses".repeat(16));
+        let mut new_db = MemoryDb::new();
+        let root = tree.commit(&mut new_db);
+
+        let changed = new_db
+            .iter()
+            .filter(|(hash, _)| old_db.get(hash).is_none())
+            .map(|(_, encoded)| encoded.clone());
+        let new_proof = update_proof::<Keccak256>(&root, b"dog", &proof, changed).unwrap();
+        assert_eq!(new_proof, tree.get_proof(&b"dog".to_vec()));
+
+        // Without the changed nodes, the new root can't be resolved.
+        assert_eq!(update_proof::<Keccak256>(&root, b"dog", &proof, []), None);
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_range(
+            data in btree_map(vec(any::<u8>(), 1..4), vec(any::<u8>(), 1..40), 1..64),
+            origin in vec(any::<u8>(), 0..4),
+            count in 0..16usize,
+        ) {
+            let mut tree = pmt_tree!(Vec<u8>);
+            for (path, value) in &data {
+                tree.insert(path.clone(), value.clone());
+            }
+            let root = *tree.compute_hash();
+
+            let remaining = data.range(origin.clone()..).count();
+            let (entries, proof) = range_of(&mut tree, &origin, count);
+            let result = verify_range_proof::<Keccak256>(&root, &origin, &entries, proof);
+            if entries.is_empty() && data.contains_key(&origin) {
+                prop_assert_eq!(result, Err(RangeProofError::RootMismatch));
+            } else if entries.is_empty() && remaining > 0 {
+                prop_assert_eq!(result, Err(RangeProofError::MissingValues));
+            } else {
+                prop_assert_eq!(result, Ok(remaining > count));
+            }
+        }
+
+        #[test]
+        fn proptest_gap(
+            paths in btree_set(vec(any::<u8>(), 1..4), 1..32),
+            mut left in vec(any::<u8>(), 1..4),
+            mut right in vec(any::<u8>(), 1..4),
+        ) {
+            if left > right {
+                std::mem::swap(&mut left, &mut right);
+            }
+
+            let mut tree = pmt_tree!(Vec<u8>);
+            for path in &paths {
+                tree.insert(path.clone(), path.repeat(16));
+            }
+            let root = *tree.compute_hash();
+
+            let is_gap = !paths.iter().any(|x| *x > left && *x < right);
+            let proof = tree.get_gap_proof(&left, &right);
+            prop_assert_eq!(verify_gap_proof::<Keccak256>(&root, &left, &right, proof), is_gap);
+        }
+    }
+}
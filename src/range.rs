@@ -0,0 +1,420 @@
+use crate::{
+    corruption::CorruptionError,
+    node::{Node, NodeKind},
+    proof::to_nibbles,
+    Encode, NodeRef, NodesStorage, PatriciaMerkleTree, ValuesStorage,
+};
+use digest::Digest;
+use std::ops::{Bound, RangeBounds};
+
+/// Remove every value whose encoded path is within `range`, returning them in ascending path
+/// order.
+///
+/// Subtrees entirely within the range are dropped without being traversed node by node, and
+/// subtrees entirely outside of it are left untouched. Only the nodes along both boundaries are
+/// rebuilt (and collapsed when left with a single child).
+///
+/// Fails if the range needs the contents of a stub.
+pub(crate) fn remove_range<P, V, H>(
+    tree: &mut PatriciaMerkleTree<P, V, H>,
+    range: impl RangeBounds<P>,
+) -> Result<Vec<(P, V)>, CorruptionError>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut remover = RangeRemover {
+        nodes: &mut tree.nodes,
+        values: &mut tree.values,
+        range: NibbleRange::new(range),
+        removed: Vec::new(),
+    };
+
+    if tree.root_ref.is_valid() {
+        tree.root_ref = remover
+            .remove_node(tree.root_ref, &mut Vec::new())
+            .map_err(|e| e.at(tree.root_ref))?;
+    }
+
+    Ok(remover.removed)
+}
+
+/// Count the values whose encoded path is within `range`.
+///
+/// Like when removing, only the nodes along both boundaries are checked against the range: the
+/// values of the subtrees in between are counted without looking at their paths.
+pub(crate) fn count_range<P, V, H>(
+    tree: &PatriciaMerkleTree<P, V, H>,
+    range: impl RangeBounds<P>,
+) -> usize
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let range = NibbleRange::new(range);
+    match tree.root_ref.is_valid() {
+        true => range.count_node(&tree.nodes, &tree.values, tree.root_ref, &mut Vec::new()),
+        false => 0,
+    }
+}
+
+struct RangeRemover<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    nodes: &'a mut NodesStorage<P, V, H>,
+    values: &'a mut ValuesStorage<P, V>,
+
+    range: NibbleRange,
+    removed: Vec<(P, V)>,
+}
+
+impl<'a, P, V, H> RangeRemover<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    /// Remove the values within the range from the subtree at `node_ref`, whose nibbles are
+    /// `prefix`, and return the reference to what's left of it.
+    fn remove_node(
+        &mut self,
+        node_ref: NodeRef,
+        prefix: &mut Vec<u8>,
+    ) -> Result<NodeRef, CorruptionError> {
+        match self.range.covers(prefix) {
+            Some(true) => {
+                self.drain(node_ref, prefix)?;
+                return Ok(NodeRef::default());
+            }
+            Some(false) => return Ok(node_ref),
+            None => {}
+        }
+
+        let node = self
+            .nodes
+            .try_remove(*node_ref)
+            .expect("inconsistent internal tree structure");
+        let removed_count = self.removed.len();
+
+        let node = match node {
+            Node::Branch(mut branch_node) => {
+                if branch_node.value_ref.is_valid() && self.range.contains(prefix) {
+                    self.removed.push(
+                        self.values
+                            .try_remove(*branch_node.value_ref)
+                            .expect("inconsistent internal tree structure"),
+                    );
+                    branch_node.value_ref = Default::default();
+                }
+
+                for (choice, child_ref) in branch_node.choices.iter_mut().enumerate() {
+                    if child_ref.is_valid() {
+                        prefix.push(choice as u8);
+                        *child_ref = self
+                            .remove_node(*child_ref, prefix)
+                            .map_err(|e| e.at(*child_ref))?;
+                        prefix.pop();
+                    }
+                }
+
+                if self.removed.len() != removed_count {
+                    branch_node.hash.mark_as_dirty();
+                }
+                branch_node.collapse(self.nodes, prefix.len(), || prefix.clone())?
+            }
+            Node::Extension(mut extension_node) => {
+                let prefix_len = prefix.len();
+                prefix.extend(extension_node.prefix.iter().map(u8::from));
+                let child_ref = self
+                    .remove_node(extension_node.child_ref, prefix)
+                    .map_err(|e| e.at(extension_node.child_ref))?;
+                prefix.truncate(prefix_len);
+
+                if self.removed.len() != removed_count {
+                    extension_node.hash.mark_as_dirty();
+                }
+                let child_node = child_ref.is_valid().then(|| {
+                    self.nodes
+                        .try_remove(*child_ref)
+                        .expect("inconsistent internal tree structure")
+                });
+                extension_node.with_child(self.nodes, child_node)
+            }
+            Node::Leaf(leaf_node) => {
+                let (path, _) = self
+                    .values
+                    .get(*leaf_node.value_ref)
+                    .expect("inconsistent internal tree structure");
+
+                if self.range.contains(&to_nibbles(path.encode().as_ref())) {
+                    self.removed.push(self.values.remove(*leaf_node.value_ref));
+                    None
+                } else {
+                    Some(leaf_node.into())
+                }
+            }
+            Node::Stub(_) => return Err(CorruptionError::within(NodeKind::Stub, prefix.clone())),
+        };
+
+        Ok(node
+            .map(|x| NodeRef::new(self.nodes.insert(x)))
+            .unwrap_or_default())
+    }
+
+    /// Remove a whole subtree, whose nibbles are `prefix`, collecting its values.
+    fn drain(&mut self, node_ref: NodeRef, prefix: &mut Vec<u8>) -> Result<(), CorruptionError> {
+        match self
+            .nodes
+            .try_remove(*node_ref)
+            .expect("inconsistent internal tree structure")
+        {
+            Node::Branch(branch_node) => {
+                if branch_node.value_ref.is_valid() {
+                    self.removed
+                        .push(self.values.remove(*branch_node.value_ref));
+                }
+                for (choice, child_ref) in branch_node.choices.into_iter().enumerate() {
+                    if child_ref.is_valid() {
+                        prefix.push(choice as u8);
+                        self.drain(child_ref, prefix).map_err(|e| e.at(child_ref))?;
+                        prefix.pop();
+                    }
+                }
+            }
+            Node::Extension(extension_node) => {
+                let prefix_len = prefix.len();
+                prefix.extend(extension_node.prefix.iter().map(u8::from));
+                self.drain(extension_node.child_ref, prefix)
+                    .map_err(|e| e.at(extension_node.child_ref))?;
+                prefix.truncate(prefix_len);
+            }
+            Node::Leaf(leaf_node) => self.removed.push(self.values.remove(*leaf_node.value_ref)),
+            Node::Stub(_) => return Err(CorruptionError::within(NodeKind::Stub, prefix.clone())),
+        }
+
+        Ok(())
+    }
+}
+
+/// A range of encoded paths, with its bounds as nibbles.
+struct NibbleRange {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl NibbleRange {
+    fn new<P>(range: impl RangeBounds<P>) -> Self
+    where
+        P: Encode,
+    {
+        Self {
+            start: range.start_bound().map(|x| to_nibbles(x.encode().as_ref())),
+            end: range.end_bound().map(|x| to_nibbles(x.encode().as_ref())),
+        }
+    }
+
+    /// Count the values within the range in the subtree at `node_ref`, whose nibbles are
+    /// `prefix`.
+    fn count_node<P, V, H>(
+        &self,
+        nodes: &NodesStorage<P, V, H>,
+        values: &ValuesStorage<P, V>,
+        node_ref: NodeRef,
+        prefix: &mut Vec<u8>,
+    ) -> usize
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        match self.covers(prefix) {
+            Some(true) => return count_values(nodes, node_ref),
+            Some(false) => return 0,
+            None => {}
+        }
+
+        match nodes
+            .get(*node_ref)
+            .expect("inconsistent internal tree structure")
+        {
+            Node::Branch(branch_node) => {
+                let mut count =
+                    (branch_node.value_ref.is_valid() && self.contains(prefix)) as usize;
+                for (choice, child_ref) in branch_node.choices.iter().enumerate() {
+                    if child_ref.is_valid() {
+                        prefix.push(choice as u8);
+                        count += self.count_node(nodes, values, *child_ref, prefix);
+                        prefix.pop();
+                    }
+                }
+
+                count
+            }
+            Node::Extension(extension_node) => {
+                let prefix_len = prefix.len();
+                prefix.extend(extension_node.prefix.iter().map(u8::from));
+                let count = self.count_node(nodes, values, extension_node.child_ref, prefix);
+                prefix.truncate(prefix_len);
+
+                count
+            }
+            Node::Leaf(leaf_node) => {
+                let (path, _) = values
+                    .get(*leaf_node.value_ref)
+                    .expect("inconsistent internal tree structure");
+
+                self.contains(&to_nibbles(path.encode().as_ref())) as usize
+            }
+            // Stubbed subtrees have no values in memory.
+            Node::Stub(_) => 0,
+        }
+    }
+
+    /// Return whether the path (in nibbles) is within the range.
+    fn contains(&self, path: &[u8]) -> bool {
+        RangeBounds::<Vec<u8>>::contains(&(self.start.as_ref(), self.end.as_ref()), &path.to_vec())
+    }
+
+    /// Return whether every path (`Some(true)`), none of them (`Some(false)`) or only some of them
+    /// (`None`) starting with `prefix` are within the range.
+    fn covers(&self, prefix: &[u8]) -> Option<bool> {
+        let diverges = |bound: &[u8]| !bound.starts_with(prefix);
+
+        let after_start = match &self.start {
+            Bound::Included(start) if prefix >= start.as_slice() => Some(true),
+            Bound::Excluded(start) if prefix > start.as_slice() => Some(true),
+            Bound::Included(start) | Bound::Excluded(start) if diverges(start) => Some(false),
+            Bound::Included(_) | Bound::Excluded(_) => None,
+            Bound::Unbounded => Some(true),
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) if prefix > end.as_slice() => Some(false),
+            Bound::Excluded(end) if prefix >= end.as_slice() => Some(false),
+            Bound::Included(end) | Bound::Excluded(end) if diverges(end) => Some(true),
+            Bound::Included(_) | Bound::Excluded(_) => None,
+            Bound::Unbounded => Some(true),
+        };
+
+        match (after_start, before_end) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// Count the values in the subtree at `node_ref`, visiting every node in it.
+fn count_values<P, V, H>(nodes: &NodesStorage<P, V, H>, node_ref: NodeRef) -> usize
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    match nodes
+        .get(*node_ref)
+        .expect("inconsistent internal tree structure")
+    {
+        Node::Branch(branch_node) => {
+            let children = branch_node.choices.iter().filter(|x| x.is_valid());
+            branch_node.value_ref.is_valid() as usize
+                + children.map(|x| count_values(nodes, *x)).sum::<usize>()
+        }
+        Node::Extension(extension_node) => count_values(nodes, extension_node.child_ref),
+        Node::Leaf(_) => 1,
+        Node::Stub(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{pmt_tree, ChangeEvent, PatriciaMerkleTree};
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+    use sha3::Keccak256;
+    use std::ops::{Bound, RangeBounds};
+
+    #[test]
+    fn remove_range() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..=255u8 {
+            tree.insert(vec![0, i], vec![i]);
+            tree.insert(vec![1, i], vec![i]);
+        }
+        let receiver = tree.watch(&[0]);
+
+        assert_eq!(tree.count_range(vec![0, 16]..vec![0, 240]), 224);
+        assert_eq!(tree.count_range(vec![0, 16]..=vec![0, 240]), 225);
+        assert_eq!(tree.count_range(vec![0, 0xFF, 0]..vec![1, 1]), 1);
+        assert_eq!(tree.count_range(..), 512);
+        assert_eq!(tree.remove_range(vec![0, 16]..vec![0, 240]), 224);
+        assert_eq!(tree.len(), 288);
+        assert_eq!(tree.get(&vec![0, 15]), Some(&vec![15]));
+        assert_eq!(tree.get(&vec![0, 16]), None);
+        assert_eq!(tree.get(&vec![0, 240]), Some(&vec![240]));
+
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 224);
+        assert_eq!(
+            events[0],
+            ChangeEvent::Remove {
+                path: vec![0, 16],
+                old_value: vec![16],
+            }
+        );
+
+        assert_eq!(tree.remove_range(vec![1]..), 256);
+        assert_eq!(tree.remove_range(..), 32);
+        assert!(tree.is_empty());
+        assert_eq!(tree.remove_range(..), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_remove_range(
+            data in btree_map(vec(any::<u8>(), 0..4), vec(any::<u8>(), 1..4), 0..128),
+            start in vec(any::<u8>(), 0..3),
+            end in vec(any::<u8>(), 0..3),
+            start_inclusive in any::<bool>(),
+            end_inclusive in any::<bool>(),
+        ) {
+            let mut tree = pmt_tree!(Vec<u8>);
+            for (path, value) in &data {
+                tree.insert(path.clone(), value.clone());
+            }
+            tree.compute_hash();
+
+            let start = match start_inclusive {
+                true => Bound::Included(start),
+                false => Bound::Excluded(start),
+            };
+            let end = match end_inclusive {
+                true => Bound::Included(end),
+                false => Bound::Excluded(end),
+            };
+            // `BTreeMap::range()` panics on empty ranges.
+            let kept = data
+                .iter()
+                .filter(|(path, _)| !RangeBounds::<Vec<u8>>::contains(&(start.as_ref(), end.as_ref()), *path))
+                .map(|(path, value)| (path.clone(), value.clone()))
+                .collect::<Vec<_>>();
+
+            let count = tree.count_range((start.clone(), end.clone()));
+            prop_assert_eq!(count, data.len() - kept.len());
+
+            let removed = tree.remove_range((start, end));
+            prop_assert_eq!(removed, data.len() - kept.len());
+            prop_assert_eq!(tree.len(), kept.len());
+            prop_assert!(tree.iter().map(|(p, v)| (p.clone(), v.clone())).eq(kept.clone()));
+
+            let mut expected = PatriciaMerkleTree::<_, _, Keccak256>::from_sorted_iter(kept);
+            prop_assert_eq!(tree.compute_hash(), expected.compute_hash());
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use crate::hashing::{NodeEncoder, NodeWriter};
+use digest::Digest;
+use smallvec::SmallVec;
+use thiserror::Error;
+
+/// Errors found while decoding an RLP-encoded node.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum DecodeError {
+    #[error("unexpected end of data")]
+    UnexpectedEnd,
+    #[error("trailing bytes after the encoded node")]
+    TrailingBytes,
+    #[error("expected a list")]
+    ExpectedList,
+    #[error("expected a byte string")]
+    ExpectedBytes,
+    #[error("invalid number of list items ({0})")]
+    InvalidItemCount(usize),
+    #[error("invalid hex-prefix path encoding")]
+    InvalidPath,
+    #[error("invalid child reference")]
+    InvalidChild,
+    #[error("integer too large")]
+    InvalidInteger,
+    #[error("invalid hash length")]
+    InvalidHash,
+    #[error("invalid value encoding")]
+    InvalidValue,
+}
+
+/// A single RLP item, borrowing its payload.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RlpItem<'a> {
+    Bytes(&'a [u8]),
+    List(&'a [u8]),
+}
+
+/// Decode the first item in `data`, returning it along with its raw encoding and the remaining
+/// bytes.
+pub(crate) fn decode_item(data: &[u8]) -> Result<(RlpItem<'_>, &[u8], &[u8]), DecodeError> {
+    let prefix = *data.first().ok_or(DecodeError::UnexpectedEnd)?;
+
+    let (header_len, payload_len, is_list) = match prefix {
+        0x00..=0x7F => (0, 1, false),
+        0x80..=0xB7 => (1, (prefix - 0x80) as usize, false),
+        0xB8..=0xBF => {
+            let len_len = (prefix - 0xB7) as usize;
+            (1 + len_len, decode_len(&data[1..], len_len)?, false)
+        }
+        0xC0..=0xF7 => (1, (prefix - 0xC0) as usize, true),
+        0xF8..=0xFF => {
+            let len_len = (prefix - 0xF7) as usize;
+            (1 + len_len, decode_len(&data[1..], len_len)?, true)
+        }
+    };
+
+    let total_len = header_len
+        .checked_add(payload_len)
+        .ok_or(DecodeError::UnexpectedEnd)?;
+    if data.len() < total_len {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+
+    let payload = &data[header_len..total_len];
+    let item = if is_list {
+        RlpItem::List(payload)
+    } else if header_len == 0 {
+        RlpItem::Bytes(&data[..1])
+    } else {
+        RlpItem::Bytes(payload)
+    };
+
+    Ok((item, &data[..total_len], &data[total_len..]))
+}
+
+/// Decode a single list spanning the whole of `data`, returning its payload.
+pub(crate) fn expect_list(data: &[u8]) -> Result<&[u8], DecodeError> {
+    let (item, _, rest) = decode_item(data)?;
+    if !rest.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+
+    match item {
+        RlpItem::List(payload) => Ok(payload),
+        RlpItem::Bytes(_) => Err(DecodeError::ExpectedList),
+    }
+}
+
+/// The items of a list along with their raw encodings.
+pub(crate) type RlpList<'a> = SmallVec<[(RlpItem<'a>, &'a [u8]); 17]>;
+
+/// Decode every item within a list's payload, along with their raw encodings.
+pub(crate) fn decode_list(mut payload: &[u8]) -> Result<RlpList<'_>, DecodeError> {
+    let mut items = SmallVec::new();
+    while !payload.is_empty() {
+        let (item, raw, rest) = decode_item(payload)?;
+        items.push((item, raw));
+        payload = rest;
+    }
+
+    Ok(items)
+}
+
+/// Write a list given its (already encoded) payload.
+pub(crate) fn write_list(encoder: &mut NodeEncoder, payload: &[u8]) {
+    encoder.write_list_header(payload.len());
+    encoder.write_raw(payload);
+}
+
+/// Decode a single list of byte strings spanning the whole of `data`.
+pub(crate) fn decode_bytes_list(data: &[u8]) -> Result<Vec<Vec<u8>>, DecodeError> {
+    decode_list(expect_list(data)?)?
+        .iter()
+        .map(|(item, _)| match item {
+            RlpItem::Bytes(x) => Ok(x.to_vec()),
+            RlpItem::List(_) => Err(DecodeError::ExpectedBytes),
+        })
+        .collect()
+}
+
+fn decode_len(data: &[u8], len_len: usize) -> Result<usize, DecodeError> {
+    if data.len() < len_len || len_len > std::mem::size_of::<usize>() {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+
+    Ok(data[..len_len]
+        .iter()
+        .fold(0usize, |acc, &x| (acc << 8) | x as usize))
+}
+
+/// A reference from a node to one of its children.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChildRef {
+    /// The child's encoding, embedded since it's shorter than a hash.
+    Inline(Vec<u8>),
+    /// The child's hash.
+    Hash(Vec<u8>),
+}
+
+impl ChildRef {
+    /// Build the reference to a node given its encoding, embedding it if it's shorter than a hash.
+    pub fn new<H>(encoded: Vec<u8>) -> Self
+    where
+        H: Digest,
+    {
+        match encoded.len() {
+            l if l < 32 => ChildRef::Inline(encoded),
+            _ => ChildRef::Hash(H::digest(encoded).to_vec()),
+        }
+    }
+}
+
+/// A node decoded from its RLP encoding. Paths and prefixes are stored as nibbles.
+///
+/// Useful to inspect nodes coming from proofs or databases without building a tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RawNode {
+    Branch {
+        choices: Box<[Option<ChildRef>; 16]>,
+        value: Option<Vec<u8>>,
+    },
+    Extension {
+        prefix: Vec<u8>,
+        child: ChildRef,
+    },
+    Leaf {
+        path: Vec<u8>,
+        value: Vec<u8>,
+    },
+}
+
+impl RawNode {
+    /// Decode a node from its RLP encoding.
+    ///
+    /// Besides the encoding itself, the node's structure is validated: hashed children must be
+    /// exactly 32 bytes long, embedded ones shorter than that and valid nodes themselves, and
+    /// extensions can't have an empty prefix.
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let (item, _, rest) = decode_item(data)?;
+        if !rest.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        let payload = match item {
+            RlpItem::List(payload) => payload,
+            RlpItem::Bytes(_) => return Err(DecodeError::ExpectedList),
+        };
+
+        let items = decode_list(payload)?;
+        match items.len() {
+            2 => {
+                let path = match items[0].0 {
+                    RlpItem::Bytes(x) => x,
+                    RlpItem::List(_) => return Err(DecodeError::ExpectedBytes),
+                };
+                let (is_leaf, path) = decode_path(path)?;
+
+                if is_leaf {
+                    let value = match items[1].0 {
+                        RlpItem::Bytes(x) => x.to_vec(),
+                        RlpItem::List(_) => return Err(DecodeError::ExpectedBytes),
+                    };
+
+                    Ok(RawNode::Leaf { path, value })
+                } else {
+                    if path.is_empty() {
+                        return Err(DecodeError::InvalidPath);
+                    }
+                    let child =
+                        decode_child(items[1].0, items[1].1)?.ok_or(DecodeError::InvalidChild)?;
+
+                    Ok(RawNode::Extension {
+                        prefix: path,
+                        child,
+                    })
+                }
+            }
+            17 => {
+                let mut choices = Box::<[Option<ChildRef>; 16]>::default();
+                for (choice, (item, raw)) in choices.iter_mut().zip(&items[..16]) {
+                    *choice = decode_child(*item, raw)?;
+                }
+
+                let value = match items[16].0 {
+                    RlpItem::Bytes([]) => None,
+                    RlpItem::Bytes(x) => Some(x.to_vec()),
+                    RlpItem::List(_) => return Err(DecodeError::ExpectedBytes),
+                };
+
+                Ok(RawNode::Branch { choices, value })
+            }
+            n => Err(DecodeError::InvalidItemCount(n)),
+        }
+    }
+
+    /// Return the node's RLP encoding (the inverse of [`decode`](Self::decode)).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = NodeEncoder::default();
+        match self {
+            RawNode::Branch { choices, value } => {
+                for choice in choices.iter() {
+                    match choice {
+                        Some(child) => encode_child(&mut payload, child),
+                        None => payload.write_bytes(&[]),
+                    }
+                }
+                payload.write_bytes(value.as_deref().unwrap_or_default());
+            }
+            RawNode::Extension { prefix, child } => {
+                payload.write_bytes(&encode_path(false, prefix));
+                encode_child(&mut payload, child);
+            }
+            RawNode::Leaf { path, value } => {
+                payload.write_bytes(&encode_path(true, path));
+                payload.write_bytes(value);
+            }
+        }
+
+        let payload = payload.finalize();
+        let mut encoder = NodeEncoder::default();
+        encoder.write_list_header(payload.len());
+        encoder.write_raw(&payload);
+        encoder.finalize()
+    }
+
+    /// Iterate over the node's children references, along with the nibbles leading to them.
+    pub fn children(&self) -> impl Iterator<Item = (&[u8], &ChildRef)> {
+        static CHOICES: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+        let (branch, extension) = match self {
+            RawNode::Branch { choices, .. } => (Some(choices), None),
+            RawNode::Extension { prefix, child } => (None, Some((prefix.as_slice(), child))),
+            RawNode::Leaf { .. } => (None, None),
+        };
+
+        branch
+            .into_iter()
+            .flat_map(|choices| {
+                choices.iter().enumerate().filter_map(|(index, choice)| {
+                    choice
+                        .as_ref()
+                        .map(|child| (&CHOICES[index..index + 1], child))
+                })
+            })
+            .chain(extension)
+    }
+}
+
+fn decode_child<'a>(item: RlpItem<'a>, raw: &'a [u8]) -> Result<Option<ChildRef>, DecodeError> {
+    match item {
+        RlpItem::Bytes([]) => Ok(None),
+        RlpItem::Bytes(x) if x.len() == 32 => Ok(Some(ChildRef::Hash(x.to_vec()))),
+        RlpItem::Bytes(_) => Err(DecodeError::InvalidChild),
+        RlpItem::List(_) if raw.len() < 32 => {
+            RawNode::decode(raw)?;
+            Ok(Some(ChildRef::Inline(raw.to_vec())))
+        }
+        RlpItem::List(_) => Err(DecodeError::InvalidChild),
+    }
+}
+
+fn encode_child(encoder: &mut NodeEncoder, child: &ChildRef) {
+    match child {
+        ChildRef::Inline(encoded) => encoder.write_raw(encoded),
+        ChildRef::Hash(hash) => encoder.write_bytes(hash),
+    }
+}
+
+/// Hex-prefix encode a path given its nibbles.
+fn encode_path(is_leaf: bool, nibbles: &[u8]) -> Vec<u8> {
+    let flag = if is_leaf { 0x20 } else { 0x00 };
+    let (mut data, pairs) = match nibbles.len() % 2 {
+        0 => (vec![flag], nibbles),
+        _ => (vec![flag | 0x10 | nibbles[0]], &nibbles[1..]),
+    };
+    data.extend(pairs.chunks(2).map(|x| (x[0] << 4) | x[1]));
+
+    data
+}
+
+/// Decode a hex-prefix encoded path, returning whether it belongs to a leaf and its nibbles.
+fn decode_path(data: &[u8]) -> Result<(bool, Vec<u8>), DecodeError> {
+    let flag = *data.first().ok_or(DecodeError::InvalidPath)?;
+
+    let (is_leaf, is_odd) = match flag >> 4 {
+        0 => (false, false),
+        1 => (false, true),
+        2 => (true, false),
+        3 => (true, true),
+        _ => return Err(DecodeError::InvalidPath),
+    };
+
+    let mut nibbles = Vec::with_capacity(2 * data.len());
+    if is_odd {
+        nibbles.push(flag & 0x0F);
+    } else if flag & 0x0F != 0 {
+        return Err(DecodeError::InvalidPath);
+    }
+    for byte in &data[1..] {
+        nibbles.push(byte >> 4);
+        nibbles.push(byte & 0x0F);
+    }
+
+    Ok((is_leaf, nibbles))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{nodes::LeafNode, pmt_node, pmt_state};
+    use sha3::Keccak256;
+
+    #[test]
+    fn decode_item_short_bytes() {
+        let (item, raw, rest) = decode_item(&[0x83, 0x01, 0x02, 0x03, 0xFF]).unwrap();
+
+        assert_eq!(item, RlpItem::Bytes(&[0x01, 0x02, 0x03]));
+        assert_eq!(raw, &[0x83, 0x01, 0x02, 0x03]);
+        assert_eq!(rest, &[0xFF]);
+    }
+
+    #[test]
+    fn decode_item_single_byte() {
+        let (item, _, rest) = decode_item(&[0x7F]).unwrap();
+
+        assert_eq!(item, RlpItem::Bytes(&[0x7F]));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn decode_item_truncated() {
+        assert_eq!(
+            decode_item(&[0x83, 0x01]).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+        assert_eq!(decode_item(&[]).unwrap_err(), DecodeError::UnexpectedEnd);
+    }
+
+    #[test]
+    fn decode_leaf() {
+        let (nodes, mut values) = pmt_state!(Vec<u8>);
+
+        let node = LeafNode::<Vec<u8>, Vec<u8>, Keccak256>::new(crate::ValueRef::new(
+            values.insert((vec![0x12, 0x34], vec![0x56])),
+        ));
+        let encoded = node.encode(&nodes, &values, 1);
+
+        assert_eq!(
+            RawNode::decode(&encoded).unwrap(),
+            RawNode::Leaf {
+                path: vec![0x2, 0x3, 0x4],
+                value: vec![0x56],
+            },
+        );
+        assert_eq!(RawNode::decode(&encoded).unwrap().encode(), encoded);
+    }
+
+    #[test]
+    fn decode_branch() {
+        let (mut nodes, mut values) = pmt_state!(Vec<u8>);
+
+        let node = pmt_node! { @(nodes, values)
+            branch {
+                2 => leaf { vec![0x20] => vec![0x20] },
+                4 => leaf { vec![0x40] => vec![0x40] },
+            } with_leaf { vec![] => vec![0xFF] }
+        };
+        let encoded = node.encode(&nodes, &values, 0);
+
+        match RawNode::decode(&encoded).unwrap() {
+            RawNode::Branch { choices, value } => {
+                assert_eq!(value, Some(vec![0xFF]));
+                assert_eq!(
+                    choices
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, x)| x.as_ref().map(|_| i))
+                        .collect::<Vec<_>>(),
+                    vec![2, 4],
+                );
+                assert!(matches!(choices[2], Some(ChildRef::Inline(_))));
+            }
+            _ => panic!("expected a branch node"),
+        }
+        assert_eq!(RawNode::decode(&encoded).unwrap().encode(), encoded);
+    }
+
+    #[test]
+    fn decode_extension() {
+        let (mut nodes, mut values) = pmt_state!(Vec<u8>);
+
+        let node = pmt_node! { @(nodes, values)
+            extension { [0], branch {
+                0 => leaf { vec![0x00] => vec![0x12, 0x34, 0x56, 0x78] },
+                1 => leaf { vec![0x01] => vec![0x34, 0x56, 0x78, 0x9A] },
+            } }
+        };
+        let encoded = node.encode(&nodes, &values, 0);
+
+        match RawNode::decode(&encoded).unwrap() {
+            RawNode::Extension { prefix, child } => {
+                assert_eq!(prefix, vec![0x0]);
+                assert!(matches!(child, ChildRef::Inline(_)));
+            }
+            _ => panic!("expected an extension node"),
+        }
+        assert_eq!(RawNode::decode(&encoded).unwrap().encode(), encoded);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert_eq!(
+            RawNode::decode(&[0x80]).unwrap_err(),
+            DecodeError::ExpectedList
+        );
+        assert_eq!(
+            RawNode::decode(&[0xC1, 0x80]).unwrap_err(),
+            DecodeError::InvalidItemCount(1)
+        );
+        assert_eq!(
+            RawNode::decode(&[0xC2, 0x40, 0x80]).unwrap_err(),
+            DecodeError::InvalidPath
+        );
+        assert_eq!(
+            RawNode::decode(&[0xC2, 0x80, 0x80, 0x00]).unwrap_err(),
+            DecodeError::TrailingBytes
+        );
+        // Extension with an empty prefix.
+        assert_eq!(
+            RawNode::decode(&[0xC3, 0x00, 0xC1, 0x80]).unwrap_err(),
+            DecodeError::InvalidPath
+        );
+        // Extension with an embedded child which isn't a node.
+        assert_eq!(
+            RawNode::decode(&[0xC3, 0x11, 0xC1, 0x80]).unwrap_err(),
+            DecodeError::InvalidItemCount(1)
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{
+    hashing::{NodeEncoder, NodeWriter},
+    rlp::{decode_bytes_list, write_list, DecodeError},
+    Encode, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use std::collections::BTreeMap;
+
+/// A tree whose paths are the hashes of the keys they're inserted with, as Ethereum's secure
+/// tries do.
+///
+/// When enabled, the keys behind the hashed paths (their preimages) are recorded so that the
+/// original keys can be listed, exported and imported later on. Preimages are kept after their
+/// values are removed.
+#[derive(Clone, Debug, Default)]
+pub struct SecureTree<V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    tree: PatriciaMerkleTree<Vec<u8>, V, H>,
+    /// The recorded preimages indexed by hash, if recording is enabled.
+    preimages: Option<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl<V, H> SecureTree<V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    /// Create an empty tree which doesn't record preimages.
+    pub fn new() -> Self {
+        Self {
+            tree: PatriciaMerkleTree::new(),
+            preimages: None,
+        }
+    }
+
+    /// Create an empty tree which records preimages.
+    pub fn with_preimages() -> Self {
+        Self {
+            tree: PatriciaMerkleTree::new(),
+            preimages: Some(BTreeMap::new()),
+        }
+    }
+
+    /// Use an existing tree, whose paths are already hashed. No preimages are known.
+    pub fn from_tree(tree: PatriciaMerkleTree<Vec<u8>, V, H>) -> Self {
+        Self {
+            tree,
+            preimages: None,
+        }
+    }
+
+    /// Return the underlying tree, indexed by hashed path.
+    pub fn tree(&self) -> &PatriciaMerkleTree<Vec<u8>, V, H> {
+        &self.tree
+    }
+
+    /// Consume the wrapper, returning the underlying tree.
+    pub fn into_tree(self) -> PatriciaMerkleTree<Vec<u8>, V, H> {
+        self.tree
+    }
+
+    /// Return whether the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Return the number of values in the tree.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Retrieve a value given its key.
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        self.tree.get(&H::digest(key).to_vec())
+    }
+
+    /// Insert a value, recording its key's preimage if enabled, and return the previous one (if
+    /// any).
+    pub fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        let path = H::digest(key).to_vec();
+        if let Some(preimages) = &mut self.preimages {
+            preimages.insert(path.clone(), key.to_vec());
+        }
+
+        self.tree.insert(path, value)
+    }
+
+    /// Remove a value given its key, returning it (if any).
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        self.tree.remove(H::digest(key).to_vec())
+    }
+
+    /// Return the root hash of the tree.
+    pub fn compute_hash(&mut self) -> &Output<H> {
+        self.tree.compute_hash()
+    }
+
+    /// Return the proof of the value with the given key (see
+    /// [`PatriciaMerkleTree::get_proof`]).
+    pub fn get_proof(&mut self, key: &[u8]) -> Vec<Vec<u8>> {
+        self.tree.get_proof(&H::digest(key).to_vec())
+    }
+
+    /// Return whether preimages are being recorded.
+    pub fn records_preimages(&self) -> bool {
+        self.preimages.is_some()
+    }
+
+    /// Start recording preimages, if not already doing so.
+    pub fn record_preimages(&mut self) {
+        self.preimages.get_or_insert_with(BTreeMap::new);
+    }
+
+    /// Return the key behind a hashed path, if known.
+    pub fn preimage(&self, hash: &[u8]) -> Option<&[u8]> {
+        self.preimages.as_ref()?.get(hash).map(Vec::as_slice)
+    }
+
+    /// Iterate over the values in ascending hashed path order, along with their hashed paths and
+    /// their keys (if known).
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>, &V)> {
+        self.tree
+            .iter()
+            .map(|(path, value)| (path.as_slice(), self.preimage(path), value))
+    }
+
+    /// Export every recorded preimage, as an RLP list of keys in ascending hash order.
+    pub fn export_preimages(&self) -> Vec<u8> {
+        let mut payload = NodeEncoder::default();
+        for key in self.preimages.iter().flat_map(BTreeMap::values) {
+            payload.write_bytes(key);
+        }
+
+        let mut encoder = NodeEncoder::default();
+        write_list(&mut encoder, &payload.finalize());
+        encoder.finalize()
+    }
+
+    /// Import preimages exported by [`export_preimages`](Self::export_preimages), enabling
+    /// recording if it wasn't, and return how many of them were new.
+    pub fn import_preimages(&mut self, data: &[u8]) -> Result<usize, DecodeError> {
+        let keys = decode_bytes_list(data)?;
+        let preimages = self.preimages.get_or_insert_with(BTreeMap::new);
+
+        let len = preimages.len();
+        preimages.extend(keys.into_iter().map(|key| (H::digest(&key).to_vec(), key)));
+        Ok(preimages.len() - len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sha3::Keccak256;
+
+    #[test]
+    fn secure_tree() {
+        let mut tree = SecureTree::<Vec<u8>, Keccak256>::new();
+        assert_eq!(tree.insert(b"dog", b"puppy".to_vec()), None);
+        assert_eq!(tree.get(b"dog"), Some(&b"puppy".to_vec()));
+        assert!(!tree.records_preimages());
+        assert_eq!(tree.iter().next().unwrap().1, None);
+
+        // Same root as hashing the paths by hand.
+        let mut expected = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, Keccak256>::new();
+        expected.insert(Keccak256::digest(b"dog").to_vec(), b"puppy".to_vec());
+        assert_eq!(tree.compute_hash(), expected.compute_hash());
+        assert_eq!(
+            tree.get_proof(b"dog"),
+            expected.get_proof(&Keccak256::digest(b"dog").to_vec())
+        );
+    }
+
+    #[test]
+    fn preimages() {
+        let mut tree = SecureTree::<Vec<u8>, Keccak256>::with_preimages();
+        for key in [&b"dog"[..], b"horse", b"doge"] {
+            tree.insert(key, key.to_vec());
+        }
+        tree.remove(b"horse");
+
+        let keys = tree
+            .iter()
+            .map(|(_, key, _)| key.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|key| tree.get(key) == Some(&key.to_vec())));
+        let hash = Keccak256::digest(b"horse");
+        assert_eq!(tree.preimage(&hash), Some(&b"horse"[..]));
+
+        // Preimages can be moved to another tree over the same paths.
+        let exported = tree.export_preimages();
+        let mut other = SecureTree::from_tree(tree.tree().clone());
+        assert_eq!(other.iter().next().unwrap().1, None);
+        assert_eq!(other.import_preimages(&exported), Ok(3));
+        assert_eq!(other.import_preimages(&exported), Ok(0));
+        assert!(other.records_preimages());
+        assert!(other.iter().map(|(_, key, _)| key.unwrap()).eq(keys));
+        assert_eq!(other.export_preimages(), exported);
+
+        assert_eq!(
+            other.import_preimages(&[0xC1, 0xC0]),
+            Err(DecodeError::ExpectedBytes)
+        );
+        assert_eq!(
+            SecureTree::<Vec<u8>, Keccak256>::new().export_preimages(),
+            [0xC0]
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{
+    diff::{is_same_subtree, Cursor},
+    storage::StoredValue,
+    Encode, PatriciaMerkleTree,
+};
+use digest::Digest;
+
+/// Walks two trees in lockstep, one nibble at a time, returning the entries found at each path
+/// in either of them in ascending path order.
+struct Lockstep<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    left: &'a PatriciaMerkleTree<P, V, H>,
+    right: &'a PatriciaMerkleTree<P, V, H>,
+    /// Pending pairs of subtrees (along with their path offset), in reverse order.
+    stack: Vec<(Cursor, Cursor, usize)>,
+    /// Whether to skip the subtrees whose hashes match.
+    skip_identical: bool,
+    /// Whether to skip the subtrees which are empty in either tree.
+    skip_one_sided: bool,
+}
+
+type Entry<'a, P, V> = Option<&'a (P, StoredValue<V>)>;
+
+impl<'a, P, V, H> Lockstep<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    fn new(
+        left: &'a PatriciaMerkleTree<P, V, H>,
+        right: &'a PatriciaMerkleTree<P, V, H>,
+        skip_identical: bool,
+        skip_one_sided: bool,
+    ) -> Self {
+        Self {
+            left,
+            right,
+            stack: vec![(Cursor::root(left), Cursor::root(right), 0)],
+            skip_identical,
+            skip_one_sided,
+        }
+    }
+
+    /// Return the entries at the next path with a value in either tree.
+    fn next_entries(&mut self) -> Option<(Entry<'a, P, V>, Entry<'a, P, V>)> {
+        while let Some((left_cursor, right_cursor, path_offset)) = self.stack.pop() {
+            if self.skip_identical
+                && is_same_subtree(
+                    self.left,
+                    self.right,
+                    left_cursor,
+                    right_cursor,
+                    path_offset,
+                )
+            {
+                continue;
+            }
+
+            let (left_value_ref, left_children) = left_cursor.expand(self.left, path_offset);
+            let (right_value_ref, right_children) = right_cursor.expand(self.right, path_offset);
+            for (left_child, right_child) in left_children.into_iter().zip(right_children).rev() {
+                let is_wanted = match (left_child, right_child) {
+                    (Cursor::Empty, Cursor::Empty) => false,
+                    (Cursor::Empty, _) | (_, Cursor::Empty) => !self.skip_one_sided,
+                    _ => true,
+                };
+                if is_wanted {
+                    self.stack.push((left_child, right_child, path_offset + 1));
+                }
+            }
+
+            let left_entry = left_value_ref.map(|x| {
+                self.left
+                    .values
+                    .get(*x)
+                    .expect("inconsistent internal tree structure")
+            });
+            let right_entry = right_value_ref.map(|x| {
+                self.right
+                    .values
+                    .get(*x)
+                    .expect("inconsistent internal tree structure")
+            });
+            if left_entry.is_some() || right_entry.is_some() {
+                return Some((left_entry, right_entry));
+            }
+        }
+
+        None
+    }
+}
+
+/// Iterator over the paths present in exactly one of two trees, in ascending path order. See
+/// [`PatriciaMerkleTree::symmetric_difference`].
+pub struct SymmetricDifference<'a, P, V, H>(Lockstep<'a, P, V, H>)
+where
+    P: Encode,
+    V: Encode,
+    H: Digest;
+
+impl<'a, P, V, H> SymmetricDifference<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    pub(crate) fn new(
+        left: &'a PatriciaMerkleTree<P, V, H>,
+        right: &'a PatriciaMerkleTree<P, V, H>,
+    ) -> Self {
+        Self(Lockstep::new(left, right, true, false))
+    }
+}
+
+impl<'a, P, V, H> Iterator for SymmetricDifference<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    type Item = &'a P;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.0.next_entries()? {
+                (Some((path, _)), None) | (None, Some((path, _))) => return Some(path),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Iterator over the paths present in both of two trees along with both values, in ascending
+/// path order. See [`PatriciaMerkleTree::intersection`].
+pub struct Intersection<'a, P, V, H>(Lockstep<'a, P, V, H>)
+where
+    P: Encode,
+    V: Encode,
+    H: Digest;
+
+impl<'a, P, V, H> Intersection<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    pub(crate) fn new(
+        left: &'a PatriciaMerkleTree<P, V, H>,
+        right: &'a PatriciaMerkleTree<P, V, H>,
+    ) -> Self {
+        Self(Lockstep::new(left, right, false, true))
+    }
+}
+
+impl<'a, P, V, H> Iterator for Intersection<'a, P, V, H>
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    /// The path and the values in both trees (the left one first).
+    type Item = (&'a P, &'a V, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let (Some((path, left_value)), Some((_, right_value))) = self.0.next_entries()? {
+                return Some((path, &**left_value, &**right_value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+
+    #[test]
+    fn symmetric_difference() {
+        let mut left = pmt_tree!(Vec<u8>);
+        for i in 0..=255u8 {
+            left.insert(vec![i, i], vec![i]);
+        }
+
+        let mut right = left.clone();
+        right.insert(vec![0x10, 0x10], vec![0xFF]);
+        right.insert(vec![0x10], vec![0x10]);
+        right.remove(vec![0x80, 0x80]);
+        right.insert(vec![0x80, 0x81], vec![0x80]);
+
+        let paths = left.symmetric_difference(&right).collect::<Vec<_>>();
+        assert_eq!(paths, [&vec![0x10], &vec![0x80, 0x80], &vec![0x80, 0x81]]);
+        assert!(right.symmetric_difference(&left).eq(paths));
+        assert_eq!(left.symmetric_difference(&left).next(), None);
+    }
+
+    #[test]
+    fn intersection() {
+        let mut left = pmt_tree!(Vec<u8>);
+        let mut right = pmt_tree!(Vec<u8>);
+        for i in 0..=255u8 {
+            left.insert(vec![i, i], vec![i]);
+            right.insert(vec![i, i ^ (i & 1)], vec![i]);
+        }
+        right.insert(vec![0x10, 0x10], vec![0xFF]);
+
+        let entries = left.intersection(&right).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 128);
+        assert!(entries.iter().all(|(path, _, _)| path[1] % 2 == 0));
+        assert_eq!(entries[8], (&vec![0x10, 0x10], &vec![0x10], &vec![0xFF]));
+        assert_eq!(left.intersection(&left).count(), 256);
+        assert_eq!(left.intersection(&pmt_tree!(Vec<u8>)).next(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_symmetric_difference(
+            left_data in btree_map(vec(0..4u8, 0..4), vec(0..2u8, 1..2), 0..64),
+            right_data in btree_map(vec(0..4u8, 0..4), vec(0..2u8, 1..2), 0..64),
+        ) {
+            let mut left = pmt_tree!(Vec<u8>);
+            for (path, value) in &left_data {
+                left.insert(path.clone(), value.clone());
+            }
+            let mut right = pmt_tree!(Vec<u8>);
+            for (path, value) in &right_data {
+                right.insert(path.clone(), value.clone());
+            }
+
+            let expected = left_data
+                .keys()
+                .filter(|x| !right_data.contains_key(*x))
+                .chain(right_data.keys().filter(|x| !left_data.contains_key(*x)))
+                .collect::<std::collections::BTreeSet<_>>();
+            prop_assert!(left.symmetric_difference(&right).eq(expected));
+
+            let expected = left_data
+                .iter()
+                .filter_map(|(path, value)| Some((path, value, right_data.get(path)?)));
+            prop_assert!(left.intersection(&right).eq(expected));
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! The `paranoid` consistency mode, which checks the tree against a plain map after every
+//! mutation.
+
+use crate::{hex, node::Node, Encode, PatriciaMerkleTree};
+use digest::Digest;
+use std::collections::BTreeMap;
+
+/// A copy of the tree's contents (encoded) kept in a plain map.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ShadowMap(BTreeMap<Vec<u8>, Vec<u8>>);
+
+impl ShadowMap {
+    pub fn insert(&mut self, path: Vec<u8>, value: Vec<u8>) {
+        self.0.insert(path, value);
+    }
+
+    pub fn remove(&mut self, path: &[u8]) {
+        self.0.remove(path);
+    }
+
+    /// Panic unless the tree has the same values as the map, in the same order.
+    ///
+    /// `operation` and `path` (encoded) describe the mutation just done, to be reported.
+    pub fn check<P, V, H>(&self, tree: &PatriciaMerkleTree<P, V, H>, operation: &str, path: &[u8])
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        let context = || format!("after {operation} at {}", hex::encode(path));
+        assert_eq!(tree.len(), self.0.len(), "length mismatch {}", context());
+
+        // Values behind stubs aren't loaded (nor shadowed), so the tree can't be iterated.
+        if tree.nodes.iter().any(|(_, x)| matches!(x, Node::Stub(_))) {
+            return;
+        }
+
+        let mut expected = self.0.iter();
+        for (path, value) in tree.iter() {
+            let (expected_path, expected_value) = expected
+                .next()
+                .unwrap_or_else(|| panic!("unexpected path {}", context()));
+            assert_eq!(
+                path.encode().as_ref(),
+                expected_path.as_slice(),
+                "path mismatch {}",
+                context()
+            );
+            assert_eq!(
+                value.encode().as_ref(),
+                expected_value.as_slice(),
+                "value mismatch at {} {}",
+                hex::encode(expected_path),
+                context()
+            );
+        }
+        if let Some((path, _)) = expected.next() {
+            panic!("missing path {} {}", hex::encode(path), context());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pmt_tree;
+
+    #[test]
+    fn paranoid() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..64u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i]);
+        }
+        for i in 0..16u8 {
+            tree.remove(vec![i, i.wrapping_mul(7)]);
+        }
+        tree.remove_range(vec![0x20]..vec![0x30]);
+        tree.compact();
+        assert_eq!(tree.shadow.0.len(), 32);
+    }
+
+    #[test]
+    #[should_panic = "value mismatch"]
+    fn paranoid_mismatch() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        tree.insert(vec![0x12], vec![1]);
+        tree.shadow.insert(vec![0x12], vec![2]);
+        tree.insert(vec![0x34], vec![3]);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::{
+    account::{exact, left_pad, trim_zeros},
+    hashing::{NodeEncoder, NodeWriter},
+    proof::{verify_range_proof, RangeProofError},
+    rlp::{decode_bytes_list, decode_list, expect_list, write_list, DecodeError, RlpItem},
+    Account, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+
+/// An `AccountRange` response of the `snap/1` protocol: a consecutive slice of accounts from the
+/// state tree along with the proofs of its boundaries.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AccountRange {
+    /// The hashed addresses and slim-encoded bodies of the accounts, in ascending hash order.
+    pub accounts: Vec<([u8; 32], Vec<u8>)>,
+    /// The nodes proving the requested origin and the last account returned.
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl AccountRange {
+    /// Return the response's RLP encoding: `[request_id, [[hash, body], ...], [node, ...]]`.
+    pub fn encode(&self, request_id: u64) -> Vec<u8> {
+        let mut accounts = NodeEncoder::default();
+        for (hash, body) in &self.accounts {
+            let mut account = NodeEncoder::default();
+            account.write_bytes(hash);
+            account.write_raw(body);
+            write_list(&mut accounts, &account.finalize());
+        }
+
+        let mut proof = NodeEncoder::default();
+        for node in &self.proof {
+            proof.write_bytes(node);
+        }
+
+        let mut payload = NodeEncoder::default();
+        payload.write_bytes(trim_zeros(&request_id.to_be_bytes()));
+        write_list(&mut payload, &accounts.finalize());
+        write_list(&mut payload, &proof.finalize());
+
+        let mut encoder = NodeEncoder::default();
+        write_list(&mut encoder, &payload.finalize());
+        encoder.finalize()
+    }
+
+    /// Decode a response from its RLP encoding, returning its request id along with it.
+    pub fn decode(data: &[u8]) -> Result<(u64, Self), DecodeError> {
+        let items = decode_list(expect_list(data)?)?;
+        let [(RlpItem::Bytes(request_id), _), (_, accounts), (_, proof)] = items[..] else {
+            return match items.len() {
+                3 => Err(DecodeError::ExpectedBytes),
+                len => Err(DecodeError::InvalidItemCount(len)),
+            };
+        };
+
+        let accounts = decode_list(expect_list(accounts)?)?
+            .iter()
+            .map(|(_, raw)| {
+                let items = decode_list(expect_list(raw)?)?;
+                match items[..] {
+                    [(RlpItem::Bytes(hash), _), (RlpItem::List(_), body)] => {
+                        Ok((exact(hash)?, body.to_vec()))
+                    }
+                    [_, _] => Err(DecodeError::InvalidHash),
+                    _ => Err(DecodeError::InvalidItemCount(items.len())),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((
+            u64::from_be_bytes(left_pad(request_id)?),
+            Self {
+                accounts,
+                proof: decode_bytes_list(proof)?,
+            },
+        ))
+    }
+}
+
+/// Build the response to a `GetAccountRange` request from the state tree.
+///
+/// Accounts are returned starting at `origin` until one at or past `limit` is included, or until
+/// the response grows past `response_bytes` (at least one account is always returned if there's
+/// any). Fails if the state tree contains a value which isn't an account.
+pub fn get_account_range<H>(
+    state: &mut PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>,
+    origin: &[u8; 32],
+    limit: &[u8; 32],
+    response_bytes: u64,
+) -> Result<AccountRange, DecodeError>
+where
+    H: Digest,
+{
+    let mut accounts = Vec::new();
+    let mut size = 0;
+    for (hash, account) in state.iter_from(&origin.to_vec()) {
+        let hash = exact(hash)?;
+        let body = Account::decode(account)?.encode_slim();
+
+        size += (hash.len() + body.len()) as u64;
+        accounts.push((hash, body));
+
+        if hash >= *limit || size > response_bytes {
+            break;
+        }
+    }
+
+    let proof = match accounts.last() {
+        Some((last, _)) => state.get_gap_proof(&origin.to_vec(), &last.to_vec()),
+        None => state.get_proof(&origin.to_vec()),
+    };
+
+    Ok(AccountRange { accounts, proof })
+}
+
+/// A `StorageRanges` response of the `snap/1` protocol: the storage slots of consecutive
+/// requested accounts, where only the last range may be incomplete and carry a proof.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StorageRanges {
+    /// The hashed keys and values of the slots within each account, in ascending hash order.
+    pub slots: Vec<Vec<([u8; 32], Vec<u8>)>>,
+    /// The nodes proving the boundaries of the last range.
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl StorageRanges {
+    /// Return the response's RLP encoding: `[request_id, [[[hash, value], ...], ...], [node, ...]]`.
+    pub fn encode(&self, request_id: u64) -> Vec<u8> {
+        let mut ranges = NodeEncoder::default();
+        for slots in &self.slots {
+            let mut range = NodeEncoder::default();
+            for (hash, value) in slots {
+                let mut slot = NodeEncoder::default();
+                slot.write_bytes(hash);
+                slot.write_bytes(value);
+                write_list(&mut range, &slot.finalize());
+            }
+            write_list(&mut ranges, &range.finalize());
+        }
+
+        let mut proof = NodeEncoder::default();
+        for node in &self.proof {
+            proof.write_bytes(node);
+        }
+
+        let mut payload = NodeEncoder::default();
+        payload.write_bytes(trim_zeros(&request_id.to_be_bytes()));
+        write_list(&mut payload, &ranges.finalize());
+        write_list(&mut payload, &proof.finalize());
+
+        let mut encoder = NodeEncoder::default();
+        write_list(&mut encoder, &payload.finalize());
+        encoder.finalize()
+    }
+
+    /// Decode a response from its RLP encoding, returning its request id along with it.
+    pub fn decode(data: &[u8]) -> Result<(u64, Self), DecodeError> {
+        let items = decode_list(expect_list(data)?)?;
+        let [(RlpItem::Bytes(request_id), _), (_, ranges), (_, proof)] = items[..] else {
+            return match items.len() {
+                3 => Err(DecodeError::ExpectedBytes),
+                len => Err(DecodeError::InvalidItemCount(len)),
+            };
+        };
+
+        let slots = decode_list(expect_list(ranges)?)?
+            .iter()
+            .map(|(_, raw)| {
+                decode_list(expect_list(raw)?)?
+                    .iter()
+                    .map(|(_, raw)| {
+                        let items = decode_list(expect_list(raw)?)?;
+                        match items[..] {
+                            [(RlpItem::Bytes(hash), _), (RlpItem::Bytes(value), _)] => {
+                                Ok((exact(hash)?, value.to_vec()))
+                            }
+                            [_, _] => Err(DecodeError::ExpectedBytes),
+                            _ => Err(DecodeError::InvalidItemCount(items.len())),
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((
+            u64::from_be_bytes(left_pad(request_id)?),
+            Self {
+                slots,
+                proof: decode_bytes_list(proof)?,
+            },
+        ))
+    }
+}
+
+/// Verify a `StorageRanges` response against the storage roots of the requested accounts (in the
+/// order they were requested), returning whether the last range is incomplete.
+///
+/// Every range but the last must hold the whole storage of its account. The last one starts at
+/// `origin` and, when it carries a proof, may stop before the end of the storage. Accounts after
+/// the last range weren't served at all.
+pub fn verify_storage_ranges<H>(
+    storage_roots: &[Output<H>],
+    origin: &[u8; 32],
+    response: &StorageRanges,
+) -> Result<bool, RangeProofError>
+where
+    H: Digest,
+{
+    // A range without a requested account can't match any root.
+    if response.slots.len() > storage_roots.len() {
+        return Err(RangeProofError::RootMismatch);
+    }
+
+    let mut has_more = false;
+    for (index, (slots, root)) in response.slots.iter().zip(storage_roots).enumerate() {
+        let entries = slots
+            .iter()
+            .map(|(hash, value)| (hash.to_vec(), value.clone()))
+            .collect::<Vec<_>>();
+
+        has_more = match index + 1 == response.slots.len() && !response.proof.is_empty() {
+            true => verify_range_proof::<H>(root, origin, &entries, response.proof.clone())?,
+            false => verify_range_proof::<H>(root, &[], &entries, [])?,
+        };
+    }
+
+    Ok(has_more)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::verify_proof;
+    use sha3::Keccak256;
+
+    fn build_state() -> PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256> {
+        let mut state = PatriciaMerkleTree::new();
+        for i in 0..64u8 {
+            let account = Account {
+                nonce: i as u64,
+                ..Default::default()
+            };
+            state.insert(Keccak256::digest([i; 20]).to_vec(), account.encode());
+        }
+
+        state
+    }
+
+    #[test]
+    fn account_range() {
+        let mut state = build_state();
+        let root = *state.compute_hash();
+        let mut sorted = state.iter().map(|(x, _)| x.clone()).collect::<Vec<_>>();
+        sorted.sort();
+
+        let origin = [0; 32];
+        let limit = exact(&sorted[9]).unwrap();
+        let range = get_account_range(&mut state, &origin, &limit, 1 << 20).unwrap();
+
+        assert_eq!(range.accounts.len(), 10);
+        for ((hash, body), path) in range.accounts.iter().zip(&sorted) {
+            assert_eq!(&hash[..], path.as_slice());
+            assert_eq!(
+                Account::decode_slim(body).unwrap().encode(),
+                *state.get(path).unwrap()
+            );
+        }
+
+        assert_eq!(
+            verify_proof::<Keccak256>(&root, &origin, range.proof.clone()),
+            Some(None),
+        );
+        assert_eq!(
+            verify_proof::<Keccak256>(&root, &sorted[9], range.proof),
+            Some(Some(state.get(&sorted[9]).unwrap().clone())),
+        );
+    }
+
+    #[test]
+    fn account_range_size_limit() {
+        let mut state = build_state();
+
+        // At least one account is returned, even if it's over the limit.
+        let range = get_account_range(&mut state, &[0; 32], &[0xFF; 32], 0).unwrap();
+        assert_eq!(range.accounts.len(), 1);
+
+        let body_len = range.accounts[0].1.len() as u64;
+        let range = get_account_range(&mut state, &[0; 32], &[0xFF; 32], 3 * (32 + body_len));
+        assert_eq!(range.unwrap().accounts.len(), 4);
+
+        let range = get_account_range(&mut state, &[0xFF; 32], &[0xFF; 32], 1 << 20).unwrap();
+        assert!(range.accounts.is_empty());
+        assert!(!range.proof.is_empty());
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut state = build_state();
+        let range = get_account_range(&mut state, &[0x80; 32], &[0xFF; 32], 256).unwrap();
+
+        let encoded = range.encode(0x1234);
+        assert_eq!(AccountRange::decode(&encoded), Ok((0x1234, range)));
+        assert_eq!(
+            AccountRange::default().encode(0),
+            vec![0xC3, 0x80, 0xC0, 0xC0]
+        );
+        assert_eq!(
+            AccountRange::decode(&[0xC2, 0x80, 0xC0]),
+            Err(DecodeError::InvalidItemCount(2)),
+        );
+    }
+
+    fn build_storage(len: u8) -> PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256> {
+        let mut storage = PatriciaMerkleTree::new();
+        for i in 0..len {
+            storage.insert(Keccak256::digest([i; 32]).to_vec(), vec![0x81, 0x80 + i]);
+        }
+
+        storage
+    }
+
+    fn slots_of(
+        storage: &PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256>,
+        origin: &[u8; 32],
+        count: usize,
+    ) -> Vec<([u8; 32], Vec<u8>)> {
+        storage
+            .iter_from(&origin.to_vec())
+            .take(count)
+            .map(|(hash, value)| (exact(hash).unwrap(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn storage_ranges() {
+        let mut storages = [build_storage(4), build_storage(0), build_storage(32)];
+        let roots = storages.each_mut().map(|x| *x.compute_hash());
+
+        let origin = [0; 32];
+        let slots = vec![
+            slots_of(&storages[0], &origin, 4),
+            Vec::new(),
+            slots_of(&storages[2], &origin, 10),
+        ];
+        let last = slots[2].last().unwrap().0.to_vec();
+        let mut response = StorageRanges {
+            slots,
+            proof: storages[2].get_gap_proof(&origin.to_vec(), &last),
+        };
+        assert_eq!(
+            verify_storage_ranges::<Keccak256>(&roots, &origin, &response),
+            Ok(true)
+        );
+
+        // Continue the last range where it stopped.
+        let mut origin = exact(&last).unwrap();
+        origin[31] += 1;
+        let slots = slots_of(&storages[2], &origin, 32);
+        let response_tail = StorageRanges {
+            proof: storages[2].get_gap_proof(&origin.to_vec(), &slots.last().unwrap().0.to_vec()),
+            slots: vec![slots],
+        };
+        assert_eq!(
+            verify_storage_ranges::<Keccak256>(&roots[2..], &origin, &response_tail),
+            Ok(false)
+        );
+
+        // Ranges before the last one must be complete.
+        response.slots[0].pop();
+        assert_eq!(
+            verify_storage_ranges::<Keccak256>(&roots, &[0; 32], &response),
+            Err(RangeProofError::RootMismatch)
+        );
+    }
+
+    #[test]
+    fn storage_ranges_encode_decode() {
+        let storage = build_storage(8);
+        let ranges = StorageRanges {
+            slots: vec![slots_of(&storage, &[0; 32], 8), Vec::new()],
+            proof: vec![vec![0xC2, 0x80, 0x80], vec![0xAB; 64]],
+        };
+
+        let encoded = ranges.encode(7);
+        assert_eq!(StorageRanges::decode(&encoded), Ok((7, ranges)));
+        assert_eq!(
+            StorageRanges::default().encode(0),
+            vec![0xC3, 0x80, 0xC0, 0xC0]
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use crate::{
+    db::{load_tree, record_commit, write_nodes, LoadError, MemoryDb, NodeDb},
+    hex, Encode, PatriciaMerkleTree,
+};
+use digest::{Digest, Output};
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+};
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"PMTS";
+const KIND_FULL: u8 = 0;
+const KIND_DELTA: u8 = 1;
+
+/// Errors found while loading a chain of snapshots.
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid snapshot header")]
+    InvalidHeader,
+    #[error("expected a full snapshot, found a delta")]
+    ExpectedFull,
+    #[error("expected a delta snapshot, found a full one")]
+    ExpectedDelta,
+    #[error(
+        "delta based on root {} doesn't follow root {}",
+        hex::encode(.found),
+        hex::encode(.expected)
+    )]
+    BrokenChain { expected: Vec<u8>, found: Vec<u8> },
+    #[error("node {} doesn't match its hash", hex::encode(.0))]
+    HashMismatch(Vec<u8>),
+    #[error(transparent)]
+    Load(#[from] LoadError),
+}
+
+/// Writes periodic checkpoints of a tree: a full snapshot first, then deltas containing only the
+/// nodes which weren't in any previous checkpoint.
+///
+/// Snapshots are a header (`PMTS`, the kind, the root hash and, for deltas, the previous root
+/// hash) followed by the nodes as `(hash, big-endian u32 length, encoded node)` records. Nodes
+/// are streamed into the writer, so wrapping it in a `BufWriter` is recommended.
+///
+/// The hashes of every node written since the last full snapshot are kept in memory, and loading
+/// requires every delta since then, so calling [`reset`](Self::reset) now and then keeps both
+/// bounded.
+#[derive(Debug)]
+pub struct SnapshotWriter<H>
+where
+    H: Digest,
+{
+    persisted: HashSet<Vec<u8>>,
+    root: Option<Output<H>>,
+}
+
+impl<H> SnapshotWriter<H>
+where
+    H: Digest,
+{
+    pub fn new() -> Self {
+        Self {
+            persisted: HashSet::new(),
+            root: None,
+        }
+    }
+
+    /// Make the next checkpoint a full snapshot.
+    pub fn reset(&mut self) {
+        self.persisted.clear();
+        self.root = None;
+    }
+
+    /// Write a checkpoint of the tree and return its root hash.
+    ///
+    /// It's a full snapshot unless there was a previous checkpoint (since the last reset), in
+    /// which case it's a delta based on it.
+    pub fn checkpoint<P, V>(
+        &mut self,
+        tree: &mut PatriciaMerkleTree<P, V, H>,
+        mut writer: impl Write,
+    ) -> io::Result<Output<H>>
+    where
+        P: Encode,
+        V: Encode,
+    {
+        let root = tree.compute_hash().clone();
+
+        writer.write_all(MAGIC)?;
+        match &self.root {
+            None => writer.write_all(&[KIND_FULL])?,
+            Some(base) => {
+                writer.write_all(&[KIND_DELTA])?;
+                writer.write_all(base)?;
+            }
+        }
+        writer.write_all(&root)?;
+
+        let mut sink = Sink {
+            writer: &mut writer,
+            persisted: &mut self.persisted,
+            error: None,
+        };
+        write_nodes(tree, &mut sink);
+        if let Some(error) = sink.error {
+            // The stored hashes may not match what was written anymore.
+            self.reset();
+            return Err(error);
+        }
+
+        writer.flush()?;
+        record_commit(tree, &root);
+        self.root = Some(root.clone());
+        Ok(root)
+    }
+}
+
+impl<H> Default for SnapshotWriter<H>
+where
+    H: Digest,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Adapts a writer into a node database which only writes the nodes not persisted yet.
+struct Sink<'a, W> {
+    writer: W,
+    persisted: &'a mut HashSet<Vec<u8>>,
+    error: Option<io::Error>,
+}
+
+impl<W> Sink<'_, W>
+where
+    W: Write,
+{
+    fn write_record(&mut self, hash: &[u8], encoded: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(encoded.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "node too large"))?;
+
+        self.writer.write_all(hash)?;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(encoded)
+    }
+}
+
+impl<W> NodeDb for Sink<'_, W>
+where
+    W: Write,
+{
+    fn get(&self, _hash: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn insert(&mut self, hash: Vec<u8>, encoded: Vec<u8>) {
+        if self.error.is_some() || self.persisted.contains(&hash) {
+            return;
+        }
+
+        match self.write_record(&hash, &encoded) {
+            Ok(()) => {
+                self.persisted.insert(hash);
+            }
+            Err(error) => self.error = Some(error),
+        }
+    }
+
+    fn remove(&mut self, _hash: &[u8]) {}
+}
+
+/// Load a tree with byte paths from a full snapshot followed by its deltas, in the order they
+/// were written.
+pub fn load_snapshot<H, R>(
+    base: impl Read,
+    deltas: impl IntoIterator<Item = R>,
+) -> Result<PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>, SnapshotError>
+where
+    H: Digest,
+    R: Read,
+{
+    let (db, root) = read_snapshot_chain::<H, R>(base, deltas)?;
+    Ok(load_tree::<H, _>(&db, &root)?)
+}
+
+/// Read the nodes of a full snapshot followed by its deltas without building the tree,
+/// returning them along with the root hash of the last one.
+///
+/// Every node is checked against its hash, but the tree isn't checked to be complete.
+pub fn read_snapshot_chain<H, R>(
+    base: impl Read,
+    deltas: impl IntoIterator<Item = R>,
+) -> Result<(MemoryDb, Vec<u8>), SnapshotError>
+where
+    H: Digest,
+    R: Read,
+{
+    let mut db = MemoryDb::new();
+    let mut root = read_snapshot::<H>(base, None, &mut db)?;
+    for delta in deltas {
+        root = read_snapshot::<H>(delta, Some(&root), &mut db)?;
+    }
+
+    Ok((db, root))
+}
+
+/// Read a snapshot's nodes into `db` and return its root hash. Deltas must be based on `base`.
+fn read_snapshot<H>(
+    mut reader: impl Read,
+    base: Option<&[u8]>,
+    db: &mut MemoryDb,
+) -> Result<Vec<u8>, SnapshotError>
+where
+    H: Digest,
+{
+    let hash_len = <H as Digest>::output_size();
+
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(SnapshotError::InvalidHeader);
+    }
+
+    match (header[4], base) {
+        (KIND_FULL, None) => {}
+        (KIND_DELTA, Some(expected)) => {
+            let mut found = vec![0; hash_len];
+            reader.read_exact(&mut found)?;
+            if found != expected {
+                return Err(SnapshotError::BrokenChain {
+                    expected: expected.to_vec(),
+                    found,
+                });
+            }
+        }
+        (KIND_FULL, Some(_)) => return Err(SnapshotError::ExpectedDelta),
+        (KIND_DELTA, None) => return Err(SnapshotError::ExpectedFull),
+        _ => return Err(SnapshotError::InvalidHeader),
+    }
+
+    let mut root = vec![0; hash_len];
+    reader.read_exact(&mut root)?;
+
+    while let Some(hash) = read_hash(&mut reader, hash_len)? {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut encoded = vec![0; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut encoded)?;
+
+        if H::digest(&encoded)[..] != hash[..] {
+            return Err(SnapshotError::HashMismatch(hash));
+        }
+        db.insert(hash, encoded);
+    }
+
+    Ok(root)
+}
+
+/// Read a record's hash, or return `None` if the reader ended cleanly before it.
+fn read_hash(reader: &mut impl Read, hash_len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut hash = vec![0; hash_len];
+    let mut filled = 0;
+    while filled < hash_len {
+        match reader.read(&mut hash[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => filled += len,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(Some(hash))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+    use sha3::Keccak256;
+
+    #[test]
+    fn checkpoints() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..=255u8 {
+            tree.insert(vec![i, i.wrapping_mul(7)], vec![i; 24]);
+        }
+
+        let mut writer = SnapshotWriter::new();
+        let mut base = Vec::new();
+        let base_root = writer.checkpoint(&mut tree, &mut base).unwrap();
+
+        tree.insert(vec![0x42, 0], vec![1; 24]);
+        tree.remove(vec![0x80, 0x80]);
+        let mut delta1 = Vec::new();
+        writer.checkpoint(&mut tree, &mut delta1).unwrap();
+
+        tree.insert(vec![0x43, 0], vec![2; 24]);
+        let mut delta2 = Vec::new();
+        let root = writer.checkpoint(&mut tree, &mut delta2).unwrap();
+
+        assert!(delta1.len() < base.len() / 4);
+        assert!(delta2.len() < base.len() / 4);
+
+        let mut loaded =
+            load_snapshot::<Keccak256, _>(&base[..], [&delta1[..], &delta2[..]]).unwrap();
+        assert_eq!(loaded.compute_hash(), &root);
+        assert_eq!(loaded.len(), tree.len());
+        assert_eq!(loaded.get(&vec![0x43, 0]), Some(&vec![2; 24]));
+
+        let mut loaded = load_snapshot::<Keccak256, &[u8]>(&base[..], []).unwrap();
+        assert_eq!(loaded.compute_hash(), &base_root);
+
+        assert!(matches!(
+            load_snapshot::<Keccak256, _>(&base[..], [&delta2[..]]),
+            Err(SnapshotError::BrokenChain { .. }),
+        ));
+        assert!(matches!(
+            load_snapshot::<Keccak256, _>(&delta1[..], [&delta2[..]]),
+            Err(SnapshotError::ExpectedFull),
+        ));
+
+        writer.reset();
+        let mut full = Vec::new();
+        writer.checkpoint(&mut tree, &mut full).unwrap();
+        let mut loaded = load_snapshot::<Keccak256, &[u8]>(&full[..], []).unwrap();
+        assert_eq!(loaded.compute_hash(), &root);
+
+        let last = full.len() - 1;
+        full[last] ^= 1;
+        assert!(matches!(
+            load_snapshot::<Keccak256, &[u8]>(&full[..], []),
+            Err(SnapshotError::HashMismatch(_)),
+        ));
+        assert!(matches!(
+            load_snapshot::<Keccak256, &[u8]>(&full[..full.len() - 1], []),
+            Err(SnapshotError::Io(_)),
+        ));
+    }
+}
@@ -0,0 +1,478 @@
+use crate::{
+    hashing::{NodeHash, NodeHashRef, NodeHasher, NodeWriter},
+    storage::{NodeRef, SharedSlab, ValueRef, ValuesStorage},
+    Encode,
+};
+use digest::{Digest, Output};
+use std::sync::Arc;
+
+/// Depth of the tree (in bits), which is also the key length.
+const DEPTH: usize = 256;
+
+/// A key within the sparse Merkle tree.
+pub type SparseKey = [u8; 32];
+
+/// Fixed-depth sparse Merkle tree with 256-bit keys.
+///
+/// Every possible key has its own leaf, and empty subtrees hash to precomputed per-level default
+/// hashes. A leaf's hash is the hash of its encoded value, while empty leaves are all zeros.
+///
+/// Unlike the Patricia Merkle tree, proofs always contain exactly one sibling per level, for both
+/// membership and non-membership.
+///
+/// Only the subtrees holding more than one value are stored as nodes, whose hashes are cached, so
+/// computing the root after a modification only rehashes the nodes along the modified paths (plus
+/// the levels between each single-value subtree and its value).
+#[derive(Clone, Debug)]
+pub struct SparseMerkleTree<V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    root: SparseChild,
+
+    nodes: SharedSlab<SparseNode<H>>,
+    values: ValuesStorage<SparseKey, V>,
+    /// Default hash of an empty subtree for every height (`0` for leaves, `DEPTH` for the root).
+    default_hashes: Arc<[Output<H>]>,
+
+    hash: (bool, Output<H>),
+}
+
+/// A subtree, as seen from its parent.
+#[derive(Clone, Copy, Debug)]
+enum SparseChild {
+    /// A subtree without values.
+    Empty,
+    /// A subtree with a single value.
+    Leaf(ValueRef),
+    /// A subtree with more than one value.
+    Branch(NodeRef),
+}
+
+#[derive(Clone, Debug)]
+struct SparseNode<H>
+where
+    H: Digest,
+{
+    /// The subtrees for a `0` and a `1` bit, respectively.
+    children: [SparseChild; 2],
+
+    hash: NodeHash<H>,
+}
+
+impl<V, H> SparseMerkleTree<V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    /// Create an empty tree.
+    pub fn new() -> Self {
+        let default_hashes = default_hashes::<H>();
+
+        Self {
+            root: SparseChild::Empty,
+            nodes: SharedSlab::new(),
+            values: ValuesStorage::new(),
+            hash: (true, default_hashes[DEPTH].clone()),
+            default_hashes: default_hashes.into(),
+        }
+    }
+
+    /// Return whether the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Return the number of values in the tree.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Retrieve a value from the tree given its key.
+    pub fn get(&self, key: &SparseKey) -> Option<&V> {
+        let mut child = self.root;
+        let mut depth = 0;
+        loop {
+            match child {
+                SparseChild::Empty => return None,
+                SparseChild::Leaf(value_ref) => {
+                    let (leaf_key, value) = &self.values[*value_ref];
+                    return (leaf_key == key).then_some(value);
+                }
+                SparseChild::Branch(node_ref) => {
+                    child = self.nodes[*node_ref].children[get_bit(key, depth) as usize];
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    /// Insert a value into the tree.
+    pub fn insert(&mut self, key: SparseKey, value: V) -> Option<V> {
+        self.hash.0 = false;
+
+        let (root, old_value) = self.insert_child(self.root, 0, key, value);
+        self.root = root;
+        old_value
+    }
+
+    /// Remove a value from the tree.
+    pub fn remove(&mut self, key: &SparseKey) -> Option<V> {
+        let (root, old_value) = self.remove_child(self.root, 0, key);
+        if old_value.is_some() {
+            self.root = root;
+            self.hash.0 = false;
+        }
+
+        old_value
+    }
+
+    /// Return the root hash of the tree (or recompute if needed).
+    pub fn compute_hash(&mut self) -> &Output<H> {
+        if !self.hash.0 {
+            self.hash.1 = self.child_hash(self.root, 0);
+            self.hash.0 = true;
+        }
+
+        &self.hash.1
+    }
+
+    /// Generate a proof of the value (or its absence) at `key`.
+    pub fn prove(&self, key: &SparseKey) -> SparseProof<H> {
+        let mut siblings = Vec::with_capacity(DEPTH);
+        let mut child = self.root;
+        for depth in 0..DEPTH {
+            let bit = get_bit(key, depth);
+            let sibling = match child {
+                SparseChild::Branch(node_ref) => {
+                    let children = &self.nodes[*node_ref].children;
+                    child = children[bit as usize];
+                    self.child_hash(children[!bit as usize], depth + 1)
+                }
+                // The subtree's only value diverges from `key` here.
+                SparseChild::Leaf(value_ref)
+                    if get_bit(&self.values[*value_ref].0, depth) != bit =>
+                {
+                    let (leaf_key, value) = &self.values[*value_ref];
+                    child = SparseChild::Empty;
+                    self.leaf_hash(leaf_key, value, depth + 1)
+                }
+                _ => self.default_hashes[DEPTH - depth - 1].clone(),
+            };
+            siblings.push(sibling);
+        }
+
+        SparseProof { siblings }
+    }
+
+    /// Insert a value into the subtree at `depth`, returning what replaces it.
+    fn insert_child(
+        &mut self,
+        child: SparseChild,
+        depth: usize,
+        key: SparseKey,
+        value: V,
+    ) -> (SparseChild, Option<V>) {
+        match child {
+            SparseChild::Empty => {
+                let value_ref = ValueRef::new(self.values.insert((key, value)));
+                (SparseChild::Leaf(value_ref), None)
+            }
+            SparseChild::Leaf(value_ref) if self.values[*value_ref].0 == key => {
+                let old_value = self.values.replace(*value_ref, value);
+                (child, old_value)
+            }
+            SparseChild::Leaf(value_ref) => {
+                // Move the existing value one level down, then insert next to it.
+                let mut children = [SparseChild::Empty; 2];
+                children[get_bit(&self.values[*value_ref].0, depth) as usize] = child;
+                let node_ref = NodeRef::new(self.nodes.insert(SparseNode {
+                    children,
+                    hash: NodeHash::default(),
+                }));
+
+                self.insert_child(SparseChild::Branch(node_ref), depth, key, value)
+            }
+            SparseChild::Branch(node_ref) => {
+                let bit = get_bit(&key, depth) as usize;
+                let next = self.nodes[*node_ref].children[bit];
+                let (next, old_value) = self.insert_child(next, depth + 1, key, value);
+
+                let node = &mut self.nodes[*node_ref];
+                node.children[bit] = next;
+                node.hash.mark_as_dirty();
+                (child, old_value)
+            }
+        }
+    }
+
+    /// Remove a value from the subtree at `depth`, returning what replaces it.
+    fn remove_child(
+        &mut self,
+        child: SparseChild,
+        depth: usize,
+        key: &SparseKey,
+    ) -> (SparseChild, Option<V>) {
+        match child {
+            SparseChild::Leaf(value_ref) if self.values[*value_ref].0 == *key => {
+                let (_, old_value) = self.values.remove(*value_ref);
+                (SparseChild::Empty, Some(old_value))
+            }
+            SparseChild::Empty | SparseChild::Leaf(_) => (child, None),
+            SparseChild::Branch(node_ref) => {
+                let bit = get_bit(key, depth) as usize;
+                let next = self.nodes[*node_ref].children[bit];
+                let (next, old_value) = self.remove_child(next, depth + 1, key);
+                if old_value.is_none() {
+                    return (child, None);
+                }
+
+                let node = &mut self.nodes[*node_ref];
+                node.children[bit] = next;
+                node.hash.mark_as_dirty();
+
+                // A single value is left, which replaces the node.
+                match node.children {
+                    [SparseChild::Empty, leaf @ SparseChild::Leaf(_)]
+                    | [leaf @ SparseChild::Leaf(_), SparseChild::Empty] => {
+                        self.nodes.remove(*node_ref);
+                        (leaf, old_value)
+                    }
+                    _ => (child, old_value),
+                }
+            }
+        }
+    }
+
+    /// Return the hash of the subtree at `depth`, computing (and caching) it if needed.
+    fn child_hash(&self, child: SparseChild, depth: usize) -> Output<H> {
+        match child {
+            SparseChild::Empty => self.default_hashes[DEPTH - depth].clone(),
+            SparseChild::Leaf(value_ref) => {
+                let (key, value) = &self.values[*value_ref];
+                self.leaf_hash(key, value, depth)
+            }
+            SparseChild::Branch(node_ref) => {
+                let node = &self.nodes[*node_ref];
+                if let Some(hash) = node.hash.extract_ref() {
+                    return to_output::<H>(hash);
+                }
+
+                let left = self.child_hash(node.children[0], depth + 1);
+                let right = self.child_hash(node.children[1], depth + 1);
+
+                let mut hasher = NodeHasher::new(&node.hash);
+                hasher.write_raw(&left);
+                hasher.write_raw(&right);
+                to_output::<H>(hasher.finalize())
+            }
+        }
+    }
+
+    /// Compute the hash of the subtree at `depth` whose only value is `value` at `key`.
+    fn leaf_hash(&self, key: &SparseKey, value: &V, depth: usize) -> Output<H> {
+        (depth..DEPTH)
+            .rev()
+            .fold(H::digest(value.encode()), |hash, depth| {
+                let sibling = &self.default_hashes[DEPTH - depth - 1];
+                if get_bit(key, depth) {
+                    H::new().chain_update(sibling).chain_update(hash).finalize()
+                } else {
+                    H::new().chain_update(hash).chain_update(sibling).finalize()
+                }
+            })
+    }
+}
+
+impl<V, H> Default for SparseMerkleTree<V, H>
+where
+    V: Encode,
+    H: Digest,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A proof of a value (or its absence) within a sparse Merkle tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SparseProof<H>
+where
+    H: Digest,
+{
+    /// The sibling hashes from the root down to the leaf.
+    pub siblings: Vec<Output<H>>,
+}
+
+impl<H> SparseProof<H>
+where
+    H: Digest,
+{
+    /// Check that `key` holds `value` (or nothing, if `None`) within the tree with the given root.
+    pub fn verify(&self, root: &[u8], key: &SparseKey, value: Option<&[u8]>) -> bool {
+        if self.siblings.len() != DEPTH {
+            return false;
+        }
+
+        let mut hash = match value {
+            Some(value) => H::digest(value),
+            None => Output::<H>::default(),
+        };
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            hash = if get_bit(key, depth) {
+                H::new().chain_update(sibling).chain_update(hash).finalize()
+            } else {
+                H::new().chain_update(hash).chain_update(sibling).finalize()
+            };
+        }
+
+        hash[..] == *root
+    }
+}
+
+fn default_hashes<H>() -> Vec<Output<H>>
+where
+    H: Digest,
+{
+    let mut hashes = Vec::with_capacity(DEPTH + 1);
+    hashes.push(Output::<H>::default());
+    for height in 0..DEPTH {
+        let hash = H::new()
+            .chain_update(&hashes[height])
+            .chain_update(&hashes[height])
+            .finalize();
+        hashes.push(hash);
+    }
+
+    hashes
+}
+
+/// Return a node's hash, which is never inlined since nodes hash two hashes.
+fn to_output<H>(hash: NodeHashRef<'_, H>) -> Output<H>
+where
+    H: Digest,
+{
+    let mut output = Output::<H>::default();
+    output.copy_from_slice(hash.as_ref());
+    output
+}
+
+/// Return the bit at `index`, starting from the most significant one.
+fn get_bit(key: &SparseKey, index: usize) -> bool {
+    (key[index >> 3] >> (7 - (index & 7))) & 1 != 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::{
+        collection::{btree_map, vec},
+        prelude::*,
+    };
+    use sha3::Keccak256;
+
+    type Tree = SparseMerkleTree<Vec<u8>, Keccak256>;
+
+    fn key(x: u8) -> SparseKey {
+        let mut key = [0; 32];
+        key[0] = x;
+        key[31] = x;
+        key
+    }
+
+    #[test]
+    fn empty_root() {
+        let mut tree = Tree::new();
+        let root = *tree.compute_hash();
+
+        assert_eq!(root, default_hashes::<Keccak256>()[DEPTH]);
+
+        tree.insert(key(1), vec![0x01]);
+        assert_ne!(tree.compute_hash(), &root);
+
+        tree.remove(&key(1));
+        assert_eq!(tree.compute_hash(), &root);
+    }
+
+    #[test]
+    fn membership_proof() {
+        let mut tree = Tree::new();
+        tree.insert(key(0x00), vec![0x00]);
+        tree.insert(key(0x80), vec![0x80]);
+        tree.insert(key(0xFF), vec![0xFF]);
+
+        let root = tree.compute_hash().to_vec();
+        let proof = tree.prove(&key(0x80));
+
+        assert_eq!(proof.siblings.len(), DEPTH);
+        assert!(proof.verify(&root, &key(0x80), Some(&[0x80])));
+        assert!(!proof.verify(&root, &key(0x80), Some(&[0x81])));
+        assert!(!proof.verify(&root, &key(0x80), None));
+        assert!(!proof.verify(&root, &key(0x81), Some(&[0x80])));
+    }
+
+    #[test]
+    fn non_membership_proof() {
+        let mut tree = Tree::new();
+        tree.insert(key(0x00), vec![0x00]);
+        tree.insert(key(0xFF), vec![0xFF]);
+
+        let root = tree.compute_hash().to_vec();
+        let proof = tree.prove(&key(0x01));
+
+        assert!(proof.verify(&root, &key(0x01), None));
+        assert!(!proof.verify(&root, &key(0x01), Some(&[0x01])));
+    }
+
+    proptest! {
+        // Every leaf costs a hash per level, which makes these tests slow.
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn proptest_order_independent(data in btree_map(any::<SparseKey>(), vec(any::<u8>(), 1..32), 1..16)) {
+            let mut forward = Tree::new();
+            let mut backward = Tree::new();
+
+            for (key, value) in &data {
+                forward.insert(*key, value.clone());
+            }
+            for (key, value) in data.iter().rev() {
+                backward.insert(*key, value.clone());
+            }
+
+            let root = forward.compute_hash().to_vec();
+            prop_assert_eq!(&root[..], &backward.compute_hash()[..]);
+
+            let (key, value) = data.iter().next().unwrap();
+            prop_assert!(forward.prove(key).verify(&root, key, Some(value)));
+        }
+
+        #[test]
+        fn proptest_remove(data in btree_map(any::<SparseKey>(), vec(any::<u8>(), 1..32), 2..16)) {
+            let mut tree = Tree::new();
+            let mut expected = Tree::new();
+
+            for (index, (key, value)) in data.iter().enumerate() {
+                tree.insert(*key, value.clone());
+                if index % 2 == 0 {
+                    expected.insert(*key, value.clone());
+                }
+            }
+            tree.compute_hash();
+            for (key, _) in data.iter().skip(1).step_by(2) {
+                prop_assert!(tree.remove(key).is_some());
+            }
+
+            let root = tree.compute_hash().to_vec();
+            prop_assert_eq!(&root[..], &expected.compute_hash()[..]);
+            prop_assert_eq!(tree.len(), expected.len());
+
+            for (index, (key, value)) in data.iter().enumerate() {
+                let value = (index % 2 == 0).then_some(value);
+                prop_assert_eq!(tree.get(key), value);
+                prop_assert!(tree.prove(key).verify(&root, key, value.map(Vec::as_slice)));
+            }
+        }
+    }
+}
@@ -1,11 +1,538 @@
-use crate::node::Node;
-use slab::Slab;
-use std::ops::Deref;
+use crate::{
+    intern::{Interner, ValueInterner},
+    node::Node,
+};
+use std::{
+    fmt::{self, Debug},
+    hash::Hash,
+    ops::{Deref, Index, IndexMut},
+    sync::{Arc, OnceLock},
+};
 
 const INVALID_REF: usize = usize::MAX;
 
-pub type NodesStorage<P, V, H> = Slab<Node<P, V, H>>;
-pub type ValuesStorage<P, V> = Slab<(P, V)>;
+pub type NodesStorage<P, V, H> = SharedSlab<Node<P, V, H>>;
+
+/// Number of index bits consumed by each level of a [`SharedSlab`].
+const CHUNK_BITS: u32 = 5;
+const CHUNK_LEN: usize = 1 << CHUNK_BITS;
+const CHUNK_MASK: usize = CHUNK_LEN - 1;
+
+/// A slab whose clones share their entries until modified.
+///
+/// Entries are stored in a tree of fixed-size chunks, so cloning a slab is O(1) and modifying an
+/// entry of a shared one only copies the chunks leading to it (O(log n)). Other chunks remain
+/// shared with the clones.
+///
+/// Keys are reused like the `slab` crate does: the last removed one is the next one to be inserted.
+pub struct SharedSlab<T> {
+    root: Arc<Chunk<T>>,
+    /// Number of levels of branch chunks above the leaf ones.
+    depth: u32,
+    /// Number of entries, either occupied or vacant.
+    entries: usize,
+    /// Number of occupied entries.
+    len: usize,
+    /// Key of the last removed entry which is still vacant, or `entries` if there's none.
+    next_vacant: usize,
+    /// How to copy a leaf chunk once it has to be unshared. Only set when cloning, so that slabs
+    /// which are never cloned don't require `T: Clone`.
+    unshare: OnceLock<Unshare<T>>,
+}
+
+enum Chunk<T> {
+    Branch(Vec<Arc<Chunk<T>>>),
+    Leaf(Vec<Entry<T>>),
+}
+
+type Unshare<T> = fn(&[Entry<T>]) -> Vec<Entry<T>>;
+
+#[derive(Clone)]
+enum Entry<T> {
+    /// A removed entry, along with the key of the previously removed one.
+    Vacant(usize),
+    Occupied(T),
+}
+
+impl<T> SharedSlab<T> {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(Chunk::Leaf(Vec::new())),
+            depth: 0,
+            entries: 0,
+            len: 0,
+            next_vacant: 0,
+            unshare: OnceLock::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of entries, including vacant ones.
+    pub fn capacity(&self) -> usize {
+        self.entries
+    }
+
+    pub fn get(&self, key: usize) -> Option<&T> {
+        match self.entry(key)? {
+            Entry::Occupied(value) => Some(value),
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    /// Return a mutable reference to the value at `key`, copying the chunks leading to it first
+    /// if they're shared.
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        match self.entry_mut(key)? {
+            Entry::Occupied(value) => Some(value),
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Insert a value and return its key.
+    pub fn insert(&mut self, value: T) -> usize {
+        let key = self.next_vacant;
+        if key == self.entries {
+            self.push(Entry::Occupied(value));
+            self.next_vacant = self.entries;
+        } else {
+            let entry = self.entry_mut(key).expect("inconsistent slab structure");
+            match std::mem::replace(entry, Entry::Occupied(value)) {
+                Entry::Vacant(next_vacant) => self.next_vacant = next_vacant,
+                Entry::Occupied(_) => panic!("inconsistent slab structure"),
+            }
+        }
+
+        self.len += 1;
+        key
+    }
+
+    /// Remove and return the value at `key`, if any.
+    pub fn try_remove(&mut self, key: usize) -> Option<T> {
+        let next_vacant = self.next_vacant;
+        let entry = self.entry_mut(key)?;
+        match std::mem::replace(entry, Entry::Vacant(next_vacant)) {
+            Entry::Occupied(value) => {
+                self.len -= 1;
+                self.next_vacant = key;
+                Some(value)
+            }
+            vacant => {
+                *entry = vacant;
+                None
+            }
+        }
+    }
+
+    /// Remove and return the value at `key`, panicking if there's none.
+    pub fn remove(&mut self, key: usize) -> T {
+        self.try_remove(key).expect("invalid key")
+    }
+
+    /// Iterate over the values along with their keys, in ascending key order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        let mut stack = vec![&*self.root];
+        let leaves = std::iter::from_fn(move || loop {
+            match stack.pop()? {
+                Chunk::Branch(children) => stack.extend(children.iter().rev().map(|x| &**x)),
+                Chunk::Leaf(entries) => return Some(entries),
+            }
+        });
+
+        leaves
+            .flatten()
+            .enumerate()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Occupied(value) => Some((key, value)),
+                Entry::Vacant(_) => None,
+            })
+    }
+
+    /// Return whether both slabs share the given entry's chunk.
+    #[cfg(test)]
+    pub fn shares_entry(&self, other: &Self, key: usize) -> bool {
+        let leaf = |slab: &Self| {
+            let mut chunk = &slab.root;
+            for level in (1..=slab.depth).rev() {
+                let Chunk::Branch(children) = &**chunk else {
+                    return None;
+                };
+                chunk = children.get((key >> (level * CHUNK_BITS)) & CHUNK_MASK)?;
+            }
+            Some(Arc::as_ptr(chunk))
+        };
+
+        leaf(self).is_some() && leaf(self) == leaf(other)
+    }
+
+    fn entry(&self, key: usize) -> Option<&Entry<T>> {
+        if key >= self.entries {
+            return None;
+        }
+
+        let mut chunk = &*self.root;
+        for level in (1..=self.depth).rev() {
+            let Chunk::Branch(children) = chunk else {
+                return None;
+            };
+            chunk = children.get((key >> (level * CHUNK_BITS)) & CHUNK_MASK)?;
+        }
+
+        match chunk {
+            Chunk::Leaf(entries) => entries.get(key & CHUNK_MASK),
+            Chunk::Branch(_) => None,
+        }
+    }
+
+    fn entry_mut(&mut self, key: usize) -> Option<&mut Entry<T>> {
+        if key >= self.entries {
+            return None;
+        }
+
+        let unshare = self.unshare.get().copied();
+        let mut chunk = make_unique(&mut self.root, unshare);
+        for level in (1..=self.depth).rev() {
+            let Chunk::Branch(children) = chunk else {
+                return None;
+            };
+            let child = children.get_mut((key >> (level * CHUNK_BITS)) & CHUNK_MASK)?;
+            chunk = make_unique(child, unshare);
+        }
+
+        match chunk {
+            Chunk::Leaf(entries) => entries.get_mut(key & CHUNK_MASK),
+            Chunk::Branch(_) => None,
+        }
+    }
+
+    /// Append an entry, adding a level on top when every chunk is full.
+    fn push(&mut self, entry: Entry<T>) {
+        let key = self.entries;
+        if key == CHUNK_LEN << (self.depth * CHUNK_BITS) {
+            let root = std::mem::replace(&mut self.root, Arc::new(Chunk::Branch(Vec::new())));
+            self.root = Arc::new(Chunk::Branch(vec![root]));
+            self.depth += 1;
+        }
+
+        let unshare = self.unshare.get().copied();
+        let mut chunk = make_unique(&mut self.root, unshare);
+        for level in (1..=self.depth).rev() {
+            let Chunk::Branch(children) = chunk else {
+                panic!("inconsistent slab structure");
+            };
+
+            let index = (key >> (level * CHUNK_BITS)) & CHUNK_MASK;
+            if index == children.len() {
+                children.push(Arc::new(match level {
+                    1 => Chunk::Leaf(Vec::with_capacity(CHUNK_LEN)),
+                    _ => Chunk::Branch(Vec::with_capacity(CHUNK_LEN)),
+                }));
+            }
+            chunk = make_unique(&mut children[index], unshare);
+        }
+
+        match chunk {
+            Chunk::Leaf(entries) => entries.push(entry),
+            Chunk::Branch(_) => panic!("inconsistent slab structure"),
+        }
+        self.entries += 1;
+    }
+}
+
+/// Return a mutable reference to the chunk, copying it first if it's shared. Only the copied
+/// chunk's direct children become shared.
+fn make_unique<T>(chunk: &mut Arc<Chunk<T>>, unshare: Option<Unshare<T>>) -> &mut Chunk<T> {
+    if Arc::get_mut(chunk).is_none() {
+        *chunk = Arc::new(match &**chunk {
+            Chunk::Branch(children) => Chunk::Branch(children.clone()),
+            Chunk::Leaf(entries) => {
+                Chunk::Leaf(unshare.expect("slabs are only shared by cloning")(entries))
+            }
+        });
+    }
+
+    Arc::get_mut(chunk).expect("the chunk has just been unshared")
+}
+
+impl<T> Clone for SharedSlab<T>
+where
+    T: Clone,
+{
+    fn clone(&self) -> Self {
+        let unshare = *self.unshare.get_or_init(|| <[Entry<T>]>::to_vec);
+        Self {
+            root: self.root.clone(),
+            depth: self.depth,
+            entries: self.entries,
+            len: self.len,
+            next_vacant: self.next_vacant,
+            unshare: OnceLock::from(unshare),
+        }
+    }
+}
+
+impl<T> Debug for SharedSlab<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<T> Default for SharedSlab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Index<usize> for SharedSlab<T> {
+    type Output = T;
+
+    fn index(&self, key: usize) -> &Self::Output {
+        self.get(key).expect("invalid key")
+    }
+}
+
+impl<T> IndexMut<usize> for SharedSlab<T> {
+    fn index_mut(&mut self, key: usize) -> &mut Self::Output {
+        self.get_mut(key).expect("invalid key")
+    }
+}
+
+/// Storage for a tree's paths and values.
+///
+/// Once interning is enabled, equal values are stored once and shared by every entry holding them,
+/// along with how many do. Values modified in place stop being shared.
+pub struct ValuesStorage<P, V> {
+    slab: SharedSlab<(P, StoredValue<V>)>,
+    interner: Option<Box<dyn Interner<V>>>,
+}
+
+/// A stored value, which may be shared with other entries.
+#[derive(Clone, Debug)]
+pub enum StoredValue<V> {
+    Owned(V),
+    Interned(Arc<V>),
+}
+
+impl<P, V> ValuesStorage<P, V> {
+    pub fn new() -> Self {
+        Self {
+            slab: SharedSlab::new(),
+            interner: None,
+        }
+    }
+
+    /// Store equal values once from now on, including those already stored.
+    pub fn enable_interning(&mut self)
+    where
+        V: Clone + Eq + Hash + Send + Sync + 'static,
+    {
+        if self.interner.is_some() {
+            return;
+        }
+
+        self.interner = Some(Box::<ValueInterner<V>>::default());
+        let keys = self.slab.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        for key in keys {
+            // The removed key is the next one to be inserted.
+            let (path, value) = self.remove(key);
+            let new_key = self.insert((path, value));
+            debug_assert_eq!(new_key, key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slab.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slab.is_empty()
+    }
+
+    /// Return the number of entries, including vacant ones.
+    pub fn capacity(&self) -> usize {
+        self.slab.capacity()
+    }
+
+    /// Return the number of distinct values shared by the entries, or zero if interning is
+    /// disabled.
+    #[cfg(test)]
+    pub fn interned_len(&self) -> usize {
+        self.interner.as_ref().map_or(0, |x| x.len())
+    }
+
+    /// Calculate approximated memory usage (both used and allocated) of the entries, counting
+    /// shared values once.
+    pub fn memory_usage(&self, entry_size: usize) -> (usize, usize) {
+        let (shared_consumed, shared_reserved) =
+            self.interner.as_ref().map_or((0, 0), |x| x.memory_usage());
+
+        (
+            entry_size * self.len() + shared_consumed,
+            entry_size * self.capacity() + shared_reserved,
+        )
+    }
+
+    pub fn get(&self, key: usize) -> Option<&(P, StoredValue<V>)> {
+        self.slab.get(key)
+    }
+
+    /// Return the path and a mutable reference to the value at `key`, which stops being shared.
+    pub fn get_mut(&mut self, key: usize) -> Option<(&P, &mut V)> {
+        let (path, value) = self.slab.get_mut(key)?;
+        if let StoredValue::Interned(shared) = value {
+            let interner = self
+                .interner
+                .as_mut()
+                .expect("interned without an interner");
+            *value = StoredValue::Owned(interner.release(shared.clone()));
+        }
+
+        match value {
+            StoredValue::Owned(value) => Some((path, value)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Replace the value at `key`, returning the previous one.
+    pub fn replace(&mut self, key: usize, value: V) -> Option<V> {
+        if !self.slab.contains(key) {
+            return None;
+        }
+
+        let value = self.store(value);
+        let (_, old_value) = self.slab.get_mut(key).expect("checked above");
+        let old_value = std::mem::replace(old_value, value);
+        Some(self.unstore(old_value))
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        self.slab.contains(key)
+    }
+
+    /// Insert a path and value and return its key.
+    pub fn insert(&mut self, (path, value): (P, V)) -> usize {
+        let value = self.store(value);
+        self.slab.insert((path, value))
+    }
+
+    /// Remove and return the path and value at `key`, if any.
+    pub fn try_remove(&mut self, key: usize) -> Option<(P, V)> {
+        let (path, value) = self.slab.try_remove(key)?;
+        Some((path, self.unstore(value)))
+    }
+
+    /// Remove and return the path and value at `key`, panicking if there's none.
+    pub fn remove(&mut self, key: usize) -> (P, V) {
+        self.try_remove(key).expect("invalid key")
+    }
+
+    /// Return an empty storage which takes over this one's interned values, so that entries can
+    /// be moved into it with [`move_entry`](Self::move_entry).
+    pub fn split_off_interner(&mut self) -> Self {
+        Self {
+            slab: SharedSlab::new(),
+            interner: self.interner.take(),
+        }
+    }
+
+    /// Move the entry at `key` from `other` without copying its value, returning its new key.
+    /// Interned values must be shared through this storage (see
+    /// [`split_off_interner`](Self::split_off_interner)).
+    pub fn move_entry(&mut self, other: &mut Self, key: usize) -> Option<usize> {
+        let entry = other.slab.try_remove(key)?;
+        Some(self.slab.insert(entry))
+    }
+
+    /// Return whether both storages share the given entry's chunk.
+    #[cfg(test)]
+    pub fn shares_entry(&self, other: &Self, key: usize) -> bool {
+        self.slab.shares_entry(&other.slab, key)
+    }
+
+    /// Iterate over the paths and values along with their keys, in ascending key order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &(P, StoredValue<V>))> {
+        self.slab.iter()
+    }
+
+    fn store(&mut self, value: V) -> StoredValue<V> {
+        match &mut self.interner {
+            Some(interner) => StoredValue::Interned(interner.intern(value)),
+            None => StoredValue::Owned(value),
+        }
+    }
+
+    fn unstore(&mut self, value: StoredValue<V>) -> V {
+        match value {
+            StoredValue::Owned(value) => value,
+            StoredValue::Interned(shared) => self
+                .interner
+                .as_mut()
+                .expect("interned without an interner")
+                .release(shared),
+        }
+    }
+}
+
+impl<P, V> Clone for ValuesStorage<P, V>
+where
+    P: Clone,
+    V: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            slab: self.slab.clone(),
+            interner: self.interner.as_ref().map(|x| x.boxed_clone()),
+        }
+    }
+}
+
+impl<P, V> Debug for ValuesStorage<P, V>
+where
+    P: Debug,
+    V: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.slab.fmt(f)
+    }
+}
+
+impl<P, V> Default for ValuesStorage<P, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P, V> Index<usize> for ValuesStorage<P, V> {
+    type Output = (P, StoredValue<V>);
+
+    fn index(&self, key: usize) -> &Self::Output {
+        self.get(key).expect("invalid key")
+    }
+}
+
+impl<V> Deref for StoredValue<V> {
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            StoredValue::Owned(value) => value,
+            StoredValue::Interned(shared) => shared,
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 #[repr(transparent)]
@@ -64,3 +591,34 @@ impl Deref for ValueRef {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+    use slab::Slab;
+
+    proptest! {
+        #[test]
+        fn proptest_slab(ops in vec(prop_oneof![Just(None), (0..2048usize).prop_map(Some)], 0..4096)) {
+            // Snapshots are cloned along the way, which mustn't see later changes.
+            let (mut shared, mut slab) = (SharedSlab::new(), Slab::new());
+            let mut snapshots = Vec::new();
+            for (i, op) in ops.into_iter().enumerate() {
+                match op {
+                    None => prop_assert_eq!(shared.insert(i), slab.insert(i)),
+                    Some(key) => prop_assert_eq!(shared.try_remove(key), slab.try_remove(key)),
+                }
+                if i % 512 == 0 {
+                    snapshots.push((shared.clone(), slab.clone()));
+                }
+            }
+            snapshots.push((shared, slab));
+
+            for (shared, slab) in snapshots {
+                prop_assert_eq!(shared.len(), slab.len());
+                prop_assert!(shared.iter().eq(slab.iter()));
+            }
+        }
+    }
+}
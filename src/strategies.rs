@@ -0,0 +1,126 @@
+//! Proptest strategies for generating paths, values and operation sequences.
+//!
+//! Used by the crate's own property tests, and exported (with the `proptest` feature) so that
+//! crates embedding the tree can reuse them.
+
+use crate::TreeOp;
+use proptest::{
+    collection::{vec, SizeRange},
+    prelude::*,
+    sample::select,
+};
+
+/// Random paths whose length (in bytes) is within `len`.
+pub fn paths(len: impl Into<SizeRange>) -> impl Strategy<Value = Vec<u8>> + Clone {
+    vec(any::<u8>(), len)
+}
+
+/// Paths made of nibbles taken from `alphabet`, with a length (in bytes) within `len`.
+///
+/// A small alphabet makes paths share prefixes which diverge at any nibble (not only at byte
+/// boundaries), exercising node splits and collapses at both nibble alignments.
+pub fn nibble_paths(
+    alphabet: &[u8],
+    len: impl Into<SizeRange>,
+) -> impl Strategy<Value = Vec<u8>> + Clone {
+    assert!(
+        !alphabet.is_empty() && alphabet.iter().all(|x| *x < 16),
+        "the alphabet must contain only nibbles",
+    );
+
+    vec((select(alphabet.to_vec()), select(alphabet.to_vec())), len)
+        .prop_map(|x| x.into_iter().map(|(hi, lo)| (hi << 4) | lo).collect())
+}
+
+/// Sets of paths grouped in `clusters` random prefixes of `prefix_len` bytes, each followed by a
+/// random suffix whose length is within `suffix_len`.
+///
+/// Paths within a cluster share a long prefix, which results in deep extension nodes.
+pub fn clustered_paths(
+    clusters: usize,
+    prefix_len: usize,
+    suffix_len: impl Into<SizeRange>,
+    count: impl Into<SizeRange>,
+) -> impl Strategy<Value = Vec<Vec<u8>>> {
+    let suffix_len = suffix_len.into();
+    let count = count.into();
+
+    vec(vec(any::<u8>(), prefix_len), clusters.max(1)).prop_flat_map(move |prefixes| {
+        vec(
+            (select(prefixes), paths(suffix_len.clone())).prop_map(|(mut prefix, suffix)| {
+                prefix.extend(suffix);
+                prefix
+            }),
+            count.clone(),
+        )
+    })
+}
+
+/// Random non-empty values of up to 64 bytes, which covers both inlined and hashed leaves.
+pub fn values() -> impl Strategy<Value = Vec<u8>> + Clone {
+    vec(any::<u8>(), 1..64)
+}
+
+/// A single tree operation on paths generated by `paths`.
+pub fn tree_op<S>(paths: S) -> impl Strategy<Value = TreeOp>
+where
+    S: Strategy<Value = Vec<u8>> + Clone,
+{
+    prop_oneof![
+        4 => (paths.clone(), values()).prop_map(|(p, v)| TreeOp::Insert(p, v)),
+        3 => paths.clone().prop_map(TreeOp::Remove),
+        1 => paths.prop_map(TreeOp::Get),
+        1 => Just(TreeOp::ComputeHash),
+    ]
+}
+
+/// A sequence of tree operations on paths generated by `paths`, with a length within `len`.
+///
+/// Meant to be fed to [`apply_ops`](crate::apply_ops).
+pub fn tree_ops<S>(paths: S, len: impl Into<SizeRange>) -> impl Strategy<Value = Vec<TreeOp>>
+where
+    S: Strategy<Value = Vec<u8>> + Clone,
+{
+    vec(tree_op(paths), len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{apply_ops, pmt_tree};
+    use sha3::Keccak256;
+
+    proptest! {
+        #[test]
+        fn proptest_nibble_paths(path in nibble_paths(&[0x0, 0xF], 1..4)) {
+            prop_assert!(path.iter().all(|x| [0x00, 0x0F, 0xF0, 0xFF].contains(x)));
+        }
+
+        #[test]
+        fn proptest_clustered_paths(paths in clustered_paths(2, 4, 0..4, 8)) {
+            let mut prefixes = paths.iter().map(|x| &x[..4]).collect::<Vec<_>>();
+            prefixes.sort();
+            prefixes.dedup();
+
+            prop_assert_eq!(paths.len(), 8);
+            prop_assert!(prefixes.len() <= 2);
+        }
+
+        #[test]
+        fn proptest_clustered_get(paths in clustered_paths(3, 8, 1..4, 1..32)) {
+            let mut tree = pmt_tree!(Vec<u8>);
+            for path in &paths {
+                tree.insert(path.clone(), path.clone());
+            }
+
+            for path in &paths {
+                prop_assert_eq!(tree.get(path), Some(path));
+            }
+        }
+
+        #[test]
+        fn proptest_apply_ops_random(ops in tree_ops(paths(0..8), 0..64)) {
+            apply_ops::<Keccak256>(ops);
+        }
+    }
+}
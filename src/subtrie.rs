@@ -0,0 +1,149 @@
+use crate::{
+    codec::Decode,
+    namespace::{Namespace, NamespaceIter},
+    DecodeError, Encode,
+};
+use digest::Digest;
+use std::marker::PhantomData;
+
+/// A typed view over the values of a tree under a prefix.
+///
+/// Keys and values are stored using their encoding, which allows a single tree (and therefore a
+/// single root hash) to host many logical tables of different types, each one under its own
+/// prefix.
+#[derive(Debug)]
+pub struct SubTrie<'a, K, V, H>
+where
+    K: Encode,
+    V: Encode + Decode,
+    H: Digest,
+{
+    namespace: Namespace<'a, Vec<u8>, H>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<'a, K, V, H> SubTrie<'a, K, V, H>
+where
+    K: Encode,
+    V: Encode + Decode,
+    H: Digest,
+{
+    pub(crate) fn new(namespace: Namespace<'a, Vec<u8>, H>) -> Self {
+        Self {
+            namespace,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Return the prefix of the view.
+    pub fn prefix(&self) -> &[u8] {
+        self.namespace.prefix()
+    }
+
+    /// Retrieve and decode a value given its key.
+    pub fn get(&self, key: &K) -> Result<Option<V>, DecodeError> {
+        self.namespace
+            .get(key.encode().as_ref())
+            .map(|x| V::decode(x))
+            .transpose()
+    }
+
+    /// Insert a value, returning the previous one (if any).
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<Option<V>, DecodeError> {
+        self.namespace
+            .insert(key.encode().as_ref(), value.encode().into_owned())
+            .map(|x| V::decode(&x))
+            .transpose()
+    }
+
+    /// Remove a value, returning it (if any).
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>, DecodeError> {
+        self.namespace
+            .remove(key.encode().as_ref())
+            .map(|x| V::decode(&x))
+            .transpose()
+    }
+
+    /// Iterate over the decoded entries of the view, in ascending encoded key order.
+    pub fn iter(&self) -> SubTrieIter<'_, K, V, H>
+    where
+        K: Decode,
+    {
+        SubTrieIter {
+            inner: self.namespace.scan(&[]),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Remove every value from the view, returning how many were removed.
+    pub fn clear(&mut self) -> usize {
+        self.namespace.clear()
+    }
+}
+
+/// Iterator over the decoded entries of a [`SubTrie`].
+pub struct SubTrieIter<'a, K, V, H>
+where
+    K: Decode,
+    V: Decode,
+    H: Digest,
+{
+    inner: NamespaceIter<'a, Vec<u8>, H>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<'a, K, V, H> Iterator for SubTrieIter<'a, K, V, H>
+where
+    K: Decode,
+    V: Decode,
+    H: Digest,
+{
+    type Item = Result<(K, V), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.inner.next()?;
+        Some(K::decode(key).and_then(|key| Ok((key, V::decode(value)?))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{pmt_tree, DecodeError};
+
+    #[test]
+    fn sub_trie() {
+        let mut tree = pmt_tree!(Vec<u8>);
+
+        let mut balances = tree.sub_trie::<String, [u8; 8]>(b"balances:");
+        assert_eq!(
+            balances.insert(&"alice".into(), &1u64.to_be_bytes()),
+            Ok(None)
+        );
+        assert_eq!(
+            balances.insert(&"bob".into(), &2u64.to_be_bytes()),
+            Ok(None)
+        );
+        assert_eq!(
+            balances.insert(&"bob".into(), &3u64.to_be_bytes()),
+            Ok(Some(2u64.to_be_bytes()))
+        );
+
+        let mut names = tree.sub_trie::<[u8; 2], String>(b"names:");
+        assert_eq!(names.insert(&[0, 1], &"alice".into()), Ok(None));
+        assert_eq!(names.get(&[0, 1]), Ok(Some("alice".into())));
+        assert_eq!(names.get(&[0, 2]), Ok(None));
+
+        let balances = tree.sub_trie::<String, [u8; 8]>(b"balances:");
+        assert_eq!(
+            balances.iter().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![
+                ("alice".into(), 1u64.to_be_bytes()),
+                ("bob".into(), 3u64.to_be_bytes()),
+            ])
+        );
+        assert_eq!(tree.len(), 3);
+
+        let names = tree.sub_trie::<[u8; 2], [u8; 8]>(b"names:");
+        assert_eq!(names.get(&[0, 1]), Err(DecodeError::InvalidValue));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{nibble::NibbleSlice, node::Node, Encode, PatriciaMerkleTree};
+use digest::Digest;
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+/// Number of bytes of a path included in a [`SlowOpEvent`], so that events can be logged without
+/// leaking whole keys.
+pub const SLOW_OP_KEY_PREFIX_LEN: usize = 8;
+
+/// An operation reported by [`SlowOpEvent`]s.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    Get,
+    Insert,
+    Remove,
+}
+
+/// An operation which took longer than the configured threshold.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SlowOpEvent {
+    pub operation: Operation,
+    /// Up to [`SLOW_OP_KEY_PREFIX_LEN`] bytes of the encoded path operated on.
+    pub key_prefix: Vec<u8>,
+    pub duration: Duration,
+    /// Number of nodes along the path, once the operation finished.
+    pub nodes_visited: usize,
+    /// Number of nodes read from a node database (always zero for in-memory trees).
+    pub backend_reads: usize,
+}
+
+impl SlowOpEvent {
+    pub(crate) fn new(
+        operation: Operation,
+        path: &[u8],
+        duration: Duration,
+        nodes_visited: usize,
+        backend_reads: usize,
+    ) -> Self {
+        Self {
+            operation,
+            key_prefix: path[..path.len().min(SLOW_OP_KEY_PREFIX_LEN)].to_vec(),
+            duration,
+            nodes_visited,
+            backend_reads,
+        }
+    }
+}
+
+/// The subscriber to slow operations, along with the threshold above which they're reported.
+///
+/// Clones of a tree are independent from it, so they start without a subscriber.
+#[derive(Debug, Default)]
+pub(crate) struct SlowOps(Option<(Duration, Sender<SlowOpEvent>)>);
+
+impl Clone for SlowOps {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl SlowOps {
+    pub fn enable(&mut self, threshold: Duration) -> Receiver<SlowOpEvent> {
+        let (sender, receiver) = channel();
+        self.0 = Some((threshold, sender));
+        receiver
+    }
+
+    /// Start timing an operation, if there's a subscriber.
+    pub fn start(&self) -> Option<Instant> {
+        self.0.as_ref().map(|_| Instant::now())
+    }
+
+    /// Report the operation started at `start` if it took at least the threshold, building the event (given its
+    /// duration) only then.
+    pub fn finish(&self, start: Option<Instant>, event: impl FnOnce(Duration) -> SlowOpEvent) {
+        let (Some(start), Some((threshold, sender))) = (start, &self.0) else {
+            return;
+        };
+
+        let duration = start.elapsed();
+        if duration >= *threshold {
+            // There's nothing to do if the subscriber is gone.
+            sender.send(event(duration)).ok();
+        }
+    }
+}
+
+/// Return the number of nodes along an encoded path, from the root down to where it ends or
+/// diverges from the tree.
+pub(crate) fn path_depth<P, V, H>(tree: &PatriciaMerkleTree<P, V, H>, path: &[u8]) -> usize
+where
+    P: Encode,
+    V: Encode,
+    H: Digest,
+{
+    let mut path = NibbleSlice::new(path);
+    let mut node_ref = tree.root_ref;
+    let mut depth = 0;
+    while let Some(node) = tree.nodes.get(*node_ref) {
+        depth += 1;
+        node_ref = match node {
+            Node::Branch(branch_node) => match path.next() {
+                Some(nibble) => branch_node.choices[nibble as usize],
+                None => break,
+            },
+            Node::Extension(extension_node) => {
+                if !path.skip_prefix(&extension_node.prefix) {
+                    break;
+                }
+
+                extension_node.child_ref
+            }
+            Node::Leaf(_) | Node::Stub(_) => break,
+        };
+    }
+
+    depth
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{pmt_tree, DbTrie, MemoryDb};
+    use sha3::Keccak256;
+
+    #[test]
+    fn slow_ops() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let receiver = tree.report_slow_ops(Duration::ZERO);
+
+        for i in 0..16u8 {
+            tree.insert(vec![0x12, 0x34, i], vec![i]);
+        }
+        tree.get(&vec![0x12, 0x34, 0x05]);
+        tree.remove(vec![0xFF; 16]);
+
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 18);
+        assert!(events[..16]
+            .iter()
+            .all(|x| x.operation == Operation::Insert));
+
+        // Root extension, branch and leaf.
+        let get = &events[16];
+        assert_eq!(get.operation, Operation::Get);
+        assert_eq!(get.key_prefix, [0x12, 0x34, 0x05]);
+        assert_eq!((get.nodes_visited, get.backend_reads), (3, 0));
+
+        let remove = &events[17];
+        assert_eq!(remove.operation, Operation::Remove);
+        assert_eq!(remove.key_prefix, [0xFF; SLOW_OP_KEY_PREFIX_LEN]);
+        assert_eq!(remove.nodes_visited, 1);
+
+        let receiver = tree.report_slow_ops(Duration::from_secs(3600));
+        tree.get(&vec![0x12]);
+        assert_eq!(receiver.try_recv().ok(), None);
+        assert_eq!(tree.clone().slow_ops.start(), None);
+
+        let mut db = MemoryDb::new();
+        let root = tree.commit(&mut db);
+        let mut trie = DbTrie::<_, Keccak256>::new(&db, root);
+        let receiver = trie.report_slow_ops(Duration::ZERO);
+        assert_eq!(trie.get(&[0x12, 0x34, 0x05]), Ok(Some(vec![0x05])));
+        let get = receiver.try_recv().unwrap();
+        // The leaf is inlined within the branch.
+        assert_eq!((get.nodes_visited, get.backend_reads), (3, 2));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::PatriciaMerkleTree;
+use digest::Digest;
+use std::{
+    collections::HashSet,
+    ops::{Bound, RangeBounds},
+};
+
+/// Builds random trees which only depend on a seed, for deterministic benchmarks and regression
+/// tests.
+///
+/// By default, paths have between 16 and 64 bytes and values have 32, as in the crate's own
+/// benchmarks. Paths and values are uniformly random bytes, so paths only share short prefixes
+/// and the tree is mostly made of branches near the root and leaves below them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestTreeBuilder {
+    seed: u64,
+    entries: usize,
+    key_len: (usize, usize),
+    value_len: (usize, usize),
+}
+
+impl TestTreeBuilder {
+    /// Start building a tree of 1000 values from `seed`.
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seed,
+            entries: 1000,
+            key_len: (16, 64),
+            value_len: (32, 32),
+        }
+    }
+
+    /// Set the number of values.
+    pub fn entries(mut self, entries: usize) -> Self {
+        self.entries = entries;
+        self
+    }
+
+    /// Set the range of path lengths, in bytes.
+    ///
+    /// Panics if the range is empty.
+    pub fn key_len(mut self, len: impl RangeBounds<usize>) -> Self {
+        self.key_len = inclusive_bounds(len);
+        self
+    }
+
+    /// Set the range of value lengths, in bytes.
+    ///
+    /// Panics if the range is empty.
+    pub fn value_len(mut self, len: impl RangeBounds<usize>) -> Self {
+        self.value_len = inclusive_bounds(len);
+        self
+    }
+
+    /// Generate the values, in the order they're inserted into the tree by
+    /// [`build`](Self::build). Paths are all distinct.
+    ///
+    /// Panics if there aren't enough distinct paths within the configured lengths.
+    pub fn generate(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let (min_len, max_len) = self.key_len;
+        let available = (min_len..=max_len)
+            .map(|len| 256f64.powi(len.min(8) as i32))
+            .sum::<f64>();
+        assert!(
+            available >= self.entries as f64,
+            "not enough distinct paths for {} values",
+            self.entries,
+        );
+
+        let mut rng = SplitMix64(self.seed);
+        let mut paths = HashSet::with_capacity(self.entries);
+        let mut entries = Vec::with_capacity(self.entries);
+        while entries.len() < self.entries {
+            let path = rng.bytes(self.key_len);
+            if paths.insert(path.clone()) {
+                entries.push((path, rng.bytes(self.value_len)));
+            }
+        }
+
+        entries
+    }
+
+    /// Build the tree.
+    pub fn build<H>(&self) -> PatriciaMerkleTree<Vec<u8>, Vec<u8>, H>
+    where
+        H: Digest,
+    {
+        let mut tree = PatriciaMerkleTree::new();
+        for (path, value) in self.generate() {
+            tree.insert(path, value);
+        }
+
+        tree
+    }
+}
+
+fn inclusive_bounds(range: impl RangeBounds<usize>) -> (usize, usize) {
+    let start = match range.start_bound() {
+        Bound::Included(x) => *x,
+        Bound::Excluded(x) => x + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(x) => *x,
+        Bound::Excluded(x) => x.checked_sub(1).expect("empty range"),
+        Bound::Unbounded => panic!("unbounded length"),
+    };
+    assert!(start <= end, "empty range");
+
+    (start, end)
+}
+
+/// A small, fast PRNG whose output only depends on its seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut x = self.0;
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^ (x >> 31)
+    }
+
+    /// Return random bytes, with a length within `(min, max)` (inclusive).
+    fn bytes(&mut self, (min, max): (usize, usize)) -> Vec<u8> {
+        let len = min + (self.next_u64() % (max - min + 1) as u64) as usize;
+        let mut bytes = Vec::with_capacity(len + 8);
+        while bytes.len() < len {
+            bytes.extend_from_slice(&self.next_u64().to_le_bytes());
+        }
+        bytes.truncate(len);
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sha3::Keccak256;
+
+    #[test]
+    fn test_tree_builder() {
+        let builder = TestTreeBuilder::seeded(7).entries(500).key_len(16..64);
+        let mut tree = builder.build::<Keccak256>();
+        assert_eq!(tree.len(), 500);
+        assert_eq!(
+            tree.compute_hash(),
+            builder.clone().build::<Keccak256>().compute_hash()
+        );
+        assert_ne!(
+            tree.compute_hash(),
+            TestTreeBuilder::seeded(8)
+                .entries(500)
+                .key_len(16..64)
+                .build::<Keccak256>()
+                .compute_hash()
+        );
+
+        let entries = builder.generate();
+        assert!(entries
+            .iter()
+            .all(|(path, value)| (16..64).contains(&path.len()) && value.len() == 32));
+        for (path, value) in &entries {
+            assert_eq!(tree.get(path), Some(value));
+        }
+
+        let entries = TestTreeBuilder::seeded(7)
+            .entries(256)
+            .key_len(1..=1)
+            .value_len(0..=4)
+            .generate();
+        assert_eq!(entries.len(), 256);
+        assert!(entries.iter().all(|(_, value)| value.len() <= 4));
+    }
+}
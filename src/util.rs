@@ -7,6 +7,13 @@ use crate::{
 use digest::{Digest, Output};
 use std::{borrow::Cow, cmp::max, fmt::Debug};
 
+/// Compute the root hash of the tree holding the items of a strictly ascending (by encoded path)
+/// iterator, without building the tree.
+///
+/// Only the rightmost path of the tree is kept in memory, so it takes `O(n·l)` time and `O(l)`
+/// memory (plus the hashes of its branches) for `n` items with paths of up to `l` nibbles.
+///
+/// Panics if the paths are not strictly ascending.
 pub fn compute_hash_from_sorted_iter<'a, P, V, H>(
     iter: impl IntoIterator<Item = &'a (P, V)>,
 ) -> Output<H>
@@ -49,7 +56,7 @@ where
                     value.as_ref(),
                 );
             }
-            (None, None) => unreachable!(),
+            (None, None) => unreachable!("frames always have a value or children"),
         }
 
         hash
@@ -129,8 +136,12 @@ where
         let path = path.encode();
         let value = value.encode();
 
-        if let Some(_top_frame) = stack.last() {
-            // TODO: Assert that path > top_frame.prefix.
+        // The top frame is always the previous leaf, which hasn't been truncated yet.
+        if let Some(top_frame) = stack.last() {
+            assert!(
+                path.as_ref() > top_frame.prefix.as_bytes(),
+                "paths must be strictly ascending"
+            );
             pop_until_target(&mut stack, path.as_ref());
         }
         stack.push(StackFrame::new_leaf(path, value));
@@ -191,7 +202,7 @@ impl<'a> NibblePrefix<'a> {
     }
 
     pub fn get_nth(&self, index: usize) -> Nibble {
-        Nibble::try_from(if index % 2 == 0 {
+        Nibble::try_from(if index & 1 == 0 {
             self.0[index >> 1] >> 4
         } else {
             // Check out of bounds when ending in half-byte.
@@ -205,7 +216,7 @@ impl<'a> NibblePrefix<'a> {
     }
 
     pub fn truncate(&mut self, prefix_len: usize) {
-        self.1 = prefix_len % 2 != 0;
+        self.1 = prefix_len & 1 != 0;
         match &mut self.0 {
             Cow::Borrowed(x) => *x = &x[..(prefix_len + 1) >> 1],
             Cow::Owned(x) => x.truncate((prefix_len + 1) >> 1),
@@ -216,11 +227,12 @@ impl<'a> NibblePrefix<'a> {
         let count = self
             .0
             .iter()
-            .take(self.len() - self.1 as usize)
+            .take(self.0.len() - self.1 as usize)
             .zip(other.iter())
             .take_while(|(a, b)| a == b)
             .count();
 
+        // Only the high nibble of a truncated last byte is part of the prefix.
         if let (Some(a), Some(b)) = (self.0.get(count), other.get(count)) {
             if a >> 4 == b >> 4 {
                 return 2 * count + 1;
@@ -301,6 +313,49 @@ mod test {
         assert_eq!(computed_hash.as_slice(), expected_hash.as_slice());
     }
 
+    #[test]
+    fn test_prefix_paths() {
+        // Values in branches, behind extensions and at the root.
+        for data in [
+            vec![(vec![0x12], vec![0x01]), (vec![0x12, 0x34], vec![0x02])],
+            vec![
+                (vec![], vec![0x01]),
+                (vec![0x12], vec![0x02]),
+                (vec![0x12, 0x34], vec![0x03]),
+                (vec![0x12, 0x35], vec![0x04]),
+                (vec![0x13], vec![0x05]),
+            ],
+            vec![
+                (vec![0x12, 0x30], vec![0x01]),
+                (vec![0x12, 0x34, 0x56], vec![0x02]),
+            ],
+        ] {
+            let mut tree = PatriciaMerkleTree::<_, _, Keccak256>::new();
+            for (path, value) in &data {
+                tree.insert(path.clone(), value.clone());
+            }
+
+            assert_eq!(
+                compute_hash_from_sorted_iter::<_, _, Keccak256>(data.iter()),
+                *tree.compute_hash()
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly ascending")]
+    fn test_unsorted_paths() {
+        const DATA: &[(&[u8], &[u8])] = &[(&[0x20], &[0x00]), (&[0x10], &[0x00])];
+        compute_hash_from_sorted_iter::<_, _, Keccak256>(DATA.iter());
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly ascending")]
+    fn test_duplicate_paths() {
+        const DATA: &[(&[u8], &[u8])] = &[(&[0x10], &[0x00]), (&[0x10], &[0x01])];
+        compute_hash_from_sorted_iter::<_, _, Keccak256>(DATA.iter());
+    }
+
     proptest! {
         #[test]
         fn proptest_compare_hashes_simple(path in vec(any::<u8>(), 1..32), value in vec(any::<u8>(), 1..100)) {
@@ -0,0 +1,176 @@
+use crate::{DecodeError, Encode, PatriciaMerkleTree};
+use digest::{Digest, Output};
+
+/// A transformation applied to values when they enter and leave a [`CodecTree`], such as
+/// compression or encryption.
+///
+/// The tree only holds the transformed bytes, which are also the ones hashed and included in
+/// proofs.
+pub trait ValueCodec {
+    /// Transform a value to be stored.
+    fn encode(&self, value: &[u8]) -> Vec<u8>;
+
+    /// Restore a value from its stored form.
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, DecodeError>;
+}
+
+/// A tree whose values are transformed by a [`ValueCodec`] when they're inserted, and restored
+/// when they're read.
+#[derive(Clone, Debug)]
+pub struct CodecTree<P, C, H>
+where
+    P: Encode,
+    C: ValueCodec,
+    H: Digest,
+{
+    tree: PatriciaMerkleTree<P, Vec<u8>, H>,
+    codec: C,
+}
+
+impl<P, C, H> CodecTree<P, C, H>
+where
+    P: Encode,
+    C: ValueCodec,
+    H: Digest,
+{
+    /// Create an empty tree.
+    pub fn new(codec: C) -> Self {
+        Self {
+            tree: PatriciaMerkleTree::new(),
+            codec,
+        }
+    }
+
+    /// Use an existing tree, whose values were already transformed by `codec`.
+    pub fn from_tree(tree: PatriciaMerkleTree<P, Vec<u8>, H>, codec: C) -> Self {
+        Self { tree, codec }
+    }
+
+    /// Return the underlying tree, whose values are transformed.
+    pub fn tree(&self) -> &PatriciaMerkleTree<P, Vec<u8>, H> {
+        &self.tree
+    }
+
+    /// Consume the wrapper, returning the underlying tree.
+    pub fn into_tree(self) -> PatriciaMerkleTree<P, Vec<u8>, H> {
+        self.tree
+    }
+
+    /// Return whether the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Return the number of values in the tree.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Retrieve and restore a value given its path.
+    pub fn get(&self, path: &P) -> Result<Option<Vec<u8>>, DecodeError> {
+        self.tree
+            .get(path)
+            .map(|x| self.codec.decode(x))
+            .transpose()
+    }
+
+    /// Transform and insert a value, returning the previous one restored (if any).
+    pub fn insert(&mut self, path: P, value: &[u8]) -> Result<Option<Vec<u8>>, DecodeError> {
+        self.tree
+            .insert(path, self.codec.encode(value))
+            .map(|x| self.codec.decode(&x))
+            .transpose()
+    }
+
+    /// Remove a value, returning it restored (if any).
+    pub fn remove(&mut self, path: P) -> Result<Option<Vec<u8>>, DecodeError> {
+        self.tree
+            .remove(path)
+            .map(|x| self.codec.decode(&x))
+            .transpose()
+    }
+
+    /// Iterate over the restored values in ascending path order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(&P, Vec<u8>), DecodeError>> {
+        self.tree
+            .iter()
+            .map(|(path, value)| Ok((path, self.codec.decode(value)?)))
+    }
+
+    /// Return the root hash, computed over the transformed values.
+    pub fn compute_hash(&mut self) -> &Output<H> {
+        self.tree.compute_hash()
+    }
+
+    /// Return the proof of the transformed value at `path` (see
+    /// [`PatriciaMerkleTree::get_proof`]).
+    pub fn get_proof(&mut self, path: &P) -> Vec<Vec<u8>> {
+        self.tree.get_proof(path)
+    }
+
+    /// Calculate approximated memory usage (both used and allocated).
+    pub fn memory_usage(&self) -> (usize, usize) {
+        self.tree.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::verify_proof;
+    use sha3::Keccak256;
+
+    /// Run-length encoding, as pairs of counts and bytes.
+    struct RunLength;
+
+    impl ValueCodec for RunLength {
+        fn encode(&self, value: &[u8]) -> Vec<u8> {
+            let mut data = Vec::new();
+            for chunk in value.chunk_by(|a, b| a == b) {
+                for run in chunk.chunks(255) {
+                    data.extend([run.len() as u8, run[0]]);
+                }
+            }
+            data
+        }
+
+        fn decode(&self, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+            let runs = data.chunks_exact(2);
+            if !runs.remainder().is_empty() {
+                return Err(DecodeError::InvalidValue);
+            }
+            Ok(runs.flat_map(|x| vec![x[1]; x[0] as usize]).collect())
+        }
+    }
+
+    #[test]
+    fn codec_tree() {
+        let mut tree = CodecTree::<Vec<u8>, _, Keccak256>::new(RunLength);
+        let value = [vec![0; 1000], vec![1; 24]].concat();
+        assert_eq!(tree.insert(vec![0x12], &value), Ok(None));
+        assert_eq!(tree.insert(vec![0x34], &[7, 7, 8]), Ok(None));
+        assert_eq!(tree.get(&vec![0x12]), Ok(Some(value.clone())));
+        assert_eq!(tree.tree().get(&vec![0x34]), Some(&vec![2, 7, 1, 8]));
+        assert!(tree.tree().get(&vec![0x12]).unwrap().len() < 16);
+
+        let values = tree.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(values, [(&vec![0x12], value), (&vec![0x34], vec![7, 7, 8])]);
+
+        // Hashes and proofs are over the transformed values.
+        let root = *tree.compute_hash();
+        let proof = tree.get_proof(&vec![0x34]);
+        assert_eq!(
+            verify_proof::<Keccak256>(&root, &[0x34], proof),
+            Some(Some(vec![2, 7, 1, 8]))
+        );
+
+        assert_eq!(tree.remove(vec![0x34]), Ok(Some(vec![7, 7, 8])));
+        let tree = CodecTree::from_tree(tree.into_tree(), RunLength);
+        assert_eq!(tree.len(), 1);
+
+        let mut raw = tree.into_tree();
+        raw.insert(vec![0x56], vec![1]);
+        let tree = CodecTree::from_tree(raw, RunLength);
+        assert_eq!(tree.get(&vec![0x56]), Err(DecodeError::InvalidValue));
+    }
+}
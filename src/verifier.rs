@@ -0,0 +1,278 @@
+//! Proof verification without allocations.
+//!
+//! Everything here only depends on `core` and `digest`: nodes and values are borrowed from the
+//! proof, and only the current node and the hash it's expected to have are kept on the stack.
+//! This makes it suitable for zkVM guests and embedded targets, where allocating is expensive or
+//! not possible at all.
+
+use crate::rlp::{decode_item, expect_list, DecodeError, RlpItem};
+use core::{fmt, iter};
+use digest::Digest;
+
+/// Errors found while verifying a proof with [`verify_proof_in_place`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VerifyError {
+    /// The node at some position of the proof doesn't have the hash its parent (or the root)
+    /// refers to.
+    HashMismatch { index: usize },
+    /// The proof ends before reaching the path's value (or where it diverges from the trie).
+    MissingNode { index: usize },
+    /// A node along the path isn't a valid node.
+    UndecodableNode { index: usize, error: DecodeError },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::HashMismatch { index } => write!(f, "node {index} has an unexpected hash"),
+            VerifyError::MissingNode { index } => write!(f, "missing node {index}"),
+            VerifyError::UndecodableNode { index, error } => {
+                write!(f, "undecodable node {index}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Where a single node leads a lookup.
+enum Step<'a> {
+    Value(Option<&'a [u8]>),
+    Hashed(&'a [u8]),
+    Inline(&'a [u8]),
+}
+
+/// Verify a proof generated by [`get_proof`](crate::PatriciaMerkleTree::get_proof) and return the
+/// value at `path`, or `None` if the proof shows there isn't one.
+///
+/// Unlike [`verify_proof`](crate::verify_proof), nodes must be in the order they were generated
+/// (from the root down) and the returned value borrows from them. Nodes after the ones needed to
+/// reach the value are ignored.
+pub fn verify_proof_in_place<'a, H>(
+    root: &[u8],
+    path: &[u8],
+    proof: &[&'a [u8]],
+) -> Result<Option<&'a [u8]>, VerifyError>
+where
+    H: Digest,
+{
+    if root == &H::digest([0x80])[..] {
+        return Ok(None);
+    }
+
+    let mut offset = 0;
+    let mut index = 0;
+    let mut encoded = next_node::<H>(proof, index, root)?;
+    loop {
+        let step = step(encoded, path, &mut offset)
+            .map_err(|error| VerifyError::UndecodableNode { index, error })?;
+
+        encoded = match step {
+            Step::Value(value) => return Ok(value),
+            Step::Hashed(hash) => {
+                index += 1;
+                next_node::<H>(proof, index, hash)?
+            }
+            Step::Inline(encoded) => encoded,
+        };
+    }
+}
+
+/// Return the node at `index`, checking it has the expected hash.
+fn next_node<'a, H>(proof: &[&'a [u8]], index: usize, hash: &[u8]) -> Result<&'a [u8], VerifyError>
+where
+    H: Digest,
+{
+    let encoded = *proof.get(index).ok_or(VerifyError::MissingNode { index })?;
+    if &H::digest(encoded)[..] != hash {
+        return Err(VerifyError::HashMismatch { index });
+    }
+
+    Ok(encoded)
+}
+
+/// Follow `path` (starting at nibble `offset`) through a single node, advancing the offset past
+/// the nibbles it consumes.
+fn step<'a>(encoded: &'a [u8], path: &[u8], offset: &mut usize) -> Result<Step<'a>, DecodeError> {
+    let payload = expect_list(encoded)?;
+    let mut items = Items(payload);
+
+    let first = items.next_item()?;
+    let second = items.next_item()?;
+    if items.0.is_empty() {
+        let prefix = match first.0 {
+            RlpItem::Bytes(x) => HexPrefix::decode(x)?,
+            RlpItem::List(_) => return Err(DecodeError::ExpectedBytes),
+        };
+        let remaining = 2 * path.len() - *offset;
+        let matches = prefix.len() <= remaining
+            && (0..prefix.len()).all(|i| prefix.nibble(i) == nibble(path, *offset + i));
+
+        return if prefix.is_leaf {
+            match second.0 {
+                RlpItem::Bytes(value) if matches && prefix.len() == remaining => {
+                    Ok(Step::Value(Some(value)))
+                }
+                RlpItem::Bytes(_) => Ok(Step::Value(None)),
+                RlpItem::List(_) => Err(DecodeError::ExpectedBytes),
+            }
+        } else if prefix.len() == 0 {
+            Err(DecodeError::InvalidPath)
+        } else if matches {
+            *offset += prefix.len();
+            child(second)?.ok_or(DecodeError::InvalidChild)
+        } else {
+            Ok(Step::Value(None))
+        };
+    }
+
+    // A branch: every item is read to make sure there are exactly 17 of them.
+    let choice = (*offset < 2 * path.len()).then(|| nibble(path, *offset) as usize);
+    let (mut selected, mut value) = (None, None);
+    let mut count = 0;
+    let rest = iter::from_fn(|| (!items.0.is_empty()).then(|| items.next_item()));
+    for item in [Ok(first), Ok(second)].into_iter().chain(rest) {
+        let item = item?;
+        match count {
+            16 => value = Some(item),
+            i if Some(i) == choice => selected = Some(item),
+            _ => {}
+        }
+        count += 1;
+    }
+
+    match (count, selected, value) {
+        (17, Some(selected), _) => {
+            *offset += 1;
+            Ok(child(selected)?.unwrap_or(Step::Value(None)))
+        }
+        (17, None, Some((RlpItem::Bytes([]), _))) => Ok(Step::Value(None)),
+        (17, None, Some((RlpItem::Bytes(value), _))) => Ok(Step::Value(Some(value))),
+        (17, None, _) => Err(DecodeError::ExpectedBytes),
+        (count, _, _) => Err(DecodeError::InvalidItemCount(count)),
+    }
+}
+
+/// Decode a reference to a child, which is `None` if empty.
+fn child<'a>((item, raw): (RlpItem<'a>, &'a [u8])) -> Result<Option<Step<'a>>, DecodeError> {
+    match item {
+        RlpItem::Bytes([]) => Ok(None),
+        RlpItem::Bytes(hash) if hash.len() == 32 => Ok(Some(Step::Hashed(hash))),
+        RlpItem::List(_) if raw.len() < 32 => Ok(Some(Step::Inline(raw))),
+        _ => Err(DecodeError::InvalidChild),
+    }
+}
+
+/// Return the nibble at `index` of a path.
+fn nibble(path: &[u8], index: usize) -> u8 {
+    match index % 2 {
+        0 => path[index / 2] >> 4,
+        _ => path[index / 2] & 0x0F,
+    }
+}
+
+/// The items remaining within a list's payload.
+struct Items<'a>(&'a [u8]);
+
+impl<'a> Items<'a> {
+    fn next_item(&mut self) -> Result<(RlpItem<'a>, &'a [u8]), DecodeError> {
+        let (item, raw, rest) = decode_item(self.0)?;
+        self.0 = rest;
+
+        Ok((item, raw))
+    }
+}
+
+/// A hex-prefix encoded path, borrowed.
+struct HexPrefix<'a> {
+    is_leaf: bool,
+    is_odd: bool,
+    data: &'a [u8],
+}
+
+impl<'a> HexPrefix<'a> {
+    fn decode(data: &'a [u8]) -> Result<Self, DecodeError> {
+        let flag = *data.first().ok_or(DecodeError::InvalidPath)?;
+        let (is_leaf, is_odd) = match flag >> 4 {
+            0 => (false, false),
+            1 => (false, true),
+            2 => (true, false),
+            3 => (true, true),
+            _ => return Err(DecodeError::InvalidPath),
+        };
+        if !is_odd && flag & 0x0F != 0 {
+            return Err(DecodeError::InvalidPath);
+        }
+
+        Ok(Self {
+            is_leaf,
+            is_odd,
+            data,
+        })
+    }
+
+    fn len(&self) -> usize {
+        2 * (self.data.len() - 1) + self.is_odd as usize
+    }
+
+    fn nibble(&self, index: usize) -> u8 {
+        // Odd paths start at the flag's low nibble, even ones at the next byte.
+        nibble(self.data, index + if self.is_odd { 1 } else { 2 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{pmt_tree, verify_proof};
+    use sha3::Keccak256;
+
+    #[test]
+    fn verify_in_place() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        for i in 0..256u16 {
+            let path = i.wrapping_mul(40503).to_be_bytes().to_vec();
+            tree.insert(path, vec![i as u8; 1 + i as usize % 40]);
+        }
+        tree.insert(vec![0x12], vec![1]);
+        tree.insert(vec![0x12, 0x34, 0x56], vec![2; 40]);
+        let root = *tree.compute_hash();
+
+        let mut paths = tree.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        paths.extend([
+            vec![],
+            vec![0x12, 0x34],
+            vec![0x12, 0x34, 0x56, 0x78],
+            vec![0xFF; 3],
+        ]);
+        for path in paths {
+            let proof = tree.get_proof(&path);
+            let borrowed = proof.iter().map(Vec::as_slice).collect::<Vec<_>>();
+
+            let value = verify_proof_in_place::<Keccak256>(&root, &path, &borrowed).unwrap();
+            let expected = verify_proof::<Keccak256>(&root, &path, proof.clone()).unwrap();
+            assert_eq!(value.map(<[u8]>::to_vec), expected);
+            assert_eq!(value, tree.get(&path).map(Vec::as_slice));
+        }
+
+        let path = vec![0x12, 0x34, 0x56];
+        let mut proof = tree.get_proof(&path);
+        let last = proof.len() - 1;
+        assert_eq!(
+            verify_proof_in_place::<Keccak256>(&root, &path, &[proof[0].as_slice()]),
+            Err(VerifyError::MissingNode { index: 1 }),
+        );
+        *proof[last].last_mut().unwrap() ^= 1;
+        let borrowed = proof.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        assert_eq!(
+            verify_proof_in_place::<Keccak256>(&root, &path, &borrowed),
+            Err(VerifyError::HashMismatch { index: last }),
+        );
+
+        let empty = Keccak256::digest([0x80]);
+        assert_eq!(
+            verify_proof_in_place::<Keccak256>(&empty, &path, &[]),
+            Ok(None)
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// A change to a watched value, with paths and values encoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChangeEvent {
+    Insert {
+        path: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Update {
+        path: Vec<u8>,
+        old_value: Vec<u8>,
+        new_value: Vec<u8>,
+    },
+    Remove {
+        path: Vec<u8>,
+        old_value: Vec<u8>,
+    },
+}
+
+impl ChangeEvent {
+    /// Return the encoded path of the changed value.
+    pub fn path(&self) -> &[u8] {
+        match self {
+            ChangeEvent::Insert { path, .. }
+            | ChangeEvent::Update { path, .. }
+            | ChangeEvent::Remove { path, .. } => path,
+        }
+    }
+}
+
+/// The subscribers to a tree's changes, along with the path prefix each one watches.
+///
+/// Clones of a tree are independent from it, so they start without subscribers.
+#[derive(Debug, Default)]
+pub(crate) struct Watchers(Vec<(Vec<u8>, Sender<ChangeEvent>)>);
+
+impl Clone for Watchers {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Watchers {
+    pub fn add(&mut self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.0.push((prefix.to_vec(), sender));
+        receiver
+    }
+
+    /// Return whether changes at `path` (encoded) have to be notified.
+    pub fn is_watched(&self, path: &[u8]) -> bool {
+        self.0.iter().any(|(prefix, _)| path.starts_with(prefix))
+    }
+
+    /// Send the event to every subscriber watching its path, dropping those which are gone.
+    pub fn notify(&mut self, event: ChangeEvent) {
+        self.0.retain(|(prefix, sender)| {
+            !event.path().starts_with(prefix) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pmt_tree;
+
+    #[test]
+    fn watch_prefix() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let receiver = tree.watch(b"do");
+
+        tree.insert(b"dog".to_vec(), b"puppy".to_vec());
+        tree.insert(b"horse".to_vec(), b"stallion".to_vec());
+        tree.insert(b"dog".to_vec(), b"hound".to_vec());
+        tree.remove(b"dog".to_vec());
+        tree.remove(b"doge".to_vec());
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [
+                ChangeEvent::Insert {
+                    path: b"dog".to_vec(),
+                    value: b"puppy".to_vec(),
+                },
+                ChangeEvent::Update {
+                    path: b"dog".to_vec(),
+                    old_value: b"puppy".to_vec(),
+                    new_value: b"hound".to_vec(),
+                },
+                ChangeEvent::Remove {
+                    path: b"dog".to_vec(),
+                    old_value: b"hound".to_vec(),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn dropped_receiver() {
+        let mut tree = pmt_tree!(Vec<u8>);
+        let receiver = tree.watch(b"");
+        drop(receiver);
+
+        tree.insert(b"dog".to_vec(), b"puppy".to_vec());
+        assert!(!tree.watchers.is_watched(b"dog"));
+        assert!(!tree.clone().watchers.is_watched(b"dog"));
+    }
+}
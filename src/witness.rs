@@ -0,0 +1,396 @@
+#[cfg(feature = "execution-witness")]
+use crate::hex;
+use crate::{
+    hashing::{NodeEncoder, NodeWriter},
+    rlp::{decode_list, expect_list, DecodeError, RlpItem},
+    Encode, PatriciaMerkleTree,
+};
+use digest::Digest;
+#[cfg(feature = "execution-witness")]
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+#[cfg(feature = "execution-witness")]
+use std::io::Read;
+#[cfg(feature = "execution-witness")]
+use thiserror::Error;
+
+/// An Ethereum address.
+pub type Address = [u8; 20];
+/// A storage slot key (before hashing).
+pub type StorageKey = [u8; 32];
+
+/// Records the nodes, keys and code touched while executing, to build a witness with which the
+/// execution can be replayed statelessly.
+///
+/// Nodes and keys are kept in the order they were first touched, without duplicates.
+#[derive(Clone, Debug, Default)]
+pub struct WitnessRecorder {
+    nodes: Vec<Vec<u8>>,
+    seen_nodes: HashSet<Vec<u8>>,
+    codes: Vec<Vec<u8>>,
+    seen_codes: HashSet<Vec<u8>>,
+    keys: Vec<Vec<u8>>,
+
+    /// Storage slots touched within each account (accounts without slots included).
+    touched: BTreeMap<Address, BTreeSet<StorageKey>>,
+}
+
+impl WitnessRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the nodes needed to prove the value at `path` (or its absence).
+    pub fn record<P, V, H>(&mut self, tree: &mut PatriciaMerkleTree<P, V, H>, path: &P)
+    where
+        P: Encode,
+        V: Encode,
+        H: Digest,
+    {
+        for node in tree.get_proof(path) {
+            if self.seen_nodes.insert(node.clone()) {
+                self.nodes.push(node);
+            }
+        }
+    }
+
+    /// Record an account access within the state tree (indexed by hashed address).
+    pub fn record_account<V, H>(
+        &mut self,
+        state: &mut PatriciaMerkleTree<Vec<u8>, V, H>,
+        address: &Address,
+    ) where
+        V: Encode,
+        H: Digest,
+    {
+        self.record(state, &H::digest(address).to_vec());
+        if !self.touched.contains_key(address) {
+            self.touched.insert(*address, BTreeSet::new());
+            self.keys.push(address.to_vec());
+        }
+    }
+
+    /// Record a storage access within an account's storage tree (indexed by hashed slot).
+    pub fn record_storage<V, H>(
+        &mut self,
+        storage: &mut PatriciaMerkleTree<Vec<u8>, V, H>,
+        address: &Address,
+        slot: &StorageKey,
+    ) where
+        V: Encode,
+        H: Digest,
+    {
+        self.record(storage, &H::digest(slot).to_vec());
+        if !self.touched.contains_key(address) {
+            self.touched.insert(*address, BTreeSet::new());
+            self.keys.push(address.to_vec());
+        }
+        if self.touched.get_mut(address).unwrap().insert(*slot) {
+            self.keys.push(slot.to_vec());
+        }
+    }
+
+    /// Record the bytecode of an executed contract.
+    pub fn record_code(&mut self, code: &[u8]) {
+        if self.seen_codes.insert(code.to_vec()) {
+            self.codes.push(code.to_vec());
+        }
+    }
+
+    /// Return the recorded nodes, in the order they were first touched.
+    pub fn nodes(&self) -> &[Vec<u8>] {
+        &self.nodes
+    }
+
+    /// Return every touched account along with the storage slots touched within it.
+    pub fn touched(&self) -> &BTreeMap<Address, BTreeSet<StorageKey>> {
+        &self.touched
+    }
+
+    /// Build an execution witness from everything recorded.
+    pub fn to_execution_witness(&self) -> ExecutionWitness {
+        ExecutionWitness {
+            state: self.nodes.clone(),
+            codes: self.codes.clone(),
+            keys: self.keys.clone(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Build an EIP-2930 access list from every touched account and storage slot, in ascending
+    /// address and slot order.
+    pub fn to_access_list(&self) -> AccessList {
+        AccessList(
+            self.touched
+                .iter()
+                .map(|(address, slots)| AccessListItem {
+                    address: *address,
+                    storage_keys: slots.iter().copied().collect(),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// An entry of an [`AccessList`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AccessListItem {
+    pub address: Address,
+    pub storage_keys: Vec<StorageKey>,
+}
+
+/// An EIP-2930 access list: the accounts and storage slots a transaction touches.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AccessList(pub Vec<AccessListItem>);
+
+impl AccessList {
+    /// Return the access list's RLP encoding: `[[address, [storage_key, ...]], ...]`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = NodeEncoder::default();
+        for item in &self.0 {
+            let mut storage_keys = NodeEncoder::default();
+            for storage_key in &item.storage_keys {
+                storage_keys.write_bytes(storage_key);
+            }
+            let storage_keys = storage_keys.finalize();
+
+            let mut item_payload = NodeEncoder::default();
+            item_payload.write_bytes(&item.address);
+            item_payload.write_list_header(storage_keys.len());
+            item_payload.write_raw(&storage_keys);
+
+            let item_payload = item_payload.finalize();
+            payload.write_list_header(item_payload.len());
+            payload.write_raw(&item_payload);
+        }
+
+        let payload = payload.finalize();
+        let mut encoder = NodeEncoder::default();
+        encoder.write_list_header(payload.len());
+        encoder.write_raw(&payload);
+        encoder.finalize()
+    }
+
+    /// Decode an access list from its RLP encoding.
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        decode_list(expect_list(data)?)?
+            .iter()
+            .map(|(_, raw)| {
+                let items = decode_list(expect_list(raw)?)?;
+                let [(RlpItem::Bytes(address), _), (_, storage_keys)] = items[..] else {
+                    return Err(DecodeError::InvalidItemCount(items.len()));
+                };
+
+                let storage_keys = decode_list(expect_list(storage_keys)?)?
+                    .iter()
+                    .map(|(item, _)| match item {
+                        RlpItem::Bytes(x) => (*x).try_into().map_err(|_| DecodeError::InvalidHash),
+                        RlpItem::List(_) => Err(DecodeError::ExpectedBytes),
+                    })
+                    .collect::<Result<_, _>>()?;
+
+                Ok(AccessListItem {
+                    address: address.try_into().map_err(|_| DecodeError::InvalidHash)?,
+                    storage_keys,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// A witness for stateless block execution, laid out like the `ExecutionWitness` returned by
+/// `debug_executionWitness` (which it can be converted from and to with the `execution-witness`
+/// feature).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExecutionWitness {
+    /// The RLP-encoded trie nodes (from both the state and storage tries).
+    pub state: Vec<Vec<u8>>,
+    /// The bytecode of every contract executed.
+    pub codes: Vec<Vec<u8>>,
+    /// The preimages of the touched keys (addresses and storage slots).
+    pub keys: Vec<Vec<u8>>,
+    /// The RLP-encoded headers of the ancestor blocks needed (for `BLOCKHASH`).
+    pub headers: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "execution-witness")]
+impl ExecutionWitness {
+    /// Return the witness as the JSON object returned by `debug_executionWitness`, whose fields
+    /// are arrays of `0x`-prefixed hex strings.
+    pub fn to_json(&self) -> String {
+        let hex_items = |items: &[Vec<u8>]| {
+            items
+                .iter()
+                .map(|x| format!("0x{}", hex::encode(x)))
+                .collect::<Vec<_>>()
+        };
+
+        serde_json::json!({
+            "state": hex_items(&self.state),
+            "codes": hex_items(&self.codes),
+            "keys": hex_items(&self.keys),
+            "headers": hex_items(&self.headers),
+        })
+        .to_string()
+    }
+
+    /// Parse a witness from the JSON object returned by `debug_executionWitness`. A missing
+    /// `headers` field is taken as empty.
+    pub fn from_json(reader: impl Read) -> Result<Self, WitnessJsonError> {
+        let witness: ExecutionWitnessJson = serde_json::from_reader(reader)?;
+        let decode_items = |items: Vec<String>, field| {
+            items
+                .iter()
+                .map(|x| hex::decode(x).ok_or(WitnessJsonError::InvalidField(field)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(Self {
+            state: decode_items(witness.state, "state")?,
+            codes: decode_items(witness.codes, "codes")?,
+            keys: decode_items(witness.keys, "keys")?,
+            headers: decode_items(witness.headers, "headers")?,
+        })
+    }
+}
+
+/// Errors found while parsing an [`ExecutionWitness`] from JSON.
+#[cfg(feature = "execution-witness")]
+#[derive(Debug, Error)]
+pub enum WitnessJsonError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid hex string in {0}")]
+    InvalidField(&'static str),
+}
+
+#[cfg(feature = "execution-witness")]
+#[derive(Deserialize)]
+struct ExecutionWitnessJson {
+    state: Vec<String>,
+    codes: Vec<String>,
+    keys: Vec<String>,
+    #[serde(default)]
+    headers: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Account;
+    use sha3::Keccak256;
+
+    type Tree = PatriciaMerkleTree<Vec<u8>, Vec<u8>, Keccak256>;
+
+    fn build_state() -> (Tree, Tree) {
+        let mut storage = Tree::new();
+        for i in 0..16u8 {
+            storage.insert(Keccak256::digest([i; 32]).to_vec(), vec![0x81, 0x80 + i]);
+        }
+
+        let mut state = Tree::new();
+        for i in 0..16u8 {
+            let account = Account {
+                nonce: i as u64,
+                storage_root: storage.compute_hash()[..].try_into().unwrap(),
+                ..Default::default()
+            };
+            state.insert(Keccak256::digest([i; 20]).to_vec(), account.encode());
+        }
+
+        (state, storage)
+    }
+
+    #[test]
+    fn record() {
+        let (mut state, mut storage) = build_state();
+
+        let mut recorder = WitnessRecorder::new();
+        recorder.record_account(&mut state, &[1; 20]);
+        recorder.record_storage(&mut storage, &[1; 20], &[3; 32]);
+        recorder.record_storage(&mut storage, &[1; 20], &[3; 32]);
+        recorder.record_account(&mut state, &[2; 20]);
+        recorder.record_code(&[0x60, 0x00]);
+
+        let witness = recorder.to_execution_witness();
+        assert_eq!(witness.keys, vec![vec![1; 20], vec![3; 32], vec![2; 20]],);
+        assert_eq!(witness.codes, vec![vec![0x60, 0x00]]);
+
+        // Every node needed to read what was touched is included once.
+        let proofs = [
+            state.get_proof(&Keccak256::digest([1; 20]).to_vec()),
+            state.get_proof(&Keccak256::digest([2; 20]).to_vec()),
+            storage.get_proof(&Keccak256::digest([3; 32]).to_vec()),
+        ];
+        for node in proofs.iter().flatten() {
+            assert_eq!(witness.state.iter().filter(|x| *x == node).count(), 1);
+        }
+        assert_eq!(witness.state[0], proofs[0][0]);
+        assert_eq!(recorder.touched()[&[1; 20]].len(), 1);
+        assert!(recorder.touched()[&[2; 20]].is_empty());
+    }
+
+    #[cfg(feature = "execution-witness")]
+    #[test]
+    fn json() {
+        let witness = ExecutionWitness {
+            state: vec![vec![0xC2, 0x80, 0x80], vec![0xAB; 64]],
+            codes: vec![vec![0x60, 0x00]],
+            keys: vec![vec![0x01; 20], vec![0x02; 32]],
+            headers: Vec::new(),
+        };
+
+        let json = witness.to_json();
+        assert_eq!(
+            ExecutionWitness::from_json(json.as_bytes()).unwrap(),
+            witness,
+        );
+        assert_eq!(
+            ExecutionWitness::default().to_json(),
+            r#"{"codes":[],"headers":[],"keys":[],"state":[]}"#,
+        );
+        assert_eq!(
+            ExecutionWitness::from_json(
+                r#"{"state":["0xc0"],"codes":[],"keys":["0x01"]}"#.as_bytes()
+            )
+            .unwrap(),
+            ExecutionWitness {
+                state: vec![vec![0xC0]],
+                keys: vec![vec![0x01]],
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            ExecutionWitness::from_json(r#"{"state":["0xzz"],"codes":[],"keys":[]}"#.as_bytes()),
+            Err(WitnessJsonError::InvalidField("state")),
+        ));
+    }
+
+    #[test]
+    fn access_list() {
+        let (mut state, mut storage) = build_state();
+
+        let mut recorder = WitnessRecorder::new();
+        recorder.record_storage(&mut storage, &[2; 20], &[5; 32]);
+        recorder.record_storage(&mut storage, &[2; 20], &[3; 32]);
+        recorder.record_account(&mut state, &[1; 20]);
+
+        let access_list = recorder.to_access_list();
+        assert_eq!(
+            access_list,
+            AccessList(vec![
+                AccessListItem {
+                    address: [1; 20],
+                    storage_keys: Vec::new(),
+                },
+                AccessListItem {
+                    address: [2; 20],
+                    storage_keys: vec![[3; 32], [5; 32]],
+                },
+            ]),
+        );
+        assert_eq!(AccessList::decode(&access_list.encode()), Ok(access_list));
+        assert_eq!(AccessList::default().encode(), vec![0xC0]);
+    }
+}
@@ -0,0 +1,32 @@
+//! Runs the official `TrieTests` fixtures when `ETHEREUM_TESTS_DIR` points to a checkout of
+//! [ethereum/tests](https://github.com/ethereum/tests).
+
+#![cfg(feature = "eth-tests")]
+
+use patricia_merkle_tree::eth_tests::run_fixtures;
+use sha3::Keccak256;
+use std::{env, fs::File, path::PathBuf};
+
+const FIXTURES: &[(&str, bool)] = &[
+    ("trietest.json", false),
+    ("trieanyorder.json", false),
+    ("trietest_secureTrie.json", true),
+    ("trieanyorder_secureTrie.json", true),
+    ("hex_encoded_securetrie_test.json", true),
+];
+
+#[test]
+fn trie_tests() {
+    let Some(tests_dir) = env::var_os("ETHEREUM_TESTS_DIR") else {
+        eprintln!("ETHEREUM_TESTS_DIR is not set, skipping");
+        return;
+    };
+
+    let fixtures_dir = PathBuf::from(tests_dir).join("TrieTests");
+    for (file_name, secure) in FIXTURES {
+        let file = File::open(fixtures_dir.join(file_name)).unwrap();
+        for result in run_fixtures::<Keccak256>(file, *secure).unwrap() {
+            assert!(result.passed(), "{file_name}: {} failed", result.name);
+        }
+    }
+}
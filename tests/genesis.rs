@@ -0,0 +1,33 @@
+//! Computes the genesis state roots of public networks from their genesis files.
+
+#![cfg(feature = "genesis")]
+
+use hex_literal::hex;
+use patricia_merkle_tree::genesis::genesis_state_root;
+use sha3::Keccak256;
+use std::{fs::File, path::PathBuf};
+
+const NETWORKS: &[(&str, [u8; 32])] = &[
+    (
+        "mainnet.json",
+        hex!("d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544"),
+    ),
+    (
+        "sepolia.json",
+        hex!("5eb6e371a698b8d68f665192350ffcecbbbf322916f4b51bd79bb6887da3f494"),
+    ),
+    (
+        "holesky.json",
+        hex!("69d8c9d72f6fa4ad42d4702b433707212f90db395eb54dc20bc85de253788783"),
+    ),
+];
+
+#[test]
+fn genesis_roots() {
+    let fixtures_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/genesis");
+    for (file_name, expected) in NETWORKS {
+        let file = File::open(fixtures_dir.join(file_name)).unwrap();
+        let root = genesis_state_root::<Keccak256>(file).unwrap();
+        assert_eq!(root[..], expected[..], "{file_name}");
+    }
+}
@@ -0,0 +1,65 @@
+//! Runs the `pmt-inspect` binary against snapshots written by the crate.
+
+#![cfg(feature = "inspect")]
+
+use patricia_merkle_tree::{PatriciaMerkleTree, SnapshotWriter};
+use sha3::Keccak256;
+use std::{fs::File, path::Path, process::Command};
+
+fn inspect(args: &[&str], files: &[&Path]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_pmt-inspect"))
+        .args(args)
+        .args(files)
+        .output()
+        .unwrap();
+
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn inspect_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = dir.path().join("base.pmts");
+    let delta = dir.path().join("delta.pmts");
+
+    let mut tree = PatriciaMerkleTree::<Vec<u8>, Vec<u8>, Keccak256>::new();
+    let mut writer = SnapshotWriter::new();
+    for i in 0..64u8 {
+        tree.insert(vec![i, 0xAA], vec![i; 40]);
+    }
+    writer
+        .checkpoint(&mut tree, File::create(&base).unwrap())
+        .unwrap();
+    tree.insert(vec![0x10, 0xBB], vec![0xFF; 40]);
+    let root = writer
+        .checkpoint(&mut tree, File::create(&delta).unwrap())
+        .unwrap();
+    let files = [base.as_path(), delta.as_path()];
+
+    let (ok, stats) = inspect(&["stats"], &files);
+    assert!(ok);
+    let root_hex = root.iter().map(|x| format!("{x:02x}")).collect::<String>();
+    assert!(stats.starts_with(&format!("root {root_hex}\nvalues 65\n")));
+
+    let (ok, dump) = inspect(&["dump", "--prefix", "10"], &files);
+    assert!(ok);
+    assert!(dump.starts_with("branch {") && dump.contains("[10, bb] => [ff, "));
+    assert!(!dump.contains("[11, aa]"));
+
+    let (ok, report) = inspect(&["verify"], &files);
+    assert!(ok);
+    assert!(report.ends_with("ok\n"));
+
+    let (ok, proof) = inspect(&["proof", "--key", "10bb"], &files);
+    assert!(ok);
+    assert!(proof.ends_with(&format!("value {}\n", "ff".repeat(40))));
+    let (ok, proof) = inspect(&["proof", "--key", "10cc"], &files);
+    assert!(ok && proof.ends_with("absent\n"));
+
+    // Deltas can't be read without their base.
+    assert!(!inspect(&["stats"], &files[1..]).0);
+    assert!(!inspect(&["proof"], &files).0);
+}